@@ -0,0 +1,168 @@
+use std::{error::Error as StdError, fmt};
+
+use regex::Regex;
+use ruma_common::{RoomAliasId, RoomId, UserId};
+use ruma_events::{AnyStateEvent, AnyTimelineEvent, StateEvent, StateEventType};
+
+use crate::{Namespace, Registration};
+
+/// A [`Registration`] with its namespaces compiled to regular expressions.
+///
+/// It can be used to check whether users, room aliases, room IDs and events belong to the
+/// application service. The regular expressions must match the whole value.
+#[derive(Clone, Debug)]
+pub struct CompiledRegistration {
+    registration: Registration,
+    users: Vec<CompiledNamespace>,
+    aliases: Vec<CompiledNamespace>,
+    rooms: Vec<CompiledNamespace>,
+}
+
+impl CompiledRegistration {
+    /// Creates a new `CompiledRegistration` by compiling the namespaces of the given
+    /// registration.
+    ///
+    /// Returns an error if the regex of one of the namespaces is invalid.
+    pub fn new(registration: Registration) -> Result<Self, InvalidNamespaceRegex> {
+        let compile = |namespaces: &[Namespace]| {
+            namespaces.iter().map(CompiledNamespace::new).collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(Self {
+            users: compile(&registration.namespaces.users)?,
+            aliases: compile(&registration.namespaces.aliases)?,
+            rooms: compile(&registration.namespaces.rooms)?,
+            registration,
+        })
+    }
+
+    /// Get the registration of the application service.
+    pub fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    /// Convert this `CompiledRegistration` back into the registration of the application service.
+    pub fn into_registration(self) -> Registration {
+        self.registration
+    }
+
+    /// Whether the given user ID is in one of the user namespaces.
+    pub fn is_user_match(&self, user_id: &UserId) -> bool {
+        is_match(&self.users, user_id.as_str())
+    }
+
+    /// Whether the given user ID is in one of the exclusive user namespaces.
+    pub fn is_exclusive_user_match(&self, user_id: &UserId) -> bool {
+        is_exclusive_match(&self.users, user_id.as_str())
+    }
+
+    /// Whether the given room alias is in one of the alias namespaces.
+    pub fn is_alias_match(&self, alias: &RoomAliasId) -> bool {
+        is_match(&self.aliases, alias.as_str())
+    }
+
+    /// Whether the given room alias is in one of the exclusive alias namespaces.
+    pub fn is_exclusive_alias_match(&self, alias: &RoomAliasId) -> bool {
+        is_exclusive_match(&self.aliases, alias.as_str())
+    }
+
+    /// Whether the given room ID is in one of the room namespaces.
+    pub fn is_room_match(&self, room_id: &RoomId) -> bool {
+        is_match(&self.rooms, room_id.as_str())
+    }
+
+    /// Whether the application service is [interested] in the given event.
+    ///
+    /// This is the case if the sender of the event, the target of a membership event or one of
+    /// the given members of the room is in the user namespaces, if the room ID is in the room
+    /// namespaces, or if the event is an `m.room.canonical_alias` event with an alias in the alias
+    /// namespaces.
+    ///
+    /// The other aliases of the room are not known from the event, so they should be checked
+    /// separately with [`CompiledRegistration::is_alias_match()`].
+    ///
+    /// [interested]: https://spec.matrix.org/latest/application-service-api/#registration
+    pub fn is_interested_in_event<'a>(
+        &self,
+        event: &AnyTimelineEvent,
+        members: impl IntoIterator<Item = &'a UserId>,
+    ) -> bool {
+        if self.is_user_match(event.sender()) || self.is_room_match(event.room_id()) {
+            return true;
+        }
+
+        if let AnyTimelineEvent::State(event) = event {
+            if event.event_type() == StateEventType::RoomMember
+                && UserId::parse(event.state_key())
+                    .is_ok_and(|user_id| self.is_user_match(&user_id))
+            {
+                return true;
+            }
+
+            if let AnyStateEvent::RoomCanonicalAlias(StateEvent::Original(event)) = event {
+                let content = &event.content;
+                if content.alias.iter().chain(&content.alt_aliases).any(|a| self.is_alias_match(a))
+                {
+                    return true;
+                }
+            }
+        }
+
+        members.into_iter().any(|user_id| self.is_user_match(user_id))
+    }
+}
+
+impl TryFrom<Registration> for CompiledRegistration {
+    type Error = InvalidNamespaceRegex;
+
+    fn try_from(registration: Registration) -> Result<Self, Self::Error> {
+        Self::new(registration)
+    }
+}
+
+/// An error when compiling the regex of a [`Namespace`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct InvalidNamespaceRegex {
+    /// The invalid regex.
+    pub regex: String,
+
+    /// The error returned by the regex compiler.
+    pub error: regex::Error,
+}
+
+impl fmt::Display for InvalidNamespaceRegex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid namespace regex `{}`: {}", self.regex, self.error)
+    }
+}
+
+impl StdError for InvalidNamespaceRegex {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CompiledNamespace {
+    exclusive: bool,
+    regex: Regex,
+}
+
+impl CompiledNamespace {
+    pub(crate) fn new(namespace: &Namespace) -> Result<Self, InvalidNamespaceRegex> {
+        // Namespaces must match the whole value.
+        let regex = Regex::new(&format!("^(?:{})$", namespace.regex))
+            .map_err(|error| InvalidNamespaceRegex { regex: namespace.regex.clone(), error })?;
+
+        Ok(Self { exclusive: namespace.exclusive, regex })
+    }
+}
+
+fn is_match(namespaces: &[CompiledNamespace], value: &str) -> bool {
+    namespaces.iter().any(|namespace| namespace.regex.is_match(value))
+}
+
+fn is_exclusive_match(namespaces: &[CompiledNamespace], value: &str) -> bool {
+    namespaces.iter().any(|namespace| namespace.exclusive && namespace.regex.is_match(value))
+}
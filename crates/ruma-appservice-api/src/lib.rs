@@ -9,11 +9,20 @@
 
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "regex")]
+mod compiled_registration;
 pub mod event;
 pub mod ping;
 pub mod query;
+#[cfg(feature = "receiver")]
+pub mod receiver;
+#[cfg(feature = "yaml")]
+pub mod registration;
 pub mod thirdparty;
 
+#[cfg(feature = "regex")]
+pub use self::compiled_registration::{CompiledRegistration, InvalidNamespaceRegex};
+
 /// A namespace defined by an application service.
 ///
 /// Used for [appservice registration](https://spec.matrix.org/latest/application-service-api/#registration).
@@ -74,7 +83,9 @@ pub struct Registration {
     pub id: String,
 
     /// The URL for the application service.
-    pub url: String,
+    ///
+    /// `None` for application services that don't receive transactions from the homeserver.
+    pub url: Option<String>,
 
     /// A unique token for application services to use to authenticate requests to Homeservers.
     pub as_token: String,
@@ -97,6 +108,29 @@ pub struct Registration {
     /// The external protocols which the application service provides (e.g. IRC).
     #[serde(skip_serializing_if = "Option::is_none")]
     pub protocols: Option<Vec<String>>,
+
+    /// Whether the application service wants to receive ephemeral data, according to [MSC2409].
+    ///
+    /// [MSC2409]: https://github.com/matrix-org/matrix-spec-proposals/pull/2409
+    #[cfg(feature = "unstable-msc2409")]
+    #[serde(
+        default,
+        rename = "de.sorunome.msc2409.push_ephemeral",
+        skip_serializing_if = "ruma_common::serde::is_default"
+    )]
+    pub receive_ephemeral: bool,
+
+    /// Whether the application service wants to receive the device list changes and one-time key
+    /// counts of its users, according to [MSC3202].
+    ///
+    /// [MSC3202]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
+    #[cfg(feature = "unstable-msc3202")]
+    #[serde(
+        default,
+        rename = "org.matrix.msc3202",
+        skip_serializing_if = "ruma_common::serde::is_default"
+    )]
+    pub device_management: bool,
 }
 
 /// Initial set of fields of `Registration`.
@@ -112,7 +146,9 @@ pub struct RegistrationInit {
     pub id: String,
 
     /// The URL for the application service.
-    pub url: String,
+    ///
+    /// `None` for application services that don't receive transactions from the homeserver.
+    pub url: Option<String>,
 
     /// A unique token for application services to use to authenticate requests to Homeservers.
     pub as_token: String,
@@ -147,6 +183,19 @@ impl From<RegistrationInit> for Registration {
             rate_limited,
             protocols,
         } = init;
-        Self { id, url, as_token, hs_token, sender_localpart, namespaces, rate_limited, protocols }
+        Self {
+            id,
+            url,
+            as_token,
+            hs_token,
+            sender_localpart,
+            namespaces,
+            rate_limited,
+            protocols,
+            #[cfg(feature = "unstable-msc2409")]
+            receive_ephemeral: false,
+            #[cfg(feature = "unstable-msc3202")]
+            device_management: false,
+        }
     }
 }
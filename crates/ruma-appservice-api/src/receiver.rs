@@ -0,0 +1,420 @@
+//! Processing of the transactions pushed by the homeserver to an application service.
+//!
+//! A [`TransactionReceiver`] checks the `hs_token` of the transactions received with the
+//! [`push_events`] endpoint, ignores the transactions it has already processed and passes their
+//! contents, in order, to a [`TransactionHandler`].
+
+#[cfg(feature = "unstable-msc3202")]
+use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    convert::Infallible,
+    error::Error as StdError,
+    fmt,
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+#[cfg(feature = "unstable-msc3202")]
+use js_int::UInt;
+use ruma_common::{serde::Raw, OwnedTransactionId, TransactionId};
+#[cfg(feature = "unstable-msc3202")]
+use ruma_common::{DeviceKeyAlgorithm, OwnedDeviceId, OwnedUserId};
+use ruma_events::AnyTimelineEvent;
+#[cfg(feature = "unstable-msc2409")]
+use ruma_events::AnyToDeviceEvent;
+
+use crate::event::push_events;
+#[cfg(feature = "unstable-msc3202")]
+use crate::event::push_events::v1::DeviceLists;
+#[cfg(feature = "unstable-msc2409")]
+use crate::event::push_events::v1::Edu;
+
+/// Storage for the IDs of the transactions that were already processed.
+#[async_trait]
+pub trait TransactionStore: Sync {
+    /// The error type of the storage.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Whether the transaction with the given ID was already processed.
+    async fn is_processed(&self, txn_id: &TransactionId) -> Result<bool, Self::Error>;
+
+    /// Remember that the transaction with the given ID was processed.
+    async fn mark_processed(&self, txn_id: &TransactionId) -> Result<(), Self::Error>;
+}
+
+/// A [`TransactionStore`] that keeps the IDs of the most recent transactions in memory.
+///
+/// The homeserver only retries the last transaction it sent, so it is not necessary to remember
+/// all of them.
+#[derive(Debug)]
+pub struct InMemoryTransactionStore {
+    capacity: usize,
+    txn_ids: Mutex<(BTreeSet<OwnedTransactionId>, VecDeque<OwnedTransactionId>)>,
+}
+
+impl InMemoryTransactionStore {
+    /// The number of transaction IDs remembered by [`InMemoryTransactionStore::new()`].
+    pub const DEFAULT_CAPACITY: usize = 1000;
+
+    /// Creates an empty `InMemoryTransactionStore` that remembers the IDs of the last
+    /// [`DEFAULT_CAPACITY`](Self::DEFAULT_CAPACITY) transactions.
+    pub fn new() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+
+    /// Creates an empty `InMemoryTransactionStore` that remembers the IDs of the last `capacity`
+    /// transactions.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { capacity, txn_ids: Default::default() }
+    }
+}
+
+impl Default for InMemoryTransactionStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl TransactionStore for InMemoryTransactionStore {
+    type Error = Infallible;
+
+    async fn is_processed(&self, txn_id: &TransactionId) -> Result<bool, Self::Error> {
+        Ok(self.txn_ids.lock().unwrap().0.contains(txn_id))
+    }
+
+    async fn mark_processed(&self, txn_id: &TransactionId) -> Result<(), Self::Error> {
+        let (set, queue) = &mut *self.txn_ids.lock().unwrap();
+
+        if set.insert(txn_id.to_owned()) {
+            queue.push_back(txn_id.to_owned());
+
+            while queue.len() > self.capacity {
+                if let Some(oldest) = queue.pop_front() {
+                    set.remove(&oldest);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The handler of the contents of the transactions received by a [`TransactionReceiver`].
+///
+/// Only [`handle_event()`](Self::handle_event) must be implemented, the other methods ignore the
+/// data they receive by default.
+#[async_trait]
+pub trait TransactionHandler: Sync {
+    /// The error type of the handler.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Handle an event of the timeline of a room.
+    async fn handle_event(&self, event: AnyTimelineEvent) -> Result<(), Self::Error>;
+
+    /// Handle an event of the timeline of a room that failed to deserialize.
+    async fn handle_invalid_event(
+        &self,
+        _event: Raw<AnyTimelineEvent>,
+        _error: serde_json::Error,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle an EDU, according to [MSC2409].
+    ///
+    /// [MSC2409]: https://github.com/matrix-org/matrix-spec-proposals/pull/2409
+    #[cfg(feature = "unstable-msc2409")]
+    async fn handle_ephemeral(&self, _edu: Edu) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a to-device event, according to [MSC2409].
+    ///
+    /// [MSC2409]: https://github.com/matrix-org/matrix-spec-proposals/pull/2409
+    #[cfg(feature = "unstable-msc2409")]
+    async fn handle_to_device(&self, _event: AnyToDeviceEvent) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a to-device event that failed to deserialize.
+    #[cfg(feature = "unstable-msc2409")]
+    async fn handle_invalid_to_device(
+        &self,
+        _event: Raw<AnyToDeviceEvent>,
+        _error: serde_json::Error,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle the device list updates of a transaction, according to [MSC3202].
+    ///
+    /// This is only called if there are updates.
+    ///
+    /// [MSC3202]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
+    #[cfg(feature = "unstable-msc3202")]
+    async fn handle_device_lists(&self, _device_lists: DeviceLists) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle the counts of the unclaimed one-time keys of the devices of the users of the
+    /// application service, according to [MSC3202].
+    ///
+    /// This is only called if there are counts.
+    ///
+    /// [MSC3202]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
+    #[cfg(feature = "unstable-msc3202")]
+    async fn handle_one_time_keys_count(
+        &self,
+        _counts: BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, BTreeMap<DeviceKeyAlgorithm, UInt>>>,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle the algorithms of the unused fallback keys of the devices of the users of the
+    /// application service, according to [MSC3202].
+    ///
+    /// This is only called if there are fallback keys.
+    ///
+    /// [MSC3202]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
+    #[cfg(feature = "unstable-msc3202")]
+    async fn handle_unused_fallback_key_types(
+        &self,
+        _key_types: BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, Vec<DeviceKeyAlgorithm>>>,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Receives the transactions pushed by the homeserver to an application service.
+///
+/// The contents of each transaction are passed to the handler in this order: the timeline events,
+/// then the EDUs and the to-device events, then the device data. A transaction is only marked as
+/// processed once all of its contents were handled successfully, so the homeserver retries it
+/// otherwise.
+///
+/// The homeserver can retry a transaction while the first attempt is still being processed, for
+/// example if the request timed out. Such a retry is rejected with
+/// [`TransactionError::InProgress`], so the homeserver retries it again later.
+#[derive(Debug)]
+pub struct TransactionReceiver<S, H> {
+    hs_token: String,
+    store: S,
+    handler: H,
+    in_flight: Mutex<BTreeSet<OwnedTransactionId>>,
+}
+
+impl<S, H> TransactionReceiver<S, H>
+where
+    S: TransactionStore,
+    H: TransactionHandler,
+{
+    /// Creates a new `TransactionReceiver` with the `hs_token` of the registration of the
+    /// application service, the given store and the given handler.
+    pub fn new(hs_token: String, store: S, handler: H) -> Self {
+        Self { hs_token, store, handler, in_flight: Default::default() }
+    }
+
+    /// The store of this receiver.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The handler of this receiver.
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// Process the given transaction.
+    ///
+    /// # Parameters
+    ///
+    /// * hs_token: The access token sent by the homeserver with the request.
+    /// * request: The transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TransactionError::Forbidden`] if the access token doesn't match the `hs_token`,
+    /// [`TransactionError::InProgress`] if the transaction is already being processed, and an
+    /// error if the store or the handler fails.
+    pub async fn receive(
+        &self,
+        hs_token: &str,
+        request: push_events::v1::Request,
+    ) -> Result<push_events::v1::Response, TransactionError> {
+        if !constant_time_eq(hs_token.as_bytes(), self.hs_token.as_bytes()) {
+            return Err(TransactionError::Forbidden);
+        }
+
+        let Some(_in_flight) = InFlight::begin(&self.in_flight, &request.txn_id) else {
+            return Err(TransactionError::InProgress);
+        };
+
+        if self.store.is_processed(&request.txn_id).await.map_err(TransactionError::store)? {
+            return Ok(push_events::v1::Response::new());
+        }
+
+        self.handle(request.events).await.map_err(TransactionError::handler)?;
+
+        #[cfg(feature = "unstable-msc2409")]
+        self.handle_ephemeral(request.ephemeral, request.to_device)
+            .await
+            .map_err(TransactionError::handler)?;
+
+        #[cfg(feature = "unstable-msc3202")]
+        self.handle_device_data(
+            request.device_lists,
+            request.device_one_time_keys_count,
+            request.device_unused_fallback_key_types,
+        )
+        .await
+        .map_err(TransactionError::handler)?;
+
+        self.store.mark_processed(&request.txn_id).await.map_err(TransactionError::store)?;
+
+        Ok(push_events::v1::Response::new())
+    }
+
+    async fn handle(&self, events: Vec<Raw<AnyTimelineEvent>>) -> Result<(), H::Error> {
+        for raw in events {
+            match raw.deserialize() {
+                Ok(event) => self.handler.handle_event(event).await?,
+                Err(error) => self.handler.handle_invalid_event(raw, error).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "unstable-msc2409")]
+    async fn handle_ephemeral(
+        &self,
+        ephemeral: Vec<Edu>,
+        to_device: Vec<Raw<AnyToDeviceEvent>>,
+    ) -> Result<(), H::Error> {
+        for edu in ephemeral {
+            self.handler.handle_ephemeral(edu).await?;
+        }
+
+        for raw in to_device {
+            match raw.deserialize() {
+                Ok(event) => self.handler.handle_to_device(event).await?,
+                Err(error) => self.handler.handle_invalid_to_device(raw, error).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "unstable-msc3202")]
+    async fn handle_device_data(
+        &self,
+        device_lists: DeviceLists,
+        one_time_keys_count: BTreeMap<
+            OwnedUserId,
+            BTreeMap<OwnedDeviceId, BTreeMap<DeviceKeyAlgorithm, UInt>>,
+        >,
+        unused_fallback_key_types: BTreeMap<
+            OwnedUserId,
+            BTreeMap<OwnedDeviceId, Vec<DeviceKeyAlgorithm>>,
+        >,
+    ) -> Result<(), H::Error> {
+        if !device_lists.is_empty() {
+            self.handler.handle_device_lists(device_lists).await?;
+        }
+
+        if !one_time_keys_count.is_empty() {
+            self.handler.handle_one_time_keys_count(one_time_keys_count).await?;
+        }
+
+        if !unused_fallback_key_types.is_empty() {
+            self.handler.handle_unused_fallback_key_types(unused_fallback_key_types).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Marks a transaction as being processed until it is dropped, even if processing fails or is
+/// cancelled.
+struct InFlight<'a> {
+    txn_ids: &'a Mutex<BTreeSet<OwnedTransactionId>>,
+    txn_id: OwnedTransactionId,
+}
+
+impl<'a> InFlight<'a> {
+    /// Marks the given transaction as being processed, or returns `None` if it already is.
+    fn begin(
+        txn_ids: &'a Mutex<BTreeSet<OwnedTransactionId>>,
+        txn_id: &TransactionId,
+    ) -> Option<Self> {
+        txn_ids
+            .lock()
+            .unwrap()
+            .insert(txn_id.to_owned())
+            .then(|| Self { txn_ids, txn_id: txn_id.to_owned() })
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.txn_ids.lock().unwrap().remove(&self.txn_id);
+    }
+}
+
+/// An error when processing a transaction with a [`TransactionReceiver`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TransactionError {
+    /// The access token of the request doesn't match the `hs_token` of the registration.
+    ///
+    /// The homeserver should get a `403 Forbidden` response with the `M_FORBIDDEN` error code.
+    Forbidden,
+
+    /// The transaction is already being processed.
+    ///
+    /// The homeserver should get an error response, so it retries the transaction later.
+    InProgress,
+
+    /// The transaction store failed.
+    Store(Box<dyn StdError + Send + Sync>),
+
+    /// The handler failed to process the contents of the transaction.
+    Handler(Box<dyn StdError + Send + Sync>),
+}
+
+impl TransactionError {
+    fn store(error: impl StdError + Send + Sync + 'static) -> Self {
+        Self::Store(Box::new(error))
+    }
+
+    fn handler(error: impl StdError + Send + Sync + 'static) -> Self {
+        Self::Handler(Box::new(error))
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Forbidden => write!(f, "the access token of the homeserver is invalid"),
+            Self::InProgress => write!(f, "the transaction is already being processed"),
+            Self::Store(err) => write!(f, "transaction store error: {err}"),
+            Self::Handler(err) => write!(f, "failed to handle transaction: {err}"),
+        }
+    }
+}
+
+impl StdError for TransactionError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Forbidden | Self::InProgress => None,
+            Self::Store(err) | Self::Handler(err) => Some(&**err),
+        }
+    }
+}
+
+/// Compare the given byte slices in a time that only depends on their length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
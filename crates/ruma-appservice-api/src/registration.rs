@@ -0,0 +1,142 @@
+//! Generation, loading and validation of [registration files] of application services.
+//!
+//! [registration files]: https://spec.matrix.org/latest/application-service-api/#registration
+
+use std::{collections::BTreeSet, error::Error as StdError, fmt};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{
+    compiled_registration::CompiledNamespace, InvalidNamespaceRegex, Namespaces, Registration,
+    RegistrationInit,
+};
+
+/// The length of the tokens generated by [`Registration::generate()`].
+const TOKEN_LENGTH: usize = 64;
+
+impl Registration {
+    /// Creates a new `Registration` with the given ID, URL, sender localpart and namespaces, and
+    /// random `as_token` and `hs_token`.
+    pub fn generate(
+        id: String,
+        url: Option<String>,
+        sender_localpart: String,
+        namespaces: Namespaces,
+    ) -> Self {
+        RegistrationInit {
+            id,
+            url,
+            as_token: generate_token(),
+            hs_token: generate_token(),
+            sender_localpart,
+            namespaces,
+            rate_limited: None,
+            protocols: None,
+        }
+        .into()
+    }
+
+    /// Parses and validates the given YAML registration file.
+    pub fn from_yaml_str(yaml: &str) -> Result<Self, RegistrationError> {
+        let registration: Self = serde_yaml::from_str(yaml)?;
+        registration.validate()?;
+        Ok(registration)
+    }
+
+    /// Serializes this registration to a YAML registration file.
+    pub fn to_yaml_string(&self) -> Result<String, RegistrationError> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+
+    /// Checks that the URL and the namespaces of this registration are valid.
+    pub fn validate(&self) -> Result<(), RegistrationError> {
+        if let Some(url) = &self.url {
+            let parsed =
+                url::Url::parse(url).map_err(|_| RegistrationError::InvalidUrl(url.clone()))?;
+
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(RegistrationError::InvalidUrl(url.clone()));
+            }
+        }
+
+        let Namespaces { users, aliases, rooms } = &self.namespaces;
+        for namespace in users.iter().chain(aliases).chain(rooms) {
+            CompiledNamespace::new(namespace)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Validates the given registrations, and checks that their IDs are unique.
+///
+/// This should be used by homeservers loading the registrations of several application services.
+pub fn validate_registrations<'a>(
+    registrations: impl IntoIterator<Item = &'a Registration>,
+) -> Result<(), RegistrationError> {
+    let mut ids = BTreeSet::new();
+
+    for registration in registrations {
+        registration.validate()?;
+
+        if !ids.insert(registration.id.as_str()) {
+            return Err(RegistrationError::DuplicateId(registration.id.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// An error when loading or validating a registration.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RegistrationError {
+    /// The registration file is not valid YAML or is missing fields.
+    Yaml(serde_yaml::Error),
+
+    /// The URL of the application service is not a valid HTTP URL.
+    InvalidUrl(String),
+
+    /// The regex of a namespace is invalid.
+    InvalidNamespaceRegex(InvalidNamespaceRegex),
+
+    /// Several registrations have the same ID.
+    DuplicateId(String),
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Yaml(err) => write!(f, "invalid registration file: {err}"),
+            Self::InvalidUrl(url) => write!(f, "invalid application service URL `{url}`"),
+            Self::InvalidNamespaceRegex(err) => write!(f, "{err}"),
+            Self::DuplicateId(id) => write!(f, "duplicate application service ID `{id}`"),
+        }
+    }
+}
+
+impl StdError for RegistrationError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Yaml(err) => Some(err),
+            Self::InvalidNamespaceRegex(err) => Some(err),
+            Self::InvalidUrl(_) | Self::DuplicateId(_) => None,
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for RegistrationError {
+    fn from(err: serde_yaml::Error) -> Self {
+        Self::Yaml(err)
+    }
+}
+
+impl From<InvalidNamespaceRegex> for RegistrationError {
+    fn from(err: InvalidNamespaceRegex) -> Self {
+        Self::InvalidNamespaceRegex(err)
+    }
+}
+
+fn generate_token() -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(TOKEN_LENGTH).map(char::from).collect()
+}
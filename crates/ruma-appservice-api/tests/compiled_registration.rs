@@ -0,0 +1,122 @@
+#![cfg(feature = "regex")]
+
+use assert_matches2::assert_matches;
+use ruma_appservice_api::{CompiledRegistration, Registration};
+use ruma_common::{room_alias_id, room_id, user_id};
+use ruma_events::AnyTimelineEvent;
+use serde_json::{from_value as from_json_value, json};
+
+fn registration() -> CompiledRegistration {
+    let registration: Registration = serde_yaml::from_str(
+        r##"
+        id: "IRC Bridge"
+        url: "http://127.0.0.1:1234"
+        as_token: "as_token"
+        hs_token: "hs_token"
+        sender_localpart: "_irc_bot"
+        namespaces:
+          users:
+            - exclusive: true
+              regex: "@_irc_bridge_.*:example\\.org"
+            - exclusive: false
+              regex: "@irc_.*:example\\.org"
+          aliases:
+            - exclusive: false
+              regex: "#_irc_bridge_.*"
+          rooms:
+            - exclusive: false
+              regex: "!irc:example\\.org"
+        "##,
+    )
+    .unwrap();
+
+    CompiledRegistration::new(registration).unwrap()
+}
+
+fn event(
+    event_type: &str,
+    sender: &str,
+    state_key: Option<&str>,
+    content: serde_json::Value,
+) -> AnyTimelineEvent {
+    let mut event = json!({
+        "type": event_type,
+        "event_id": "$event:example.org",
+        "room_id": "!room:example.org",
+        "sender": sender,
+        "origin_server_ts": 1,
+        "content": content,
+    });
+    if let Some(state_key) = state_key {
+        event["state_key"] = state_key.into();
+    }
+    from_json_value(event).unwrap()
+}
+
+#[test]
+fn namespace_matches() {
+    let registration = registration();
+
+    assert!(registration.is_user_match(user_id!("@_irc_bridge_alice:example.org")));
+    assert!(registration.is_exclusive_user_match(user_id!("@_irc_bridge_alice:example.org")));
+    assert!(registration.is_user_match(user_id!("@irc_bob:example.org")));
+    assert!(!registration.is_exclusive_user_match(user_id!("@irc_bob:example.org")));
+    // The regex must match the whole user ID.
+    assert!(!registration.is_user_match(user_id!("@irc_bob:example.org.evil")));
+    assert!(!registration.is_user_match(user_id!("@alice:example.org")));
+
+    assert!(registration.is_alias_match(room_alias_id!("#_irc_bridge_matrix:example.org")));
+    assert!(
+        !registration.is_exclusive_alias_match(room_alias_id!("#_irc_bridge_matrix:example.org"))
+    );
+    assert!(!registration.is_alias_match(room_alias_id!("#matrix:example.org")));
+
+    assert!(registration.is_room_match(room_id!("!irc:example.org")));
+    assert!(!registration.is_room_match(room_id!("!room:example.org")));
+}
+
+#[test]
+fn interest_in_events() {
+    let registration = registration();
+    let alice = user_id!("@alice:example.org");
+
+    let message = event(
+        "m.room.message",
+        "@irc_bob:example.org",
+        None,
+        json!({ "msgtype": "m.text", "body": "hi" }),
+    );
+    assert!(registration.is_interested_in_event(&message, []));
+
+    let message =
+        event("m.room.message", alice.as_str(), None, json!({ "msgtype": "m.text", "body": "hi" }));
+    assert!(!registration.is_interested_in_event(&message, [alice]));
+    assert!(
+        registration.is_interested_in_event(&message, [alice, user_id!("@irc_bob:example.org")])
+    );
+
+    let invite = event(
+        "m.room.member",
+        alice.as_str(),
+        Some("@_irc_bridge_carol:example.org"),
+        json!({ "membership": "invite" }),
+    );
+    assert!(registration.is_interested_in_event(&invite, []));
+
+    let canonical_alias = event(
+        "m.room.canonical_alias",
+        alice.as_str(),
+        Some(""),
+        json!({ "alias": "#matrix:example.org", "alt_aliases": ["#_irc_bridge_matrix:example.org"] }),
+    );
+    assert!(registration.is_interested_in_event(&canonical_alias, []));
+}
+
+#[test]
+fn invalid_regex() {
+    let mut registration = registration().into_registration();
+    registration.namespaces.rooms[0].regex = "!irc(:example\\.org".to_owned();
+
+    assert_matches!(CompiledRegistration::new(registration), Err(error));
+    assert_eq!(error.regex, "!irc(:example\\.org");
+}
@@ -0,0 +1,117 @@
+#![cfg(feature = "yaml")]
+
+use assert_matches2::assert_matches;
+use ruma_appservice_api::{
+    registration::{validate_registrations, RegistrationError},
+    Namespace, Namespaces, Registration,
+};
+
+fn namespaces() -> Namespaces {
+    let mut namespaces = Namespaces::new();
+    namespaces.users.push(Namespace::new(true, "@_irc_bridge_.*:example\\.org".to_owned()));
+    namespaces.aliases.push(Namespace::new(false, "#_irc_bridge_.*".to_owned()));
+    namespaces
+}
+
+#[test]
+fn generate_and_load() {
+    let registration = Registration::generate(
+        "IRC Bridge".to_owned(),
+        Some("http://127.0.0.1:1234".to_owned()),
+        "_irc_bot".to_owned(),
+        namespaces(),
+    );
+    assert_eq!(registration.as_token.len(), 64);
+    assert_ne!(registration.as_token, registration.hs_token);
+
+    let yaml = registration.to_yaml_string().unwrap();
+    let loaded = Registration::from_yaml_str(&yaml).unwrap();
+
+    assert_eq!(loaded.id, "IRC Bridge");
+    assert_eq!(loaded.url.as_deref(), Some("http://127.0.0.1:1234"));
+    assert_eq!(loaded.as_token, registration.as_token);
+    assert_eq!(loaded.hs_token, registration.hs_token);
+    assert_eq!(loaded.sender_localpart, "_irc_bot");
+    assert_eq!(loaded.namespaces.users[0].regex, "@_irc_bridge_.*:example\\.org");
+    assert!(loaded.namespaces.users[0].exclusive);
+    assert_eq!(loaded.namespaces.aliases[0].regex, "#_irc_bridge_.*");
+    assert_eq!(loaded.namespaces.rooms.len(), 0);
+}
+
+#[test]
+fn load_without_url() {
+    let registration = Registration::from_yaml_str(
+        r#"
+        id: "IRC Bridge"
+        url: null
+        as_token: "as_token"
+        hs_token: "hs_token"
+        sender_localpart: "_irc_bot"
+        namespaces: {}
+        "#,
+    )
+    .unwrap();
+    assert_eq!(registration.url, None);
+
+    let yaml = registration.to_yaml_string().unwrap();
+    assert!(yaml.contains("url: null"));
+    let loaded = Registration::from_yaml_str(&yaml).unwrap();
+    assert_eq!(loaded.url, None);
+}
+
+#[cfg(all(feature = "unstable-msc2409", feature = "unstable-msc3202"))]
+#[test]
+fn unstable_flags() {
+    let registration = Registration::from_yaml_str(
+        r#"
+        id: "IRC Bridge"
+        url: "http://127.0.0.1:1234"
+        as_token: "as_token"
+        hs_token: "hs_token"
+        sender_localpart: "_irc_bot"
+        namespaces: {}
+        de.sorunome.msc2409.push_ephemeral: true
+        org.matrix.msc3202: true
+        "#,
+    )
+    .unwrap();
+    assert!(registration.receive_ephemeral);
+    assert!(registration.device_management);
+
+    let yaml = registration.to_yaml_string().unwrap();
+    assert!(yaml.contains("de.sorunome.msc2409.push_ephemeral: true"));
+    assert!(yaml.contains("org.matrix.msc3202: true"));
+}
+
+#[test]
+fn validation_errors() {
+    let registration = |id: &str, url: &str, regex: &str| {
+        let mut namespaces = Namespaces::new();
+        namespaces.rooms.push(Namespace::new(false, regex.to_owned()));
+        Registration::generate(id.to_owned(), Some(url.to_owned()), "_bot".to_owned(), namespaces)
+    };
+
+    let result = Registration::from_yaml_str("id: \"IRC Bridge\"");
+    assert_matches!(result, Err(RegistrationError::Yaml(_)));
+
+    let invalid_url = registration("bridge", "127.0.0.1:1234", "!room:example\\.org");
+    assert_matches!(invalid_url.validate(), Err(RegistrationError::InvalidUrl(url)));
+    assert_eq!(url, "127.0.0.1:1234");
+
+    let invalid_regex = registration("bridge", "https://bridge.example.org", "!room(");
+    assert_matches!(
+        Registration::from_yaml_str(&invalid_regex.to_yaml_string().unwrap()),
+        Err(RegistrationError::InvalidNamespaceRegex(err))
+    );
+    assert_eq!(err.regex, "!room(");
+
+    let first = registration("bridge", "https://bridge.example.org", "!a:example\\.org");
+    let second = registration("bridge", "https://other.example.org", "!b:example\\.org");
+    let third = registration("other", "https://other.example.org", "!b:example\\.org");
+    validate_registrations([&first, &third]).unwrap();
+    assert_matches!(
+        validate_registrations([&first, &third, &second]),
+        Err(RegistrationError::DuplicateId(id))
+    );
+    assert_eq!(id, "bridge");
+}
@@ -0,0 +1,165 @@
+#![cfg(feature = "receiver")]
+
+use std::{fmt, sync::Mutex};
+
+use assert_matches2::assert_matches;
+use async_trait::async_trait;
+use ruma_appservice_api::{
+    event::push_events,
+    receiver::{
+        InMemoryTransactionStore, TransactionError, TransactionHandler, TransactionReceiver,
+        TransactionStore,
+    },
+};
+use ruma_common::{serde::Raw, OwnedEventId, TransactionId};
+use ruma_events::AnyTimelineEvent;
+use serde_json::{from_value as from_json_value, json};
+
+#[derive(Debug)]
+struct Failed;
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("failed")
+    }
+}
+
+impl std::error::Error for Failed {}
+
+#[derive(Default)]
+struct Handler {
+    events: Mutex<Vec<OwnedEventId>>,
+    invalid: Mutex<usize>,
+    fail: Mutex<bool>,
+}
+
+#[async_trait]
+impl TransactionHandler for Handler {
+    type Error = Failed;
+
+    async fn handle_event(&self, event: AnyTimelineEvent) -> Result<(), Self::Error> {
+        // Let concurrent transactions make progress.
+        tokio::task::yield_now().await;
+
+        if *self.fail.lock().unwrap() {
+            return Err(Failed);
+        }
+        self.events.lock().unwrap().push(event.event_id().to_owned());
+        Ok(())
+    }
+
+    async fn handle_invalid_event(
+        &self,
+        _event: Raw<AnyTimelineEvent>,
+        _error: serde_json::Error,
+    ) -> Result<(), Self::Error> {
+        *self.invalid.lock().unwrap() += 1;
+        Ok(())
+    }
+}
+
+fn event(event_id: &str) -> Raw<AnyTimelineEvent> {
+    from_json_value(json!({
+        "type": "m.room.message",
+        "event_id": event_id,
+        "room_id": "!room:example.org",
+        "sender": "@alice:example.org",
+        "origin_server_ts": 1,
+        "content": { "msgtype": "m.text", "body": "hi" },
+    }))
+    .unwrap()
+}
+
+fn transaction(txn_id: &str, events: Vec<Raw<AnyTimelineEvent>>) -> push_events::v1::Request {
+    push_events::v1::Request::new(txn_id.into(), events)
+}
+
+fn receiver() -> TransactionReceiver<InMemoryTransactionStore, Handler> {
+    TransactionReceiver::new(
+        "hs_token".to_owned(),
+        InMemoryTransactionStore::new(),
+        Handler::default(),
+    )
+}
+
+#[tokio::test]
+async fn events_in_order() {
+    let receiver = receiver();
+    let invalid = from_json_value(json!({ "type": "m.room.message" })).unwrap();
+
+    receiver
+        .receive("hs_token", transaction("1", vec![event("$a"), invalid, event("$b")]))
+        .await
+        .unwrap();
+    receiver.receive("hs_token", transaction("2", vec![event("$c")])).await.unwrap();
+
+    assert_eq!(*receiver.handler().events.lock().unwrap(), ["$a", "$b", "$c"]);
+    assert_eq!(*receiver.handler().invalid.lock().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn deduplicate_retries() {
+    let receiver = receiver();
+
+    receiver.receive("hs_token", transaction("1", vec![event("$a")])).await.unwrap();
+    receiver.receive("hs_token", transaction("1", vec![event("$a")])).await.unwrap();
+    assert_eq!(*receiver.handler().events.lock().unwrap(), ["$a"]);
+
+    // A failed transaction is processed again when retried.
+    *receiver.handler().fail.lock().unwrap() = true;
+    let result = receiver.receive("hs_token", transaction("2", vec![event("$b")])).await;
+    assert_matches!(result, Err(TransactionError::Handler(_)));
+    assert!(!receiver.store().is_processed(<&TransactionId>::from("2")).await.unwrap());
+
+    *receiver.handler().fail.lock().unwrap() = false;
+    receiver.receive("hs_token", transaction("2", vec![event("$b")])).await.unwrap();
+    assert_eq!(*receiver.handler().events.lock().unwrap(), ["$a", "$b"]);
+}
+
+#[tokio::test]
+async fn reject_concurrent_retries() {
+    let receiver = receiver();
+
+    let (first, retry) = tokio::join!(
+        receiver.receive("hs_token", transaction("1", vec![event("$a")])),
+        receiver.receive("hs_token", transaction("1", vec![event("$a")])),
+    );
+    first.unwrap();
+    assert_matches!(retry, Err(TransactionError::InProgress));
+    assert_eq!(*receiver.handler().events.lock().unwrap(), ["$a"]);
+
+    // The transaction can be retried once the first attempt failed.
+    *receiver.handler().fail.lock().unwrap() = true;
+    let (first, retry) = tokio::join!(
+        receiver.receive("hs_token", transaction("2", vec![event("$b")])),
+        receiver.receive("hs_token", transaction("2", vec![event("$b")])),
+    );
+    assert_matches!(first, Err(TransactionError::Handler(_)));
+    assert_matches!(retry, Err(TransactionError::InProgress));
+
+    *receiver.handler().fail.lock().unwrap() = false;
+    receiver.receive("hs_token", transaction("2", vec![event("$b")])).await.unwrap();
+    assert_eq!(*receiver.handler().events.lock().unwrap(), ["$a", "$b"]);
+}
+
+#[tokio::test]
+async fn reject_invalid_hs_token() {
+    let receiver = receiver();
+
+    let result = receiver.receive("as_token", transaction("1", vec![event("$a")])).await;
+    assert_matches!(result, Err(TransactionError::Forbidden));
+    assert!(receiver.handler().events.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn store_capacity() {
+    let store = InMemoryTransactionStore::with_capacity(2);
+
+    for txn_id in ["1", "2", "3"] {
+        store.mark_processed(txn_id.into()).await.unwrap();
+    }
+
+    assert!(!store.is_processed("1".into()).await.unwrap());
+    assert!(store.is_processed("2".into()).await.unwrap());
+    assert!(store.is_processed("3".into()).await.unwrap());
+}
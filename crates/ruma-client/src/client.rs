@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -8,23 +9,49 @@ use async_stream::try_stream;
 use futures_core::stream::Stream;
 use ruma_client_api::{
     account::register::{self, RegistrationKind},
-    session::login::{self, v3::LoginInfo},
+    discovery::get_capabilities::{self, Capabilities},
+    session::{
+        login::{self, v3::LoginInfo},
+        refresh_token,
+    },
     sync::sync_events,
     uiaa::UserIdentifier,
 };
 use ruma_common::{
-    api::{MatrixVersion, OutgoingRequest, SendAccessToken},
+    api::{
+        AuthScheme, MatrixVersion, OutgoingRequest, SendAccessToken, VersionHistory,
+        VersioningDecision,
+    },
     presence::PresenceState,
     DeviceId, UserId,
 };
+use tracing::{warn, Instrument};
 
+use self::session::{is_soft_logout, SessionCallback};
 use crate::{
-    add_user_id_to_query, send_customized_request, Error, HttpClient, ResponseError, ResponseResult,
+    add_user_id_to_query, deserialize_response, send_customized_request, send_span,
+    serialize_request, Error, HttpClient, ResponseError, ResponseResult,
 };
 
+#[cfg(feature = "appservice")]
+mod appservice;
 mod builder;
+mod media;
+mod retry;
+mod session;
+mod sync;
+mod uiaa;
 
-pub use self::builder::ClientBuilder;
+#[cfg(feature = "appservice")]
+pub use self::appservice::{AppserviceClient, AppserviceError, InvalidRegistration};
+pub use self::{
+    builder::ClientBuilder,
+    media::{MediaDownload, MediaUpload},
+    retry::RetryPolicy,
+    session::Session,
+    sync::{NoSyncStore, SyncSettings, SyncStore, SyncUpdate, TimelineGap},
+    uiaa::{UiaaHandler, UiaaRequest},
+};
 
 /// A client for the Matrix client-server API.
 #[derive(Clone, Debug)]
@@ -39,11 +66,29 @@ struct ClientData<C> {
     /// The underlying HTTP client.
     http_client: C,
 
-    /// The access token, if logged in.
-    access_token: Mutex<Option<String>>,
+    /// The session, if logged in.
+    session: Mutex<Option<Session>>,
+
+    /// The function to call when the session changes.
+    on_session_change: Option<SessionCallback>,
 
     /// The (known) Matrix versions the homeserver supports.
     supported_matrix_versions: Vec<MatrixVersion>,
+
+    /// The unstable features the homeserver supports, if they were fetched.
+    unstable_features: Option<BTreeMap<String, bool>>,
+
+    /// The capabilities of the homeserver for the current user, once they were fetched.
+    capabilities: Mutex<Option<Capabilities>>,
+
+    /// The maximum size of uploads allowed by the homeserver, once it was fetched.
+    max_upload_size: Mutex<Option<u64>>,
+
+    /// The policy to retry failed requests.
+    retry_policy: RetryPolicy,
+
+    /// Whether to request a refresh token when logging in or registering.
+    request_refresh_token: bool,
 }
 
 impl Client<()> {
@@ -54,11 +99,103 @@ impl Client<()> {
 }
 
 impl<C> Client<C> {
+    /// Get the URL of the homeserver.
+    pub fn homeserver_url(&self) -> &str {
+        &self.0.homeserver_url
+    }
+
+    /// Get the (known) Matrix versions the homeserver supports.
+    pub fn supported_matrix_versions(&self) -> &[MatrixVersion] {
+        &self.0.supported_matrix_versions
+    }
+
+    /// Get the unstable features advertised by the homeserver.
+    ///
+    /// This is `None` if the supported Matrix versions were set manually with
+    /// [`ClientBuilder::supported_matrix_versions()`], unless the homeserver URL was discovered.
+    pub fn unstable_features(&self) -> Option<&BTreeMap<String, bool>> {
+        self.0.unstable_features.as_ref()
+    }
+
+    /// Whether the homeserver advertises support for the given unstable feature, usually named
+    /// after an MSC, like `org.matrix.msc3575`.
+    ///
+    /// When the unstable features are known, requests to endpoints that are only available on an
+    /// unstable path for the [supported Matrix versions][Self::supported_matrix_versions] fail
+    /// with [`Error::UnsupportedUnstableFeature`] if the homeserver doesn't advertise the unstable
+    /// feature in the path.
+    pub fn supports_unstable_feature(&self, feature: &str) -> bool {
+        self.0
+            .unstable_features
+            .as_ref()
+            .and_then(|features| features.get(feature).copied())
+            .unwrap_or(false)
+    }
+
     /// Get a copy of the current `access_token`, if any.
+    pub fn access_token(&self) -> Option<String> {
+        self.0
+            .session
+            .lock()
+            .expect("session mutex was poisoned")
+            .as_ref()
+            .map(|session| session.access_token.clone())
+    }
+
+    /// Get a copy of the current session, if any.
     ///
     /// Useful for serializing and persisting the session to be restored later.
-    pub fn access_token(&self) -> Option<String> {
-        self.0.access_token.lock().expect("session mutex was poisoned").clone()
+    pub fn session(&self) -> Option<Session> {
+        self.0.session.lock().expect("session mutex was poisoned").clone()
+    }
+
+    /// Replace the session and notify the session change callback.
+    fn set_session(&self, session: Option<Session>) {
+        let previous = std::mem::replace(
+            &mut *self.0.session.lock().expect("session mutex was poisoned"),
+            session.clone(),
+        );
+
+        // The capabilities depend on the user.
+        let user_id = |session: &Option<Session>| session.as_ref().and_then(|s| s.user_id.clone());
+        if user_id(&previous) != user_id(&session) {
+            *self.0.capabilities.lock().expect("capabilities mutex was poisoned") = None;
+        }
+
+        if let (Some(session), Some(on_session_change)) = (session, &self.0.on_session_change) {
+            (on_session_change.0)(&session);
+        }
+    }
+
+    /// Make sure the homeserver supports the unstable feature of the endpoint of `R`, if it is only
+    /// available on an unstable path.
+    ///
+    /// Nothing is checked if the unstable features were not fetched.
+    fn check_unstable_feature<R: OutgoingRequest, E>(
+        &self,
+    ) -> Result<(), Error<E, R::EndpointError>> {
+        let Some(unstable_features) = &self.0.unstable_features else {
+            return Ok(());
+        };
+
+        match missing_unstable_feature(
+            &R::METADATA.history,
+            &self.0.supported_matrix_versions,
+            unstable_features,
+        ) {
+            Some(feature) => Err(Error::UnsupportedUnstableFeature { feature: feature.to_owned() }),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the session has a refresh token.
+    fn has_refresh_token(&self) -> bool {
+        self.0
+            .session
+            .lock()
+            .expect("session mutex was poisoned")
+            .as_ref()
+            .is_some_and(|session| session.refresh_token.is_some())
     }
 }
 
@@ -69,6 +206,9 @@ impl<C: HttpClient> Client<C> {
     }
 
     /// Makes a request to a Matrix API endpoint including additional URL parameters.
+    ///
+    /// The request is retried according to the [`RetryPolicy`] of the client, and after
+    /// refreshing the access token if it expired, so `customize` might be called several times.
     pub async fn send_customized_request<R, F>(
         &self,
         request: R,
@@ -76,23 +216,120 @@ impl<C: HttpClient> Client<C> {
     ) -> ResponseResult<C, R>
     where
         R: OutgoingRequest,
-        F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
+        F: Fn(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
     {
-        let access_token = self.access_token();
-        let send_access_token = match access_token.as_deref() {
-            Some(at) => SendAccessToken::IfRequired(at),
-            None => SendAccessToken::None,
-        };
+        self.check_unstable_feature::<R, _>()?;
+
+        if R::METADATA.authentication != AuthScheme::None
+            && self.session().is_some_and(|s| s.is_expired() && s.refresh_token.is_some())
+            && self.refresh_access_token().await.is_err()
+        {
+            warn!("failed to refresh expired access token");
+        }
 
-        send_customized_request(
+        let mut attempt = 1;
+        let mut refreshed = false;
+        loop {
+            let access_token = self.access_token();
+            let send_access_token = match access_token.as_deref() {
+                Some(at) => SendAccessToken::IfRequired(at),
+                None => SendAccessToken::None,
+            };
+
+            let http_req = serialize_request::<C, R, _>(
+                &self.0.homeserver_url,
+                send_access_token,
+                &self.0.supported_matrix_versions,
+                request.clone(),
+                &customize,
+            )?;
+
+            let result = self
+                .0
+                .http_client
+                .send_http_request(http_req)
+                .instrument(send_span::<C, R>(&self.0.homeserver_url))
+                .await;
+
+            if !refreshed && is_soft_logout(&result) && self.has_refresh_token() {
+                refreshed = true;
+
+                // Another request might have refreshed the access token in the meantime.
+                if access_token == self.access_token() && self.refresh_access_token().await.is_err()
+                {
+                    warn!("failed to refresh access token");
+                    return deserialize_response::<C, R>(result.map_err(Error::Response)?);
+                }
+
+                continue;
+            }
+
+            match self.0.retry_policy.delay_for(&R::METADATA.method, attempt, &result) {
+                Some(delay) => {
+                    warn!(attempt, ?delay, "request failed, retrying");
+                    futures_timer::Delay::new(delay).await;
+                    attempt += 1;
+                }
+                None => return deserialize_response::<C, R>(result.map_err(Error::Response)?),
+            }
+        }
+    }
+
+    /// Get a new access token with the refresh token of the session.
+    ///
+    /// This is done automatically when the homeserver reports that the access token expired, so
+    /// this method only needs to be called to refresh the access token ahead of time.
+    ///
+    /// Returns [`Error::AuthenticationRequired`] if the session has no refresh token.
+    pub async fn refresh_access_token(
+        &self,
+    ) -> Result<refresh_token::v3::Response, Error<C::Error, ruma_client_api::Error>> {
+        let refresh_token = self
+            .session()
+            .and_then(|session| session.refresh_token)
+            .ok_or(Error::AuthenticationRequired)?;
+
+        let response = send_customized_request(
             &self.0.http_client,
             &self.0.homeserver_url,
-            send_access_token,
+            SendAccessToken::None,
             &self.0.supported_matrix_versions,
-            request,
-            customize,
+            refresh_token::v3::Request::new(refresh_token),
+            |_| Ok(()),
         )
-        .await
+        .await?;
+
+        if let Some(mut session) = self.session() {
+            session.update_tokens(
+                response.access_token.clone(),
+                response.refresh_token.clone(),
+                response.expires_in_ms,
+            );
+            self.set_session(Some(session));
+        }
+
+        Ok(response)
+    }
+
+    /// Get the capabilities of the homeserver for the current user.
+    ///
+    /// They are only fetched with a [`get_capabilities`] request the first time, and cached until
+    /// the user of the session changes.
+    pub async fn capabilities(
+        &self,
+    ) -> Result<Capabilities, Error<C::Error, ruma_client_api::Error>> {
+        if let Some(capabilities) =
+            self.0.capabilities.lock().expect("capabilities mutex was poisoned").clone()
+        {
+            return Ok(capabilities);
+        }
+
+        let capabilities =
+            self.send_request(get_capabilities::v3::Request::new()).await?.capabilities;
+        *self.0.capabilities.lock().expect("capabilities mutex was poisoned") =
+            Some(capabilities.clone());
+
+        Ok(capabilities)
     }
 
     /// Makes a request to a Matrix API endpoint as a virtual user.
@@ -109,8 +346,10 @@ impl<C: HttpClient> Client<C> {
 
     /// Log in with a username and password.
     ///
-    /// In contrast to [`send_request`][Self::send_request], this method stores the access token
-    /// returned by the endpoint in this client, in addition to returning it.
+    /// In contrast to [`send_request`][Self::send_request], this method stores the session
+    /// returned by the endpoint in this client, in addition to returning it. A refresh token is
+    /// requested if it was enabled with [`ClientBuilder::request_refresh_token()`], so the access
+    /// token can be refreshed when it expires.
     pub async fn log_in(
         &self,
         user: &str,
@@ -126,37 +365,52 @@ impl<C: HttpClient> Client<C> {
             .send_request(assign!(login::v3::Request::new(login_info), {
                 device_id: device_id.map(ToOwned::to_owned),
                 initial_device_display_name: initial_device_display_name.map(ToOwned::to_owned),
+                refresh_token: self.0.request_refresh_token,
             }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = Some(response.access_token.clone());
+        let mut session = Session::new(response.access_token.clone());
+        session.update_tokens(
+            response.access_token.clone(),
+            response.refresh_token.clone(),
+            response.expires_in,
+        );
+        session.user_id = Some(response.user_id.clone());
+        session.device_id = Some(response.device_id.clone());
+        self.set_session(Some(session));
 
         Ok(response)
     }
 
     /// Register as a guest.
     ///
-    /// In contrast to [`send_request`][Self::send_request], this method stores the access token
+    /// In contrast to [`send_request`][Self::send_request], this method stores the session
     /// returned by the endpoint in this client, in addition to returning it.
     pub async fn register_guest(
         &self,
     ) -> Result<register::v3::Response, Error<C::Error, ruma_client_api::uiaa::UiaaResponse>> {
         let response = self
-            .send_request(assign!(register::v3::Request::new(), { kind: RegistrationKind::Guest }))
+            .send_request(assign!(register::v3::Request::new(), {
+                kind: RegistrationKind::Guest,
+                refresh_token: self.0.request_refresh_token,
+            }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = response.access_token.clone();
+        self.set_session_from_registration(&response);
 
         Ok(response)
     }
 
     /// Register as a new user on this server.
     ///
-    /// In contrast to [`send_request`][Self::send_request], this method stores the access token
+    /// In contrast to [`send_request`][Self::send_request], this method stores the session
     /// returned by the endpoint in this client, in addition to returning it.
     ///
     /// The username is the local part of the returned user_id. If it is omitted from this request,
     /// the server will generate one.
+    ///
+    /// This method doesn't complete the stages of the User-Interactive Authentication API, use
+    /// [`send_uiaa_request`][Self::send_uiaa_request] if the homeserver requires them.
     pub async fn register_user(
         &self,
         username: Option<&str>,
@@ -165,17 +419,35 @@ impl<C: HttpClient> Client<C> {
         let response = self
             .send_request(assign!(register::v3::Request::new(), {
                 username: username.map(ToOwned::to_owned),
-                password: Some(password.to_owned())
+                password: Some(password.to_owned()),
+                refresh_token: self.0.request_refresh_token,
             }))
             .await?;
 
-        *self.0.access_token.lock().unwrap() = response.access_token.clone();
+        self.set_session_from_registration(&response);
 
         Ok(response)
     }
 
+    /// Store the session returned by a registration, if any.
+    fn set_session_from_registration(&self, response: &register::v3::Response) {
+        let Some(access_token) = response.access_token.clone() else {
+            self.set_session(None);
+            return;
+        };
+
+        let mut session = Session::new(access_token.clone());
+        session.update_tokens(access_token, response.refresh_token.clone(), response.expires_in);
+        session.user_id = Some(response.user_id.clone());
+        session.device_id = response.device_id.clone();
+        self.set_session(Some(session));
+    }
+
     /// Convenience method that represents repeated calls to the sync_events endpoint as a stream.
     ///
+    /// The stream ends at the first error. See [`sync_with_store`][Self::sync_with_store] for a
+    /// sync loop that survives transient errors.
+    ///
     /// # Example:
     ///
     /// ```no_run
@@ -227,3 +499,36 @@ impl<C: HttpClient> Client<C> {
         }
     }
 }
+
+/// Get the unstable feature in the path of an endpoint with the given version history, if it is
+/// only available on an unstable path for the given Matrix versions and the feature is not
+/// advertised in `unstable_features`.
+///
+/// The feature is the segment following `unstable` in the path, like `org.matrix.msc3575`. A
+/// versioned segment, like `org.matrix.msc3814.v1`, also matches the unversioned feature.
+fn missing_unstable_feature(
+    history: &VersionHistory,
+    versions: &[MatrixVersion],
+    unstable_features: &BTreeMap<String, bool>,
+) -> Option<&'static str> {
+    if history.versioning_decision_for(versions) != VersioningDecision::Unstable {
+        return None;
+    }
+
+    // Older unstable paths don't name a feature, like `/_matrix/client/unstable/rooms/…`.
+    let feature = history
+        .unstable()?
+        .split('/')
+        .skip_while(|segment| *segment != "unstable")
+        .nth(1)
+        .filter(|segment| segment.contains('.'))?;
+
+    let supported = unstable_features.iter().any(|(name, &enabled)| {
+        enabled
+            && feature
+                .strip_prefix(name.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    });
+
+    (!supported).then_some(feature)
+}
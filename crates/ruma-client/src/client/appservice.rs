@@ -0,0 +1,422 @@
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use assign::assign;
+use ruma_appservice_api::{CompiledRegistration, InvalidNamespaceRegex, Registration};
+use ruma_client_api::{
+    account::register::{self, LoginType},
+    error::{ErrorBody, ErrorKind},
+    uiaa::UiaaResponse,
+};
+use ruma_common::{
+    api::{error::FromHttpResponseError, OutgoingRequest, SendAccessToken},
+    OwnedServerName, OwnedUserId, UserId,
+};
+
+use super::{Client, Session};
+use crate::{Error, HttpClient, HttpClientExt};
+
+/// A client for the Matrix client-server API acting as an application service.
+///
+/// It authenticates with the `as_token` of the [`Registration`] of the application service, and
+/// can send requests as the virtual users in its exclusive user namespaces. Virtual users are
+/// registered the first time a request is sent as them.
+#[derive(Clone, Debug)]
+pub struct AppserviceClient<C> {
+    client: Client<C>,
+    registration: Arc<CompiledRegistration>,
+    sender: OwnedUserId,
+    registered_users: Arc<Mutex<BTreeSet<OwnedUserId>>>,
+}
+
+impl<C> AppserviceClient<C> {
+    /// Creates a new `AppserviceClient` from the given client, the registration of the application
+    /// service and the name of the homeserver it is registered on.
+    ///
+    /// The session of the client is replaced by a session with the `as_token` of the
+    /// registration.
+    ///
+    /// Returns an error if the sender localpart or one of the namespaces of the registration is
+    /// invalid.
+    pub fn new(
+        client: Client<C>,
+        registration: Registration,
+        server_name: OwnedServerName,
+    ) -> Result<Self, InvalidRegistration> {
+        let sender =
+            UserId::parse_with_server_name(registration.sender_localpart.as_str(), &server_name)
+                .map_err(|_| {
+                    InvalidRegistration::SenderLocalpart(registration.sender_localpart.clone())
+                })?;
+
+        let registration = CompiledRegistration::new(registration)?;
+
+        let mut session = Session::new(registration.registration().as_token.clone());
+        session.user_id = Some(sender.clone());
+        client.set_session(Some(session));
+
+        Ok(Self {
+            client,
+            registration: Arc::new(registration),
+            registered_users: Arc::new(Mutex::new(BTreeSet::from([sender.clone()]))),
+            sender,
+        })
+    }
+
+    /// Get the underlying client.
+    pub fn client(&self) -> &Client<C> {
+        &self.client
+    }
+
+    /// Get the registration of the application service.
+    pub fn registration(&self) -> &CompiledRegistration {
+        &self.registration
+    }
+
+    /// Get the ID of the user of the application service, from its sender localpart.
+    pub fn sender(&self) -> &UserId {
+        &self.sender
+    }
+
+    /// Whether this application service can send requests as the given user.
+    ///
+    /// This is the case for the sender of the application service, and for the users matching one
+    /// of its exclusive user namespaces.
+    pub fn can_masquerade_as(&self, user_id: &UserId) -> bool {
+        user_id == self.sender || self.registration.is_exclusive_user_match(user_id)
+    }
+}
+
+impl<C: HttpClient> AppserviceClient<C> {
+    /// Makes a request to a Matrix API endpoint as the sender of the application service.
+    pub async fn send_request<R: OutgoingRequest>(
+        &self,
+        request: R,
+    ) -> Result<R::IncomingResponse, AppserviceError<C::Error, R::EndpointError>> {
+        Ok(self.client.send_request(request).await?)
+    }
+
+    /// Makes a request to a Matrix API endpoint as the given virtual user.
+    ///
+    /// The user is registered first if it wasn't yet by this client. Returns
+    /// [`AppserviceError::Forbidden`] without sending the request if the user is not in the
+    /// exclusive user namespaces of the application service.
+    pub async fn send_request_as<R: OutgoingRequest>(
+        &self,
+        user_id: &UserId,
+        request: R,
+    ) -> Result<R::IncomingResponse, AppserviceError<C::Error, R::EndpointError>> {
+        self.ensure_registered(user_id).await?;
+        Ok(self.client.send_request_as(user_id, request).await?)
+    }
+
+    /// Makes a request to a Matrix API endpoint as the given device of the given virtual user,
+    /// according to [MSC3202].
+    ///
+    /// The user is registered first if it wasn't yet by this client. Returns
+    /// [`AppserviceError::Forbidden`] without sending the request if the user is not in the
+    /// exclusive user namespaces of the application service.
+    ///
+    /// [MSC3202]: https://github.com/matrix-org/matrix-spec-proposals/pull/3202
+    #[cfg(feature = "unstable-msc3202")]
+    pub async fn send_request_as_device<R: OutgoingRequest>(
+        &self,
+        user_id: &UserId,
+        device_id: &ruma_common::DeviceId,
+        request: R,
+    ) -> Result<R::IncomingResponse, AppserviceError<C::Error, R::EndpointError>> {
+        self.ensure_registered(user_id).await?;
+
+        let params =
+            [("user_id", user_id.as_str()), ("org.matrix.msc3202.device_id", device_id.as_str())];
+        let response = self
+            .client
+            .send_customized_request(request, |http_request| {
+                crate::add_params_to_query::<C, R>(http_request, params)
+            })
+            .await?;
+
+        Ok(response)
+    }
+
+    /// Register the given virtual user, if it wasn't yet by this client.
+    ///
+    /// If the homeserver responds that the user ID is already taken, the user is considered
+    /// registered. Returns [`AppserviceError::Forbidden`] if the user is not in the exclusive user
+    /// namespaces of the application service.
+    pub async fn ensure_registered<F>(
+        &self,
+        user_id: &UserId,
+    ) -> Result<(), AppserviceError<C::Error, F>> {
+        if !self.can_masquerade_as(user_id) {
+            return Err(AppserviceError::Forbidden(user_id.to_owned()));
+        }
+
+        if self
+            .registered_users
+            .lock()
+            .expect("registered users mutex was poisoned")
+            .contains(user_id)
+        {
+            return Ok(());
+        }
+
+        let request = assign!(register::v3::Request::new(), {
+            username: Some(user_id.localpart().to_owned()),
+            login_type: Some(LoginType::ApplicationService),
+            inhibit_login: true,
+        });
+
+        // The registration endpoint doesn't require authentication, but the `as_token` must be
+        // sent to register a virtual user.
+        let client = &self.client.0;
+        let result = client
+            .http_client
+            .send_matrix_request(
+                &client.homeserver_url,
+                SendAccessToken::Always(&self.registration.registration().as_token),
+                &client.supported_matrix_versions,
+                request,
+            )
+            .await;
+
+        match result {
+            Ok(_) => {}
+            Err(Error::FromHttpResponse(FromHttpResponseError::Server(
+                UiaaResponse::MatrixError(error),
+            ))) if matches!(error.body, ErrorBody::Standard { kind: ErrorKind::UserInUse, .. }) => {
+            }
+            Err(error) => return Err(AppserviceError::Registration(error)),
+        }
+
+        self.registered_users
+            .lock()
+            .expect("registered users mutex was poisoned")
+            .insert(user_id.to_owned());
+
+        Ok(())
+    }
+}
+
+/// An error when creating an [`AppserviceClient`] from an invalid [`Registration`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum InvalidRegistration {
+    /// The sender localpart doesn't form a valid user ID.
+    SenderLocalpart(String),
+
+    /// The regular expression of a namespace is invalid.
+    Namespace(InvalidNamespaceRegex),
+}
+
+impl fmt::Display for InvalidRegistration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SenderLocalpart(localpart) => write!(f, "Invalid sender localpart: {localpart}"),
+            Self::Namespace(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidRegistration {}
+
+impl From<InvalidNamespaceRegex> for InvalidRegistration {
+    fn from(err: InvalidNamespaceRegex) -> Self {
+        Self::Namespace(err)
+    }
+}
+
+/// An error that can occur when sending a request with an [`AppserviceClient`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum AppserviceError<E, F> {
+    /// The user is not in the exclusive user namespaces of the application service.
+    Forbidden(OwnedUserId),
+
+    /// Registering the virtual user failed.
+    Registration(Error<E, UiaaResponse>),
+
+    /// Sending the request failed.
+    Request(Error<E, F>),
+}
+
+impl<E: fmt::Display, F: fmt::Display> fmt::Display for AppserviceError<E, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Forbidden(user_id) => {
+                write!(f, "The user {user_id} is not in the namespaces of the application service.")
+            }
+            Self::Registration(err) => write!(f, "Registering the virtual user failed: {err}"),
+            Self::Request(err) => err.fmt(f),
+        }
+    }
+}
+
+impl<E, F> From<Error<E, F>> for AppserviceError<E, F> {
+    fn from(err: Error<E, F>) -> Self {
+        Self::Request(err)
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display, F: fmt::Debug + fmt::Display> std::error::Error
+    for AppserviceError<E, F>
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use assert_matches2::assert_matches;
+    use http::StatusCode;
+    use ruma_appservice_api::{Namespace, Namespaces, Registration, RegistrationInit};
+    use ruma_client_api::{account::register, profile::get_display_name};
+    use ruma_common::{api::MatrixVersion, owned_server_name, user_id};
+    use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+    use super::{AppserviceClient, AppserviceError, InvalidRegistration};
+    use crate::{http_client::mock::MockHttpClient, Client, Session};
+
+    fn registration() -> Registration {
+        let mut namespaces = Namespaces::new();
+        namespaces.users = vec![
+            Namespace::new(true, "@_bridge_.*:example\\.org".to_owned()),
+            Namespace::new(false, "@shared_.*:example\\.org".to_owned()),
+        ];
+
+        RegistrationInit {
+            id: "bridge".to_owned(),
+            url: Some("https://bridge.example.org".to_owned()),
+            as_token: "as_token".to_owned(),
+            hs_token: "hs_token".to_owned(),
+            sender_localpart: "_bridge".to_owned(),
+            namespaces,
+            rate_limited: None,
+            protocols: None,
+        }
+        .into()
+    }
+
+    async fn client(http_client: MockHttpClient) -> AppserviceClient<MockHttpClient> {
+        let client = Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .http_client(http_client)
+            .await
+            .unwrap();
+        AppserviceClient::new(client, registration(), owned_server_name!("example.org")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn masquerade_in_exclusive_namespaces() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<register::v3::Request>(
+                StatusCode::OK,
+                json!({ "user_id": "@_bridge_alice:example.org" }),
+            )
+            .on_json::<get_display_name::v3::Request>(
+                StatusCode::OK,
+                json!({ "displayname": "Alice" }),
+            );
+        let client = client(http_client.clone()).await;
+
+        assert!(client.can_masquerade_as(user_id!("@_bridge:example.org")));
+        assert!(client.can_masquerade_as(user_id!("@_bridge_alice:example.org")));
+        assert!(!client.can_masquerade_as(user_id!("@_bridge_alice:example.org.evil")));
+        assert!(!client.can_masquerade_as(user_id!("@shared_bob:example.org")));
+
+        let request =
+            || get_display_name::v3::Request::new(user_id!("@bob:example.org").to_owned());
+        let result = client.send_request_as(user_id!("@shared_bob:example.org"), request()).await;
+        assert_matches!(result, Err(AppserviceError::Forbidden(user_id)));
+        assert_eq!(user_id, "@shared_bob:example.org");
+        assert_eq!(http_client.take_requests().len(), 0);
+
+        let alice = user_id!("@_bridge_alice:example.org");
+        for _ in 0..2 {
+            let response = client.send_request_as(alice, request()).await.unwrap();
+            assert_eq!(response.displayname.as_deref(), Some("Alice"));
+        }
+
+        // The user is only registered once.
+        let requests = http_client.take_requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].headers()[http::header::AUTHORIZATION], "Bearer as_token");
+        let body: JsonValue = from_json_slice(requests[0].body()).unwrap();
+        assert_eq!(body["type"], "m.login.application_service");
+        assert_eq!(body["username"], "_bridge_alice");
+        assert_eq!(requests[1].uri().query(), Some("user_id=%40_bridge_alice%3Aexample.org"));
+    }
+
+    #[tokio::test]
+    async fn invalid_registration_keeps_session() {
+        let session_changes = Arc::new(AtomicUsize::new(0));
+        let client = Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .session(Some(Session::new("access_token".to_owned())))
+            .on_session_change({
+                let session_changes = session_changes.clone();
+                move |_| {
+                    session_changes.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .http_client(MockHttpClient::new())
+            .await
+            .unwrap();
+
+        let mut registration = registration();
+        registration.namespaces.users.push(Namespace::new(true, "@_bridge_(".to_owned()));
+
+        let result =
+            AppserviceClient::new(client.clone(), registration, owned_server_name!("example.org"));
+        assert_matches!(result, Err(InvalidRegistration::Namespace(_)));
+        assert_eq!(client.access_token().as_deref(), Some("access_token"));
+        assert_eq!(session_changes.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn user_in_use_is_registered() {
+        let http_client = MockHttpClient::new();
+        http_client.on_json::<register::v3::Request>(
+            StatusCode::BAD_REQUEST,
+            json!({ "errcode": "M_USER_IN_USE", "error": "User ID already taken" }),
+        );
+        let client = client(http_client.clone()).await;
+
+        let alice = user_id!("@_bridge_alice:example.org");
+        client.ensure_registered::<()>(alice).await.unwrap();
+        client.ensure_registered::<()>(alice).await.unwrap();
+        assert_eq!(http_client.take_requests().len(), 1);
+    }
+
+    #[cfg(feature = "unstable-msc3202")]
+    #[tokio::test]
+    async fn masquerade_as_device() {
+        let http_client = MockHttpClient::new();
+        http_client.on_json::<get_display_name::v3::Request>(
+            StatusCode::OK,
+            json!({ "displayname": "Bridge" }),
+        );
+        let client = client(http_client.clone()).await;
+
+        let request = get_display_name::v3::Request::new(client.sender().to_owned());
+        client
+            .send_request_as_device(client.sender(), ruma_common::device_id!("DEVICE"), request)
+            .await
+            .unwrap();
+
+        let requests = http_client.take_requests();
+        assert_eq!(
+            requests[0].uri().query(),
+            Some("user_id=%40_bridge%3Aexample.org&org.matrix.msc3202.device_id=DEVICE")
+        );
+    }
+}
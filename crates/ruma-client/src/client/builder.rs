@@ -1,9 +1,14 @@
 use std::sync::{Arc, Mutex};
 
-use ruma_client_api::discovery::get_supported_versions;
-use ruma_common::api::{MatrixVersion, SendAccessToken};
+use http::StatusCode;
+use ruma_client_api::discovery::{discover_homeserver, get_supported_versions};
+use ruma_common::{
+    api::{error::FromHttpResponseError, MatrixVersion, SendAccessToken},
+    OwnedServerName, ServerName, UserId,
+};
+use tracing::debug;
 
-use super::{Client, ClientData};
+use super::{session::SessionCallback, Client, ClientData, RetryPolicy, Session};
 use crate::{DefaultConstructibleHttpClient, Error, HttpClient, HttpClientExt};
 
 /// A [`Client`] builder.
@@ -11,43 +16,116 @@ use crate::{DefaultConstructibleHttpClient, Error, HttpClient, HttpClientExt};
 /// This type can be used to construct a `Client` through a few method calls.
 pub struct ClientBuilder {
     homeserver_url: Option<String>,
-    access_token: Option<String>,
+    server_name: Option<OwnedServerName>,
+    session: Option<Session>,
+    on_session_change: Option<SessionCallback>,
     supported_matrix_versions: Option<Vec<MatrixVersion>>,
+    retry_policy: RetryPolicy,
+    request_refresh_token: bool,
 }
 
 impl ClientBuilder {
     pub(super) fn new() -> Self {
-        Self { homeserver_url: None, access_token: None, supported_matrix_versions: None }
+        Self {
+            homeserver_url: None,
+            server_name: None,
+            session: None,
+            on_session_change: None,
+            supported_matrix_versions: None,
+            retry_policy: RetryPolicy::disabled(),
+            request_refresh_token: false,
+        }
     }
 
     /// Set the homeserver URL.
     ///
-    /// The homeserver URL must be set before calling [`build()`][Self::build] or
-    /// [`http_client()`][Self::http_client].
+    /// The homeserver URL, or a server name to discover it from, must be set before calling
+    /// [`build()`][Self::build] or [`http_client()`][Self::http_client].
     pub fn homeserver_url(self, url: String) -> Self {
         Self { homeserver_url: Some(url), ..self }
     }
 
+    /// Set the server name to discover the homeserver URL from.
+    ///
+    /// Unless the homeserver URL was also set, [`build()`][Self::build] or
+    /// [`http_client()`][Self::http_client] will do a [`discover_homeserver`] request to the
+    /// server name to get the homeserver URL, falling back to `https://{server_name}` if the
+    /// server name doesn't advertise any. The homeserver URL is then validated with a
+    /// [`get_supported_versions`] request.
+    pub fn server_name(self, server_name: OwnedServerName) -> Self {
+        Self { server_name: Some(server_name), ..self }
+    }
+
+    /// Set the server name to discover the homeserver URL from to the server name of the given
+    /// user ID.
+    ///
+    /// See [`server_name()`][Self::server_name] for more details.
+    pub fn user_id(self, user_id: &UserId) -> Self {
+        self.server_name(user_id.server_name().to_owned())
+    }
+
     /// Set the access token.
+    ///
+    /// This is a shortcut for [`session()`][Self::session] with a session that only contains an
+    /// access token, which cannot be refreshed.
     pub fn access_token(self, access_token: Option<String>) -> Self {
-        Self { access_token, ..self }
+        Self { session: access_token.map(Session::new), ..self }
+    }
+
+    /// Set the session, usually one that was persisted with a callback set via
+    /// [`on_session_change()`][Self::on_session_change].
+    pub fn session(self, session: Option<Session>) -> Self {
+        Self { session, ..self }
+    }
+
+    /// Set a function to call every time the session of the client changes, after logging in,
+    /// registering or refreshing the access token.
+    ///
+    /// This can be used to persist the session.
+    pub fn on_session_change(self, f: impl Fn(&Session) + Send + Sync + 'static) -> Self {
+        Self { on_session_change: Some(SessionCallback(Arc::new(f))), ..self }
     }
 
     /// Set the supported Matrix versions.
     ///
     /// This method generally *shouldn't* be called. The [`build()`][Self::build] or
     /// [`http_client()`][Self::http_client] method will take care of doing a
-    /// [`get_supported_versions`] request to find out about the supported versions.
+    /// [`get_supported_versions`] request to find out about the supported versions and unstable
+    /// features.
     pub fn supported_matrix_versions(self, versions: Vec<MatrixVersion>) -> Self {
         Self { supported_matrix_versions: Some(versions), ..self }
     }
 
+    /// Set the policy to retry requests that failed because of rate-limiting or transient errors.
+    ///
+    /// Defaults to [`RetryPolicy::disabled()`].
+    pub fn retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Self { retry_policy, ..self }
+    }
+
+    /// Set whether to request a refresh token when logging in or registering.
+    ///
+    /// With a refresh token, the access token returned by the homeserver can expire, and the
+    /// client refreshes it before retrying the request that failed. Applications persisting the
+    /// session should set a callback with [`on_session_change()`][Self::on_session_change] to
+    /// store the new tokens.
+    ///
+    /// Defaults to `false`.
+    pub fn request_refresh_token(self, request_refresh_token: bool) -> Self {
+        Self { request_refresh_token, ..self }
+    }
+
     /// Finish building the [`Client`].
     ///
     /// Uses [`DefaultConstructibleHttpClient::default()`] to create an HTTP client instance.
     /// Unless the supported Matrix versions were manually set via
     /// [`supported_matrix_versions`][Self::supported_matrix_versions], this will do a
     /// [`get_supported_versions`] request to find out about the supported versions.
+    ///
+    /// This also works with a [`WithMiddleware`] HTTP client if the middleware implements
+    /// `Default`.
+    ///
+    /// [`WithMiddleware`]: crate::http_client::WithMiddleware
     pub async fn build<C>(self) -> Result<Client<C>, Error<C::Error, ruma_client_api::Error>>
     where
         C: DefaultConstructibleHttpClient,
@@ -60,6 +138,18 @@ impl ClientBuilder {
     /// Unless the supported Matrix versions were manually set via
     /// [`supported_matrix_versions`][Self::supported_matrix_versions], this will do a
     /// [`get_supported_versions`] request to find out about the supported versions.
+    ///
+    /// If only the server name was set, the homeserver URL is discovered first, see
+    /// [`server_name()`][Self::server_name].
+    ///
+    /// To send the requests of the client through [`Middleware`]s, wrap the HTTP client with
+    /// [`HttpClientExt::with_middleware()`] before passing it to this method.
+    ///
+    /// # Panics
+    ///
+    /// Panics if neither the homeserver URL nor the server name were set.
+    ///
+    /// [`Middleware`]: crate::http_client::Middleware
     pub async fn http_client<C>(
         self,
         http_client: C,
@@ -67,29 +157,187 @@ impl ClientBuilder {
     where
         C: HttpClient,
     {
-        let homeserver_url = self
-            .homeserver_url
-            .expect("homeserver URL has to be set prior to calling .build() or .http_client()");
-
-        let supported_matrix_versions = match self.supported_matrix_versions {
-            Some(versions) => versions,
-            None => http_client
-                .send_matrix_request(
-                    &homeserver_url,
-                    SendAccessToken::None,
-                    &[MatrixVersion::V1_0],
-                    get_supported_versions::Request::new(),
-                )
-                .await?
-                .known_versions()
-                .collect(),
+        // A discovered homeserver URL needs to be validated.
+        let (homeserver_url, validate) = match (self.homeserver_url, &self.server_name) {
+            (Some(url), _) => (url, false),
+            (None, Some(server_name)) => {
+                (discover_homeserver_url(&http_client, server_name).await?, true)
+            }
+            (None, None) => {
+                panic!("homeserver URL or server name has to be set prior to calling .build()")
+            }
         };
 
+        let (supported_matrix_versions, unstable_features) =
+            match (self.supported_matrix_versions, validate) {
+                (Some(versions), false) => (versions, None),
+                (versions, _) => {
+                    let response = http_client
+                        .send_matrix_request(
+                            &homeserver_url,
+                            SendAccessToken::None,
+                            &[MatrixVersion::V1_0],
+                            get_supported_versions::Request::new(),
+                        )
+                        .await?;
+
+                    let versions = versions.unwrap_or_else(|| response.known_versions().collect());
+                    (versions, Some(response.unstable_features))
+                }
+            };
+
         Ok(Client(Arc::new(ClientData {
             homeserver_url,
             http_client,
-            access_token: Mutex::new(self.access_token),
+            session: Mutex::new(self.session),
+            on_session_change: self.on_session_change,
             supported_matrix_versions,
+            unstable_features,
+            capabilities: Mutex::new(None),
+            max_upload_size: Mutex::new(None),
+            retry_policy: self.retry_policy,
+            request_refresh_token: self.request_refresh_token,
         })))
     }
 }
+
+/// Get the homeserver URL advertised by the given server name.
+async fn discover_homeserver_url<C: HttpClient>(
+    http_client: &C,
+    server_name: &ServerName,
+) -> Result<String, Error<C::Error, ruma_client_api::Error>> {
+    let server_url = format!("https://{server_name}");
+
+    match http_client
+        .send_matrix_request(
+            &server_url,
+            SendAccessToken::None,
+            &[MatrixVersion::V1_0],
+            discover_homeserver::Request::new(),
+        )
+        .await
+    {
+        Ok(response) => Ok(response.homeserver.base_url.trim_end_matches('/').to_owned()),
+        Err(Error::FromHttpResponse(FromHttpResponseError::Server(error)))
+            if error.status_code == StatusCode::NOT_FOUND =>
+        {
+            debug!("{server_name} doesn't advertise a homeserver, falling back to {server_url}");
+            Ok(server_url)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_matches;
+    use http::StatusCode;
+    use ruma_client_api::discovery::{
+        discover_homeserver, get_capabilities, get_supported_versions,
+    };
+    use ruma_common::{api::MatrixVersion, server_name, user_id};
+    use serde_json::{json, Value as JsonValue};
+
+    use crate::{
+        http_client::mock::{MockError, MockHttpClient},
+        Client, Error,
+    };
+
+    fn versions() -> JsonValue {
+        json!({
+            "versions": ["v1.1"],
+            "unstable_features": { "org.matrix.msc3575": true, "org.matrix.msc2285": false },
+        })
+    }
+
+    #[tokio::test]
+    async fn discover_homeserver() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<discover_homeserver::Request>(
+                StatusCode::OK,
+                json!({ "m.homeserver": { "base_url": "https://matrix.example.org/" } }),
+            )
+            .on_json::<get_supported_versions::Request>(StatusCode::OK, versions());
+
+        let client = Client::builder()
+            .user_id(user_id!("@alice:example.org"))
+            .http_client(http_client.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(client.homeserver_url(), "https://matrix.example.org");
+        assert_eq!(client.supported_matrix_versions(), [MatrixVersion::V1_1]);
+        assert!(client.supports_unstable_feature("org.matrix.msc3575"));
+        assert!(!client.supports_unstable_feature("org.matrix.msc2285"));
+        assert!(!client.supports_unstable_feature("org.matrix.msc0000"));
+
+        let requests = http_client.take_requests();
+        assert_eq!(requests[0].uri(), "https://example.org/.well-known/matrix/client");
+        assert_eq!(requests[1].uri(), "https://matrix.example.org/_matrix/client/versions");
+    }
+
+    #[tokio::test]
+    async fn discovery_falls_back_to_server_name() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<discover_homeserver::Request>(
+                StatusCode::NOT_FOUND,
+                json!({ "errcode": "M_NOT_FOUND", "error": "Not found" }),
+            )
+            .on_json::<get_supported_versions::Request>(StatusCode::OK, versions());
+
+        let client = Client::builder()
+            .server_name(server_name!("example.org").to_owned())
+            .supported_matrix_versions(vec![MatrixVersion::V1_3])
+            .http_client(http_client.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(client.homeserver_url(), "https://example.org");
+        // The versions that were set manually are kept.
+        assert_eq!(client.supported_matrix_versions(), [MatrixVersion::V1_3]);
+        assert!(client.supports_unstable_feature("org.matrix.msc3575"));
+
+        let requests = http_client.take_requests();
+        assert_eq!(requests[1].uri(), "https://example.org/_matrix/client/versions");
+    }
+
+    #[tokio::test]
+    async fn invalid_homeserver() {
+        let http_client = MockHttpClient::new();
+        http_client.on_json::<discover_homeserver::Request>(
+            StatusCode::OK,
+            json!({ "m.homeserver": { "base_url": "https://matrix.example.org" } }),
+        );
+
+        let result = Client::builder()
+            .server_name(server_name!("example.org").to_owned())
+            .http_client(http_client)
+            .await;
+
+        assert_matches!(result, Err(Error::Response(MockError::UnmatchedRequest { uri, .. })));
+        assert_eq!(uri, "https://matrix.example.org/_matrix/client/versions");
+    }
+
+    #[tokio::test]
+    async fn capabilities_are_cached() {
+        let http_client = MockHttpClient::new();
+        http_client.on_json::<get_capabilities::v3::Request>(
+            StatusCode::OK,
+            json!({ "capabilities": { "m.change_password": { "enabled": false } } }),
+        );
+
+        let client = Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .access_token(Some("access_token".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .http_client(http_client.clone())
+            .await
+            .unwrap();
+
+        assert!(!client.capabilities().await.unwrap().change_password.enabled);
+        assert!(!client.capabilities().await.unwrap().change_password.enabled);
+        assert_eq!(http_client.take_requests().len(), 1);
+    }
+}
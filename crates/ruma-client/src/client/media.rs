@@ -0,0 +1,431 @@
+use std::{
+    fmt,
+    future::poll_fn,
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use async_stream::try_stream;
+use futures_io::AsyncRead;
+use http::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE};
+use js_int::UInt;
+use ruma_client_api::media::{
+    create_content, create_content_async, get_content, get_content_thumbnail, get_media_config,
+};
+use ruma_common::{
+    api::{error::FromHttpResponseError, EndpointError, OutgoingRequest, SendAccessToken},
+    MxcUri,
+};
+use tracing::Instrument;
+
+use super::Client;
+use crate::{
+    deserialize_response,
+    http_client::{collect_stream, reader_stream, ByteStream, StreamingHttpClient},
+    send_span, serialize_request, Error, HttpClient, ResponseResult,
+};
+
+/// A media file to upload with [`Client::upload_media()`] or [`Client::upload_reserved_media()`].
+#[non_exhaustive]
+pub struct MediaUpload {
+    /// The content of the file.
+    pub body: ByteStream,
+
+    /// The size of the file in bytes, if known.
+    ///
+    /// If it is set, it is checked against the maximum upload size of the homeserver before
+    /// sending the request.
+    pub size: Option<u64>,
+
+    /// The MIME type of the file, if known.
+    pub content_type: Option<String>,
+
+    /// The name of the file, if any.
+    pub filename: Option<String>,
+}
+
+impl MediaUpload {
+    /// Creates a new `MediaUpload` with the given content.
+    pub fn new(body: ByteStream) -> Self {
+        Self { body, size: None, content_type: None, filename: None }
+    }
+
+    /// Creates a new `MediaUpload` with the content of the given reader.
+    pub fn from_reader<R>(reader: R) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        Self::new(reader_stream(reader))
+    }
+
+    /// Set the size of the file in bytes.
+    pub fn size(self, size: u64) -> Self {
+        Self { size: Some(size), ..self }
+    }
+
+    /// Set the MIME type of the file.
+    pub fn content_type(self, content_type: String) -> Self {
+        Self { content_type: Some(content_type), ..self }
+    }
+
+    /// Set the name of the file.
+    pub fn filename(self, filename: String) -> Self {
+        Self { filename: Some(filename), ..self }
+    }
+}
+
+impl fmt::Debug for MediaUpload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MediaUpload")
+            .field("size", &self.size)
+            .field("content_type", &self.content_type)
+            .field("filename", &self.filename)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A media file downloaded with [`Client::download_media()`] or [`Client::download_thumbnail()`].
+#[non_exhaustive]
+pub struct MediaDownload {
+    /// The content of the file.
+    pub body: ByteStream,
+
+    /// The size of the file in bytes, if the homeserver sent it.
+    pub size: Option<u64>,
+
+    /// The MIME type of the file, if the homeserver sent it.
+    pub content_type: Option<String>,
+
+    /// The value of the `Content-Disposition` header, with the name of the file, if the homeserver
+    /// sent it.
+    pub content_disposition: Option<String>,
+}
+
+impl fmt::Debug for MediaDownload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MediaDownload")
+            .field("size", &self.size)
+            .field("content_type", &self.content_type)
+            .field("content_disposition", &self.content_disposition)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: HttpClient> Client<C> {
+    /// Get the maximum size of uploads allowed by the homeserver, in bytes.
+    ///
+    /// It is only fetched with a [`get_media_config`] request the first time, and cached
+    /// afterwards.
+    pub async fn max_upload_size(&self) -> Result<u64, Error<C::Error, ruma_client_api::Error>> {
+        if let Some(max_upload_size) =
+            *self.0.max_upload_size.lock().expect("max upload size mutex was poisoned")
+        {
+            return Ok(max_upload_size);
+        }
+
+        let max_upload_size =
+            self.send_request(get_media_config::v3::Request::new()).await?.upload_size.into();
+        *self.0.max_upload_size.lock().expect("max upload size mutex was poisoned") =
+            Some(max_upload_size);
+
+        Ok(max_upload_size)
+    }
+}
+
+impl<C: StreamingHttpClient> Client<C> {
+    /// Upload a media file without buffering it.
+    ///
+    /// Returns [`Error::MediaTooLarge`] if the file is larger than
+    /// [`max_upload_size()`](Self::max_upload_size), before sending it if its size is known.
+    ///
+    /// Unlike [`send_request()`](Self::send_request), the request is not retried if it fails.
+    pub async fn upload_media(
+        &self,
+        upload: MediaUpload,
+    ) -> Result<create_content::v3::Response, Error<C::Error, ruma_client_api::Error>> {
+        let mut request = create_content::v3::Request::new(Vec::new());
+        request.content_type = upload.content_type;
+        request.filename = upload.filename;
+
+        self.send_upload(request, upload.body, upload.size).await
+    }
+
+    /// Upload the content of a media file whose MXC URI was reserved with a [`create_mxc_uri`]
+    /// request, without buffering it.
+    ///
+    /// Returns [`Error::MediaTooLarge`] if the file is larger than
+    /// [`max_upload_size()`](Self::max_upload_size), before sending it if its size is known.
+    ///
+    /// Unlike [`send_request()`](Self::send_request), the request is not retried if it fails.
+    ///
+    /// [`create_mxc_uri`]: ruma_client_api::media::create_mxc_uri
+    pub async fn upload_reserved_media(
+        &self,
+        content_uri: &MxcUri,
+        upload: MediaUpload,
+    ) -> Result<create_content_async::v3::Response, Error<C::Error, ruma_client_api::Error>> {
+        let (server_name, media_id) = content_uri.parts().map_err(Error::InvalidMxcUri)?;
+        let mut request = create_content_async::v3::Request::new(
+            media_id.to_owned(),
+            server_name.to_owned(),
+            Vec::new(),
+        );
+        request.content_type = upload.content_type;
+        request.filename = upload.filename;
+
+        self.send_upload(request, upload.body, upload.size).await
+    }
+
+    /// Download a media file without buffering it.
+    ///
+    /// The request is sent to the homeserver of the client, with the server name of the MXC URI,
+    /// so remote media are fetched from the server that created them.
+    pub async fn download_media(
+        &self,
+        content_uri: &MxcUri,
+    ) -> Result<MediaDownload, Error<C::Error, ruma_client_api::Error>> {
+        let (server_name, media_id) = content_uri.parts().map_err(Error::InvalidMxcUri)?;
+        let request = get_content::v3::Request::new(media_id.to_owned(), server_name.to_owned());
+
+        self.send_download(request).await
+    }
+
+    /// Download a thumbnail of a media file without buffering it.
+    ///
+    /// The request is sent to the homeserver of the client, with the server name of the MXC URI,
+    /// so remote media are fetched from the server that created them.
+    pub async fn download_thumbnail(
+        &self,
+        content_uri: &MxcUri,
+        width: UInt,
+        height: UInt,
+        method: Option<get_content_thumbnail::v3::Method>,
+    ) -> Result<MediaDownload, Error<C::Error, ruma_client_api::Error>> {
+        let (server_name, media_id) = content_uri.parts().map_err(Error::InvalidMxcUri)?;
+        let mut request = get_content_thumbnail::v3::Request::new(
+            media_id.to_owned(),
+            server_name.to_owned(),
+            width,
+            height,
+        );
+        request.method = method;
+
+        self.send_download(request).await
+    }
+
+    /// Send the given upload request with the given streamed body.
+    async fn send_upload<R>(
+        &self,
+        request: R,
+        body: ByteStream,
+        size: Option<u64>,
+    ) -> ResponseResult<C, R>
+    where
+        R: OutgoingRequest<EndpointError = ruma_client_api::Error>,
+    {
+        self.check_unstable_feature::<R, _>()?;
+
+        let max_size = self.max_upload_size().await?;
+        if size.is_some_and(|size| size > max_size) {
+            return Err(Error::MediaTooLarge { max_size });
+        }
+
+        let access_token = self.access_token();
+        let send_access_token = match access_token.as_deref() {
+            Some(at) => SendAccessToken::IfRequired(at),
+            None => SendAccessToken::None,
+        };
+        let mut http_req = request.try_into_http_request::<Vec<u8>>(
+            &self.0.homeserver_url,
+            send_access_token,
+            &self.0.supported_matrix_versions,
+        )?;
+        if let Some(size) = size {
+            http_req.headers_mut().insert(CONTENT_LENGTH, size.into());
+        }
+
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let http_req = http_req.map(|_| limit_size(body, max_size, exceeded.clone()));
+
+        let result = self
+            .0
+            .http_client
+            .send_http_request_streaming_body(http_req)
+            .instrument(send_span::<C, R>(&self.0.homeserver_url))
+            .await;
+
+        if exceeded.load(Ordering::SeqCst) {
+            return Err(Error::MediaTooLarge { max_size });
+        }
+
+        deserialize_response::<C, R>(result.map_err(Error::Response)?)
+    }
+
+    /// Send the given download request and stream the body of the response.
+    async fn send_download<R>(
+        &self,
+        request: R,
+    ) -> Result<MediaDownload, Error<C::Error, ruma_client_api::Error>>
+    where
+        R: OutgoingRequest<EndpointError = ruma_client_api::Error>,
+    {
+        self.check_unstable_feature::<R, _>()?;
+
+        let access_token = self.access_token();
+        let send_access_token = match access_token.as_deref() {
+            Some(at) => SendAccessToken::IfRequired(at),
+            None => SendAccessToken::None,
+        };
+        let http_req = serialize_request::<C, R, _>(
+            &self.0.homeserver_url,
+            send_access_token,
+            &self.0.supported_matrix_versions,
+            request,
+            |_| Ok(()),
+        )?;
+
+        let http_res = self
+            .0
+            .http_client
+            .send_http_request_streaming_response(http_req)
+            .instrument(send_span::<C, R>(&self.0.homeserver_url))
+            .await
+            .map_err(Error::Response)?;
+
+        let (parts, body) = http_res.into_parts();
+        if !parts.status.is_success() {
+            // Error responses are small, and need to be deserialized.
+            let body = collect_stream(body).await.unwrap_or_default();
+            let error =
+                ruma_client_api::Error::from_http_response(http::Response::from_parts(parts, body));
+            return Err(Error::FromHttpResponse(FromHttpResponseError::Server(error)));
+        }
+
+        let header = |name| parts.headers.get(name)?.to_str().ok().map(ToOwned::to_owned);
+        Ok(MediaDownload {
+            size: header(CONTENT_LENGTH).and_then(|size| size.parse().ok()),
+            content_type: header(CONTENT_TYPE),
+            content_disposition: header(CONTENT_DISPOSITION),
+            body,
+        })
+    }
+}
+
+/// Make the given stream fail when it is larger than the given size, and set `exceeded`.
+fn limit_size(mut body: ByteStream, max_size: u64, exceeded: Arc<AtomicBool>) -> ByteStream {
+    Box::pin(try_stream! {
+        let mut size = 0;
+
+        while let Some(chunk) = poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+
+            if size > max_size {
+                exceeded.store(true, Ordering::SeqCst);
+                Err(io::Error::new(io::ErrorKind::InvalidData, "media is too large"))?;
+            }
+
+            yield chunk;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_matches;
+    use http::StatusCode;
+    use ruma_client_api::media::{create_content, get_content, get_media_config};
+    use ruma_common::{api::MatrixVersion, mxc_uri, MxcUri};
+    use serde_json::json;
+
+    use super::MediaUpload;
+    use crate::{
+        http_client::{collect_stream, mock::MockHttpClient},
+        Client, Error,
+    };
+
+    async fn client(http_client: MockHttpClient) -> Client<MockHttpClient> {
+        Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .access_token(Some("abc".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .http_client(http_client)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn upload_honours_max_size() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<get_media_config::v3::Request>(
+                StatusCode::OK,
+                json!({ "m.upload.size": 10 }),
+            )
+            .on_json::<create_content::v3::Request>(
+                StatusCode::OK,
+                json!({ "content_uri": "mxc://example.org/abc" }),
+            );
+        let client = client(http_client.clone()).await;
+
+        let upload = MediaUpload::from_reader(&b"hello"[..]).content_type("text/plain".to_owned());
+        let response = client.upload_media(upload).await.unwrap();
+        assert_eq!(response.content_uri, "mxc://example.org/abc");
+
+        let requests = http_client.take_requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].headers()[http::header::CONTENT_TYPE], "text/plain");
+        assert_eq!(requests[1].body(), b"hello");
+
+        // The size is known, the request is not sent.
+        let upload = MediaUpload::from_reader(&b"hello world"[..]).size(11);
+        let result = client.upload_media(upload).await;
+        assert_matches!(result, Err(Error::MediaTooLarge { max_size: 10 }));
+        assert_eq!(http_client.take_requests().len(), 0);
+
+        // The size is unknown, the body fails while it is read.
+        let upload = MediaUpload::from_reader(&b"hello world"[..]);
+        let result = client.upload_media(upload).await;
+        assert_matches!(result, Err(Error::MediaTooLarge { max_size: 10 }));
+    }
+
+    #[tokio::test]
+    async fn download_from_media_server() {
+        let http_client = MockHttpClient::new();
+        http_client.on::<get_content::v3::Request>(
+            http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "image/png")
+                .header(http::header::CONTENT_LENGTH, "3")
+                .body(b"png".to_vec())
+                .unwrap(),
+        );
+        let client = client(http_client.clone()).await;
+
+        let download = client.download_media(mxc_uri!("mxc://remote.org/media")).await.unwrap();
+        assert_eq!(download.content_type.as_deref(), Some("image/png"));
+        assert_eq!(download.size, Some(3));
+        assert_eq!(collect_stream(download.body).await.unwrap(), b"png");
+
+        let requests = http_client.take_requests();
+        assert_eq!(requests[0].uri().path(), "/_matrix/media/v3/download/remote.org/media");
+
+        let result = client.download_media(<&MxcUri>::from("mxc://remote.org")).await;
+        assert_matches!(result, Err(Error::InvalidMxcUri(_)));
+    }
+
+    #[tokio::test]
+    async fn download_error() {
+        let http_client = MockHttpClient::new();
+        http_client.on_json::<get_content::v3::Request>(
+            StatusCode::NOT_FOUND,
+            json!({ "errcode": "M_NOT_FOUND", "error": "Not found" }),
+        );
+        let client = client(http_client).await;
+
+        let result = client.download_media(mxc_uri!("mxc://example.org/media")).await;
+        assert_matches!(result, Err(Error::FromHttpResponse(_)));
+    }
+}
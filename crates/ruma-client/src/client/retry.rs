@@ -0,0 +1,289 @@
+use std::time::Duration;
+
+use http::{header::RETRY_AFTER, Method, StatusCode};
+use serde::Deserialize;
+
+/// A policy to retry requests that failed because of rate-limiting or transient errors.
+///
+/// A request is retried when:
+///
+/// * the homeserver answered with `429 Too Many Requests`, which is usually an `M_LIMIT_EXCEEDED`
+///   error. The request was not processed, so it is retried whatever its method. The delay
+///   requested by the homeserver, with `retry_after_ms` or a `Retry-After` header, is honoured.
+/// * the homeserver answered with a `5xx` status code, or no response could be obtained. The
+///   request might have been processed, so it is only retried if its method is idempotent, unless
+///   [`retry_non_idempotent`][Self::retry_non_idempotent] is set.
+///
+/// Other requests are retried after an exponential backoff: the first retry waits for the
+/// [initial backoff][Self::initial_backoff], and every further retry waits twice as long as the
+/// previous one, up to the [maximum backoff][Self::max_backoff].
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    retry_non_idempotent: bool,
+}
+
+impl RetryPolicy {
+    /// Creates a new `RetryPolicy` with the default settings.
+    ///
+    /// Requests are sent at most 5 times, with an initial backoff of 500 milliseconds, a maximum
+    /// backoff of 30 seconds and jitter.
+    pub fn new() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: true,
+            retry_non_idempotent: false,
+        }
+    }
+
+    /// Creates a new `RetryPolicy` that never retries requests.
+    pub fn disabled() -> Self {
+        Self { max_attempts: 1, ..Self::new() }
+    }
+
+    /// Set the maximum number of times a request is sent, including the first attempt.
+    ///
+    /// A value of 0 is treated like 1.
+    pub fn max_attempts(self, max_attempts: u32) -> Self {
+        Self { max_attempts, ..self }
+    }
+
+    /// Set the delay before the first retry.
+    pub fn initial_backoff(self, initial_backoff: Duration) -> Self {
+        Self { initial_backoff, ..self }
+    }
+
+    /// Set the maximum delay between two attempts.
+    ///
+    /// If the homeserver asks to wait longer than that, the request is not retried.
+    pub fn max_backoff(self, max_backoff: Duration) -> Self {
+        Self { max_backoff, ..self }
+    }
+
+    /// Set whether a random part of the backoff is removed, to avoid clients that failed at the
+    /// same time retrying at the same time.
+    pub fn jitter(self, jitter: bool) -> Self {
+        Self { jitter, ..self }
+    }
+
+    /// Set whether requests with a method that is not idempotent, like `POST`, are retried after
+    /// a server error or a network error.
+    pub fn retry_non_idempotent(self, retry_non_idempotent: bool) -> Self {
+        Self { retry_non_idempotent, ..self }
+    }
+
+    /// The delay to wait before sending a request again after the given failed attempt, starting
+    /// at 1, or `None` if the request should not be retried.
+    pub(crate) fn delay_for<B: AsRef<[u8]>, E>(
+        &self,
+        method: &Method,
+        attempt: u32,
+        result: &Result<http::Response<B>, E>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        let retry_after = match result {
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                retry_after(response)
+            }
+            Ok(response) if response.status().is_server_error() && self.can_retry(method) => None,
+            Err(_) if self.can_retry(method) => None,
+            _ => return None,
+        };
+
+        match retry_after {
+            Some(delay) if delay > self.max_backoff => None,
+            Some(delay) => Some(delay),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+
+    fn can_retry(&self, method: &Method) -> bool {
+        self.retry_non_idempotent || method.is_idempotent()
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .checked_mul(2_u32.saturating_pow(attempt - 1))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff));
+
+        if self.jitter {
+            // Keep at least half of the backoff.
+            let half = backoff / 2;
+            half + Duration::from_millis(fastrand::u64(..=half.as_millis() as u64))
+        } else {
+            backoff
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The delay requested by the homeserver in a `429 Too Many Requests` response.
+fn retry_after<B: AsRef<[u8]>>(response: &http::Response<B>) -> Option<Duration> {
+    #[derive(Deserialize)]
+    struct LimitExceeded {
+        retry_after_ms: Option<u64>,
+    }
+
+    if let Ok(LimitExceeded { retry_after_ms: Some(retry_after_ms) }) =
+        serde_json::from_slice(response.body().as_ref())
+    {
+        return Some(Duration::from_millis(retry_after_ms));
+    }
+
+    // Only the delay-seconds form of the header is supported.
+    let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use assert_matches2::assert_matches;
+    use http::{header::RETRY_AFTER, Method, StatusCode};
+    use ruma_client_api::{discovery::get_supported_versions, session::logout};
+    use ruma_common::api::MatrixVersion;
+    use serde_json::json;
+
+    use super::RetryPolicy;
+    use crate::{http_client::mock::MockHttpClient, Client, Error};
+
+    fn response(status: StatusCode, body: &str) -> Result<http::Response<Vec<u8>>, ()> {
+        Ok(http::Response::builder().status(status).body(body.as_bytes().to_vec()).unwrap())
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::new().initial_backoff(Duration::from_secs(1)).jitter(false)
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = policy().max_backoff(Duration::from_secs(3));
+        let res = response(StatusCode::BAD_GATEWAY, "");
+
+        assert_eq!(policy.delay_for(&Method::GET, 1, &res), Some(Duration::from_secs(1)));
+        assert_eq!(policy.delay_for(&Method::GET, 2, &res), Some(Duration::from_secs(2)));
+        assert_eq!(policy.delay_for(&Method::GET, 3, &res), Some(Duration::from_secs(3)));
+        assert_eq!(policy.delay_for(&Method::GET, 5, &res), None);
+    }
+
+    #[test]
+    fn jitter_keeps_half_of_the_backoff() {
+        let policy = policy().jitter(true);
+        let res = response(StatusCode::SERVICE_UNAVAILABLE, "");
+
+        let delay = policy.delay_for(&Method::GET, 2, &res).unwrap();
+        assert!(delay >= Duration::from_secs(1));
+        assert!(delay <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn idempotency() {
+        let policy = policy();
+        let server_error = response(StatusCode::INTERNAL_SERVER_ERROR, "");
+        let limit_exceeded = response(StatusCode::TOO_MANY_REQUESTS, "");
+
+        assert_eq!(policy.delay_for(&Method::POST, 1, &server_error), None);
+        assert_eq!(
+            policy.delay_for(&Method::POST, 1, &Err::<http::Response<Vec<u8>>, _>(())),
+            None
+        );
+        assert!(policy.delay_for(&Method::POST, 1, &limit_exceeded).is_some());
+        assert!(policy.delay_for(&Method::PUT, 1, &server_error).is_some());
+        assert!(policy
+            .retry_non_idempotent(true)
+            .delay_for(&Method::POST, 1, &server_error)
+            .is_some());
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let res = response(StatusCode::FORBIDDEN, r#"{"errcode":"M_FORBIDDEN","error":""}"#);
+        assert_eq!(policy().delay_for(&Method::GET, 1, &res), None);
+    }
+
+    #[test]
+    fn honour_retry_after() {
+        let policy = policy();
+        let res = response(
+            StatusCode::TOO_MANY_REQUESTS,
+            r#"{"errcode":"M_LIMIT_EXCEEDED","error":"Too many requests","retry_after_ms":2500}"#,
+        );
+        assert_eq!(policy.delay_for(&Method::POST, 1, &res), Some(Duration::from_millis(2500)));
+
+        let res = Ok::<_, ()>(
+            http::Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(RETRY_AFTER, "7")
+                .body(Vec::new())
+                .unwrap(),
+        );
+        assert_eq!(policy.delay_for(&Method::POST, 1, &res), Some(Duration::from_secs(7)));
+
+        let res = response(StatusCode::TOO_MANY_REQUESTS, r#"{"retry_after_ms":60000}"#);
+        assert_eq!(policy.delay_for(&Method::POST, 1, &res), None);
+    }
+
+    async fn client(http_client: MockHttpClient) -> Client<MockHttpClient> {
+        Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .access_token(Some("access_token".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .retry_policy(RetryPolicy::new().initial_backoff(Duration::from_millis(1)))
+            .http_client(http_client)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_retries_rate_limited_request() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<get_supported_versions::Request>(
+                StatusCode::TOO_MANY_REQUESTS,
+                json!({ "errcode": "M_LIMIT_EXCEEDED", "retry_after_ms": 1 }),
+            )
+            .on::<get_supported_versions::Request>(
+                http::Response::builder().status(StatusCode::BAD_GATEWAY).body(Vec::new()).unwrap(),
+            )
+            .on_json::<get_supported_versions::Request>(
+                StatusCode::OK,
+                json!({ "versions": ["v1.1"] }),
+            );
+        let client = client(http_client.clone()).await;
+
+        let response = client.send_request(get_supported_versions::Request::new()).await.unwrap();
+        assert_eq!(response.versions, ["v1.1"]);
+        assert_eq!(http_client.take_requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn client_does_not_retry_non_idempotent_request() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<logout::v3::Request>(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "errcode": "M_UNKNOWN", "error": "Oops" }),
+            )
+            .on_json::<logout::v3::Request>(StatusCode::OK, json!({}));
+        let client = client(http_client.clone()).await;
+
+        let result = client.send_request(logout::v3::Request::new()).await;
+        assert_matches!(result, Err(Error::FromHttpResponse(_)));
+        assert_eq!(http_client.take_requests().len(), 1);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use http::StatusCode;
+use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+
+/// The session of a [`Client`](super::Client).
+///
+/// It can be serialized to be persisted, and restored later with
+/// [`ClientBuilder::session()`](super::ClientBuilder::session). To be notified when it changes,
+/// for example when the access token is refreshed, use
+/// [`ClientBuilder::on_session_change()`](super::ClientBuilder::on_session_change).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Session {
+    /// The access token used to authenticate requests.
+    pub access_token: String,
+
+    /// The token used to get a new access token when it expires, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+
+    /// When the access token expires, if it does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<MilliSecondsSinceUnixEpoch>,
+
+    /// The ID of the logged-in user, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<OwnedUserId>,
+
+    /// The ID of the device of the session, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<OwnedDeviceId>,
+}
+
+impl Session {
+    /// Creates a new `Session` with the given access token.
+    pub fn new(access_token: String) -> Self {
+        Self { access_token, refresh_token: None, expires_at: None, user_id: None, device_id: None }
+    }
+
+    /// Whether the access token has expired, according to [`expires_at`][Self::expires_at].
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= MilliSecondsSinceUnixEpoch::now())
+    }
+
+    /// Update this session with a new access token, refresh token and lifetime of the access
+    /// token.
+    ///
+    /// The refresh token is only replaced if a new one is provided.
+    pub(crate) fn update_tokens(
+        &mut self,
+        access_token: String,
+        refresh_token: Option<String>,
+        expires_in: Option<Duration>,
+    ) {
+        self.access_token = access_token;
+        if refresh_token.is_some() {
+            self.refresh_token = refresh_token;
+        }
+        self.expires_at = expires_in.and_then(expires_at);
+    }
+}
+
+/// Convert the lifetime of an access token to the time it expires.
+fn expires_at(expires_in: Duration) -> Option<MilliSecondsSinceUnixEpoch> {
+    MilliSecondsSinceUnixEpoch::from_system_time(SystemTime::now().checked_add(expires_in)?)
+}
+
+/// Whether the given response is an `M_UNKNOWN_TOKEN` error with `soft_logout` set, meaning that
+/// the access token expired.
+pub(crate) fn is_soft_logout<B: AsRef<[u8]>, E>(result: &Result<http::Response<B>, E>) -> bool {
+    #[derive(Deserialize)]
+    struct UnknownToken {
+        errcode: String,
+        #[serde(default)]
+        soft_logout: bool,
+    }
+
+    let Ok(response) = result else {
+        return false;
+    };
+
+    response.status() == StatusCode::UNAUTHORIZED
+        && serde_json::from_slice::<UnknownToken>(response.body().as_ref())
+            .is_ok_and(|error| error.errcode == "M_UNKNOWN_TOKEN" && error.soft_logout)
+}
+
+/// A function called with the new session every time it changes.
+#[derive(Clone)]
+pub(crate) struct SessionCallback(pub(crate) Arc<dyn Fn(&Session) + Send + Sync>);
+
+impl fmt::Debug for SessionCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCallback").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use http::{header::AUTHORIZATION, StatusCode};
+    use ruma_client_api::{
+        discovery::get_supported_versions,
+        session::{login, refresh_token},
+    };
+    use ruma_common::{api::MatrixVersion, owned_device_id, owned_user_id};
+    use serde_json::{
+        from_slice as from_json_slice, from_value as from_json_value, json,
+        to_value as to_json_value, Value as JsonValue,
+    };
+
+    use super::Session;
+    use crate::{http_client::mock::MockHttpClient, Client};
+
+    #[test]
+    fn serde_roundtrip() {
+        let mut session = Session::new("access".to_owned());
+        session.user_id = Some(owned_user_id!("@alice:example.org"));
+        session.device_id = Some(owned_device_id!("ABCDEF"));
+        session.update_tokens(
+            "new_access".to_owned(),
+            Some("refresh".to_owned()),
+            Some(Duration::from_secs(60)),
+        );
+        assert!(!session.is_expired());
+
+        let json = to_json_value(&session).unwrap();
+        assert_eq!(json["access_token"], "new_access");
+        assert_eq!(json["refresh_token"], "refresh");
+        assert_eq!(json["user_id"], "@alice:example.org");
+        assert!(json["expires_at"].is_u64());
+
+        let restored: Session = from_json_value(json).unwrap();
+        assert_eq!(restored.access_token, "new_access");
+        assert_eq!(restored.expires_at, session.expires_at);
+
+        let session: Session = from_json_value(json!({ "access_token": "access" })).unwrap();
+        assert_eq!(session.refresh_token, None);
+        assert!(!session.is_expired());
+    }
+
+    #[tokio::test]
+    async fn refresh_on_soft_logout() {
+        let mut session = Session::new("old_access".to_owned());
+        session.refresh_token = Some("old_refresh".to_owned());
+        let changes = Arc::new(Mutex::new(Vec::new()));
+
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<get_supported_versions::Request>(
+                StatusCode::UNAUTHORIZED,
+                json!({ "errcode": "M_UNKNOWN_TOKEN", "error": "Expired", "soft_logout": true }),
+            )
+            .on_json::<get_supported_versions::Request>(
+                StatusCode::OK,
+                json!({ "versions": ["v1.3"] }),
+            )
+            .on_json::<refresh_token::v3::Request>(
+                StatusCode::OK,
+                json!({
+                    "access_token": "new_access",
+                    "refresh_token": "new_refresh",
+                    "expires_in_ms": 60000,
+                }),
+            );
+
+        let client = Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .session(Some(session))
+            .on_session_change({
+                let changes = changes.clone();
+                move |session| changes.lock().unwrap().push(session.clone())
+            })
+            .supported_matrix_versions(vec![MatrixVersion::V1_3])
+            .http_client(http_client.clone())
+            .await
+            .unwrap();
+
+        let response = client
+            .send_customized_request(get_supported_versions::Request::new(), |req| {
+                // Force authentication on an endpoint that doesn't need it.
+                req.headers_mut()
+                    .entry(AUTHORIZATION)
+                    .or_insert_with(|| "Bearer none".parse().unwrap());
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.versions, ["v1.3"]);
+
+        let requests = http_client.take_requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].uri().path(), "/_matrix/client/v3/refresh");
+        assert_eq!(requests[1].headers().get(AUTHORIZATION), None);
+
+        let session = client.session().unwrap();
+        assert_eq!(session.access_token, "new_access");
+        assert_eq!(session.refresh_token.as_deref(), Some("new_refresh"));
+        assert!(session.expires_at.is_some());
+
+        let changes = changes.lock().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].access_token, "new_access");
+    }
+
+    #[tokio::test]
+    async fn hard_logout_is_not_refreshed() {
+        let mut session = Session::new("access".to_owned());
+        session.refresh_token = Some("refresh".to_owned());
+
+        let http_client = MockHttpClient::new();
+        http_client.on_json::<get_supported_versions::Request>(
+            StatusCode::UNAUTHORIZED,
+            json!({ "errcode": "M_UNKNOWN_TOKEN", "error": "Logged out" }),
+        );
+
+        let client = Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .session(Some(session))
+            .supported_matrix_versions(vec![MatrixVersion::V1_3])
+            .http_client(http_client.clone())
+            .await
+            .unwrap();
+
+        client.send_request(get_supported_versions::Request::new()).await.unwrap_err();
+        assert_eq!(http_client.take_requests().len(), 1);
+        assert_eq!(client.access_token().as_deref(), Some("access"));
+    }
+
+    #[tokio::test]
+    async fn refresh_token_is_opt_in() {
+        let http_client = MockHttpClient::new();
+        http_client.on_json::<login::v3::Request>(
+            StatusCode::OK,
+            json!({
+                "access_token": "access",
+                "device_id": "DEVICE",
+                "user_id": "@alice:example.org",
+                "refresh_token": "refresh",
+            }),
+        );
+
+        for request_refresh_token in [false, true] {
+            let client = Client::builder()
+                .homeserver_url("https://example.org".to_owned())
+                .supported_matrix_versions(vec![MatrixVersion::V1_3])
+                .request_refresh_token(request_refresh_token)
+                .http_client(http_client.clone())
+                .await
+                .unwrap();
+
+            client.log_in("alice", "secret", None, None).await.unwrap();
+
+            let requests = http_client.take_requests();
+            let body: JsonValue = from_json_slice(requests[0].body()).unwrap();
+            assert_eq!(body.get("refresh_token").is_some(), request_refresh_token);
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use std::{sync::Arc, time::Duration};
+
+use assign::assign;
+use async_stream::stream;
+use async_trait::async_trait;
+use futures_core::stream::Stream;
+use http::StatusCode;
+use ruma_client_api::{
+    account::whoami,
+    filter::{create_filter, FilterDefinition},
+    message::get_message_events,
+    sync::sync_events::{
+        self,
+        v3::{Filter, Timeline},
+    },
+};
+use ruma_common::{api::error::FromHttpResponseError, presence::PresenceState, OwnedRoomId};
+use tracing::warn;
+
+use super::Client;
+use crate::{Error, HttpClient};
+
+/// A store persisting the position of the sync loop of [`Client::sync_with_store`], so it can be
+/// resumed later.
+#[async_trait]
+pub trait SyncStore: Send + Sync {
+    /// Load the `next_batch` token of the last sync response, if any.
+    async fn load_next_batch(&self) -> Option<String>;
+
+    /// Save the `next_batch` token of the last sync response.
+    ///
+    /// This is called before the sync response is yielded.
+    async fn save_next_batch(&self, next_batch: &str);
+}
+
+#[async_trait]
+impl<T: SyncStore + ?Sized> SyncStore for Arc<T> {
+    async fn load_next_batch(&self) -> Option<String> {
+        (**self).load_next_batch().await
+    }
+
+    async fn save_next_batch(&self, next_batch: &str) {
+        (**self).save_next_batch(next_batch).await;
+    }
+}
+
+/// A [`SyncStore`] that doesn't persist anything, so every sync loop starts from scratch.
+#[derive(Clone, Copy, Debug, Default)]
+#[allow(clippy::exhaustive_structs)]
+pub struct NoSyncStore;
+
+#[async_trait]
+impl SyncStore for NoSyncStore {
+    async fn load_next_batch(&self) -> Option<String> {
+        None
+    }
+
+    async fn save_next_batch(&self, _next_batch: &str) {}
+}
+
+/// The settings of the sync loop of [`Client::sync_with_store`].
+#[derive(Clone, Debug)]
+pub struct SyncSettings {
+    filter: Option<FilterDefinition>,
+    set_presence: PresenceState,
+    timeout: Option<Duration>,
+    full_state: bool,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl SyncSettings {
+    /// Creates a new `SyncSettings` with the default settings.
+    ///
+    /// There is no filter, the presence is set to online, the timeout is 30 seconds and transient
+    /// errors are retried with an initial backoff of 1 second and a maximum backoff of 1 minute.
+    pub fn new() -> Self {
+        Self {
+            filter: None,
+            set_presence: PresenceState::Online,
+            timeout: Some(Duration::from_secs(30)),
+            full_state: false,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    /// Set the filter to apply to the sync responses.
+    ///
+    /// It is uploaded once when the sync loop starts, and its ID is used for every request.
+    pub fn filter(self, filter: FilterDefinition) -> Self {
+        Self { filter: Some(filter), ..self }
+    }
+
+    /// Set the presence state of the user during the sync loop.
+    pub fn set_presence(self, set_presence: PresenceState) -> Self {
+        Self { set_presence, ..self }
+    }
+
+    /// Set the maximum time to wait for new events in a single request.
+    pub fn timeout(self, timeout: Option<Duration>) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Set whether the full state should be included in the first sync response.
+    pub fn full_state(self, full_state: bool) -> Self {
+        Self { full_state, ..self }
+    }
+
+    /// Set the delay before retrying after the first transient error.
+    ///
+    /// Every further consecutive error waits twice as long as the previous one, up to the
+    /// [maximum backoff][Self::max_backoff].
+    pub fn initial_backoff(self, initial_backoff: Duration) -> Self {
+        Self { initial_backoff, ..self }
+    }
+
+    /// Set the maximum delay before retrying after a transient error.
+    pub fn max_backoff(self, max_backoff: Duration) -> Self {
+        Self { max_backoff, ..self }
+    }
+
+    /// The delay before retrying after the given number of consecutive errors, or `None` if the
+    /// error is not transient.
+    fn backoff<E>(
+        &self,
+        error: &Error<E, ruma_client_api::Error>,
+        failures: u32,
+    ) -> Option<Duration> {
+        let transient = match error {
+            Error::Response(_) => true,
+            Error::FromHttpResponse(FromHttpResponseError::Server(error)) => {
+                error.status_code == StatusCode::TOO_MANY_REQUESTS
+                    || error.status_code.is_server_error()
+            }
+            _ => false,
+        };
+
+        transient.then(|| {
+            self.initial_backoff
+                .checked_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+                .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+        })
+    }
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A sync response yielded by [`Client::sync_with_store`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SyncUpdate {
+    /// The sync response.
+    pub response: sync_events::v3::Response,
+
+    /// The gaps in the timelines of the rooms in the response.
+    pub gaps: Vec<TimelineGap>,
+}
+
+/// A gap in the timeline of a room, because its timeline in a sync response was limited.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct TimelineGap {
+    /// The ID of the room.
+    pub room_id: OwnedRoomId,
+
+    /// The token to paginate backwards from the start of the timeline of the room in the sync
+    /// response.
+    pub prev_batch: String,
+
+    /// The `next_batch` token of the previous sync response, where the gap ends.
+    ///
+    /// This is `None` for the first sync response, in which case the gap extends to the start of
+    /// the room.
+    pub since: Option<String>,
+}
+
+impl TimelineGap {
+    /// Creates a [`get_message_events`] request to fill this gap, from the start of the timeline
+    /// in the sync response backwards to the previous sync response.
+    ///
+    /// More requests might be needed to fill the gap completely, until the response has no `end`
+    /// token.
+    pub fn backfill_request(&self) -> get_message_events::v3::Request {
+        let mut request = get_message_events::v3::Request::backward(self.room_id.clone())
+            .from(self.prev_batch.clone());
+        request.to = self.since.clone();
+        request
+    }
+
+    fn from_timeline(
+        room_id: &OwnedRoomId,
+        timeline: &Timeline,
+        since: Option<&str>,
+    ) -> Option<Self> {
+        let prev_batch = timeline.prev_batch.as_ref().filter(|_| timeline.limited)?;
+        Some(Self {
+            room_id: room_id.clone(),
+            prev_batch: prev_batch.clone(),
+            since: since.map(ToOwned::to_owned),
+        })
+    }
+}
+
+impl<C: HttpClient> Client<C> {
+    /// Represents repeated calls to the sync_events endpoint as a stream, resuming from and saving
+    /// the position in the given store.
+    ///
+    /// In contrast to [`sync`][Self::sync], transient errors, i.e. network errors, server errors
+    /// and rate-limiting, are retried with an exponential backoff instead of ending the stream.
+    /// Other errors are yielded and end the stream.
+    ///
+    /// Every yielded [`SyncUpdate`] contains the rooms whose timeline has a gap, which can be
+    /// filled with [`TimelineGap::backfill_request`].
+    ///
+    /// # Example:
+    ///
+    /// ```no_run
+    /// # use ruma_client::{NoSyncStore, SyncSettings};
+    /// # use tokio_stream::{StreamExt as _};
+    /// # let homeserver_url = "https://example.com".parse().unwrap();
+    /// # async {
+    /// # let client = ruma_client::Client::builder()
+    /// #     .homeserver_url(homeserver_url)
+    /// #     .build::<ruma_client::http_client::Dummy>()
+    /// #     .await?;
+    /// let mut sync_stream = Box::pin(client.sync_with_store(SyncSettings::new(), NoSyncStore));
+    /// while let Some(update) = sync_stream.try_next().await? {
+    ///     for gap in update.gaps {
+    ///         let messages = client.send_request(gap.backfill_request()).await?;
+    ///     }
+    /// }
+    /// # Result::<(), ruma_client::Error<_, _>>::Ok(())
+    /// # };
+    /// ```
+    pub fn sync_with_store<'a, S>(
+        &'a self,
+        settings: SyncSettings,
+        store: S,
+    ) -> impl Stream<Item = Result<SyncUpdate, Error<C::Error, ruma_client_api::Error>>> + 'a
+    where
+        S: SyncStore + 'a,
+    {
+        stream! {
+            let mut failures = 0;
+
+            let filter = match &settings.filter {
+                Some(definition) => loop {
+                    match self.upload_filter(definition.clone()).await {
+                        Ok(filter_id) => break Some(Filter::FilterId(filter_id)),
+                        Err(error) => {
+                            failures += 1;
+                            let Some(delay) = settings.backoff(&error, failures) else {
+                                yield Err(error);
+                                return;
+                            };
+
+                            warn!(?delay, "failed to upload the sync filter, retrying");
+                            futures_timer::Delay::new(delay).await;
+                        }
+                    }
+                },
+                None => None,
+            };
+
+            let mut since = store.load_next_batch().await;
+            // The full state is only needed once, it is not requested again after a response.
+            let mut full_state = settings.full_state;
+            loop {
+                let request = assign!(sync_events::v3::Request::new(), {
+                    filter: filter.clone(),
+                    since: since.clone(),
+                    full_state,
+                    set_presence: settings.set_presence.clone(),
+                    timeout: settings.timeout,
+                });
+
+                let response = match self.send_request(request).await {
+                    Ok(response) => response,
+                    Err(error) => {
+                        failures += 1;
+                        let Some(delay) = settings.backoff(&error, failures) else {
+                            yield Err(error);
+                            return;
+                        };
+
+                        warn!(?delay, "sync request failed, retrying");
+                        futures_timer::Delay::new(delay).await;
+                        continue;
+                    }
+                };
+                failures = 0;
+                full_state = false;
+
+                store.save_next_batch(&response.next_batch).await;
+
+                let rooms = &response.rooms;
+                let gaps = rooms
+                    .join
+                    .iter()
+                    .map(|(room_id, room)| (room_id, &room.timeline))
+                    .chain(rooms.leave.iter().map(|(room_id, room)| (room_id, &room.timeline)))
+                    .filter_map(|(room_id, timeline)| {
+                        TimelineGap::from_timeline(room_id, timeline, since.as_deref())
+                    })
+                    .collect();
+
+                since = Some(response.next_batch.clone());
+                yield Ok(SyncUpdate { response, gaps });
+            }
+        }
+    }
+
+    /// Upload the given filter for the current user and return its ID.
+    async fn upload_filter(
+        &self,
+        filter: FilterDefinition,
+    ) -> Result<String, Error<C::Error, ruma_client_api::Error>> {
+        let user_id = match self.session().and_then(|session| session.user_id) {
+            Some(user_id) => user_id,
+            None => self.send_request(whoami::v3::Request::new()).await?.user_id,
+        };
+
+        Ok(self.send_request(create_filter::v3::Request::new(user_id, filter)).await?.filter_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use assert_matches2::assert_matches;
+    use async_trait::async_trait;
+    use http::StatusCode;
+    use ruma_client_api::{
+        filter::{create_filter, FilterDefinition},
+        sync::sync_events,
+    };
+    use ruma_common::{api::MatrixVersion, owned_user_id};
+    use serde_json::json;
+    use tokio_stream::StreamExt as _;
+
+    use super::{SyncSettings, SyncStore};
+    use crate::{http_client::mock::MockHttpClient, Client, Error, Session};
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<Option<String>>);
+
+    #[async_trait]
+    impl SyncStore for MemoryStore {
+        async fn load_next_batch(&self) -> Option<String> {
+            self.0.lock().unwrap().clone()
+        }
+
+        async fn save_next_batch(&self, next_batch: &str) {
+            *self.0.lock().unwrap() = Some(next_batch.to_owned());
+        }
+    }
+
+    async fn client(http_client: MockHttpClient) -> Client<MockHttpClient> {
+        let mut session = Session::new("access_token".to_owned());
+        session.user_id = Some(owned_user_id!("@alice:example.org"));
+
+        Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .session(Some(session))
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .http_client(http_client)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resumable_sync() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<create_filter::v3::Request>(StatusCode::OK, json!({ "filter_id": "f1" }))
+            .on::<sync_events::v3::Request>(
+                http::Response::builder().status(StatusCode::BAD_GATEWAY).body(Vec::new()).unwrap(),
+            )
+            .on_json::<sync_events::v3::Request>(
+                StatusCode::OK,
+                json!({
+                    "next_batch": "s2",
+                    "rooms": {
+                        "join": {
+                            "!gap:example.org": {
+                                "timeline": { "limited": true, "prev_batch": "p1", "events": [] }
+                            },
+                            "!nogap:example.org": {
+                                "timeline": { "limited": false, "prev_batch": "p2", "events": [] }
+                            }
+                        }
+                    }
+                }),
+            )
+            .on_json::<sync_events::v3::Request>(StatusCode::OK, json!({ "next_batch": "s3" }));
+        let client = client(http_client.clone()).await;
+        let store = Arc::new(MemoryStore(Mutex::new(Some("s1".to_owned()))));
+        let settings = SyncSettings::new()
+            .filter(FilterDefinition::ignore_all())
+            .timeout(None)
+            .initial_backoff(Duration::from_millis(1));
+
+        let mut stream = Box::pin(client.sync_with_store(settings, store.clone()));
+
+        let update = stream.next().await.unwrap().unwrap();
+        assert_eq!(update.response.next_batch, "s2");
+        assert_eq!(update.gaps.len(), 1);
+        assert_eq!(update.gaps[0].room_id, "!gap:example.org");
+        let backfill = update.gaps[0].backfill_request();
+        assert_eq!(backfill.from.as_deref(), Some("p1"));
+        assert_eq!(backfill.to.as_deref(), Some("s1"));
+        assert_eq!(store.load_next_batch().await.as_deref(), Some("s2"));
+
+        let update = stream.next().await.unwrap().unwrap();
+        assert!(update.gaps.is_empty());
+        assert_eq!(store.load_next_batch().await.as_deref(), Some("s3"));
+
+        let requests: Vec<_> =
+            http_client.take_requests().iter().map(|request| request.uri().to_string()).collect();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(
+            requests[0],
+            "https://example.org/_matrix/client/v3/user/@alice:example.org/filter"
+        );
+        assert!(requests[1].contains("filter=f1") && requests[1].contains("since=s1"));
+        assert!(requests[2].contains("filter=f1") && requests[2].contains("since=s1"));
+        assert!(requests[3].contains("filter=f1") && requests[3].contains("since=s2"));
+    }
+
+    #[tokio::test]
+    async fn full_state_only_in_first_request() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<sync_events::v3::Request>(StatusCode::OK, json!({ "next_batch": "s1" }))
+            .on_json::<sync_events::v3::Request>(StatusCode::OK, json!({ "next_batch": "s2" }));
+        let client = client(http_client.clone()).await;
+        let settings = SyncSettings::new().full_state(true).timeout(None);
+
+        let mut stream = Box::pin(client.sync_with_store(settings, MemoryStore::default()));
+        stream.next().await.unwrap().unwrap();
+        stream.next().await.unwrap().unwrap();
+
+        let requests: Vec<_> =
+            http_client.take_requests().iter().map(|request| request.uri().to_string()).collect();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("full_state=true"));
+        assert!(!requests[1].contains("full_state=true"));
+    }
+
+    #[tokio::test]
+    async fn terminal_error_ends_sync() {
+        let http_client = MockHttpClient::new();
+        http_client.on_json::<sync_events::v3::Request>(
+            StatusCode::UNAUTHORIZED,
+            json!({ "errcode": "M_UNKNOWN_TOKEN", "error": "Logged out" }),
+        );
+        let client = client(http_client).await;
+
+        let mut stream =
+            Box::pin(client.sync_with_store(SyncSettings::new(), MemoryStore::default()));
+
+        assert_matches!(stream.next().await, Some(Err(Error::FromHttpResponse(_))));
+        assert_matches!(stream.next().await, None);
+    }
+}
@@ -0,0 +1,290 @@
+use async_trait::async_trait;
+use ruma_client_api::{
+    account::{add_3pid, change_password, deactivate, register},
+    device::{delete_device, delete_devices},
+    keys::upload_signing_keys,
+    session::get_login_token,
+    uiaa::{AuthData, AuthType, UiaaInfo, UiaaResponse},
+};
+use ruma_common::api::{error::FromHttpResponseError, OutgoingRequest};
+
+use super::Client;
+use crate::{Error, HttpClient, ResponseResult};
+
+/// A request to an endpoint protected by the [User-Interactive Authentication API].
+///
+/// [User-Interactive Authentication API]: https://spec.matrix.org/latest/client-server-api/#user-interactive-authentication-api
+pub trait UiaaRequest: OutgoingRequest<EndpointError = UiaaResponse> {
+    /// Set the authentication data sent with the request.
+    fn set_auth(&mut self, auth: Option<AuthData>);
+}
+
+macro_rules! impl_uiaa_request {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl UiaaRequest for $ty {
+                fn set_auth(&mut self, auth: Option<AuthData>) {
+                    self.auth = auth;
+                }
+            }
+        )*
+    };
+}
+
+impl_uiaa_request!(
+    add_3pid::v3::Request,
+    change_password::v3::Request,
+    deactivate::v3::Request,
+    delete_device::v3::Request,
+    delete_devices::v3::Request,
+    get_login_token::v1::Request,
+    register::v3::Request,
+    upload_signing_keys::v3::Request,
+);
+
+/// A handler completing the stages of the User-Interactive Authentication API for
+/// [`Client::send_uiaa_request`].
+#[async_trait]
+pub trait UiaaHandler: Send {
+    /// Get the authentication data to complete the given stage, or `None` if this handler doesn't
+    /// support it.
+    ///
+    /// `info` contains the parameters of the stages, the session and the error of the previous
+    /// attempt, if any. If the returned authentication data has no session, the session of `info`
+    /// is added to it.
+    async fn handle_stage(&mut self, stage: &AuthType, info: &UiaaInfo) -> Option<AuthData>;
+}
+
+impl<C: HttpClient> Client<C> {
+    /// Makes a request to an endpoint protected by the User-Interactive Authentication API,
+    /// completing the authentication stages with the given handler.
+    ///
+    /// The request is sent again with the authentication data of the next stage every time the
+    /// homeserver asks for more authentication. The next stage is taken from the first flow that
+    /// matches the completed stages, falling back to the next flows if the handler doesn't support
+    /// it.
+    ///
+    /// Returns the last [`UiaaResponse::AuthResponse`] as an error if the handler doesn't support
+    /// any of the next stages, or if the homeserver rejects the authentication data of a stage,
+    /// i.e. if the response has an `auth_error` and no new completed stage.
+    pub async fn send_uiaa_request<R, H>(
+        &self,
+        mut request: R,
+        handler: &mut H,
+    ) -> ResponseResult<C, R>
+    where
+        R: UiaaRequest,
+        H: UiaaHandler + ?Sized,
+    {
+        let mut completed = None;
+
+        loop {
+            let info = match self.send_request(request.clone()).await {
+                Err(Error::FromHttpResponse(FromHttpResponseError::Server(
+                    UiaaResponse::AuthResponse(info),
+                ))) => info,
+                result => return result,
+            };
+
+            // Sending the same stage again would most likely fail the same way, forever.
+            if info.auth_error.is_some() && completed.as_ref() == Some(&info.completed) {
+                return Err(auth_response_error(info));
+            }
+            completed = Some(info.completed.clone());
+
+            match next_stage_auth(&info, handler).await {
+                Some(auth) => request.set_auth(Some(auth)),
+                None => return Err(auth_response_error(info)),
+            }
+        }
+    }
+}
+
+/// Ask the handler for the authentication data of the next stage of the flows matching the
+/// completed stages.
+async fn next_stage_auth<H>(info: &UiaaInfo, handler: &mut H) -> Option<AuthData>
+where
+    H: UiaaHandler + ?Sized,
+{
+    let mut tried = Vec::new();
+
+    for flow in info.flows.iter().filter(|flow| flow.stages.starts_with(&info.completed)) {
+        let Some(stage) = flow.stages.get(info.completed.len()) else {
+            continue;
+        };
+
+        if tried.contains(&stage) {
+            continue;
+        }
+        tried.push(stage);
+
+        if let Some(auth) = handler.handle_stage(stage, info).await {
+            return Some(with_session(auth, info.session.as_deref()));
+        }
+    }
+
+    None
+}
+
+/// The error returned by [`Client::send_uiaa_request`] when the authentication can't be completed.
+fn auth_response_error<E>(info: UiaaInfo) -> Error<E, UiaaResponse> {
+    Error::FromHttpResponse(FromHttpResponseError::Server(UiaaResponse::AuthResponse(info)))
+}
+
+/// Add the given session to the authentication data, if it has none.
+fn with_session(auth: AuthData, session: Option<&str>) -> AuthData {
+    let (Some(session), None, Some(auth_type)) = (session, auth.session(), auth.auth_type()) else {
+        return auth;
+    };
+
+    AuthData::new(auth_type.as_str(), Some(session.to_owned()), auth.data().into_owned())
+        .unwrap_or(auth)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_matches;
+    use async_trait::async_trait;
+    use http::StatusCode;
+    use ruma_client_api::{
+        account::register,
+        uiaa::{AuthData, AuthType, Dummy, UiaaInfo, UiaaResponse},
+    };
+    use ruma_common::api::{error::FromHttpResponseError, MatrixVersion};
+    use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+    use super::UiaaHandler;
+    use crate::{http_client::mock::MockHttpClient, Client, Error};
+
+    fn auth_response() -> JsonValue {
+        json!({
+            "flows": [
+                { "stages": ["m.login.recaptcha", "m.login.dummy"] },
+                { "stages": ["m.login.dummy"] }
+            ],
+            "params": {},
+            "session": "xyz"
+        })
+    }
+
+    fn request_bodies(http_client: &MockHttpClient) -> Vec<JsonValue> {
+        http_client
+            .take_requests()
+            .iter()
+            .map(|request| from_json_slice(request.body()).unwrap())
+            .collect()
+    }
+
+    /// Only supports the dummy stage.
+    struct DummyHandler {
+        stages: Vec<AuthType>,
+    }
+
+    #[async_trait]
+    impl UiaaHandler for DummyHandler {
+        async fn handle_stage(&mut self, stage: &AuthType, _info: &UiaaInfo) -> Option<AuthData> {
+            self.stages.push(stage.clone());
+            (*stage == AuthType::Dummy).then(|| AuthData::Dummy(Dummy::new()))
+        }
+    }
+
+    async fn client(http_client: MockHttpClient) -> Client<MockHttpClient> {
+        Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .http_client(http_client)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn complete_supported_flow() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<register::v3::Request>(StatusCode::UNAUTHORIZED, auth_response())
+            .on_json::<register::v3::Request>(
+            StatusCode::OK,
+            json!({ "user_id": "@alice:example.org" }),
+        );
+        let client = client(http_client.clone()).await;
+        let mut handler = DummyHandler { stages: Vec::new() };
+
+        let response =
+            client.send_uiaa_request(register::v3::Request::new(), &mut handler).await.unwrap();
+        assert_eq!(response.user_id, "@alice:example.org");
+        assert_eq!(handler.stages, [AuthType::ReCaptcha, AuthType::Dummy]);
+
+        let bodies = request_bodies(&http_client);
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0].get("auth"), None);
+        assert_eq!(bodies[1]["auth"]["type"], "m.login.dummy");
+        assert_eq!(bodies[1]["auth"]["session"], "xyz");
+    }
+
+    #[tokio::test]
+    async fn unsupported_flows() {
+        let http_client = MockHttpClient::new();
+        http_client.on_json::<register::v3::Request>(
+            StatusCode::UNAUTHORIZED,
+            json!({ "flows": [{ "stages": ["m.login.recaptcha"] }], "params": {} }),
+        );
+        let client = client(http_client.clone()).await;
+        let mut handler = DummyHandler { stages: Vec::new() };
+
+        let result = client.send_uiaa_request(register::v3::Request::new(), &mut handler).await;
+        assert_matches!(
+            result,
+            Err(Error::FromHttpResponse(FromHttpResponseError::Server(
+                UiaaResponse::AuthResponse(info)
+            )))
+        );
+        assert_eq!(info.flows.len(), 1);
+        assert_eq!(request_bodies(&http_client).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_stage() {
+        let mut rejected = auth_response();
+        rejected["errcode"] = "M_FORBIDDEN".into();
+        rejected["error"] = "Invalid captcha".into();
+
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<register::v3::Request>(StatusCode::UNAUTHORIZED, auth_response())
+            .on_json::<register::v3::Request>(StatusCode::UNAUTHORIZED, rejected);
+        let client = client(http_client.clone()).await;
+        let mut handler = DummyHandler { stages: Vec::new() };
+
+        let result = client.send_uiaa_request(register::v3::Request::new(), &mut handler).await;
+        assert_matches!(
+            result,
+            Err(Error::FromHttpResponse(FromHttpResponseError::Server(
+                UiaaResponse::AuthResponse(info)
+            )))
+        );
+        assert_eq!(info.auth_error.unwrap().message, "Invalid captcha");
+        assert_eq!(handler.stages, [AuthType::ReCaptcha, AuthType::Dummy]);
+        assert_eq!(request_bodies(&http_client).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn terminal_error() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<register::v3::Request>(StatusCode::UNAUTHORIZED, auth_response())
+            .on_json::<register::v3::Request>(
+            StatusCode::FORBIDDEN,
+            json!({ "errcode": "M_FORBIDDEN", "error": "Registration disabled" }),
+        );
+        let client = client(http_client).await;
+        let mut handler = DummyHandler { stages: Vec::new() };
+
+        let result = client.send_uiaa_request(register::v3::Request::new(), &mut handler).await;
+        assert_matches!(
+            result,
+            Err(Error::FromHttpResponse(FromHttpResponseError::Server(UiaaResponse::MatrixError(
+                _
+            ))))
+        );
+    }
+}
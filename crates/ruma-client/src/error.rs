@@ -2,7 +2,10 @@
 
 use std::fmt::{self, Debug, Display, Formatter};
 
-use ruma_common::api::error::{FromHttpResponseError, IntoHttpError};
+use ruma_common::{
+    api::error::{FromHttpResponseError, IntoHttpError},
+    MxcUriError,
+};
 
 /// An error that can occur during client operations.
 #[derive(Debug)]
@@ -22,6 +25,22 @@ pub enum Error<E, F> {
 
     /// Converting the HTTP response to one of ruma's types failed.
     FromHttpResponse(FromHttpResponseError<F>),
+
+    /// The media to upload is larger than the maximum size allowed by the homeserver.
+    MediaTooLarge {
+        /// The maximum size of uploads, in bytes.
+        max_size: u64,
+    },
+
+    /// The MXC URI of the media is invalid.
+    InvalidMxcUri(MxcUriError),
+
+    /// The endpoint is only available on an unstable path, and the homeserver doesn't advertise
+    /// support for its unstable feature.
+    UnsupportedUnstableFeature {
+        /// The unstable feature in the path of the endpoint, like `org.matrix.msc3575`.
+        feature: String,
+    },
 }
 
 impl<E: Display, F: Display> Display for Error<E, F> {
@@ -34,6 +53,13 @@ impl<E: Display, F: Display> Display for Error<E, F> {
             Self::Url(err) => write!(f, "Invalid URL: {err}"),
             Self::Response(err) => write!(f, "Couldn't obtain a response: {err}"),
             Self::FromHttpResponse(err) => write!(f, "HTTP response conversion failed: {err}"),
+            Self::MediaTooLarge { max_size } => {
+                write!(f, "The media is larger than the maximum upload size of {max_size} bytes.")
+            }
+            Self::InvalidMxcUri(err) => write!(f, "Invalid MXC URI: {err}"),
+            Self::UnsupportedUnstableFeature { feature } => {
+                write!(f, "The homeserver doesn't support the unstable feature {feature}.")
+            }
         }
     }
 }
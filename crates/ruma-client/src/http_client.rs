@@ -16,8 +16,12 @@ use crate::{add_user_id_to_query, ResponseError, ResponseResult};
 mod hyper;
 #[cfg(feature = "isahc")]
 mod isahc;
+mod middleware;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
 #[cfg(feature = "reqwest")]
 mod reqwest;
+mod streaming;
 
 #[cfg(feature = "hyper")]
 pub use self::hyper::Hyper;
@@ -29,6 +33,12 @@ pub use self::hyper::HyperRustls;
 pub use self::isahc::Isahc;
 #[cfg(feature = "reqwest")]
 pub use self::reqwest::Reqwest;
+#[cfg(feature = "client-api")]
+pub(crate) use self::streaming::collect_stream;
+pub use self::{
+    middleware::{Middleware, WithMiddleware},
+    streaming::{reader_stream, ByteStream, StreamingHttpClient},
+};
 
 /// An HTTP client that can be used to send requests to a Matrix homeserver.
 #[async_trait]
@@ -124,11 +134,35 @@ pub trait HttpClientExt: HttpClient {
             add_user_id_to_query::<Self, R>(user_id),
         )
     }
+
+    /// Send the requests of this HTTP client through the given middleware.
+    ///
+    /// Calling this method on the result adds another middleware, that is called before the
+    /// previous ones.
+    fn with_middleware<M>(self, middleware: M) -> WithMiddleware<Self, M>
+    where
+        Self: Sized,
+    {
+        WithMiddleware::new(self, middleware)
+    }
 }
 
 #[async_trait]
 impl<T: HttpClient> HttpClientExt for T {}
 
+/// The path templates of the endpoint of a request, added to its extensions when it is serialized
+/// so they can be recorded by a [`mock::Recorder`].
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Clone, Debug)]
+pub(crate) struct EndpointPaths(pub(crate) Vec<&'static str>);
+
+#[cfg(any(test, feature = "test-util"))]
+impl EndpointPaths {
+    pub(crate) fn of<R: OutgoingRequest>() -> Self {
+        Self(R::METADATA.history.all_paths().collect())
+    }
+}
+
 #[doc(hidden)]
 #[derive(Debug)]
 #[allow(clippy::exhaustive_structs)]
@@ -1,8 +1,14 @@
+use std::io;
+
+use async_stream::try_stream;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
-use hyper::client::{connect::Connect, HttpConnector};
+use hyper::{
+    body::HttpBody,
+    client::{connect::Connect, HttpConnector},
+};
 
-use super::{DefaultConstructibleHttpClient, HttpClient};
+use super::{ByteStream, DefaultConstructibleHttpClient, HttpClient, StreamingHttpClient};
 
 /// A basic hyper HTTP client.
 ///
@@ -45,6 +51,37 @@ where
     }
 }
 
+#[async_trait]
+impl<C> StreamingHttpClient for hyper::Client<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    async fn send_http_request_streaming_body(
+        &self,
+        req: http::Request<ByteStream>,
+    ) -> Result<http::Response<Bytes>, hyper::Error> {
+        let (head, body) =
+            self.request(req.map(hyper::body::Body::wrap_stream)).await?.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok(http::Response::from_parts(head, body))
+    }
+
+    async fn send_http_request_streaming_response(
+        &self,
+        req: http::Request<BytesMut>,
+    ) -> Result<http::Response<ByteStream>, hyper::Error> {
+        let res = self.request(req.map(|body| hyper::body::Body::from(body.freeze()))).await?;
+
+        Ok(res.map(|mut body| -> ByteStream {
+            Box::pin(try_stream! {
+                while let Some(chunk) = body.data().await {
+                    yield chunk.map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+                }
+            })
+        }))
+    }
+}
+
 #[cfg(feature = "hyper")]
 impl DefaultConstructibleHttpClient for Hyper {
     fn default() -> Self {
@@ -1,7 +1,18 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Mutex,
+    task::{ready, Context, Poll},
+};
+
 use async_trait::async_trait;
+use bytes::Bytes;
+use futures_io::AsyncRead;
 use futures_lite::AsyncReadExt;
+use http::header::CONTENT_LENGTH;
+use isahc::AsyncBody;
 
-use super::HttpClient;
+use super::{reader_stream, ByteStream, HttpClient, StreamingHttpClient};
 
 /// The `isahc` crate's `HttpClient`.
 pub type Isahc = isahc::HttpClient;
@@ -22,3 +33,67 @@ impl HttpClient for Isahc {
         Ok(http::Response::from_parts(head, full_body))
     }
 }
+
+#[async_trait]
+impl StreamingHttpClient for Isahc {
+    async fn send_http_request_streaming_body(
+        &self,
+        req: http::Request<ByteStream>,
+    ) -> Result<http::Response<Vec<u8>>, isahc::Error> {
+        let (parts, body) = req.into_parts();
+        let reader = StreamReader { stream: Mutex::new(body), chunk: Bytes::new() };
+
+        // Without a length, the body is sent with chunked transfer encoding.
+        let length =
+            parts.headers.get(CONTENT_LENGTH).and_then(|length| length.to_str().ok()?.parse().ok());
+        let body = match length {
+            Some(length) => AsyncBody::from_reader_sized(reader, length),
+            None => AsyncBody::from_reader(reader),
+        };
+
+        let (head, mut body) =
+            self.send_async(http::Request::from_parts(parts, body)).await?.into_parts();
+        let mut full_body = Vec::new();
+        body.read_to_end(&mut full_body).await?;
+        Ok(http::Response::from_parts(head, full_body))
+    }
+
+    async fn send_http_request_streaming_response(
+        &self,
+        req: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<ByteStream>, isahc::Error> {
+        let res = self.send_async(req).await?;
+        Ok(res.map(reader_stream))
+    }
+}
+
+/// A reader over the chunks of a [`ByteStream`], as required by `isahc::AsyncBody::from_reader`.
+struct StreamReader {
+    /// The stream, in a `Mutex` because the reader must be `Sync`.
+    stream: Mutex<ByteStream>,
+
+    /// The rest of the last chunk of the stream.
+    chunk: Bytes,
+}
+
+impl AsyncRead for StreamReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let stream = this.stream.get_mut().expect("stream mutex was poisoned");
+
+        while this.chunk.is_empty() {
+            match ready!(stream.as_mut().poll_next(cx)) {
+                Some(chunk) => this.chunk = chunk?,
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+
+        let len = buf.len().min(this.chunk.len());
+        buf[..len].copy_from_slice(&this.chunk.split_to(len));
+        Poll::Ready(Ok(len))
+    }
+}
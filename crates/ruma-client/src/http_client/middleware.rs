@@ -0,0 +1,298 @@
+use async_trait::async_trait;
+
+use super::{ByteStream, DefaultConstructibleHttpClient, HttpClient, StreamingHttpClient};
+
+/// A middleware wrapping the requests sent by an [`HttpClient`] of type `C`.
+///
+/// Middlewares can inspect and modify requests before they are sent, and responses before they are
+/// returned, for example to add headers, record metrics or enter tracing spans. They are added to
+/// an HTTP client with [`HttpClientExt::with_middleware()`], and several middlewares can be stacked
+/// by calling it several times. The resulting HTTP client can then be used with
+/// [`ClientBuilder::http_client()`].
+///
+/// Middlewares that don't depend on a specific HTTP client should be implemented for every
+/// `C: HttpClient`, so they can be used with any of them.
+///
+/// Requests with a streamed body or response, sent through [`StreamingHttpClient`], don't go
+/// through [`handle()`][Self::handle]. [`on_request()`][Self::on_request] is called with the head
+/// of the request and an empty body, and [`on_response()`][Self::on_response] is only called if
+/// the response body is not streamed.
+///
+/// # Example
+///
+/// ```
+/// use async_trait::async_trait;
+/// use ruma_client::http_client::{HttpClient, Middleware};
+///
+/// /// Adds a request ID header to every request.
+/// struct RequestId;
+///
+/// #[async_trait]
+/// impl<C> Middleware<C> for RequestId
+/// where
+///     C: HttpClient,
+///     C::ResponseBody: Send,
+/// {
+///     async fn on_request(&self, request: &mut http::Request<C::RequestBody>) {
+///         request.headers_mut().insert("x-request-id", http::HeaderValue::from_static("abc"));
+///     }
+/// }
+/// ```
+///
+/// [`HttpClientExt::with_middleware()`]: super::HttpClientExt::with_middleware
+/// [`ClientBuilder::http_client()`]: crate::ClientBuilder::http_client
+#[async_trait]
+pub trait Middleware<C>: Send + Sync
+where
+    C: HttpClient,
+    C::ResponseBody: Send,
+{
+    /// Called before the request is sent.
+    async fn on_request(&self, _request: &mut http::Request<C::RequestBody>) {}
+
+    /// Called with the result of the request, before it is returned.
+    async fn on_response(&self, _response: &mut Result<http::Response<C::ResponseBody>, C::Error>) {
+    }
+
+    /// Send the request with the given HTTP client, which is the next layer.
+    ///
+    /// By default, this calls [`on_request()`][Self::on_request] and
+    /// [`on_response()`][Self::on_response] around the request. It can be overridden to keep
+    /// state across the request, like a timer or a tracing span, or to not send the request at
+    /// all.
+    async fn handle(
+        &self,
+        mut request: http::Request<C::RequestBody>,
+        next: &C,
+    ) -> Result<http::Response<C::ResponseBody>, C::Error> {
+        self.on_request(&mut request).await;
+        let mut response = next.send_http_request(request).await;
+        self.on_response(&mut response).await;
+        response
+    }
+}
+
+/// An [`HttpClient`] sending requests through a [`Middleware`].
+///
+/// This is usually created with [`HttpClientExt::with_middleware()`].
+///
+/// [`HttpClientExt::with_middleware()`]: super::HttpClientExt::with_middleware
+#[derive(Clone, Debug)]
+pub struct WithMiddleware<C, M> {
+    inner: C,
+    middleware: M,
+}
+
+impl<C, M> WithMiddleware<C, M> {
+    /// Creates a new `WithMiddleware` sending the requests of the given HTTP client through the
+    /// given middleware.
+    pub fn new(inner: C, middleware: M) -> Self {
+        Self { inner, middleware }
+    }
+
+    /// Get a reference to the wrapped HTTP client.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Get a reference to the middleware.
+    pub fn middleware(&self) -> &M {
+        &self.middleware
+    }
+}
+
+#[async_trait]
+impl<C, M> HttpClient for WithMiddleware<C, M>
+where
+    C: HttpClient + Send,
+    C::ResponseBody: Send,
+    M: Middleware<C>,
+{
+    type RequestBody = C::RequestBody;
+    type ResponseBody = C::ResponseBody;
+    type Error = C::Error;
+
+    async fn send_http_request(
+        &self,
+        req: http::Request<C::RequestBody>,
+    ) -> Result<http::Response<C::ResponseBody>, C::Error> {
+        self.middleware.handle(req, &self.inner).await
+    }
+}
+
+#[async_trait]
+impl<C, M> StreamingHttpClient for WithMiddleware<C, M>
+where
+    C: StreamingHttpClient + Send,
+    C::ResponseBody: Send,
+    M: Middleware<C>,
+{
+    async fn send_http_request_streaming_body(
+        &self,
+        req: http::Request<ByteStream>,
+    ) -> Result<http::Response<C::ResponseBody>, C::Error> {
+        let (parts, body) = req.into_parts();
+        let mut head = http::Request::from_parts(parts, C::RequestBody::default());
+        self.middleware.on_request(&mut head).await;
+
+        let (parts, _) = head.into_parts();
+        let mut response = self
+            .inner
+            .send_http_request_streaming_body(http::Request::from_parts(parts, body))
+            .await;
+        self.middleware.on_response(&mut response).await;
+        response
+    }
+
+    async fn send_http_request_streaming_response(
+        &self,
+        mut req: http::Request<C::RequestBody>,
+    ) -> Result<http::Response<ByteStream>, C::Error> {
+        self.middleware.on_request(&mut req).await;
+        self.inner.send_http_request_streaming_response(req).await
+    }
+}
+
+impl<C, M> DefaultConstructibleHttpClient for WithMiddleware<C, M>
+where
+    C: DefaultConstructibleHttpClient + Send,
+    C::ResponseBody: Send,
+    M: Middleware<C> + Default,
+{
+    fn default() -> Self {
+        Self::new(C::default(), M::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use async_trait::async_trait;
+    use http::{HeaderValue, StatusCode};
+
+    use super::Middleware;
+    use crate::{
+        http_client::{ByteStream, StreamingHttpClient},
+        HttpClient, HttpClientExt,
+    };
+
+    /// Returns the headers of the request in the body of the response.
+    struct EchoHeaders;
+
+    #[async_trait]
+    impl HttpClient for EchoHeaders {
+        type RequestBody = Vec<u8>;
+        type ResponseBody = Vec<u8>;
+        type Error = ();
+
+        async fn send_http_request(
+            &self,
+            req: http::Request<Vec<u8>>,
+        ) -> Result<http::Response<Vec<u8>>, ()> {
+            Ok(http::Response::new(header_names(&req).into_bytes()))
+        }
+    }
+
+    #[async_trait]
+    impl StreamingHttpClient for EchoHeaders {
+        async fn send_http_request_streaming_body(
+            &self,
+            req: http::Request<ByteStream>,
+        ) -> Result<http::Response<Vec<u8>>, ()> {
+            Ok(http::Response::new(header_names(&req).into_bytes()))
+        }
+
+        async fn send_http_request_streaming_response(
+            &self,
+            req: http::Request<Vec<u8>>,
+        ) -> Result<http::Response<ByteStream>, ()> {
+            let body: ByteStream = Box::pin(tokio_stream::empty());
+            let mut response = http::Response::new(body);
+            response.headers_mut().insert("x-echo", header_names(&req).parse().unwrap());
+            Ok(response)
+        }
+    }
+
+    fn header_names<B>(req: &http::Request<B>) -> String {
+        req.headers().keys().map(|name| name.as_str()).collect::<Vec<_>>().join(",")
+    }
+
+    struct SetHeader(&'static str);
+
+    #[async_trait]
+    impl<C> Middleware<C> for SetHeader
+    where
+        C: HttpClient,
+        C::ResponseBody: Send,
+    {
+        async fn on_request(&self, request: &mut http::Request<C::RequestBody>) {
+            request.headers_mut().insert(self.0, HeaderValue::from_static("1"));
+        }
+    }
+
+    #[derive(Default)]
+    struct Metrics {
+        requests: AtomicUsize,
+        statuses: Mutex<Vec<StatusCode>>,
+    }
+
+    #[async_trait]
+    impl<C> Middleware<C> for Metrics
+    where
+        C: HttpClient,
+        C::ResponseBody: Send,
+    {
+        async fn handle(
+            &self,
+            request: http::Request<C::RequestBody>,
+            next: &C,
+        ) -> Result<http::Response<C::ResponseBody>, C::Error> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let response = next.send_http_request(request).await;
+            if let Ok(response) = &response {
+                self.statuses.lock().unwrap().push(response.status());
+            }
+            response
+        }
+    }
+
+    #[tokio::test]
+    async fn stacked_middlewares() {
+        let client = EchoHeaders
+            .with_middleware(SetHeader("x-first"))
+            .with_middleware(SetHeader("x-second"))
+            .with_middleware(Metrics::default());
+
+        let response = client.send_http_request(http::Request::new(Vec::new())).await.unwrap();
+        // The outermost middleware runs first.
+        assert_eq!(response.body(), b"x-second,x-first");
+
+        let metrics = client.middleware();
+        assert_eq!(metrics.requests.load(Ordering::SeqCst), 1);
+        assert_eq!(*metrics.statuses.lock().unwrap(), [StatusCode::OK]);
+    }
+
+    #[tokio::test]
+    async fn streaming_requests() {
+        let client =
+            EchoHeaders.with_middleware(SetHeader("x-first")).with_middleware(Metrics::default());
+
+        let body: ByteStream = Box::pin(tokio_stream::empty());
+        let response =
+            client.send_http_request_streaming_body(http::Request::new(body)).await.unwrap();
+        assert_eq!(response.body(), b"x-first");
+
+        let response = client
+            .send_http_request_streaming_response(http::Request::new(Vec::new()))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-echo"], "x-first");
+
+        // `handle()` is not called for streaming requests.
+        assert_eq!(client.middleware().requests.load(Ordering::SeqCst), 0);
+    }
+}
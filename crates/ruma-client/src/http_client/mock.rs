@@ -0,0 +1,701 @@
+//! An [`HttpClient`] returning canned responses, to test code using a [`Client`] without a
+//! homeserver.
+//!
+//! Responses can be registered for an endpoint with [`MockHttpClient::on()`], or replayed from a
+//! [`Fixture`] captured from a live homeserver with a [`Recorder`].
+//!
+//! [`Client`]: crate::Client
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    error::Error as StdError,
+    fmt, fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use async_stream::stream;
+use async_trait::async_trait;
+use http::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+    HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri,
+};
+use ruma_common::{
+    api::{Metadata, OutgoingRequest},
+    serde::Base64,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice as from_json_slice, to_vec as to_json_vec, Value as JsonValue};
+
+use super::{
+    streaming::collect_stream, ByteStream, DefaultConstructibleHttpClient, EndpointPaths,
+    HttpClient, Middleware, StreamingHttpClient,
+};
+
+/// An [`HttpClient`] returning canned responses.
+///
+/// Requests are matched by method and path, ignoring the query string and the body. When several
+/// responses are registered for the same endpoint, they are returned in order and the last one is
+/// repeated once the others have been used.
+///
+/// Cloning a `MockHttpClient` returns a handle to the same state, so responses can still be
+/// registered and requests inspected after it was passed to a [`Client`].
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(feature = "client-api")]
+/// # async {
+/// use http::StatusCode;
+/// use ruma_client::{http_client::mock::MockHttpClient, Client};
+/// use ruma_client_api::discovery::get_supported_versions;
+/// use serde_json::json;
+///
+/// let http_client = MockHttpClient::new();
+/// http_client.on_json::<get_supported_versions::Request>(
+///     StatusCode::OK,
+///     json!({ "versions": ["v1.1"] }),
+/// );
+///
+/// let client = Client::builder()
+///     .homeserver_url("https://example.org".to_owned())
+///     .http_client(http_client.clone())
+///     .await?;
+/// assert_eq!(http_client.take_requests().len(), 1);
+/// # Result::<(), ruma_client::Error<_, _>>::Ok(())
+/// # };
+/// ```
+///
+/// [`Client`]: crate::Client
+#[derive(Clone, Debug, Default)]
+pub struct MockHttpClient {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    routes: Vec<Route>,
+    requests: Vec<http::Request<Vec<u8>>>,
+}
+
+impl MockHttpClient {
+    /// Creates a new `MockHttpClient` without any response.
+    pub fn new() -> Self {
+        Self { state: Default::default() }
+    }
+
+    /// Creates a new `MockHttpClient` replaying the responses of the given fixture.
+    ///
+    /// The responses are matched by the path templates of the endpoints of the recorded requests,
+    /// so requests with different path parameters, like a generated transaction ID, still match.
+    /// Requests that were not sent with an endpoint type are matched by their recorded path.
+    pub fn from_fixture(fixture: Fixture) -> Self {
+        let client = Self::new();
+
+        {
+            let mut state = client.state.lock().unwrap();
+            for exchange in fixture.exchanges {
+                let Ok(method) = exchange.request.method.parse() else {
+                    continue;
+                };
+                let paths = if exchange.request.path_templates.is_empty() {
+                    vec![exchange.request.path]
+                } else {
+                    exchange.request.path_templates
+                };
+                state.add_response(method, paths, exchange.response.into());
+            }
+        }
+
+        client
+    }
+
+    /// Register a response for the endpoint of the request type `R`.
+    ///
+    /// The response is returned for all the requests matching the method and any of the paths of
+    /// the endpoint's [`Metadata`].
+    pub fn on<R: OutgoingRequest>(&self, response: http::Response<Vec<u8>>) -> &Self {
+        self.on_metadata(&R::METADATA, response)
+    }
+
+    /// Register a response with the given status code and JSON body for the endpoint of the
+    /// request type `R`.
+    pub fn on_json<R: OutgoingRequest>(&self, status: StatusCode, body: JsonValue) -> &Self {
+        let response = http::Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(to_json_vec(&body).expect("JSON values always serialize"))
+            .expect("the response is valid");
+        self.on::<R>(response)
+    }
+
+    /// Register a response for the endpoint with the given metadata.
+    pub fn on_metadata(&self, metadata: &Metadata, response: http::Response<Vec<u8>>) -> &Self {
+        let paths = metadata.history.all_paths().map(ToOwned::to_owned).collect();
+        self.state.lock().unwrap().add_response(metadata.method.clone(), paths, response.into());
+        self
+    }
+
+    /// Take the requests received since the last call to this method.
+    pub fn take_requests(&self) -> Vec<http::Request<Vec<u8>>> {
+        std::mem::take(&mut self.state.lock().unwrap().requests)
+    }
+}
+
+impl MockState {
+    fn add_response(&mut self, method: Method, paths: Vec<String>, response: CannedResponse) {
+        match self.routes.iter_mut().find(|route| route.method == method && route.paths == paths) {
+            Some(route) => route.responses.push_back(response),
+            None => self.routes.push(Route { method, paths, responses: [response].into() }),
+        }
+    }
+}
+
+#[async_trait]
+impl HttpClient for MockHttpClient {
+    type RequestBody = Vec<u8>;
+    type ResponseBody = Vec<u8>;
+    type Error = MockError;
+
+    async fn send_http_request(
+        &self,
+        req: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<Vec<u8>>, MockError> {
+        let mut state = self.state.lock().unwrap();
+
+        let response = state
+            .routes
+            .iter_mut()
+            .find(|route| route.matches(req.method(), req.uri().path()))
+            .and_then(Route::next_response);
+        let unmatched =
+            || MockError::UnmatchedRequest { method: req.method().clone(), uri: req.uri().clone() };
+        let result = response.ok_or_else(unmatched);
+
+        state.requests.push(req);
+        result
+    }
+}
+
+/// Streamed bodies are buffered.
+#[async_trait]
+impl StreamingHttpClient for MockHttpClient {
+    async fn send_http_request_streaming_body(
+        &self,
+        req: http::Request<ByteStream>,
+    ) -> Result<http::Response<Vec<u8>>, MockError> {
+        let (parts, body) = req.into_parts();
+        let body = collect_stream(body).await.map_err(MockError::Body)?;
+        self.send_http_request(http::Request::from_parts(parts, body)).await
+    }
+
+    async fn send_http_request_streaming_response(
+        &self,
+        req: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<ByteStream>, MockError> {
+        let response = self.send_http_request(req).await?;
+        Ok(response.map(|body| -> ByteStream { Box::pin(stream! { yield Ok(body.into()) }) }))
+    }
+}
+
+impl DefaultConstructibleHttpClient for MockHttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The responses registered for an endpoint.
+#[derive(Debug)]
+struct Route {
+    method: Method,
+
+    /// The path templates of the endpoint, where segments starting with `:` match any value.
+    paths: Vec<String>,
+
+    responses: VecDeque<CannedResponse>,
+}
+
+impl Route {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        *method == self.method && self.paths.iter().any(|template| path_matches(template, path))
+    }
+
+    fn next_response(&mut self) -> Option<http::Response<Vec<u8>>> {
+        let response = if self.responses.len() > 1 {
+            self.responses.pop_front()?
+        } else {
+            self.responses.front()?.clone()
+        };
+        Some(response.into())
+    }
+}
+
+/// Whether the given path matches the path template.
+fn path_matches(template: &str, path: &str) -> bool {
+    let mut template_segments = template.split('/');
+    let mut path_segments = path.split('/');
+
+    loop {
+        match (template_segments.next(), path_segments.next()) {
+            (None, None) => return true,
+            (Some(template), Some(segment)) => {
+                if !template.starts_with(':') && template != segment {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// A response that can be returned several times.
+#[derive(Clone, Debug)]
+struct CannedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl From<http::Response<Vec<u8>>> for CannedResponse {
+    fn from(response: http::Response<Vec<u8>>) -> Self {
+        let (parts, body) = response.into_parts();
+        Self { status: parts.status, headers: parts.headers, body }
+    }
+}
+
+impl From<CannedResponse> for http::Response<Vec<u8>> {
+    fn from(response: CannedResponse) -> Self {
+        let mut http_response = http::Response::new(response.body);
+        *http_response.status_mut() = response.status;
+        *http_response.headers_mut() = response.headers;
+        http_response
+    }
+}
+
+impl From<RecordedResponse> for CannedResponse {
+    fn from(response: RecordedResponse) -> Self {
+        let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK);
+
+        let mut headers = HeaderMap::new();
+        for (name, values) in response.headers {
+            let Ok(name) = HeaderName::try_from(name) else {
+                continue;
+            };
+            for value in values {
+                if let Ok(value) = HeaderValue::try_from(value) {
+                    headers.append(&name, value);
+                }
+            }
+        }
+
+        // Fixtures recorded without headers only know that the body is JSON.
+        if matches!(response.body, RecordedBody::Json { .. }) && !headers.contains_key(CONTENT_TYPE)
+        {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        }
+
+        Self { status, headers, body: response.body.into_bytes() }
+    }
+}
+
+/// The error returned by [`MockHttpClient`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MockError {
+    /// No response matches the request.
+    UnmatchedRequest {
+        /// The method of the request.
+        method: Method,
+
+        /// The URI of the request.
+        uri: Uri,
+    },
+
+    /// Reading the streamed body of the request failed.
+    Body(io::Error),
+}
+
+impl fmt::Display for MockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnmatchedRequest { method, uri } => {
+                write!(f, "no response matches the request {method} {uri}")
+            }
+            Self::Body(error) => write!(f, "reading the request body failed: {error}"),
+        }
+    }
+}
+
+impl StdError for MockError {}
+
+/// Exchanges with a homeserver captured by a [`Recorder`], that can be replayed with
+/// [`MockHttpClient::from_fixture()`].
+///
+/// Fixtures are stored as JSON. The headers of the requests are not recorded, but their bodies
+/// and the headers and bodies of the responses are, so fixtures can contain passwords and access
+/// tokens.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Fixture {
+    exchanges: Vec<Exchange>,
+}
+
+impl Fixture {
+    /// Load a fixture from the JSON file at the given path.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(from_json_slice(&fs::read(path)?)?)
+    }
+
+    /// Save this fixture as JSON to the file at the given path.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = io::BufWriter::new(fs::File::create(path)?);
+        Ok(serde_json::to_writer_pretty(file, self)?)
+    }
+
+    /// The number of exchanges in this fixture.
+    pub fn len(&self) -> usize {
+        self.exchanges.len()
+    }
+
+    /// Whether this fixture doesn't contain any exchange.
+    pub fn is_empty(&self) -> bool {
+        self.exchanges.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Exchange {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    path: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    path_templates: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query: Option<String>,
+    #[serde(flatten)]
+    body: RecordedBody,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, Vec<String>>,
+    #[serde(flatten)]
+    body: RecordedBody,
+}
+
+/// A body, stored as JSON when possible to keep fixtures readable.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum RecordedBody {
+    Json { body: JsonValue },
+    Raw { raw_body: Base64 },
+    Empty {},
+}
+
+impl RecordedBody {
+    fn new(bytes: &[u8]) -> Self {
+        if bytes.is_empty() {
+            Self::Empty {}
+        } else if let Ok(body) = from_json_slice(bytes) {
+            Self::Json { body }
+        } else {
+            Self::Raw { raw_body: Base64::new(bytes.to_vec()) }
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Json { body } => to_json_vec(&body).expect("JSON values always serialize"),
+            Self::Raw { raw_body } => raw_body.into_inner(),
+            Self::Empty {} => Vec::new(),
+        }
+    }
+}
+
+/// A [`Middleware`] recording the requests and responses of an HTTP client into a [`Fixture`].
+///
+/// Cloning a `Recorder` returns a handle to the same fixture, so it can be saved after the HTTP
+/// client was passed to a [`Client`]. Requests that fail without a response are not recorded.
+///
+/// The path templates of the endpoints are recorded along with the paths of the requests sent
+/// with an endpoint type, so the responses can be replayed for any path parameters. The
+/// `Content-Length` and `Transfer-Encoding` headers of the responses are not recorded, since the
+/// bodies might be reformatted.
+///
+/// # Example
+///
+/// ```no_run
+/// # #[cfg(all(feature = "client-api", feature = "reqwest"))]
+/// # async {
+/// use ruma_client::{
+///     http_client::{mock::Recorder, Reqwest},
+///     Client, HttpClientExt,
+/// };
+///
+/// let recorder = Recorder::new();
+/// let client = Client::builder()
+///     .homeserver_url("https://example.org".to_owned())
+///     .http_client(Reqwest::new().with_middleware(recorder.clone()))
+///     .await?;
+/// client.log_in("alice", "secret", None, None).await?;
+///
+/// recorder.fixture().save("tests/fixtures/log_in.json")?;
+/// # Result::<(), Box<dyn std::error::Error>>::Ok(())
+/// # };
+/// ```
+///
+/// [`Client`]: crate::Client
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    exchanges: Arc<Mutex<Vec<Exchange>>>,
+}
+
+impl Recorder {
+    /// Creates a new `Recorder` with an empty fixture.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the fixture recorded so far.
+    pub fn fixture(&self) -> Fixture {
+        Fixture { exchanges: self.exchanges.lock().unwrap().clone() }
+    }
+}
+
+#[async_trait]
+impl<C> Middleware<C> for Recorder
+where
+    C: HttpClient,
+    C::RequestBody: AsRef<[u8]>,
+    C::ResponseBody: Send,
+{
+    async fn handle(
+        &self,
+        request: http::Request<C::RequestBody>,
+        next: &C,
+    ) -> Result<http::Response<C::ResponseBody>, C::Error> {
+        let recorded_request = RecordedRequest {
+            method: request.method().as_str().to_owned(),
+            path: request.uri().path().to_owned(),
+            path_templates: request
+                .extensions()
+                .get::<EndpointPaths>()
+                .map(|paths| paths.0.iter().map(|&path| path.to_owned()).collect())
+                .unwrap_or_default(),
+            query: request.uri().query().map(ToOwned::to_owned),
+            body: RecordedBody::new(request.body().as_ref()),
+        };
+
+        let response = next.send_http_request(request).await;
+
+        if let Ok(response) = &response {
+            let recorded_response = RecordedResponse {
+                status: response.status().as_u16(),
+                headers: record_headers(response.headers()),
+                body: RecordedBody::new(response.body().as_ref()),
+            };
+            self.exchanges
+                .lock()
+                .unwrap()
+                .push(Exchange { request: recorded_request, response: recorded_response });
+        }
+
+        response
+    }
+}
+
+/// Convert the given headers to strings, ignoring the ones that depend on the encoding of the body.
+fn record_headers(headers: &HeaderMap) -> BTreeMap<String, Vec<String>> {
+    let mut recorded = BTreeMap::<_, Vec<_>>::new();
+    for (name, value) in headers {
+        if *name == CONTENT_LENGTH || *name == TRANSFER_ENCODING {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            recorded.entry(name.as_str().to_owned()).or_default().push(value.to_owned());
+        }
+    }
+    recorded
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "client-api")]
+    use assert_matches2::assert_matches;
+    use http::StatusCode;
+    use ruma_client_api::{profile::get_display_name, sync::sync_events};
+    #[cfg(feature = "client-api")]
+    use ruma_client_api::{session::login, user_directory::search_users};
+    use ruma_common::{
+        api::{MatrixVersion, SendAccessToken},
+        owned_user_id,
+    };
+    use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+    #[cfg(feature = "client-api")]
+    use super::MockError;
+    use super::{path_matches, Fixture, MockHttpClient, Recorder};
+    #[cfg(feature = "client-api")]
+    use crate::{Client, Error};
+    use crate::{HttpClient, HttpClientExt};
+
+    #[test]
+    fn match_path_templates() {
+        let template = "/_matrix/client/v3/rooms/:room_id/state/:event_type/:state_key";
+        assert!(path_matches(template, "/_matrix/client/v3/rooms/!a:b.c/state/m.room.name/"));
+        assert!(path_matches(template, "/_matrix/client/v3/rooms/!a:b.c/state/m.room.name/key"));
+        assert!(!path_matches(template, "/_matrix/client/v3/rooms/!a:b.c/state/m.room.name"));
+        assert!(!path_matches(template, "/_matrix/client/v3/rooms/!a:b.c/members/m.room.name/"));
+    }
+
+    #[cfg(feature = "client-api")]
+    async fn client(http_client: MockHttpClient) -> Client<MockHttpClient> {
+        Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .http_client(http_client)
+            .await
+            .unwrap()
+    }
+
+    #[cfg(feature = "client-api")]
+    #[tokio::test]
+    async fn log_in_and_sync() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<login::v3::Request>(
+                StatusCode::OK,
+                json!({
+                    "access_token": "abc",
+                    "device_id": "DEVICE",
+                    "user_id": "@alice:example.org",
+                }),
+            )
+            .on_json::<sync_events::v3::Request>(StatusCode::OK, json!({ "next_batch": "s1" }))
+            .on_json::<sync_events::v3::Request>(StatusCode::OK, json!({ "next_batch": "s2" }));
+        let client = client(http_client.clone()).await;
+
+        let response = client.log_in("alice", "secret", None, None).await.unwrap();
+        assert_eq!(response.access_token, "abc");
+
+        for next_batch in ["s1", "s2", "s2"] {
+            let response = client.send_request(sync_events::v3::Request::new()).await.unwrap();
+            assert_eq!(response.next_batch, next_batch);
+        }
+
+        let requests = http_client.take_requests();
+        assert_eq!(requests.len(), 4);
+        let login_body: JsonValue = from_json_slice(requests[0].body()).unwrap();
+        assert_eq!(login_body["password"], "secret");
+        assert_eq!(requests[1].headers()[http::header::AUTHORIZATION], "Bearer abc");
+
+        let result = client.send_request(search_users::v3::Request::new("bob".to_owned())).await;
+        assert_matches!(result, Err(Error::Response(MockError::UnmatchedRequest { uri, .. })));
+        assert_eq!(uri.path(), "/_matrix/client/v3/user_directory/search");
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let live = MockHttpClient::new();
+        live.on_json::<sync_events::v3::Request>(StatusCode::OK, json!({ "next_batch": "s1" }))
+            .on_json::<sync_events::v3::Request>(
+            StatusCode::TOO_MANY_REQUESTS,
+            json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "Slow down" }),
+        );
+
+        let recorder = Recorder::new();
+        let recording = live.with_middleware(recorder.clone());
+        let request = sync_events::v3::Request::new();
+        recording
+            .send_matrix_request(
+                "https://example.org",
+                SendAccessToken::IfRequired("abc"),
+                &[MatrixVersion::V1_1],
+                request.clone(),
+            )
+            .await
+            .unwrap();
+        recording
+            .send_matrix_request(
+                "https://example.org",
+                SendAccessToken::IfRequired("abc"),
+                &[MatrixVersion::V1_1],
+                request,
+            )
+            .await
+            .unwrap_err();
+
+        let json = serde_json::to_value(recorder.fixture()).unwrap();
+        assert_eq!(json[0]["request"]["method"], "GET");
+        assert_eq!(json[0]["request"]["path"], "/_matrix/client/v3/sync");
+        assert_eq!(json[0]["request"]["path_templates"][0], "/_matrix/client/r0/sync");
+        assert_eq!(json[0]["response"]["headers"], json!({ "content-type": ["application/json"] }));
+        assert_eq!(json[0]["response"]["body"]["next_batch"], "s1");
+        assert_eq!(json[1]["response"]["status"], 429);
+
+        let fixture: Fixture = serde_json::from_value(json).unwrap();
+        assert_eq!(fixture.len(), 2);
+        let replay = MockHttpClient::from_fixture(fixture);
+
+        let request = http::Request::get("https://example.org/_matrix/client/v3/sync?since=s1")
+            .body(Vec::new())
+            .unwrap();
+        let response = replay.send_http_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: JsonValue = from_json_slice(response.body()).unwrap();
+        assert_eq!(body["next_batch"], "s1");
+
+        let request = http::Request::get("https://example.org/_matrix/client/v3/sync")
+            .body(Vec::new())
+            .unwrap();
+        let response = replay.send_http_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn replay_with_other_path_parameters() {
+        let live = MockHttpClient::new();
+        let response = http::Response::builder()
+            .header("content-type", "application/json")
+            .header("content-length", "26")
+            .header("x-request-id", "abc")
+            .body(br#"{"displayname":"Alice"}"#.to_vec())
+            .unwrap();
+        live.on::<get_display_name::v3::Request>(response);
+
+        let recorder = Recorder::new();
+        live.with_middleware(recorder.clone())
+            .send_matrix_request(
+                "https://example.org",
+                SendAccessToken::None,
+                &[MatrixVersion::V1_1],
+                get_display_name::v3::Request::new(owned_user_id!("@alice:example.org")),
+            )
+            .await
+            .unwrap();
+
+        let json = serde_json::to_value(recorder.fixture()).unwrap();
+        assert_eq!(
+            json[0]["response"]["headers"],
+            json!({ "content-type": ["application/json"], "x-request-id": ["abc"] })
+        );
+
+        let replay = MockHttpClient::from_fixture(serde_json::from_value(json).unwrap());
+        let request = http::Request::get(
+            "https://example.org/_matrix/client/v3/profile/@bob:example.org/displayname",
+        )
+        .body(Vec::new())
+        .unwrap();
+        let response = replay.send_http_request(request).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "abc");
+        let body: JsonValue = from_json_slice(response.body()).unwrap();
+        assert_eq!(body["displayname"], "Alice");
+    }
+}
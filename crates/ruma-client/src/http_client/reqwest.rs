@@ -1,9 +1,16 @@
-use std::mem;
+use std::{
+    io, mem,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
 
+use async_stream::try_stream;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
+use futures_core::Stream;
 
-use super::{DefaultConstructibleHttpClient, HttpClient};
+use super::{ByteStream, DefaultConstructibleHttpClient, HttpClient, StreamingHttpClient};
 
 /// The `reqwest` crate's `Client`.
 pub type Reqwest = reqwest::Client;
@@ -19,17 +26,67 @@ impl HttpClient for Reqwest {
         req: http::Request<BytesMut>,
     ) -> Result<http::Response<Bytes>, reqwest::Error> {
         let req = req.map(|body| body.freeze()).try_into()?;
-        let mut res = self.execute(req).await?;
+        let res = self.execute(req).await?;
+        let (http_builder, res) = response_builder(res);
 
-        let mut http_builder =
-            http::Response::builder().status(res.status()).version(res.version());
-        mem::swap(
-            http_builder.headers_mut().expect("http::response::Builder to be usable"),
-            res.headers_mut(),
-        );
+        Ok(http_builder.body(res.bytes().await?).expect("http::Response construction to work"))
+    }
+}
+
+#[async_trait]
+impl StreamingHttpClient for Reqwest {
+    async fn send_http_request_streaming_body(
+        &self,
+        req: http::Request<ByteStream>,
+    ) -> Result<http::Response<Bytes>, reqwest::Error> {
+        let req = req.map(|body| reqwest::Body::wrap_stream(SyncByteStream(Mutex::new(body))));
+        let res = self.execute(req.try_into()?).await?;
+        let (http_builder, res) = response_builder(res);
 
         Ok(http_builder.body(res.bytes().await?).expect("http::Response construction to work"))
     }
+
+    async fn send_http_request_streaming_response(
+        &self,
+        req: http::Request<BytesMut>,
+    ) -> Result<http::Response<ByteStream>, reqwest::Error> {
+        let req = req.map(|body| body.freeze()).try_into()?;
+        let res = self.execute(req).await?;
+        let (http_builder, mut res) = response_builder(res);
+
+        let body: ByteStream = Box::pin(try_stream! {
+            while let Some(chunk) = res
+                .chunk()
+                .await
+                .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?
+            {
+                yield chunk;
+            }
+        });
+        Ok(http_builder.body(body).expect("http::Response construction to work"))
+    }
+}
+
+/// Get a builder for an `http::Response` with the status, version and headers of the given
+/// response.
+fn response_builder(mut res: reqwest::Response) -> (http::response::Builder, reqwest::Response) {
+    let mut http_builder = http::Response::builder().status(res.status()).version(res.version());
+    mem::swap(
+        http_builder.headers_mut().expect("http::response::Builder to be usable"),
+        res.headers_mut(),
+    );
+    (http_builder, res)
+}
+
+/// A [`ByteStream`] that is `Sync`, as required by `reqwest::Body::wrap_stream`.
+struct SyncByteStream(Mutex<ByteStream>);
+
+impl Stream for SyncByteStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().0.get_mut().expect("stream mutex was poisoned").as_mut().poll_next(cx)
+    }
 }
 
 impl DefaultConstructibleHttpClient for Reqwest {
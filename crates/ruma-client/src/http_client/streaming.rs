@@ -0,0 +1,68 @@
+use std::{future::poll_fn, io, pin::Pin};
+
+use async_stream::try_stream;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_io::AsyncRead;
+
+use super::HttpClient;
+
+/// A stream of bytes, used as the body of HTTP requests and responses that are not buffered.
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// An [`HttpClient`] that can stream the bodies of requests and responses instead of buffering
+/// them, for example to upload and download large media files.
+#[async_trait]
+pub trait StreamingHttpClient: HttpClient {
+    /// Send an `http::Request` with a streamed body to get back an `http::Response`.
+    ///
+    /// The `Content-Length` header should be set on the request if the length of the body is
+    /// known, otherwise the body might be sent with chunked transfer encoding.
+    async fn send_http_request_streaming_body(
+        &self,
+        req: http::Request<ByteStream>,
+    ) -> Result<http::Response<Self::ResponseBody>, Self::Error>;
+
+    /// Send an `http::Request` to get back an `http::Response` with a streamed body.
+    ///
+    /// The response is returned as soon as its head is received.
+    async fn send_http_request_streaming_response(
+        &self,
+        req: http::Request<Self::RequestBody>,
+    ) -> Result<http::Response<ByteStream>, Self::Error>;
+}
+
+/// Convert the given reader to a [`ByteStream`].
+///
+/// To use a reader implementing `tokio::io::AsyncRead`, convert it first with
+/// `tokio_util::compat`.
+pub fn reader_stream<R>(reader: R) -> ByteStream
+where
+    R: AsyncRead + Send + 'static,
+{
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    Box::pin(try_stream! {
+        let mut reader = Box::pin(reader);
+        let mut buf = vec![0; CHUNK_SIZE];
+
+        loop {
+            let len = poll_fn(|cx| reader.as_mut().poll_read(cx, &mut buf)).await?;
+            if len == 0 {
+                break;
+            }
+
+            yield Bytes::copy_from_slice(&buf[..len]);
+        }
+    })
+}
+
+/// Read the whole stream into memory.
+pub(crate) async fn collect_stream(mut stream: ByteStream) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+        bytes.extend_from_slice(&chunk?);
+    }
+    Ok(bytes)
+}
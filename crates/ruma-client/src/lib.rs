@@ -95,6 +95,9 @@
 //!   * `reqwest-rustls-manual-roots`
 //!   * `reqwest-rustls-webpki-roots`
 //!   * `reqwest-rustls-native-roots`
+//!
+//! The `test-util` feature activates the `http_client::mock` module, with an HTTP client returning
+//! canned responses to test code using a `Client` without a homeserver.
 
 #![warn(missing_docs)]
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
@@ -105,15 +108,25 @@ use ruma_common::{
     api::{MatrixVersion, OutgoingRequest, SendAccessToken},
     UserId,
 };
-use tracing::{info_span, Instrument};
+use tracing::{info_span, Instrument, Span};
+
+#[cfg(any(test, feature = "test-util"))]
+use self::http_client::EndpointPaths;
 
 #[cfg(feature = "client-api")]
 mod client;
 mod error;
 pub mod http_client;
+#[cfg(feature = "unstable-msc3575")]
+pub mod sliding_sync;
 
+#[cfg(feature = "appservice")]
+pub use self::client::{AppserviceClient, AppserviceError, InvalidRegistration};
 #[cfg(feature = "client-api")]
-pub use self::client::{Client, ClientBuilder};
+pub use self::client::{
+    Client, ClientBuilder, MediaDownload, MediaUpload, NoSyncStore, RetryPolicy, Session,
+    SyncSettings, SyncStore, SyncUpdate, TimelineGap, UiaaHandler, UiaaRequest,
+};
 pub use self::{
     error::Error,
     http_client::{DefaultConstructibleHttpClient, HttpClient, HttpClientExt},
@@ -140,23 +153,14 @@ where
     R: OutgoingRequest,
     F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
 {
-    let http_req =
-        info_span!("serialize_request", request_type = type_name::<R>()).in_scope(move || {
-            request
-                .try_into_http_request(homeserver_url, send_access_token, for_versions)
-                .map_err(ResponseError::<C, R>::from)
-                .and_then(|mut req| {
-                    customize(&mut req)?;
-                    Ok(req)
-                })
-        });
-
-    let send_span = info_span!(
-        "send_request",
-        request_type = type_name::<R>(),
-        http_client = type_name::<C>(),
+    let http_req = serialize_request::<C, R, F>(
         homeserver_url,
+        send_access_token,
+        for_versions,
+        request,
+        customize,
     );
+    let send_span = send_span::<C, R>(homeserver_url);
 
     async move {
         let http_res = http_client
@@ -165,33 +169,78 @@ where
             .await
             .map_err(Error::Response)?;
 
-        let res =
-            info_span!("deserialize_response", response_type = type_name::<R::IncomingResponse>())
-                .in_scope(move || {
-                    ruma_common::api::IncomingResponse::try_from_http_response(http_res)
-                })?;
-
-        Ok(res)
+        deserialize_response::<C, R>(http_res)
     }
 }
 
+fn serialize_request<C, R, F>(
+    homeserver_url: &str,
+    send_access_token: SendAccessToken<'_>,
+    for_versions: &[MatrixVersion],
+    request: R,
+    customize: F,
+) -> Result<http::Request<C::RequestBody>, ResponseError<C, R>>
+where
+    C: HttpClient + ?Sized,
+    R: OutgoingRequest,
+    F: FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>>,
+{
+    info_span!("serialize_request", request_type = type_name::<R>()).in_scope(move || {
+        request
+            .try_into_http_request(homeserver_url, send_access_token, for_versions)
+            .map_err(ResponseError::<C, R>::from)
+            .and_then(|mut req| {
+                #[cfg(any(test, feature = "test-util"))]
+                req.extensions_mut().insert(EndpointPaths::of::<R>());
+                customize(&mut req)?;
+                Ok(req)
+            })
+    })
+}
+
+fn send_span<C: HttpClient + ?Sized, R: OutgoingRequest>(homeserver_url: &str) -> Span {
+    info_span!(
+        "send_request",
+        request_type = type_name::<R>(),
+        http_client = type_name::<C>(),
+        homeserver_url,
+    )
+}
+
+fn deserialize_response<C: HttpClient + ?Sized, R: OutgoingRequest>(
+    http_res: http::Response<C::ResponseBody>,
+) -> ResponseResult<C, R> {
+    let res =
+        info_span!("deserialize_response", response_type = type_name::<R::IncomingResponse>())
+            .in_scope(move || {
+                ruma_common::api::IncomingResponse::try_from_http_response(http_res)
+            })?;
+
+    Ok(res)
+}
+
 fn add_user_id_to_query<C: HttpClient + ?Sized, R: OutgoingRequest>(
     user_id: &UserId,
-) -> impl FnOnce(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>> + '_ {
+) -> impl Fn(&mut http::Request<C::RequestBody>) -> Result<(), ResponseError<C, R>> + '_ {
+    move |http_request| add_params_to_query::<C, R>(http_request, [("user_id", user_id.as_str())])
+}
+
+fn add_params_to_query<'a, C: HttpClient + ?Sized, R: OutgoingRequest>(
+    http_request: &mut http::Request<C::RequestBody>,
+    params: impl serde::Serialize + 'a,
+) -> Result<(), ResponseError<C, R>> {
     use assign::assign;
     use http::uri::Uri;
 
-    move |http_request| {
-        let extra_params = serde_html_form::to_string([("user_id", user_id)]).unwrap();
-        let uri = http_request.uri_mut();
-        let new_path_and_query = match uri.query() {
-            Some(params) => format!("{}?{params}&{extra_params}", uri.path()),
-            None => format!("{}?{extra_params}", uri.path()),
-        };
-        *uri = Uri::from_parts(assign!(uri.clone().into_parts(), {
-            path_and_query: Some(new_path_and_query.parse()?),
-        }))?;
-
-        Ok(())
-    }
+    let extra_params = serde_html_form::to_string(params).unwrap();
+    let uri = http_request.uri_mut();
+    let new_path_and_query = match uri.query() {
+        Some(params) => format!("{}?{params}&{extra_params}", uri.path()),
+        None => format!("{}?{extra_params}", uri.path()),
+    };
+    *uri = Uri::from_parts(assign!(uri.clone().into_parts(), {
+        path_and_query: Some(new_path_and_query.parse()?),
+    }))?;
+
+    Ok(())
 }
@@ -0,0 +1,935 @@
+//! Client-side state of [sliding sync] (MSC3575).
+//!
+//! [`SlidingSync`] builds the requests to the [`v4`] endpoint and applies their
+//! responses to maintain the ordered room lists, the data of the rooms and the data of the
+//! extensions. It doesn't send any request itself, so it can be used with any HTTP client, and
+//! [`Client::sliding_sync`] drives it with a [`Client`].
+//!
+//! [sliding sync]: https://github.com/matrix-org/matrix-spec-proposals/pull/3575
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    time::Duration,
+};
+
+use assign::assign;
+use js_int::UInt;
+use ruma_client_api::{
+    error::{ErrorBody, ErrorKind},
+    sync::sync_events::{
+        v4::{
+            self, AccountData, ExtensionsConfig, Receipts, RoomSubscription, SlidingOp,
+            SlidingSyncRoom, SyncList, SyncRequestList, Typing, E2EE,
+        },
+        DeviceLists,
+    },
+};
+use ruma_common::{
+    api::error::FromHttpResponseError, serde::Raw, DeviceKeyAlgorithm, OwnedRoomId, RoomId,
+};
+use ruma_events::{
+    receipt::SyncReceiptEvent, typing::SyncTypingEvent, AnyGlobalAccountDataEvent,
+    AnyRoomAccountDataEvent, AnyToDeviceEvent,
+};
+use serde_json::{value::to_raw_value as to_raw_json_value, Map as JsonObject, Value as JsonValue};
+use tracing::warn;
+
+use crate::{Client, Error, HttpClient};
+
+/// The client-side state of a sliding sync connection.
+#[derive(Clone, Debug, Default)]
+pub struct SlidingSync {
+    pos: Option<String>,
+    delta_token: Option<String>,
+    lists: BTreeMap<String, SlidingSyncList>,
+    room_subscriptions: BTreeMap<OwnedRoomId, RoomSubscription>,
+    unsubscribe_rooms: BTreeSet<OwnedRoomId>,
+    extensions: ExtensionsConfig,
+    rooms: BTreeMap<OwnedRoomId, SlidingSyncRoom>,
+    to_device_since: Option<String>,
+    device_lists: DeviceLists,
+    device_one_time_keys_count: BTreeMap<DeviceKeyAlgorithm, UInt>,
+    device_unused_fallback_key_types: Option<Vec<DeviceKeyAlgorithm>>,
+    account_data: BTreeMap<String, Raw<AnyGlobalAccountDataEvent>>,
+    room_account_data: BTreeMap<OwnedRoomId, BTreeMap<String, Raw<AnyRoomAccountDataEvent>>>,
+    receipts: BTreeMap<OwnedRoomId, Raw<SyncReceiptEvent>>,
+    typing: BTreeMap<OwnedRoomId, Raw<SyncTypingEvent>>,
+}
+
+impl SlidingSync {
+    /// Creates a new `SlidingSync` without any list, room subscription or extension.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The position of the connection, if any response was applied since it was created or
+    /// [reset][Self::reset].
+    pub fn pos(&self) -> Option<&str> {
+        self.pos.as_deref()
+    }
+
+    /// Add a list with the given name and configuration, or replace the configuration of the list
+    /// with that name.
+    pub fn set_list(&mut self, name: String, config: SyncRequestList) -> &mut SlidingSyncList {
+        let list = self.lists.entry(name).or_default();
+        list.config = config;
+        list
+    }
+
+    /// Remove the list with the given name.
+    pub fn remove_list(&mut self, name: &str) -> Option<SlidingSyncList> {
+        self.lists.remove(name)
+    }
+
+    /// Get the list with the given name.
+    pub fn list(&self, name: &str) -> Option<&SlidingSyncList> {
+        self.lists.get(name)
+    }
+
+    /// Get a mutable reference to the list with the given name, to change its configuration.
+    pub fn list_mut(&mut self, name: &str) -> Option<&mut SlidingSyncList> {
+        self.lists.get_mut(name)
+    }
+
+    /// Get the lists, by name.
+    pub fn lists(&self) -> &BTreeMap<String, SlidingSyncList> {
+        &self.lists
+    }
+
+    /// Subscribe to the room with the given ID, whether it is in a list or not.
+    pub fn subscribe(&mut self, room_id: OwnedRoomId, subscription: RoomSubscription) {
+        self.unsubscribe_rooms.remove(&room_id);
+        self.room_subscriptions.insert(room_id, subscription);
+    }
+
+    /// Unsubscribe from the room with the given ID.
+    pub fn unsubscribe(&mut self, room_id: &RoomId) {
+        if self.room_subscriptions.remove(room_id).is_some() {
+            self.unsubscribe_rooms.insert(room_id.to_owned());
+        }
+    }
+
+    /// Set the configuration of the extensions.
+    ///
+    /// The `since` token of the to-device extension is managed automatically.
+    pub fn set_extensions(&mut self, extensions: ExtensionsConfig) {
+        self.extensions = extensions;
+    }
+
+    /// Get the data of the rooms received so far, by room ID.
+    pub fn rooms(&self) -> &BTreeMap<OwnedRoomId, SlidingSyncRoom> {
+        &self.rooms
+    }
+
+    /// Get the data received so far for the room with the given ID.
+    ///
+    /// The fields of the room are merged with every response: metadata is replaced by newer
+    /// values, required state is appended, and the timeline is appended unless the new timeline
+    /// is limited, in which case it replaces the previous one.
+    pub fn room(&self, room_id: &RoomId) -> Option<&SlidingSyncRoom> {
+        self.rooms.get(room_id)
+    }
+
+    /// Forget the data received for the room with the given ID, for example once its timeline was
+    /// processed.
+    pub fn forget_room(&mut self, room_id: &RoomId) -> Option<SlidingSyncRoom> {
+        self.rooms.remove(room_id)
+    }
+
+    /// Get the latest global account data, by event type.
+    pub fn account_data(&self) -> &BTreeMap<String, Raw<AnyGlobalAccountDataEvent>> {
+        &self.account_data
+    }
+
+    /// Get the latest account data of the room with the given ID, by event type.
+    pub fn room_account_data(
+        &self,
+        room_id: &RoomId,
+    ) -> Option<&BTreeMap<String, Raw<AnyRoomAccountDataEvent>>> {
+        self.room_account_data.get(room_id)
+    }
+
+    /// Get the receipts of the rooms, by room ID.
+    ///
+    /// The receipt events of every response are merged, so every event contains the latest
+    /// receipt of every user for every receipt type.
+    pub fn receipts(&self) -> &BTreeMap<OwnedRoomId, Raw<SyncReceiptEvent>> {
+        &self.receipts
+    }
+
+    /// Get the latest typing notifications of the rooms, by room ID.
+    pub fn typing(&self) -> &BTreeMap<OwnedRoomId, Raw<SyncTypingEvent>> {
+        &self.typing
+    }
+
+    /// Take the device list changes accumulated since the last call.
+    pub fn take_device_lists(&mut self) -> DeviceLists {
+        mem::take(&mut self.device_lists)
+    }
+
+    /// Get the latest number of unclaimed one-time keys of the device, by algorithm.
+    pub fn device_one_time_keys_count(&self) -> &BTreeMap<DeviceKeyAlgorithm, UInt> {
+        &self.device_one_time_keys_count
+    }
+
+    /// Get the latest unused fallback key algorithms of the device, if any were received.
+    pub fn device_unused_fallback_key_types(&self) -> Option<&[DeviceKeyAlgorithm]> {
+        self.device_unused_fallback_key_types.as_deref()
+    }
+
+    /// Reset the position of the connection and the room lists, for example because the server
+    /// forgot about the connection.
+    ///
+    /// The data of the rooms and of the extensions is kept.
+    pub fn reset(&mut self) {
+        self.pos = None;
+        self.delta_token = None;
+        for list in self.lists.values_mut() {
+            list.entries.clear();
+            list.count = 0;
+        }
+    }
+
+    /// Build the request for the current state.
+    pub fn build_request(&self) -> v4::Request {
+        let mut extensions = self.extensions.clone();
+        if extensions.to_device.enabled == Some(true) {
+            extensions.to_device.since = self.to_device_since.clone();
+        }
+
+        assign!(v4::Request::new(), {
+            pos: self.pos.clone(),
+            delta_token: self.delta_token.clone(),
+            lists: self
+                .lists
+                .iter()
+                .map(|(name, list)| (name.clone(), list.config.clone()))
+                .collect(),
+            room_subscriptions: self.room_subscriptions.clone(),
+            unsubscribe_rooms: self.unsubscribe_rooms.iter().cloned().collect(),
+            extensions,
+        })
+    }
+
+    /// Apply a response to the state.
+    ///
+    /// Returns a summary of what changed, along with the to-device events of the response, which
+    /// are not kept.
+    pub fn apply_response(&mut self, response: v4::Response) -> SlidingSyncUpdate {
+        let mut update = SlidingSyncUpdate::default();
+
+        self.pos = Some(response.pos);
+        if response.delta_token.is_some() {
+            self.delta_token = response.delta_token;
+        }
+        self.unsubscribe_rooms.clear();
+
+        for (name, list) in response.lists {
+            match self.lists.get_mut(&name) {
+                Some(state) => {
+                    state.apply(list);
+                    update.lists.insert(name);
+                }
+                None => warn!("received operations for unknown list {name}"),
+            }
+        }
+
+        for (room_id, room) in response.rooms {
+            match self.rooms.get_mut(&room_id) {
+                Some(state) => merge_room(state, room),
+                None => {
+                    self.rooms.insert(room_id.clone(), room);
+                }
+            }
+            update.rooms.insert(room_id);
+        }
+
+        let extensions = response.extensions;
+        if let Some(to_device) = extensions.to_device {
+            self.to_device_since = Some(to_device.next_batch);
+            update.to_device = to_device.events;
+        }
+        self.apply_e2ee(extensions.e2ee);
+        self.apply_account_data(extensions.account_data);
+        self.apply_receipts(extensions.receipts);
+        self.apply_typing(extensions.typing);
+
+        update
+    }
+
+    fn apply_e2ee(&mut self, e2ee: E2EE) {
+        let device_lists = &mut self.device_lists;
+        for user_id in e2ee.device_lists.changed {
+            device_lists.left.retain(|id| *id != user_id);
+            if !device_lists.changed.contains(&user_id) {
+                device_lists.changed.push(user_id);
+            }
+        }
+        for user_id in e2ee.device_lists.left {
+            device_lists.changed.retain(|id| *id != user_id);
+            if !device_lists.left.contains(&user_id) {
+                device_lists.left.push(user_id);
+            }
+        }
+
+        if !e2ee.device_one_time_keys_count.is_empty() {
+            self.device_one_time_keys_count = e2ee.device_one_time_keys_count;
+        }
+        if e2ee.device_unused_fallback_key_types.is_some() {
+            self.device_unused_fallback_key_types = e2ee.device_unused_fallback_key_types;
+        }
+    }
+
+    fn apply_account_data(&mut self, account_data: AccountData) {
+        for event in account_data.global {
+            if let Some(event_type) = event_type(&event) {
+                self.account_data.insert(event_type, event);
+            }
+        }
+
+        for (room_id, events) in account_data.rooms {
+            let room_account_data = self.room_account_data.entry(room_id).or_default();
+            for event in events {
+                if let Some(event_type) = event_type(&event) {
+                    room_account_data.insert(event_type, event);
+                }
+            }
+        }
+    }
+
+    fn apply_receipts(&mut self, receipts: Receipts) {
+        for (room_id, event) in receipts.rooms {
+            let event = match self.receipts.get(&room_id) {
+                Some(previous) => merge_receipts(previous, event),
+                None => event,
+            };
+            self.receipts.insert(room_id, event);
+        }
+    }
+
+    fn apply_typing(&mut self, typing: Typing) {
+        self.typing.extend(typing.rooms);
+    }
+}
+
+/// A summary of the changes of a [`SlidingSync`] after applying a response.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct SlidingSyncUpdate {
+    /// The names of the lists that were updated.
+    pub lists: BTreeSet<String>,
+
+    /// The IDs of the rooms that were updated.
+    pub rooms: BTreeSet<OwnedRoomId>,
+
+    /// The to-device events of the response.
+    pub to_device: Vec<Raw<AnyToDeviceEvent>>,
+}
+
+/// The state of a list of a [`SlidingSync`].
+#[derive(Clone, Debug, Default)]
+pub struct SlidingSyncList {
+    config: SyncRequestList,
+    entries: Vec<RoomListEntry>,
+    count: usize,
+}
+
+impl SlidingSyncList {
+    /// Get the configuration of the list.
+    pub fn config(&self) -> &SyncRequestList {
+        &self.config
+    }
+
+    /// Set the ranges of the list that should be synced.
+    pub fn set_ranges(&mut self, ranges: Vec<(UInt, UInt)>) {
+        self.config.ranges = ranges;
+    }
+
+    /// The total number of rooms in the list, according to the server.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The entries of the list, in order.
+    ///
+    /// Only the entries in the synced ranges are filled, the list might be shorter than
+    /// [`count()`][Self::count] if the ranges don't reach the end of the list.
+    pub fn entries(&self) -> &[RoomListEntry] {
+        &self.entries
+    }
+
+    /// The IDs of the rooms in the list that are up to date, in order.
+    pub fn room_ids(&self) -> impl Iterator<Item = &RoomId> {
+        self.entries.iter().filter_map(|entry| match entry {
+            RoomListEntry::Filled(room_id) => Some(&**room_id),
+            _ => None,
+        })
+    }
+
+    fn apply(&mut self, list: SyncList) {
+        self.count = to_index(list.count);
+
+        for op in list.ops {
+            match (op.op, op.range, op.index) {
+                (SlidingOp::Sync, Some((start, _)), _) => {
+                    let start = to_index(start);
+                    if start >= self.count {
+                        warn!("SYNC operation at index {start} outside of the list");
+                        continue;
+                    }
+                    self.fill(start + op.room_ids.len());
+
+                    let entries = self.entries.get_mut(start..).unwrap_or_default();
+                    for (entry, room_id) in entries.iter_mut().zip(op.room_ids) {
+                        *entry = RoomListEntry::Filled(room_id);
+                    }
+                }
+                (SlidingOp::Invalidate, Some((start, end)), _) => {
+                    let end = to_index(end).saturating_add(1).min(self.entries.len());
+                    for entry in self.entries.get_mut(to_index(start)..end).unwrap_or_default() {
+                        if let RoomListEntry::Filled(room_id) = entry {
+                            *entry = RoomListEntry::Invalidated(room_id.clone());
+                        }
+                    }
+                }
+                (SlidingOp::Delete, _, Some(index)) => {
+                    let index = to_index(index);
+                    if index >= self.entries.len() {
+                        continue;
+                    }
+
+                    // Move the following rooms up, until the next hole.
+                    let end = self.next_hole(index + 1).unwrap_or(self.entries.len());
+                    self.entries[index..end].rotate_left(1);
+                    self.entries[end - 1] = RoomListEntry::Empty;
+                }
+                (SlidingOp::Insert, _, Some(index)) => {
+                    let Some(room_id) = op.room_id else {
+                        warn!("INSERT operation without a room ID");
+                        continue;
+                    };
+                    let index = to_index(index);
+                    if index >= self.count {
+                        warn!("INSERT operation at index {index} outside of the list");
+                        continue;
+                    }
+                    self.fill(index + 1);
+
+                    // Move the following rooms down, until the next hole.
+                    let end = self.next_hole(index).unwrap_or_else(|| {
+                        self.entries.push(RoomListEntry::Empty);
+                        self.entries.len() - 1
+                    });
+                    self.entries[index..=end].rotate_right(1);
+                    self.entries[index] = RoomListEntry::Filled(room_id);
+                }
+                (op, ..) => warn!("ignoring invalid or unsupported operation {op:?}"),
+            }
+        }
+
+        self.entries.truncate(self.count);
+    }
+
+    /// Make sure the list has at least `len` entries, and at most `count` entries.
+    fn fill(&mut self, len: usize) {
+        let len = len.min(self.count);
+        if self.entries.len() < len {
+            self.entries.resize(len, RoomListEntry::Empty);
+        }
+    }
+
+    /// The index of the first entry that is not filled, from the given index.
+    fn next_hole(&self, from: usize) -> Option<usize> {
+        self.entries
+            .get(from..)?
+            .iter()
+            .position(|entry| !matches!(entry, RoomListEntry::Filled(_)))
+            .map(|position| from + position)
+    }
+}
+
+/// An entry of a [`SlidingSyncList`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_enums)]
+pub enum RoomListEntry {
+    /// The entry was never synced.
+    Empty,
+
+    /// The entry contains a room that is up to date.
+    Filled(OwnedRoomId),
+
+    /// The entry contained a room but it is no longer up to date, because it left the synced
+    /// ranges.
+    Invalidated(OwnedRoomId),
+}
+
+impl RoomListEntry {
+    /// The ID of the room in this entry, if any.
+    pub fn room_id(&self) -> Option<&RoomId> {
+        match self {
+            Self::Empty => None,
+            Self::Filled(room_id) | Self::Invalidated(room_id) => Some(room_id),
+        }
+    }
+}
+
+impl<C: HttpClient> Client<C> {
+    /// Send a sliding sync request built from the given state, and apply the response to it.
+    ///
+    /// If the server forgot about the connection, the state is [reset][SlidingSync::reset] and the
+    /// request is sent again.
+    pub async fn sliding_sync(
+        &self,
+        state: &mut SlidingSync,
+        timeout: Option<Duration>,
+    ) -> Result<SlidingSyncUpdate, Error<C::Error, ruma_client_api::Error>> {
+        let request = assign!(state.build_request(), { timeout });
+        let response = match self.send_request(request).await {
+            Err(Error::FromHttpResponse(FromHttpResponseError::Server(
+                ruma_client_api::Error {
+                    body: ErrorBody::Standard { kind: ErrorKind::UnknownPos, .. },
+                    ..
+                },
+            ))) if state.pos.is_some() => {
+                warn!("sliding sync position unknown to the server, resetting");
+                state.reset();
+                self.send_request(assign!(state.build_request(), { timeout })).await?
+            }
+            result => result?,
+        };
+
+        Ok(state.apply_response(response))
+    }
+}
+
+/// Convert a list index to a `usize`.
+fn to_index(index: UInt) -> usize {
+    u64::from(index).try_into().unwrap_or(usize::MAX)
+}
+
+/// Get the type of the given account data event.
+fn event_type<T>(event: &Raw<T>) -> Option<String> {
+    event.get_field("type").ok().flatten()
+}
+
+/// Merge the content of two receipt events.
+///
+/// The receipts of the new event replace the receipts of the same type, user and thread in the
+/// previous event. If either event can't be parsed, the new one is returned.
+fn merge_receipts(
+    previous: &Raw<SyncReceiptEvent>,
+    new: Raw<SyncReceiptEvent>,
+) -> Raw<SyncReceiptEvent> {
+    let (Ok(Some(mut content)), Ok(Some(new_content))) = (
+        previous.get_field::<JsonObject<String, JsonValue>>("content"),
+        new.get_field::<JsonObject<String, JsonValue>>("content"),
+    ) else {
+        return new;
+    };
+
+    // The receipt type, user ID and thread ID of every new receipt.
+    let mut keys = BTreeSet::new();
+    for receipts in new_content.values() {
+        for (receipt_type, users) in receipts.as_object().into_iter().flatten() {
+            for (user_id, receipt) in users.as_object().into_iter().flatten() {
+                keys.insert((receipt_type.clone(), user_id.clone(), thread_id(receipt)));
+            }
+        }
+    }
+
+    for receipts in content.values_mut() {
+        let Some(receipts) = receipts.as_object_mut() else { continue };
+        for (receipt_type, users) in receipts.iter_mut() {
+            let Some(users) = users.as_object_mut() else { continue };
+            users.retain(|user_id, receipt| {
+                !keys.contains(&(receipt_type.clone(), user_id.clone(), thread_id(receipt)))
+            });
+        }
+        receipts.retain(|_, users| users.as_object().map_or(true, |users| !users.is_empty()));
+    }
+    content.retain(|_, receipts| receipts.as_object().map_or(true, |r| !r.is_empty()));
+
+    for (event_id, receipts) in new_content {
+        match (content.get_mut(&event_id).and_then(JsonValue::as_object_mut), receipts) {
+            (Some(previous), JsonValue::Object(receipts)) => {
+                for (receipt_type, users) in receipts {
+                    match (
+                        previous.get_mut(&receipt_type).and_then(JsonValue::as_object_mut),
+                        users,
+                    ) {
+                        (Some(previous), JsonValue::Object(users)) => previous.extend(users),
+                        (_, users) => {
+                            previous.insert(receipt_type, users);
+                        }
+                    }
+                }
+            }
+            (_, receipts) => {
+                content.insert(event_id, receipts);
+            }
+        }
+    }
+
+    let event = serde_json::json!({ "type": "m.receipt", "content": content });
+    to_raw_json_value(&event).map(Raw::from_json).unwrap_or(new)
+}
+
+/// Get the thread ID of the given receipt.
+fn thread_id(receipt: &JsonValue) -> Option<String> {
+    receipt.get("thread_id")?.as_str().map(ToOwned::to_owned)
+}
+
+/// Merge an update of a room into its previous data.
+fn merge_room(room: &mut SlidingSyncRoom, update: SlidingSyncRoom) {
+    if update.initial == Some(true) {
+        *room = update;
+        return;
+    }
+
+    if update.name.is_some() {
+        room.name = update.name;
+    }
+    if update.avatar.is_some() {
+        room.avatar = update.avatar;
+    }
+    if update.is_dm.is_some() {
+        room.is_dm = update.is_dm;
+    }
+    if update.invite_state.is_some() {
+        room.invite_state = update.invite_state;
+    }
+    if !update.unread_notifications.is_empty() {
+        room.unread_notifications = update.unread_notifications;
+    }
+    if update.joined_count.is_some() {
+        room.joined_count = update.joined_count;
+    }
+    if update.invited_count.is_some() {
+        room.invited_count = update.invited_count;
+    }
+    if update.num_live.is_some() {
+        room.num_live = update.num_live;
+    }
+    if update.timestamp.is_some() {
+        room.timestamp = update.timestamp;
+    }
+
+    room.required_state.extend(update.required_state);
+
+    if update.limited {
+        room.timeline = update.timeline;
+        room.prev_batch = update.prev_batch;
+        room.limited = true;
+    } else {
+        room.timeline.extend(update.timeline);
+        if room.prev_batch.is_none() {
+            room.prev_batch = update.prev_batch;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_matches;
+    use assign::assign;
+    use http::StatusCode;
+    use ruma_client_api::{discovery::get_supported_versions, sync::sync_events::v4};
+    use ruma_common::{api::MatrixVersion, owned_room_id, owned_user_id, room_id};
+    use serde_json::{from_value as from_json_value, json, Value as JsonValue};
+
+    use super::{RoomListEntry, SlidingSync};
+    use crate::{http_client::mock::MockHttpClient, Client, Error};
+
+    fn response(pos: &str, json: JsonValue) -> v4::Response {
+        assign!(v4::Response::new(pos.to_owned()), {
+            lists: from_json_value(json["lists"].clone()).unwrap_or_default(),
+            rooms: from_json_value(json["rooms"].clone()).unwrap_or_default(),
+            extensions: from_json_value(json["extensions"].clone()).unwrap_or_default(),
+        })
+    }
+
+    fn filled(ids: &[&str]) -> Vec<RoomListEntry> {
+        ids.iter()
+            .map(|id| RoomListEntry::Filled(format!("!{id}:example.org").try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn list_operations() {
+        let mut state = SlidingSync::new();
+        state.set_list("all".to_owned(), Default::default());
+
+        let update = state.apply_response(response(
+            "1",
+            json!({
+                "lists": {
+                    "all": {
+                        "count": 5,
+                        "ops": [{
+                            "op": "SYNC",
+                            "range": [0, 2],
+                            "room_ids": ["!a:example.org", "!b:example.org", "!c:example.org"],
+                        }],
+                    },
+                },
+            }),
+        ));
+        assert!(update.lists.contains("all"));
+        let list = state.list("all").unwrap();
+        assert_eq!(list.count(), 5);
+        assert_eq!(list.entries(), filled(&["a", "b", "c"]));
+
+        // Move c to the top.
+        state.apply_response(response(
+            "2",
+            json!({
+                "lists": {
+                    "all": {
+                        "count": 5,
+                        "ops": [
+                            { "op": "DELETE", "index": 2 },
+                            { "op": "INSERT", "index": 0, "room_id": "!c:example.org" },
+                        ],
+                    },
+                },
+            }),
+        ));
+        assert_eq!(state.list("all").unwrap().entries(), filled(&["c", "a", "b"]));
+
+        // A new room at the top, pushing the others down.
+        state.apply_response(response(
+            "3",
+            json!({
+                "lists": {
+                    "all": {
+                        "count": 6,
+                        "ops": [{ "op": "INSERT", "index": 0, "room_id": "!d:example.org" }],
+                    },
+                },
+            }),
+        ));
+        assert_eq!(state.list("all").unwrap().entries(), filled(&["d", "c", "a", "b"]));
+
+        state.apply_response(response(
+            "4",
+            json!({
+                "lists": {
+                    "all": {
+                        "count": 3,
+                        "ops": [{ "op": "INVALIDATE", "range": [1, 5] }],
+                    },
+                },
+            }),
+        ));
+        let list = state.list("all").unwrap();
+        assert_eq!(
+            list.entries(),
+            [
+                RoomListEntry::Filled(owned_room_id!("!d:example.org")),
+                RoomListEntry::Invalidated(owned_room_id!("!c:example.org")),
+                RoomListEntry::Invalidated(owned_room_id!("!a:example.org")),
+            ]
+        );
+        assert_eq!(list.room_ids().collect::<Vec<_>>(), [room_id!("!d:example.org")]);
+
+        // A range outside of the list is ignored.
+        state.apply_response(response(
+            "5",
+            json!({
+                "lists": {
+                    "all": {
+                        "count": 5,
+                        "ops": [{
+                            "op": "SYNC",
+                            "range": [10, 19],
+                            "room_ids": ["!e:example.org"],
+                        }],
+                    },
+                },
+            }),
+        ));
+        assert_eq!(state.list("all").unwrap().entries().len(), 3);
+    }
+
+    #[test]
+    fn request_state() {
+        let mut state = SlidingSync::new();
+        state.subscribe(owned_room_id!("!a:example.org"), Default::default());
+        state.set_extensions(from_json_value(json!({ "to_device": { "enabled": true } })).unwrap());
+
+        let request = state.build_request();
+        assert_eq!(request.pos, None);
+        assert_eq!(request.room_subscriptions.len(), 1);
+        assert_eq!(request.extensions.to_device.since, None);
+
+        state.apply_response(response(
+            "1",
+            json!({ "extensions": { "to_device": { "next_batch": "t1", "events": [] } } }),
+        ));
+        state.unsubscribe(room_id!("!a:example.org"));
+
+        let request = state.build_request();
+        assert_eq!(request.pos.as_deref(), Some("1"));
+        assert!(request.room_subscriptions.is_empty());
+        assert_eq!(request.unsubscribe_rooms, [owned_room_id!("!a:example.org")]);
+        assert_eq!(request.extensions.to_device.since.as_deref(), Some("t1"));
+
+        state.apply_response(response("2", json!({})));
+        assert!(state.build_request().unsubscribe_rooms.is_empty());
+
+        state.reset();
+        assert_eq!(state.build_request().pos, None);
+    }
+
+    #[test]
+    fn merge_rooms_and_extensions() {
+        let mut state = SlidingSync::new();
+
+        state.apply_response(response(
+            "1",
+            json!({
+                "rooms": {
+                    "!a:example.org": {
+                        "name": "Room A",
+                        "initial": true,
+                        "timeline": [{ "type": "m.room.message" }],
+                        "prev_batch": "p1",
+                    },
+                },
+                "extensions": {
+                    "e2ee": {
+                        "device_lists": { "changed": ["@bob:example.org"] },
+                        "device_one_time_keys_count": { "signed_curve25519": 50 },
+                    },
+                    "account_data": {
+                        "global": [{ "type": "m.direct", "content": { "a": 1 } }],
+                    },
+                    "receipts": {
+                        "rooms": {
+                            "!a:example.org": {
+                                "type": "m.receipt",
+                                "content": {
+                                    "$1": { "m.read": { "@bob:example.org": { "ts": 1 } } },
+                                },
+                            },
+                        },
+                    },
+                },
+            }),
+        ));
+
+        let update = state.apply_response(response(
+            "2",
+            json!({
+                "rooms": {
+                    "!a:example.org": {
+                        "timeline": [{ "type": "m.room.message" }],
+                        "prev_batch": "p2",
+                    },
+                },
+                "extensions": {
+                    "e2ee": { "device_lists": { "left": ["@bob:example.org"] } },
+                    "account_data": {
+                        "global": [{ "type": "m.direct", "content": { "a": 2 } }],
+                    },
+                    "receipts": {
+                        "rooms": {
+                            "!a:example.org": {
+                                "type": "m.receipt",
+                                "content": {
+                                    "$2": { "m.read": { "@bob:example.org": { "ts": 2 } } },
+                                },
+                            },
+                        },
+                    },
+                },
+            }),
+        ));
+        assert_eq!(update.rooms.len(), 1);
+
+        let room = state.room(room_id!("!a:example.org")).unwrap();
+        assert_eq!(room.name.as_deref(), Some("Room A"));
+        assert_eq!(room.timeline.len(), 2);
+        assert_eq!(room.prev_batch.as_deref(), Some("p1"));
+
+        let device_lists = state.take_device_lists();
+        assert!(device_lists.changed.is_empty());
+        assert_eq!(device_lists.left, [owned_user_id!("@bob:example.org")]);
+        assert!(state.take_device_lists().is_empty());
+        assert_eq!(state.device_one_time_keys_count().len(), 1);
+
+        let direct = state.account_data()["m.direct"].get_field::<JsonValue>("content").unwrap();
+        assert_eq!(direct, Some(json!({ "a": 2 })));
+
+        let receipts = state.receipts()[room_id!("!a:example.org")]
+            .get_field::<JsonValue>("content")
+            .unwrap()
+            .unwrap();
+        assert_eq!(receipts, json!({ "$2": { "m.read": { "@bob:example.org": { "ts": 2 } } } }));
+    }
+
+    #[tokio::test]
+    async fn reset_unknown_pos() {
+        let http_client = MockHttpClient::new();
+        http_client
+            .on_json::<v4::Request>(StatusCode::OK, json!({ "pos": "1" }))
+            .on_json::<v4::Request>(
+                StatusCode::BAD_REQUEST,
+                json!({ "errcode": "M_UNKNOWN_POS", "error": "Unknown position" }),
+            )
+            .on_json::<v4::Request>(StatusCode::OK, json!({ "pos": "2" }));
+
+        let client = Client::builder()
+            .homeserver_url("https://example.org".to_owned())
+            .access_token(Some("access_token".to_owned()))
+            .supported_matrix_versions(vec![MatrixVersion::V1_1])
+            .http_client(http_client.clone())
+            .await
+            .unwrap();
+
+        let mut state = SlidingSync::new();
+        client.sliding_sync(&mut state, None).await.unwrap();
+        assert_eq!(state.pos(), Some("1"));
+        client.sliding_sync(&mut state, None).await.unwrap();
+        assert_eq!(state.pos(), Some("2"));
+
+        let requests = http_client.take_requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].uri().query(), Some("pos=1"));
+        assert!(!requests[2].uri().to_string().contains("pos="));
+    }
+    #[tokio::test]
+    async fn unstable_feature() {
+        async fn build_client(
+            unstable_features: JsonValue,
+        ) -> (Client<MockHttpClient>, MockHttpClient) {
+            let http_client = MockHttpClient::new();
+            http_client
+                .on_json::<get_supported_versions::Request>(
+                    StatusCode::OK,
+                    json!({ "versions": ["v1.1"], "unstable_features": unstable_features }),
+                )
+                .on_json::<v4::Request>(StatusCode::OK, json!({ "pos": "1" }));
+
+            let client = Client::builder()
+                .homeserver_url("https://example.org".to_owned())
+                .access_token(Some("access_token".to_owned()))
+                .http_client(http_client.clone())
+                .await
+                .unwrap();
+            (client, http_client)
+        }
+
+        let (client, http_client) = build_client(json!({ "org.matrix.msc3575": false })).await;
+        let result = client.sliding_sync(&mut SlidingSync::new(), None).await;
+        assert_matches!(result, Err(Error::UnsupportedUnstableFeature { feature }));
+        assert_eq!(feature, "org.matrix.msc3575");
+        // Only the versions were requested.
+        assert_eq!(http_client.take_requests().len(), 1);
+
+        let (client, http_client) = build_client(json!({ "org.matrix.msc3575": true })).await;
+        client.sliding_sync(&mut SlidingSync::new(), None).await.unwrap();
+        assert_eq!(http_client.take_requests().len(), 2);
+    }
+}
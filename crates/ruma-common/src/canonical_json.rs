@@ -8,7 +8,7 @@ use serde_json::Value as JsonValue;
 mod value;
 
 pub use self::value::{CanonicalJsonObject, CanonicalJsonValue};
-use crate::{serde::Raw, RoomVersionId};
+use crate::{room_version_rules::RedactionRules, serde::Raw, RoomVersionId};
 
 /// The set of possible errors when serializing to canonical JSON.
 #[cfg(feature = "canonical-json")]
@@ -148,7 +148,9 @@ pub trait RedactionEvent {}
 /// # Parameters
 ///
 /// * `object`: A JSON object to redact.
-/// * `version`: The room version, determines which keys to keep for a few event types.
+/// * `version`: The room version, determines which keys to keep for a few event types. Room
+///   versions that are not known to Ruma use the rules of room version 11, use
+///   [`redact_with_rules`] to redact events of custom room versions.
 /// * `redacted_because`: If this is set, an `unsigned` object with a `redacted_because` field set
 ///   to the given value is added to the event after redaction.
 ///
@@ -161,11 +163,23 @@ pub trait RedactionEvent {}
 /// * `object` contains a field called `signatures` that is not a JSON object.
 /// * `object` is missing the `type` field or the field is not a JSON string.
 pub fn redact(
-    mut object: CanonicalJsonObject,
+    object: CanonicalJsonObject,
     version: &RoomVersionId,
     redacted_because: Option<RedactedBecause>,
 ) -> Result<CanonicalJsonObject, RedactionError> {
-    redact_in_place(&mut object, version, redacted_because)?;
+    redact_with_rules(object, &redaction_rules(version), redacted_because)
+}
+
+/// Redacts an event using the given redaction rules.
+///
+/// Functionally equivalent to `redact`, with the redaction rules of the room version provided
+/// directly.
+pub fn redact_with_rules(
+    mut object: CanonicalJsonObject,
+    rules: &RedactionRules,
+    redacted_because: Option<RedactedBecause>,
+) -> Result<CanonicalJsonObject, RedactionError> {
+    redact_in_place_with_rules(&mut object, rules, redacted_because)?;
     Ok(object)
 }
 
@@ -176,13 +190,22 @@ pub fn redact_in_place(
     event: &mut CanonicalJsonObject,
     version: &RoomVersionId,
     redacted_because: Option<RedactedBecause>,
+) -> Result<(), RedactionError> {
+    redact_in_place_with_rules(event, &redaction_rules(version), redacted_because)
+}
+
+/// Redacts an event in-place using the given redaction rules.
+///
+/// Functionally equivalent to `redact_with_rules`, only this'll redact the event in-place.
+pub fn redact_in_place_with_rules(
+    event: &mut CanonicalJsonObject,
+    rules: &RedactionRules,
+    redacted_because: Option<RedactedBecause>,
 ) -> Result<(), RedactionError> {
     // Get the content keys here even if they're only needed inside the branch below, because we
     // can't teach rust that this is a disjoint borrow with `get_mut("content")`.
     let allowed_content_keys = match event.get("type") {
-        Some(CanonicalJsonValue::String(event_type)) => {
-            allowed_content_keys_for(event_type, version)
-        }
+        Some(CanonicalJsonValue::String(event_type)) => allowed_content_keys_for(event_type, rules),
         Some(_) => return Err(RedactionError::not_of_type("type", JsonType::String)),
         None => return Err(RedactionError::field_missing_from_object("type")),
     };
@@ -198,7 +221,7 @@ pub fn redact_in_place(
 
     let mut old_event = mem::take(event);
 
-    for &key in allowed_event_keys_for(version) {
+    for &key in allowed_event_keys_for(rules) {
         if let Some(value) = old_event.remove(key) {
             event.insert(key.to_owned(), value);
         }
@@ -223,7 +246,24 @@ pub fn redact_content_in_place(
     version: &RoomVersionId,
     event_type: impl AsRef<str>,
 ) -> Result<(), RedactionError> {
-    object_retain_keys(object, allowed_content_keys_for(event_type.as_ref(), version))
+    redact_content_in_place_with_rules(object, &redaction_rules(version), event_type)
+}
+
+/// Redacts event content using the given redaction rules.
+///
+/// Edits the `object` in-place.
+pub fn redact_content_in_place_with_rules(
+    object: &mut CanonicalJsonObject,
+    rules: &RedactionRules,
+    event_type: impl AsRef<str>,
+) -> Result<(), RedactionError> {
+    object_retain_keys(object, allowed_content_keys_for(event_type.as_ref(), rules))
+}
+
+/// The redaction rules of the given room version, or those of the latest room version if it is
+/// unknown.
+fn redaction_rules(version: &RoomVersionId) -> RedactionRules {
+    version.rules().map_or(RedactionRules::V11, |rules| rules.redaction)
 }
 
 fn object_retain_keys(
@@ -278,18 +318,9 @@ fn object_retain_some_keys(
 
 /// The fields that are allowed to remain in an event during redaction depending on the room
 /// version.
-fn allowed_event_keys_for(version: &RoomVersionId) -> &'static [&'static str] {
-    match version {
-        RoomVersionId::V1
-        | RoomVersionId::V2
-        | RoomVersionId::V3
-        | RoomVersionId::V4
-        | RoomVersionId::V5
-        | RoomVersionId::V6
-        | RoomVersionId::V7
-        | RoomVersionId::V8
-        | RoomVersionId::V9
-        | RoomVersionId::V10 => &[
+fn allowed_event_keys_for(rules: &RedactionRules) -> &'static [&'static str] {
+    if rules.keep_origin_membership_prev_state {
+        &[
             "event_id",
             "type",
             "room_id",
@@ -305,8 +336,9 @@ fn allowed_event_keys_for(version: &RoomVersionId) -> &'static [&'static str] {
             "origin",
             "origin_server_ts",
             "membership",
-        ],
-        _ => &[
+        ]
+    } else {
+        &[
             "event_id",
             "type",
             "room_id",
@@ -319,7 +351,7 @@ fn allowed_event_keys_for(version: &RoomVersionId) -> &'static [&'static str] {
             "prev_events",
             "auth_events",
             "origin_server_ts",
-        ],
+        ]
     }
 }
 
@@ -370,6 +402,12 @@ static ROOM_MEMBER_V11: AllowedKeys = AllowedKeys::some_nested(
 /// Allowed keys in the `third_party_invite` field of `m.room.member`'s content according to room
 /// version 11.
 static ROOM_MEMBER_THIRD_PARTY_INVITE_V11: AllowedKeys = AllowedKeys::some(&["signed"]);
+/// Allowed keys in `m.room.member`'s content with the `third_party_invite` rule of room version 11
+/// but not the `join_authorised_via_users_server` rule of room version 9.
+static ROOM_MEMBER_THIRD_PARTY_INVITE: AllowedKeys = AllowedKeys::some_nested(
+    &["membership"],
+    &[("third_party_invite", &ROOM_MEMBER_THIRD_PARTY_INVITE_V11)],
+);
 
 /// Allowed keys in `m.room.create`'s content according to room version 1.
 static ROOM_CREATE_V1: AllowedKeys = AllowedKeys::some(&["creator"]);
@@ -417,82 +455,30 @@ static ROOM_HISTORY_VISIBILITY_V1: AllowedKeys = AllowedKeys::some(&["history_vi
 /// Allowed keys in `m.room.redaction`'s content according to room version 11.
 static ROOM_REDACTION_V11: AllowedKeys = AllowedKeys::some(&["redacts"]);
 
-fn allowed_content_keys_for(event_type: &str, version: &RoomVersionId) -> &'static AllowedKeys {
+fn allowed_content_keys_for(event_type: &str, rules: &RedactionRules) -> &'static AllowedKeys {
     match event_type {
-        "m.room.member" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7
-            | RoomVersionId::V8 => &ROOM_MEMBER_V1,
-            RoomVersionId::V9 | RoomVersionId::V10 => &ROOM_MEMBER_V9,
-            _ => &ROOM_MEMBER_V11,
-        },
-        "m.room.create" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7
-            | RoomVersionId::V8
-            | RoomVersionId::V9
-            | RoomVersionId::V10 => &ROOM_CREATE_V1,
-            _ => &AllowedKeys::All,
-        },
-        "m.room.join_rules" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7 => &ROOM_JOIN_RULES_V1,
-            _ => &ROOM_JOIN_RULES_V8,
-        },
-        "m.room.power_levels" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7
-            | RoomVersionId::V8
-            | RoomVersionId::V9
-            | RoomVersionId::V10 => &ROOM_POWER_LEVELS_V1,
-            _ => &ROOM_POWER_LEVELS_V11,
-        },
-        "m.room.aliases" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5 => &ROOM_ALIASES_V1,
-            // All other room versions, including custom ones, are treated by version 6 rules.
-            // TODO: Should we return an error for unknown versions instead?
-            _ => &AllowedKeys::None,
+        "m.room.member" => match (
+            rules.keep_room_member_join_authorised_via_users_server,
+            rules.keep_room_member_third_party_invite_signed,
+        ) {
+            (false, false) => &ROOM_MEMBER_V1,
+            (true, false) => &ROOM_MEMBER_V9,
+            (false, true) => &ROOM_MEMBER_THIRD_PARTY_INVITE,
+            (true, true) => &ROOM_MEMBER_V11,
         },
+        "m.room.create" if rules.keep_create_content => &AllowedKeys::All,
+        "m.room.create" => &ROOM_CREATE_V1,
+        "m.room.join_rules" if rules.keep_join_rules_allow => &ROOM_JOIN_RULES_V8,
+        "m.room.join_rules" => &ROOM_JOIN_RULES_V1,
+        "m.room.power_levels" if rules.keep_room_power_levels_invite => &ROOM_POWER_LEVELS_V11,
+        "m.room.power_levels" => &ROOM_POWER_LEVELS_V1,
+        "m.room.aliases" if rules.keep_room_aliases_aliases => &ROOM_ALIASES_V1,
         #[cfg(feature = "unstable-msc2870")]
-        "m.room.server_acl" if version.as_str() == "org.matrix.msc2870" => &ROOM_SERVER_ACL_MSC2870,
+        "m.room.server_acl" if rules.keep_room_server_acl_allow_deny_allow_ip_literals => {
+            &ROOM_SERVER_ACL_MSC2870
+        }
         "m.room.history_visibility" => &ROOM_HISTORY_VISIBILITY_V1,
-        "m.room.redaction" => match version {
-            RoomVersionId::V1
-            | RoomVersionId::V2
-            | RoomVersionId::V3
-            | RoomVersionId::V4
-            | RoomVersionId::V5
-            | RoomVersionId::V6
-            | RoomVersionId::V7
-            | RoomVersionId::V8
-            | RoomVersionId::V9
-            | RoomVersionId::V10 => &AllowedKeys::None,
-            _ => &ROOM_REDACTION_V11,
-        },
+        "m.room.redaction" if rules.keep_room_redaction_redacts => &ROOM_REDACTION_V11,
         _ => &AllowedKeys::None,
     }
 }
@@ -508,9 +494,10 @@ mod tests {
     };
 
     use super::{
-        redact_in_place, to_canonical_value, try_from_json_map, value::CanonicalJsonValue,
+        redact_in_place, redact_with_rules, to_canonical_value, try_from_json_map,
+        value::CanonicalJsonValue,
     };
-    use crate::RoomVersionId;
+    use crate::{room_version_rules::RoomVersionRules, RoomVersionId};
 
     #[test]
     fn serialize_canon() {
@@ -765,4 +752,46 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn redact_with_custom_rules() {
+        let original_event = json!({
+            "content": {
+              "aliases": ["#somewhere:localhost"],
+            },
+            "event_id": "$152037280074GZeOm:localhost",
+            "origin": "localhost",
+            "origin_server_ts": 1,
+            "sender": "@example:localhost",
+            "state_key": "room.com",
+            "room_id": "!room:room.com",
+            "type": "m.room.aliases",
+        });
+
+        assert_matches!(
+            CanonicalJsonValue::try_from(original_event),
+            Ok(CanonicalJsonValue::Object(object))
+        );
+
+        let mut rules = RoomVersionRules::V11.redaction;
+        rules.keep_room_aliases_aliases = true;
+        let object = redact_with_rules(object, &rules, None).unwrap();
+
+        let redacted_event = to_json_value(&object).unwrap();
+
+        assert_eq!(
+            redacted_event,
+            json!({
+                "content": {
+                  "aliases": ["#somewhere:localhost"],
+                },
+                "event_id": "$152037280074GZeOm:localhost",
+                "origin_server_ts": 1,
+                "sender": "@example:localhost",
+                "state_key": "room.com",
+                "room_id": "!room:room.com",
+                "type": "m.room.aliases",
+            })
+        );
+    }
 }
@@ -0,0 +1,342 @@
+//! Types for the rules applied to the different room versions.
+//!
+//! Every [`RoomVersionId`] known to Ruma has a [`RoomVersionRules`], returned by
+//! [`RoomVersionId::rules()`]. Custom room versions can be described by copying the rules of the
+//! closest known version and changing the fields that differ:
+//!
+//! ```
+//! use ruma_common::room_version_rules::RoomVersionRules;
+//!
+//! let mut rules = RoomVersionRules::V11;
+//! rules.redaction.keep_room_aliases_aliases = true;
+//! ```
+//!
+//! [`RoomVersionId`]: crate::RoomVersionId
+//! [`RoomVersionId::rules()`]: crate::RoomVersionId::rules
+
+/// The stability of a room version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_enums)]
+pub enum RoomDisposition {
+    /// A room version that has a stable specification.
+    Stable,
+
+    /// A room version that is not yet fully specified.
+    Unstable,
+}
+
+/// The format of the events of a room version, and of their IDs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum EventFormatVersion {
+    /// `$id:server` event ID format, with the event ID included in the event.
+    V1,
+
+    /// MSC1659-style `$hash` event ID format: introduced for room version 3.
+    V2,
+
+    /// MSC1884-style `$hash` event ID format using URL-safe base64: introduced for room version 4.
+    V3,
+}
+
+/// The state resolution algorithm of a room version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum StateResolutionVersion {
+    /// State resolution for rooms at version 1.
+    V1,
+
+    /// State resolution for room at version 2 or later.
+    V2,
+}
+
+/// The rules of a room version.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct RoomVersionRules {
+    /// The stability of the room version.
+    pub disposition: RoomDisposition,
+
+    /// The format of the events and of their IDs.
+    pub event_format: EventFormatVersion,
+
+    /// The state resolution algorithm.
+    pub state_res: StateResolutionVersion,
+
+    /// Whether the `valid_until_ts` of signing keys must be enforced.
+    pub enforce_key_validity: bool,
+
+    /// Whether canonical JSON is strictly enforced, which doesn't allow:
+    ///
+    /// * Integers outside the range of [-2 ^ 53 + 1, 2 ^ 53 - 1]
+    /// * Floats
+    /// * NaN, Infinity, -Infinity
+    pub strict_canonical_json: bool,
+
+    /// The rules for the authorization of events.
+    pub authorization: AuthorizationRules,
+
+    /// The rules for the redaction of events.
+    pub redaction: RedactionRules,
+}
+
+impl RoomVersionRules {
+    /// Rules for [room version 1].
+    ///
+    /// [room version 1]: https://spec.matrix.org/latest/rooms/v1/
+    pub const V1: Self = Self {
+        disposition: RoomDisposition::Stable,
+        event_format: EventFormatVersion::V1,
+        state_res: StateResolutionVersion::V1,
+        enforce_key_validity: false,
+        strict_canonical_json: false,
+        authorization: AuthorizationRules::V1,
+        redaction: RedactionRules::V1,
+    };
+
+    /// Rules for [room version 2].
+    ///
+    /// [room version 2]: https://spec.matrix.org/latest/rooms/v2/
+    pub const V2: Self = Self { state_res: StateResolutionVersion::V2, ..Self::V1 };
+
+    /// Rules for [room version 3].
+    ///
+    /// [room version 3]: https://spec.matrix.org/latest/rooms/v3/
+    pub const V3: Self = Self {
+        event_format: EventFormatVersion::V2,
+        authorization: AuthorizationRules::V3,
+        ..Self::V2
+    };
+
+    /// Rules for [room version 4].
+    ///
+    /// [room version 4]: https://spec.matrix.org/latest/rooms/v4/
+    pub const V4: Self = Self { event_format: EventFormatVersion::V3, ..Self::V3 };
+
+    /// Rules for [room version 5].
+    ///
+    /// [room version 5]: https://spec.matrix.org/latest/rooms/v5/
+    pub const V5: Self = Self { enforce_key_validity: true, ..Self::V4 };
+
+    /// Rules for [room version 6].
+    ///
+    /// [room version 6]: https://spec.matrix.org/latest/rooms/v6/
+    pub const V6: Self = Self {
+        strict_canonical_json: true,
+        authorization: AuthorizationRules::V6,
+        redaction: RedactionRules::V6,
+        ..Self::V5
+    };
+
+    /// Rules for [room version 7].
+    ///
+    /// [room version 7]: https://spec.matrix.org/latest/rooms/v7/
+    pub const V7: Self = Self { authorization: AuthorizationRules::V7, ..Self::V6 };
+
+    /// Rules for [room version 8].
+    ///
+    /// [room version 8]: https://spec.matrix.org/latest/rooms/v8/
+    pub const V8: Self =
+        Self { authorization: AuthorizationRules::V8, redaction: RedactionRules::V8, ..Self::V7 };
+
+    /// Rules for [room version 9].
+    ///
+    /// [room version 9]: https://spec.matrix.org/latest/rooms/v9/
+    pub const V9: Self = Self { redaction: RedactionRules::V9, ..Self::V8 };
+
+    /// Rules for [room version 10].
+    ///
+    /// [room version 10]: https://spec.matrix.org/latest/rooms/v10/
+    pub const V10: Self = Self { authorization: AuthorizationRules::V10, ..Self::V9 };
+
+    /// Rules for [room version 11].
+    ///
+    /// [room version 11]: https://spec.matrix.org/latest/rooms/v11/
+    pub const V11: Self = Self {
+        authorization: AuthorizationRules::V11,
+        redaction: RedactionRules::V11,
+        ..Self::V10
+    };
+
+    /// Rules for the unstable room version of [MSC2870].
+    ///
+    /// [MSC2870]: https://github.com/matrix-org/matrix-spec-proposals/pull/2870
+    #[cfg(feature = "unstable-msc2870")]
+    pub const MSC2870: Self = Self {
+        disposition: RoomDisposition::Unstable,
+        redaction: RedactionRules::MSC2870,
+        ..Self::V11
+    };
+}
+
+/// The rules for the authorization of events in a room version.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct AuthorizationRules {
+    /// Whether `m.room.aliases` events have special auth rules, as before room version 6.
+    ///
+    /// See [MSC2432](https://github.com/matrix-org/matrix-spec-proposals/pull/2432).
+    pub special_case_aliases_auth: bool,
+
+    /// Whether the `notifications` key is checked in `m.room.power_levels` events.
+    ///
+    /// See [MSC2209](https://github.com/matrix-org/matrix-spec-proposals/pull/2209).
+    pub limit_notifications_power_levels: bool,
+
+    /// Whether redaction events need extra checks, as the redacted event cannot be checked by
+    /// its ID.
+    pub extra_redaction_checks: bool,
+
+    /// Whether knocking is allowed.
+    ///
+    /// See [room version 7](https://spec.matrix.org/latest/rooms/v7/).
+    pub allow_knocking: bool,
+
+    /// Whether the `restricted` join rule is supported.
+    ///
+    /// See [MSC3289](https://github.com/matrix-org/matrix-spec-proposals/pull/3289).
+    pub restricted_join_rules: bool,
+
+    /// Whether the `knock_restricted` join rule is supported.
+    ///
+    /// See [MSC3787](https://github.com/matrix-org/matrix-spec-proposals/pull/3787).
+    pub knock_restricted_join_rule: bool,
+
+    /// Whether power levels must be integers.
+    ///
+    /// See [MSC3667](https://github.com/matrix-org/matrix-spec-proposals/pull/3667).
+    pub integer_power_levels: bool,
+
+    /// Whether the room creator is the `sender` of the `m.room.create` event, instead of the
+    /// `creator` field of its content.
+    ///
+    /// See [MSC2175](https://github.com/matrix-org/matrix-spec-proposals/pull/2175).
+    pub use_room_create_sender: bool,
+}
+
+impl AuthorizationRules {
+    /// Authorization rules for room versions 1 and 2.
+    pub const V1: Self = Self {
+        special_case_aliases_auth: true,
+        limit_notifications_power_levels: false,
+        extra_redaction_checks: false,
+        allow_knocking: false,
+        restricted_join_rules: false,
+        knock_restricted_join_rule: false,
+        integer_power_levels: false,
+        use_room_create_sender: false,
+    };
+
+    /// Authorization rules for room versions 3 to 5.
+    pub const V3: Self = Self { extra_redaction_checks: true, ..Self::V1 };
+
+    /// Authorization rules for room version 6.
+    pub const V6: Self = Self {
+        special_case_aliases_auth: false,
+        limit_notifications_power_levels: true,
+        ..Self::V3
+    };
+
+    /// Authorization rules for room version 7.
+    pub const V7: Self = Self { allow_knocking: true, ..Self::V6 };
+
+    /// Authorization rules for room versions 8 and 9.
+    pub const V8: Self = Self { restricted_join_rules: true, ..Self::V7 };
+
+    /// Authorization rules for room version 10.
+    pub const V10: Self =
+        Self { knock_restricted_join_rule: true, integer_power_levels: true, ..Self::V8 };
+
+    /// Authorization rules for room version 11.
+    pub const V11: Self = Self { use_room_create_sender: true, ..Self::V10 };
+}
+
+/// The rules for the redaction of events in a room version.
+///
+/// They list the fields that are kept in addition to the ones that are kept in every room
+/// version.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct RedactionRules {
+    /// Whether the `origin`, `membership` and `prev_state` top-level fields are kept.
+    pub keep_origin_membership_prev_state: bool,
+
+    /// Whether the whole content of `m.room.create` events is kept, rather than only `creator`.
+    pub keep_create_content: bool,
+
+    /// Whether the `allow` field of `m.room.join_rules` events is kept.
+    pub keep_join_rules_allow: bool,
+
+    /// Whether the `join_authorised_via_users_server` field of `m.room.member` events is kept.
+    pub keep_room_member_join_authorised_via_users_server: bool,
+
+    /// Whether the `signed` field of the `third_party_invite` of `m.room.member` events is kept.
+    pub keep_room_member_third_party_invite_signed: bool,
+
+    /// Whether the `invite` field of `m.room.power_levels` events is kept.
+    pub keep_room_power_levels_invite: bool,
+
+    /// Whether the `aliases` field of `m.room.aliases` events is kept.
+    pub keep_room_aliases_aliases: bool,
+
+    /// Whether the `redacts` field of `m.room.redaction` events is kept.
+    pub keep_room_redaction_redacts: bool,
+
+    /// Whether the `redacts` field of `m.room.redaction` events is in their `content` rather than
+    /// at the top level of the event.
+    ///
+    /// See [MSC2174](https://github.com/matrix-org/matrix-spec-proposals/pull/2174).
+    pub content_field_redacts: bool,
+
+    /// Whether the `allow`, `deny` and `allow_ip_literals` fields of `m.room.server_acl` events
+    /// are kept.
+    ///
+    /// See [MSC2870](https://github.com/matrix-org/matrix-spec-proposals/pull/2870).
+    #[cfg(feature = "unstable-msc2870")]
+    pub keep_room_server_acl_allow_deny_allow_ip_literals: bool,
+}
+
+impl RedactionRules {
+    /// Redaction rules for room versions 1 to 5.
+    pub const V1: Self = Self {
+        keep_origin_membership_prev_state: true,
+        keep_create_content: false,
+        keep_join_rules_allow: false,
+        keep_room_member_join_authorised_via_users_server: false,
+        keep_room_member_third_party_invite_signed: false,
+        keep_room_power_levels_invite: false,
+        keep_room_aliases_aliases: true,
+        keep_room_redaction_redacts: false,
+        content_field_redacts: false,
+        #[cfg(feature = "unstable-msc2870")]
+        keep_room_server_acl_allow_deny_allow_ip_literals: false,
+    };
+
+    /// Redaction rules for room versions 6 and 7.
+    pub const V6: Self = Self { keep_room_aliases_aliases: false, ..Self::V1 };
+
+    /// Redaction rules for room version 8.
+    pub const V8: Self = Self { keep_join_rules_allow: true, ..Self::V6 };
+
+    /// Redaction rules for room versions 9 and 10.
+    pub const V9: Self =
+        Self { keep_room_member_join_authorised_via_users_server: true, ..Self::V8 };
+
+    /// Redaction rules for room version 11.
+    pub const V11: Self = Self {
+        keep_origin_membership_prev_state: false,
+        keep_create_content: true,
+        keep_room_member_third_party_invite_signed: true,
+        keep_room_power_levels_invite: true,
+        keep_room_redaction_redacts: true,
+        content_field_redacts: true,
+        ..Self::V9
+    };
+
+    /// Redaction rules for the unstable room version of [MSC2870].
+    ///
+    /// [MSC2870]: https://github.com/matrix-org/matrix-spec-proposals/pull/2870
+    #[cfg(feature = "unstable-msc2870")]
+    pub const MSC2870: Self =
+        Self { keep_room_server_acl_allow_deny_allow_ip_literals: true, ..Self::V11 };
+}
@@ -11,6 +11,7 @@ use yap::{IntoTokens, TokenLocation, Tokens};
 /// when using a web framework that supports typed headers.
 ///
 /// [spec]: https://spec.matrix.org/latest/server-server-api/#request-authentication
+#[derive(Clone, Debug)]
 #[non_exhaustive]
 pub struct XMatrix {
     /// The server name of the sending server.
@@ -0,0 +1,283 @@
+//! Extraction of the credentials of incoming requests.
+//!
+//! The credentials expected by an endpoint are declared by the [`AuthScheme`] of its
+//! [`Metadata`]. [`extract_credentials()`] reads them from the request, or returns the error that
+//! should be sent back to the client. The metadata of a request can be found with
+//! [`Router::metadata()`](crate::routing::Router::metadata).
+
+use headers::authorization::Credentials as _;
+use http::{header::AUTHORIZATION, StatusCode};
+use percent_encoding::percent_decode_str;
+use ruma_common::{
+    api::{AuthScheme, Metadata},
+    OwnedUserId, UserId,
+};
+use thiserror::Error;
+
+use crate::authorization::XMatrix;
+
+/// The credentials of an incoming request.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum Credentials {
+    /// The request has no credentials.
+    None,
+
+    /// The request was authenticated with an access token.
+    AccessToken(AccessToken),
+
+    /// The request was signed by a homeserver.
+    ///
+    /// The signature still needs to be verified, for example with
+    /// [`verify_request()`](crate::signing::verify_request).
+    ServerSignatures(XMatrix),
+}
+
+/// An access token and the user it is used for.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AccessToken {
+    /// The access token.
+    pub token: String,
+
+    /// The user the request is sent as, from the `user_id` query parameter, if any.
+    ///
+    /// This is only allowed for application services, so the server must check that the access
+    /// token belongs to an application service whose namespaces contain this user.
+    pub asserted_user_id: Option<OwnedUserId>,
+}
+
+/// An error when extracting the credentials of a request.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CredentialsError {
+    /// The endpoint requires an access token but the request has none.
+    #[error("missing access token")]
+    MissingToken,
+
+    /// The request has an access token in both the `Authorization` header and the query string.
+    #[error("the access token must not be sent in both the Authorization header and the query")]
+    AmbiguousToken,
+
+    /// The endpoint requires signed requests but the request has no valid `X-Matrix`
+    /// `Authorization` header.
+    #[error("missing or invalid X-Matrix Authorization header")]
+    MissingSignature,
+
+    /// The `user_id` query parameter is not a valid user ID.
+    #[error("invalid user_id query parameter: {0}")]
+    InvalidUserId(String),
+}
+
+impl CredentialsError {
+    /// The HTTP status code of the response for this error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::MissingToken | Self::MissingSignature => StatusCode::UNAUTHORIZED,
+            Self::AmbiguousToken | Self::InvalidUserId(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// The Matrix error code of the response for this error.
+    pub fn errcode(&self) -> &'static str {
+        match self {
+            Self::MissingToken | Self::AmbiguousToken => "M_MISSING_TOKEN",
+            Self::MissingSignature => "M_UNAUTHORIZED",
+            Self::InvalidUserId(_) => "M_INVALID_PARAM",
+        }
+    }
+
+    /// Convert this error to an HTTP response with a standard Matrix error body.
+    pub fn into_http_response(self) -> http::Response<Vec<u8>> {
+        crate::error_response(self.status_code(), self.errcode(), self.to_string())
+    }
+}
+
+/// Extract the credentials of the given request, according to the authentication scheme of the
+/// given endpoint metadata.
+///
+/// For [`AuthScheme::AccessToken`], the access token is read from the `Authorization: Bearer`
+/// header or the deprecated `access_token` query parameter, along with the `user_id` query
+/// parameter of application services. For [`AuthScheme::ServerSignatures`], the `X-Matrix`
+/// `Authorization` header is parsed.
+///
+/// For [`AuthScheme::None`], an access token is still extracted if the request has one, since
+/// some of these endpoints, like registration, accept one optionally.
+pub fn extract_credentials<B>(
+    request: &http::Request<B>,
+    metadata: &Metadata,
+) -> Result<Credentials, CredentialsError> {
+    match metadata.authentication {
+        AuthScheme::None => {
+            Ok(access_token(request)?.map_or(Credentials::None, Credentials::AccessToken))
+        }
+        AuthScheme::AccessToken => access_token(request)?
+            .map(Credentials::AccessToken)
+            .ok_or(CredentialsError::MissingToken),
+        AuthScheme::ServerSignatures => request
+            .headers()
+            .get_all(AUTHORIZATION)
+            .iter()
+            .find_map(XMatrix::decode)
+            .map(Credentials::ServerSignatures)
+            .ok_or(CredentialsError::MissingSignature),
+    }
+}
+
+/// Get the access token of the given request, if any.
+fn access_token<B>(request: &http::Request<B>) -> Result<Option<AccessToken>, CredentialsError> {
+    let header_token = request.headers().get(AUTHORIZATION).and_then(|value| {
+        let (scheme, token) = value.to_str().ok()?.split_once(' ')?;
+        scheme.eq_ignore_ascii_case("Bearer").then(|| token.trim().to_owned())
+    });
+    let query = request.uri().query().unwrap_or_default();
+    let query_token = query_param(query, "access_token");
+
+    let token = match (header_token, query_token) {
+        (Some(_), Some(_)) => return Err(CredentialsError::AmbiguousToken),
+        (Some(token), None) | (None, Some(token)) => token,
+        (None, None) => return Ok(None),
+    };
+
+    let asserted_user_id = query_param(query, "user_id")
+        .map(|user_id| {
+            UserId::parse(&user_id).map_err(|_| CredentialsError::InvalidUserId(user_id))
+        })
+        .transpose()?;
+
+    Ok(Some(AccessToken { token, asserted_user_id }))
+}
+
+/// Get the decoded value of the given parameter of a query string.
+fn query_param(query: &str, name: &str) -> Option<String> {
+    let decode =
+        |s: &str| percent_decode_str(&s.replace('+', " ")).decode_utf8_lossy().into_owned();
+
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| decode(key) == name)
+        .map(|(_, value)| decode(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches2::assert_matches;
+    use http::{header::AUTHORIZATION, StatusCode};
+    use ruma_common::{
+        api::{Metadata, OutgoingRequest},
+        metadata, user_id,
+    };
+    use ruma_federation_api::{discovery::get_server_version, membership::create_join_event};
+
+    use super::{extract_credentials, Credentials, CredentialsError};
+
+    const ACCESS_TOKEN_METADATA: Metadata = metadata! {
+        method: GET,
+        rate_limited: false,
+        authentication: AccessToken,
+        history: {
+            1.0 => "/_matrix/app/v1/test",
+        }
+    };
+
+    fn request(uri: &str, authorization: Option<&str>) -> http::Request<Vec<u8>> {
+        let mut builder = http::Request::builder().uri(uri);
+        if let Some(authorization) = authorization {
+            builder = builder.header(AUTHORIZATION, authorization);
+        }
+        builder.body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn access_token() {
+        let credentials = extract_credentials(
+            &request("/_matrix/app/v1/test", Some("Bearer secret")),
+            &ACCESS_TOKEN_METADATA,
+        )
+        .unwrap();
+        assert_matches!(credentials, Credentials::AccessToken(token));
+        assert_eq!(token.token, "secret");
+        assert_eq!(token.asserted_user_id, None);
+
+        let credentials = extract_credentials(
+            &request(
+                "/_matrix/app/v1/test?access_token=se%2Bcret&user_id=%40bot%3Aexample.org",
+                None,
+            ),
+            &ACCESS_TOKEN_METADATA,
+        )
+        .unwrap();
+        assert_matches!(credentials, Credentials::AccessToken(token));
+        assert_eq!(token.token, "se+cret");
+        assert_eq!(token.asserted_user_id.as_deref(), Some(user_id!("@bot:example.org")));
+    }
+
+    #[test]
+    fn access_token_errors() {
+        let error =
+            extract_credentials(&request("/_matrix/app/v1/test", None), &ACCESS_TOKEN_METADATA)
+                .unwrap_err();
+        assert_matches!(&error, CredentialsError::MissingToken);
+        let response = error.into_http_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(String::from_utf8(response.into_body()).unwrap().contains("M_MISSING_TOKEN"));
+
+        let error = extract_credentials(
+            &request("/_matrix/app/v1/test?access_token=secret", Some("Bearer secret")),
+            &ACCESS_TOKEN_METADATA,
+        )
+        .unwrap_err();
+        assert_matches!(&error, CredentialsError::AmbiguousToken);
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+
+        let error = extract_credentials(
+            &request("/_matrix/app/v1/test?user_id=bot", Some("Bearer secret")),
+            &ACCESS_TOKEN_METADATA,
+        )
+        .unwrap_err();
+        assert_matches!(error, CredentialsError::InvalidUserId(user_id));
+        assert_eq!(user_id, "bot");
+    }
+
+    #[test]
+    fn optional_access_token() {
+        let metadata = &get_server_version::v1::Request::METADATA;
+
+        let credentials =
+            extract_credentials(&request("/_matrix/federation/v1/version", None), metadata)
+                .unwrap();
+        assert_matches!(credentials, Credentials::None);
+
+        let credentials = extract_credentials(
+            &request("/_matrix/federation/v1/version", Some("bearer secret")),
+            metadata,
+        )
+        .unwrap();
+        assert_matches!(credentials, Credentials::AccessToken(token));
+        assert_eq!(token.token, "secret");
+    }
+
+    #[test]
+    fn server_signatures() {
+        let metadata = &create_join_event::v2::Request::METADATA;
+        let uri = "/_matrix/federation/v2/send_join/!room:example.org/$event";
+
+        let credentials = extract_credentials(
+            &request(
+                uri,
+                Some(r#"X-Matrix origin="origin.example.org",destination="example.org",key="ed25519:key1",sig="dGVzdA""#),
+            ),
+            metadata,
+        )
+        .unwrap();
+        assert_matches!(credentials, Credentials::ServerSignatures(x_matrix));
+        assert_eq!(x_matrix.origin, "origin.example.org");
+        assert_eq!(x_matrix.key, "ed25519:key1");
+
+        let error =
+            extract_credentials(&request(uri, Some("Bearer secret")), metadata).unwrap_err();
+        assert_matches!(&error, CredentialsError::MissingSignature);
+        assert_eq!(error.errcode(), "M_UNAUTHORIZED");
+    }
+}
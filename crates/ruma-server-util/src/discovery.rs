@@ -0,0 +1,473 @@
+//! Resolution of server names to the address of their homeserver.
+//!
+//! Implements the [server discovery algorithm][spec] of the Matrix Server-Server API: IP literals
+//! and explicit ports are used as-is, otherwise `/.well-known/matrix/server` delegation and SRV
+//! records are looked up, falling back to port 8448. The network lookups are performed by a
+//! [`ResolverBackend`].
+//!
+//! [spec]: https://spec.matrix.org/latest/server-server-api/#resolving-server-names
+
+use std::{
+    collections::BTreeMap,
+    error::Error as StdError,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use http::header::CACHE_CONTROL;
+use ruma_common::{api::IncomingResponse, OwnedServerName, ServerName};
+use ruma_federation_api::discovery::discover_homeserver;
+use tracing::debug;
+
+/// The default port of the federation API.
+const DEFAULT_PORT: u16 = 8448;
+
+/// How long to cache a well-known response that doesn't specify a caching duration.
+const DEFAULT_WELL_KNOWN_CACHE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The maximum duration to cache a well-known response.
+const MAX_WELL_KNOWN_CACHE_DURATION: Duration = Duration::from_secs(48 * 60 * 60);
+
+/// How long to cache the absence of a valid well-known response.
+const WELL_KNOWN_ERROR_CACHE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// The network lookups needed to resolve server names.
+#[async_trait]
+pub trait ResolverBackend: Sync {
+    /// The type of the body of well-known responses.
+    type ResponseBody: AsRef<[u8]> + Send;
+
+    /// The error type of the lookups.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Send a `GET` request to `https://{hostname}/.well-known/matrix/server`, following redirects.
+    async fn get_well_known(
+        &self,
+        hostname: &str,
+    ) -> Result<http::Response<Self::ResponseBody>, Self::Error>;
+
+    /// Look up the SRV records of the given name, e.g. `_matrix-fed._tcp.example.com`.
+    ///
+    /// Should return an empty list if the name has no SRV records.
+    async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>, Self::Error>;
+}
+
+/// A DNS SRV record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_structs)]
+pub struct SrvRecord {
+    /// The priority of the target, lower values are preferred.
+    pub priority: u16,
+
+    /// The relative weight of targets with the same priority, higher values are preferred.
+    pub weight: u16,
+
+    /// The port of the service on the target.
+    pub port: u16,
+
+    /// The hostname of the target.
+    pub target: String,
+}
+
+/// Where to send the federation requests for a server name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::exhaustive_structs)]
+pub struct Destination {
+    /// The host to connect to.
+    ///
+    /// This is either an IP literal, with brackets for IPv6 addresses, or a hostname to resolve
+    /// with AAAA or A records.
+    pub host: String,
+
+    /// The port to connect to.
+    pub port: u16,
+
+    /// The value to use for the `Host` header of requests.
+    ///
+    /// The TLS certificate presented by the destination must be valid for the host part of this
+    /// value.
+    pub host_header: String,
+}
+
+impl Destination {
+    fn new(host: impl Into<String>, port: u16, host_header: impl Into<String>) -> Self {
+        Self { host: host.into(), port, host_header: host_header.into() }
+    }
+
+    /// The `host:port` to connect to.
+    pub fn authority(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// Resolves server names to the [`Destination`] of their federation requests.
+///
+/// Well-known responses are cached according to their `Cache-Control` header, for 24 hours by
+/// default and 48 hours at most. The absence of a valid response is cached for an hour.
+#[derive(Debug)]
+pub struct ServerResolver<B> {
+    backend: B,
+    well_known_cache: Mutex<BTreeMap<String, CachedWellKnown>>,
+}
+
+#[derive(Debug)]
+struct CachedWellKnown {
+    server: Option<OwnedServerName>,
+    expires_at: Instant,
+}
+
+impl<B> ServerResolver<B>
+where
+    B: ResolverBackend,
+{
+    /// Creates a new `ServerResolver` with the given backend.
+    pub fn new(backend: B) -> Self {
+        Self { backend, well_known_cache: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// The backend of this resolver.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Resolves the given server name.
+    ///
+    /// Failed lookups are treated like missing records, so this always returns a destination.
+    pub async fn resolve(&self, server_name: &ServerName) -> Destination {
+        let hostname = server_name.host();
+
+        // Step 1: IP literal, and step 2: explicit port.
+        if server_name.is_ip_literal() || server_name.port().is_some() {
+            return Destination::new(
+                hostname,
+                server_name.port().unwrap_or(DEFAULT_PORT),
+                server_name.as_str(),
+            );
+        }
+
+        // Step 3: well-known delegation.
+        if let Some(delegated) = self.well_known(hostname).await {
+            let delegated_hostname = delegated.host();
+
+            // Steps 3.1 and 3.2: IP literal or explicit port.
+            if delegated.is_ip_literal() || delegated.port().is_some() {
+                return Destination::new(
+                    delegated_hostname,
+                    delegated.port().unwrap_or(DEFAULT_PORT),
+                    delegated.as_str(),
+                );
+            }
+
+            // Steps 3.3 and 3.4: SRV records.
+            if let Some(destination) = self.srv_destination(delegated_hostname).await {
+                return destination;
+            }
+
+            // Step 3.5: default port.
+            return Destination::new(delegated_hostname, DEFAULT_PORT, delegated_hostname);
+        }
+
+        // Steps 4 and 5: SRV records.
+        if let Some(destination) = self.srv_destination(hostname).await {
+            return destination;
+        }
+
+        // Step 6: default port.
+        Destination::new(hostname, DEFAULT_PORT, hostname)
+    }
+
+    /// Get the delegated server name of the given hostname, from the cache or the network.
+    async fn well_known(&self, hostname: &str) -> Option<OwnedServerName> {
+        if let Some(cached) = self.well_known_cache.lock().unwrap().get(hostname) {
+            if cached.expires_at > Instant::now() {
+                return cached.server.clone();
+            }
+        }
+
+        let (server, cache_duration) = match self.backend.get_well_known(hostname).await {
+            Ok(response) => {
+                let cache_duration = cache_duration(&response);
+                match discover_homeserver::Response::try_from_http_response(response) {
+                    Ok(response) => (Some(response.server), cache_duration),
+                    Err(error) => {
+                        debug!("Invalid well-known response from {hostname}: {error}");
+                        (None, WELL_KNOWN_ERROR_CACHE_DURATION)
+                    }
+                }
+            }
+            Err(error) => {
+                debug!("Failed to fetch well-known of {hostname}: {error}");
+                (None, WELL_KNOWN_ERROR_CACHE_DURATION)
+            }
+        };
+
+        self.well_known_cache.lock().unwrap().insert(
+            hostname.to_owned(),
+            CachedWellKnown { server: server.clone(), expires_at: Instant::now() + cache_duration },
+        );
+
+        server
+    }
+
+    /// Get the destination of the given hostname from its SRV records.
+    ///
+    /// The `_matrix-fed._tcp` records are preferred over the deprecated `_matrix._tcp` ones.
+    async fn srv_destination(&self, hostname: &str) -> Option<Destination> {
+        for service in ["_matrix-fed._tcp", "_matrix._tcp"] {
+            let name = format!("{service}.{hostname}");
+
+            let records = match self.backend.lookup_srv(&name).await {
+                Ok(records) => records,
+                Err(error) => {
+                    debug!("Failed to look up SRV records of {name}: {error}");
+                    continue;
+                }
+            };
+
+            if let Some(record) = select_srv_record(records) {
+                let target = record.target.trim_end_matches('.');
+                return Some(Destination::new(target, record.port, hostname));
+            }
+        }
+
+        None
+    }
+}
+
+/// Selects the SRV record to use: the one with the lowest priority, and then the highest weight.
+///
+/// Records with the target `.` mean that the service is not available and are ignored.
+fn select_srv_record(records: Vec<SrvRecord>) -> Option<SrvRecord> {
+    records
+        .into_iter()
+        .filter(|record| record.target != "." && !record.target.is_empty())
+        .min_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)))
+}
+
+/// Get the duration to cache a well-known response for from its `Cache-Control` header.
+fn cache_duration<T>(response: &http::Response<T>) -> Duration {
+    if !response.status().is_success() {
+        return WELL_KNOWN_ERROR_CACHE_DURATION;
+    }
+
+    response
+        .headers()
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|directive| directive.trim().strip_prefix("max-age=")?.parse().ok())
+        .map_or(DEFAULT_WELL_KNOWN_CACHE_DURATION, Duration::from_secs)
+        .min(MAX_WELL_KNOWN_CACHE_DURATION)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use http::{header::CACHE_CONTROL, Response, StatusCode};
+    use ruma_common::server_name;
+
+    use super::{cache_duration, Destination, ResolverBackend, ServerResolver, SrvRecord};
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("lookup failed")]
+    struct LookupError;
+
+    #[derive(Default)]
+    struct TestBackend {
+        well_known: BTreeMap<&'static str, &'static str>,
+        srv: BTreeMap<&'static str, Vec<SrvRecord>>,
+        well_known_requests: AtomicUsize,
+    }
+
+    impl TestBackend {
+        fn with_well_known(mut self, hostname: &'static str, body: &'static str) -> Self {
+            self.well_known.insert(hostname, body);
+            self
+        }
+
+        fn with_srv(mut self, name: &'static str, port: u16, target: &str) -> Self {
+            self.srv.entry(name).or_default().push(SrvRecord {
+                priority: 10,
+                weight: 0,
+                port,
+                target: target.to_owned(),
+            });
+            self
+        }
+    }
+
+    #[async_trait]
+    impl ResolverBackend for TestBackend {
+        type ResponseBody = Vec<u8>;
+        type Error = LookupError;
+
+        async fn get_well_known(&self, hostname: &str) -> Result<Response<Vec<u8>>, LookupError> {
+            self.well_known_requests.fetch_add(1, Ordering::SeqCst);
+            let body = self.well_known.get(hostname).ok_or(LookupError)?;
+            Ok(Response::new(body.as_bytes().to_vec()))
+        }
+
+        async fn lookup_srv(&self, name: &str) -> Result<Vec<SrvRecord>, LookupError> {
+            Ok(self.srv.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    #[tokio::test]
+    async fn ip_literal() {
+        let resolver = ServerResolver::new(TestBackend::default());
+
+        assert_eq!(
+            resolver.resolve(server_name!("1.2.3.4")).await,
+            Destination::new("1.2.3.4", 8448, "1.2.3.4")
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("[1234:5678::abcd]:5678")).await,
+            Destination::new("[1234:5678::abcd]", 5678, "[1234:5678::abcd]:5678")
+        );
+        assert_eq!(resolver.backend().well_known_requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn explicit_port() {
+        let resolver = ServerResolver::new(
+            TestBackend::default().with_well_known("example.com", r#"{"m.server":"other.com"}"#),
+        );
+
+        assert_eq!(
+            resolver.resolve(server_name!("example.com:1234")).await,
+            Destination::new("example.com", 1234, "example.com:1234")
+        );
+        assert_eq!(resolver.backend().well_known_requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn well_known_delegation() {
+        let resolver = ServerResolver::new(
+            TestBackend::default()
+                .with_well_known("example.com", r#"{"m.server":"matrix.example.com:443"}"#)
+                .with_well_known("ip.example.com", r#"{"m.server":"5.6.7.8"}"#)
+                .with_well_known("srv.example.com", r#"{"m.server":"matrix.srv.example.com"}"#)
+                .with_srv("_matrix-fed._tcp.matrix.srv.example.com", 8000, "backend.example.com.")
+                .with_well_known("plain.example.com", r#"{"m.server":"matrix.plain.example.com"}"#),
+        );
+
+        assert_eq!(
+            resolver.resolve(server_name!("example.com")).await,
+            Destination::new("matrix.example.com", 443, "matrix.example.com:443")
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("ip.example.com")).await,
+            Destination::new("5.6.7.8", 8448, "5.6.7.8")
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("srv.example.com")).await,
+            Destination::new("backend.example.com", 8000, "matrix.srv.example.com")
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("plain.example.com")).await,
+            Destination::new("matrix.plain.example.com", 8448, "matrix.plain.example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn srv_without_well_known() {
+        let resolver = ServerResolver::new(
+            TestBackend::default()
+                .with_srv("_matrix._tcp.example.com", 9000, "legacy.example.com")
+                .with_srv("_matrix-fed._tcp.example.com", 8000, "fed.example.com")
+                .with_srv("_matrix._tcp.legacy.com", 9000, "legacy.example.com")
+                .with_well_known("invalid.com", r#"{"m.server":"not a server name"}"#)
+                .with_srv("_matrix-fed._tcp.invalid.com", 8000, "fed.example.com"),
+        );
+
+        assert_eq!(
+            resolver.resolve(server_name!("example.com")).await,
+            Destination::new("fed.example.com", 8000, "example.com")
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("legacy.com")).await,
+            Destination::new("legacy.example.com", 9000, "legacy.com")
+        );
+        assert_eq!(
+            resolver.resolve(server_name!("invalid.com")).await,
+            Destination::new("fed.example.com", 8000, "invalid.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn default_port() {
+        let resolver = ServerResolver::new(TestBackend::default());
+
+        assert_eq!(
+            resolver.resolve(server_name!("example.com")).await,
+            Destination::new("example.com", 8448, "example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn well_known_is_cached() {
+        let resolver = ServerResolver::new(
+            TestBackend::default()
+                .with_well_known("example.com", r#"{"m.server":"matrix.example.com:443"}"#),
+        );
+
+        resolver.resolve(server_name!("example.com")).await;
+        resolver.resolve(server_name!("example.com")).await;
+        assert_eq!(resolver.backend().well_known_requests.load(Ordering::SeqCst), 1);
+
+        resolver.resolve(server_name!("other.com")).await;
+        resolver.resolve(server_name!("other.com")).await;
+        assert_eq!(resolver.backend().well_known_requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn srv_record_selection() {
+        let record = |priority, weight, target: &str| SrvRecord {
+            priority,
+            weight,
+            port: 8448,
+            target: target.to_owned(),
+        };
+
+        let selected = super::select_srv_record(vec![
+            record(20, 100, "low-priority.example.com"),
+            record(10, 1, "light.example.com"),
+            record(10, 5, "heavy.example.com"),
+            record(0, 0, "."),
+        ]);
+        assert_eq!(selected.unwrap().target, "heavy.example.com");
+    }
+
+    #[test]
+    fn well_known_cache_duration() {
+        let response = |cache_control: Option<&'static str>| {
+            let mut response = Response::new(());
+            if let Some(cache_control) = cache_control {
+                response.headers_mut().insert(CACHE_CONTROL, cache_control.parse().unwrap());
+            }
+            response
+        };
+
+        assert_eq!(cache_duration(&response(None)), Duration::from_secs(24 * 60 * 60));
+        assert_eq!(
+            cache_duration(&response(Some("public, max-age=600"))),
+            Duration::from_secs(600)
+        );
+        assert_eq!(
+            cache_duration(&response(Some("max-age=31536000"))),
+            Duration::from_secs(48 * 60 * 60)
+        );
+
+        let mut not_found = response(None);
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        assert_eq!(cache_duration(&not_found), Duration::from_secs(60 * 60));
+    }
+}
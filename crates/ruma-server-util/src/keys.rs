@@ -0,0 +1,865 @@
+//! Resolution of the signing keys of homeservers.
+//!
+//! Homeservers publish their signing keys at [`GET /_matrix/key/v2/server`][get_server_keys], and
+//! notary servers can be queried for the keys of other servers at
+//! [`POST /_matrix/key/v2/query`][get_remote_server_keys_batch]. [`KeyResolver`] uses these
+//! endpoints through a [`KeyFetcher`] to build [`PublicKeyMap`]s of verified keys, and keeps the
+//! keys in a [`KeyStorage`] to avoid fetching them again.
+//!
+//! [get_server_keys]: https://spec.matrix.org/latest/server-server-api/#get_matrixkeyv2server
+//! [get_remote_server_keys_batch]: https://spec.matrix.org/latest/server-server-api/#post_matrixkeyv2query
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    error::Error as StdError,
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use js_int::UInt;
+use ruma_common::{
+    room_version_rules::RoomVersionRules,
+    serde::{Base64, Raw},
+    CanonicalJsonObject, CanonicalJsonValue, MilliSecondsSinceUnixEpoch, OwnedServerName,
+    OwnedServerSigningKeyId, RoomVersionId, ServerName,
+};
+use ruma_federation_api::discovery::{
+    get_remote_server_keys_batch::{self, v2::QueryCriteria},
+    get_server_keys, OldVerifyKey, ServerSigningKeys,
+};
+use ruma_signatures::{required_keys_with_rules, verify_json, PublicKeyMap, PublicKeySet};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+/// The maximum time for which fetched keys are considered valid, regardless of their
+/// `valid_until_ts`.
+const MAX_KEY_VALIDITY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// An error encountered when resolving the signing keys of a homeserver.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The [`KeyStorage`] returned an error.
+    #[error("key storage error: {0}")]
+    Storage(#[source] Box<dyn StdError + Send + Sync>),
+
+    /// A key document could not be deserialized.
+    #[error("invalid key document: {0}")]
+    InvalidKeyDocument(#[source] serde_json::Error),
+
+    /// A key document is about another server than the one it was requested for.
+    #[error("expected keys of {expected}, found keys of {found}")]
+    ServerNameMismatch {
+        /// The server whose keys were requested.
+        expected: OwnedServerName,
+
+        /// The server named in the key document.
+        found: OwnedServerName,
+    },
+
+    /// A key document is not signed by a known key of the given server.
+    #[error("key document is not signed by {0}")]
+    MissingSignature(OwnedServerName),
+
+    /// Verifying the signatures of a key document, or reading the signatures of an event, failed.
+    #[error(transparent)]
+    Signatures(#[from] ruma_signatures::Error),
+
+    /// No valid keys could be found for the given server, neither in storage, nor from the server
+    /// itself, nor from any of the notary servers.
+    #[error("could not find valid signing keys for {0}")]
+    KeysNotFound(OwnedServerName),
+}
+
+/// Storage for the verified signing keys of homeservers.
+#[async_trait]
+pub trait KeyStorage: Sync {
+    /// The error type of the storage.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Get the stored keys of the given server, if any.
+    async fn server_keys(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<Option<ServerSigningKeys>, Self::Error>;
+
+    /// Store the keys of a server, replacing the previously stored ones.
+    async fn store_server_keys(&self, keys: ServerSigningKeys) -> Result<(), Self::Error>;
+}
+
+/// A way to query the federation key endpoints.
+#[async_trait]
+pub trait KeyFetcher: Sync {
+    /// The error type of the fetcher.
+    type Error: StdError + Send + Sync + 'static;
+
+    /// Send a [`get_server_keys`] request to the given server.
+    async fn fetch_server_keys(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<get_server_keys::v2::Response, Self::Error>;
+
+    /// Send a [`get_remote_server_keys_batch`] request to the given notary server.
+    async fn query_notary_server(
+        &self,
+        notary: &ServerName,
+        request: get_remote_server_keys_batch::v2::Request,
+    ) -> Result<get_remote_server_keys_batch::v2::Response, Self::Error>;
+}
+
+/// A [`KeyStorage`] that keeps keys in memory.
+#[derive(Debug, Default)]
+pub struct InMemoryKeyStorage {
+    keys: RwLock<BTreeMap<OwnedServerName, ServerSigningKeys>>,
+}
+
+impl InMemoryKeyStorage {
+    /// Creates an empty `InMemoryKeyStorage`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl KeyStorage for InMemoryKeyStorage {
+    type Error = Infallible;
+
+    async fn server_keys(
+        &self,
+        server_name: &ServerName,
+    ) -> Result<Option<ServerSigningKeys>, Self::Error> {
+        Ok(self.keys.read().unwrap().get(server_name).cloned())
+    }
+
+    async fn store_server_keys(&self, keys: ServerSigningKeys) -> Result<(), Self::Error> {
+        self.keys.write().unwrap().insert(keys.server_name.clone(), keys);
+        Ok(())
+    }
+}
+
+/// Resolves and verifies the signing keys of homeservers.
+///
+/// Keys are looked up in the storage first. If they are missing or expired, they are fetched from
+/// the server itself, then from each of the notary servers in turn. Fetched key documents are only
+/// accepted if they are signed by the server they belong to, and, when they come from a notary
+/// server, by the notary server too.
+#[derive(Debug)]
+pub struct KeyResolver<S, F> {
+    storage: S,
+    fetcher: F,
+    notary_servers: Vec<OwnedServerName>,
+}
+
+impl<S, F> KeyResolver<S, F>
+where
+    S: KeyStorage,
+    F: KeyFetcher,
+{
+    /// Creates a new `KeyResolver` with the given storage and fetcher, and no notary servers.
+    pub fn new(storage: S, fetcher: F) -> Self {
+        Self { storage, fetcher, notary_servers: Vec::new() }
+    }
+
+    /// Sets the notary servers to query, in order, when keys can't be fetched from a server
+    /// directly.
+    pub fn notary_servers(mut self, notary_servers: Vec<OwnedServerName>) -> Self {
+        self.notary_servers = notary_servers;
+        self
+    }
+
+    /// The storage of this resolver.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Get the public keys of the given server.
+    ///
+    /// # Parameters
+    ///
+    /// * server_name: The server to get the keys of.
+    /// * key_ids: The IDs of the keys to get. If this is empty, all the current keys of the server
+    ///   are returned.
+    /// * valid_at: The time at which the keys must be valid. If this is `None`, the validity period
+    ///   of the keys is ignored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage fails or if not all the requested keys could be found.
+    pub async fn server_keys(
+        &self,
+        server_name: &ServerName,
+        key_ids: &[OwnedServerSigningKeyId],
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) -> Result<PublicKeySet, Error> {
+        if let Some(keys) = self.server_keys_without_notary(server_name, key_ids, valid_at).await? {
+            return Ok(keys);
+        }
+
+        for notary in &self.notary_servers {
+            match self.fetch_from_notary(notary, server_name, key_ids, valid_at).await {
+                Ok(Some(keys)) => return Ok(keys),
+                Ok(None) => {}
+                Err(error @ Error::Storage(_)) => return Err(error),
+                Err(error) => {
+                    warn!("Failed to fetch keys of {server_name} from notary {notary}: {error}");
+                }
+            }
+        }
+
+        Err(Error::KeysNotFound(server_name.to_owned()))
+    }
+
+    /// Get the public keys of several servers.
+    ///
+    /// `servers` maps the name of each server to the IDs of the keys to get. See
+    /// [`KeyResolver::server_keys()`] for the meaning of the parameters.
+    pub async fn public_key_map(
+        &self,
+        servers: &BTreeMap<OwnedServerName, Vec<OwnedServerSigningKeyId>>,
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) -> Result<PublicKeyMap, Error> {
+        let mut public_key_map = PublicKeyMap::new();
+
+        for (server_name, key_ids) in servers {
+            let keys = self.server_keys(server_name, key_ids, valid_at).await?;
+            public_key_map.insert(server_name.as_str().to_owned(), keys);
+        }
+
+        Ok(public_key_map)
+    }
+
+    /// Get the public keys needed to verify the given event with
+    /// [`verify_event`](ruma_signatures::verify_event).
+    ///
+    /// The keys must be valid at the `origin_server_ts` of the event, except in room versions
+    /// where the validity period of keys is ignored. Like `verify_event`, the rules of the latest
+    /// room version are used if `room_version` is unknown.
+    pub async fn public_keys_for_event(
+        &self,
+        event: &CanonicalJsonObject,
+        room_version: &RoomVersionId,
+    ) -> Result<PublicKeyMap, Error> {
+        let rules = room_version.rules().unwrap_or(RoomVersionRules::V11);
+        self.public_keys_for_event_with_rules(event, &rules).await
+    }
+
+    /// Get the public keys needed to verify the given event with
+    /// [`verify_event_with_rules`](ruma_signatures::verify_event_with_rules).
+    ///
+    /// This is the same as [`KeyResolver::public_keys_for_event()`], with the rules of the room
+    /// version provided directly, to support custom room versions.
+    pub async fn public_keys_for_event_with_rules(
+        &self,
+        event: &CanonicalJsonObject,
+        rules: &RoomVersionRules,
+    ) -> Result<PublicKeyMap, Error> {
+        let servers = required_keys_with_rules(event, rules)?;
+
+        let valid_at = if rules.enforce_key_validity {
+            event.get("origin_server_ts").and_then(|ts| match ts {
+                CanonicalJsonValue::Integer(ts) => {
+                    Some(MilliSecondsSinceUnixEpoch(UInt::try_from(i64::from(*ts)).ok()?))
+                }
+                _ => None,
+            })
+        } else {
+            None
+        };
+
+        self.public_key_map(&servers, valid_at).await
+    }
+
+    /// Get the keys of a server from the storage or from the server itself.
+    async fn server_keys_without_notary(
+        &self,
+        server_name: &ServerName,
+        key_ids: &[OwnedServerSigningKeyId],
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) -> Result<Option<PublicKeySet>, Error> {
+        let stored = self.storage.server_keys(server_name).await.map_err(storage_error)?;
+        if let Some(keys) = stored.as_ref().and_then(|keys| usable_keys(keys, key_ids, valid_at)) {
+            return Ok(Some(keys));
+        }
+
+        let response = match self.fetcher.fetch_server_keys(server_name).await {
+            Ok(response) => response,
+            Err(error) => {
+                debug!("Failed to fetch keys of {server_name} from the server itself: {error}");
+                return Ok(None);
+            }
+        };
+
+        let keys = match verify_key_document(&response.server_key, server_name) {
+            Ok((keys, _)) => keys,
+            Err(error) => {
+                warn!("Invalid keys received from {server_name}: {error}");
+                return Ok(None);
+            }
+        };
+
+        let keys = merge_keys(keys, stored);
+        let usable = usable_keys(&keys, key_ids, valid_at);
+        self.storage.store_server_keys(keys).await.map_err(storage_error)?;
+
+        Ok(usable)
+    }
+
+    /// Get the keys of a server from a notary server.
+    async fn fetch_from_notary(
+        &self,
+        notary: &ServerName,
+        server_name: &ServerName,
+        key_ids: &[OwnedServerSigningKeyId],
+        valid_at: Option<MilliSecondsSinceUnixEpoch>,
+    ) -> Result<Option<PublicKeySet>, Error> {
+        let mut criteria = QueryCriteria::new();
+        criteria.minimum_valid_until_ts = valid_at;
+        let query = BTreeMap::from([(
+            server_name.to_owned(),
+            key_ids.iter().map(|key_id| (key_id.clone(), criteria.clone())).collect(),
+        )]);
+
+        let response = match self
+            .fetcher
+            .query_notary_server(notary, get_remote_server_keys_batch::v2::Request::new(query))
+            .await
+        {
+            Ok(response) => response,
+            Err(error) => {
+                debug!("Failed to query notary {notary}: {error}");
+                return Ok(None);
+            }
+        };
+
+        let notary_keys = match self
+            .server_keys_without_notary(notary, &[], Some(MilliSecondsSinceUnixEpoch::now()))
+            .await?
+        {
+            Some(keys) => keys,
+            None => return Err(Error::KeysNotFound(notary.to_owned())),
+        };
+
+        let mut stored = self.storage.server_keys(server_name).await.map_err(storage_error)?;
+        let mut found = false;
+
+        for raw_keys in &response.server_keys {
+            let (keys, object) = verify_key_document(raw_keys, server_name)?;
+            verify_signatures_by(&object, notary, &notary_keys)?;
+
+            stored = Some(merge_keys(keys, stored));
+            found = true;
+        }
+
+        let keys = match stored {
+            Some(keys) if found => keys,
+            _ => return Ok(None),
+        };
+        let usable = usable_keys(&keys, key_ids, valid_at);
+        self.storage.store_server_keys(keys).await.map_err(storage_error)?;
+
+        Ok(usable)
+    }
+}
+
+fn storage_error<E: StdError + Send + Sync + 'static>(error: E) -> Error {
+    Error::Storage(Box::new(error))
+}
+
+/// Deserializes a key document and checks that it belongs to, and is signed by, the given server.
+fn verify_key_document(
+    raw_keys: &Raw<ServerSigningKeys>,
+    server_name: &ServerName,
+) -> Result<(ServerSigningKeys, CanonicalJsonObject), Error> {
+    let keys = raw_keys.deserialize().map_err(Error::InvalidKeyDocument)?;
+    let object = raw_keys.deserialize_as().map_err(Error::InvalidKeyDocument)?;
+
+    if keys.server_name != server_name {
+        return Err(Error::ServerNameMismatch {
+            expected: server_name.to_owned(),
+            found: keys.server_name,
+        });
+    }
+
+    let public_keys = keys
+        .verify_keys
+        .iter()
+        .map(|(key_id, verify_key)| (key_id.to_string(), verify_key.key.clone()))
+        .collect();
+    verify_signatures_by(&object, server_name, &public_keys)?;
+
+    Ok((keys, object))
+}
+
+/// Verifies the signatures of the given server on a JSON object.
+///
+/// Only signatures made with one of the given public keys are checked, and there must be at least
+/// one of them.
+fn verify_signatures_by(
+    object: &CanonicalJsonObject,
+    server_name: &ServerName,
+    public_keys: &PublicKeySet,
+) -> Result<(), Error> {
+    let signature_set: CanonicalJsonObject = match object
+        .get("signatures")
+        .and_then(CanonicalJsonValue::as_object)
+        .and_then(|signatures| signatures.get(server_name.as_str()))
+        .and_then(CanonicalJsonValue::as_object)
+    {
+        Some(signature_set) => signature_set
+            .iter()
+            .filter(|(key_id, _)| public_keys.contains_key(*key_id))
+            .map(|(key_id, signature)| (key_id.clone(), signature.clone()))
+            .collect(),
+        None => CanonicalJsonObject::new(),
+    };
+
+    if signature_set.is_empty() {
+        return Err(Error::MissingSignature(server_name.to_owned()));
+    }
+
+    let mut object = object.clone();
+    object.insert(
+        "signatures".to_owned(),
+        CanonicalJsonValue::Object(BTreeMap::from([(
+            server_name.as_str().to_owned(),
+            CanonicalJsonValue::Object(signature_set),
+        )])),
+    );
+
+    let public_key_map = BTreeMap::from([(server_name.as_str().to_owned(), public_keys.clone())]);
+    verify_json(&public_key_map, &object)?;
+
+    Ok(())
+}
+
+/// Merges newly fetched keys with the previously known keys of the same server.
+///
+/// Keys that are not advertised anymore are kept as old keys, and the validity of the new keys is
+/// capped to [`MAX_KEY_VALIDITY`] from now.
+fn merge_keys(
+    mut keys: ServerSigningKeys,
+    previous: Option<ServerSigningKeys>,
+) -> ServerSigningKeys {
+    if let Some(max_valid_until_ts) = SystemTime::now()
+        .checked_add(MAX_KEY_VALIDITY)
+        .and_then(MilliSecondsSinceUnixEpoch::from_system_time)
+    {
+        keys.valid_until_ts = keys.valid_until_ts.min(max_valid_until_ts);
+    }
+
+    if let Some(previous) = previous {
+        for (key_id, verify_key) in previous.verify_keys {
+            if !keys.verify_keys.contains_key(&key_id) {
+                keys.old_verify_keys
+                    .entry(key_id)
+                    .or_insert_with(|| OldVerifyKey::new(previous.valid_until_ts, verify_key.key));
+            }
+        }
+
+        for (key_id, old_verify_key) in previous.old_verify_keys {
+            if !keys.verify_keys.contains_key(&key_id) {
+                keys.old_verify_keys.entry(key_id).or_insert(old_verify_key);
+            }
+        }
+    }
+
+    keys
+}
+
+/// Gets the requested keys from the given key document, if they are all valid at the given time.
+///
+/// If no key IDs are requested, returns all the current keys.
+fn usable_keys(
+    keys: &ServerSigningKeys,
+    key_ids: &[OwnedServerSigningKeyId],
+    valid_at: Option<MilliSecondsSinceUnixEpoch>,
+) -> Option<PublicKeySet> {
+    let current_keys_valid = valid_at.map_or(true, |ts| keys.valid_until_ts >= ts);
+
+    if key_ids.is_empty() {
+        let public_keys: PublicKeySet = keys
+            .verify_keys
+            .iter()
+            .filter(|_| current_keys_valid)
+            .map(|(key_id, verify_key)| (key_id.to_string(), verify_key.key.clone()))
+            .collect();
+
+        return (!public_keys.is_empty()).then_some(public_keys);
+    }
+
+    key_ids
+        .iter()
+        .map(|key_id| {
+            let key: Option<&Base64> = if let Some(verify_key) =
+                keys.verify_keys.get(key_id).filter(|_| current_keys_valid)
+            {
+                Some(&verify_key.key)
+            } else {
+                keys.old_verify_keys
+                    .get(key_id)
+                    .filter(|old_key| valid_at.map_or(true, |ts| old_key.expired_ts >= ts))
+                    .map(|old_key| &old_key.key)
+            };
+
+            key.map(|key| (key_id.to_string(), key.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, SystemTime},
+    };
+
+    use async_trait::async_trait;
+    use js_int::uint;
+    use ruma_common::{
+        canonical_json::to_canonical_value,
+        room_version_rules::RoomVersionRules,
+        serde::{Base64, Raw},
+        server_name, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedServerSigningKeyId,
+        RoomVersionId, ServerName,
+    };
+    use ruma_federation_api::discovery::{
+        get_remote_server_keys_batch, get_server_keys, OldVerifyKey, ServerSigningKeys, VerifyKey,
+    };
+    use ruma_signatures::{sign_json, Ed25519KeyPair};
+    use serde_json::{from_value as from_json_value, json};
+
+    use super::{Error, InMemoryKeyStorage, KeyFetcher, KeyResolver, KeyStorage};
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("not found")]
+    struct NotFound;
+
+    #[derive(Default)]
+    struct TestFetcher {
+        server_keys: BTreeMap<OwnedServerName, Raw<ServerSigningKeys>>,
+        notary_keys: BTreeMap<OwnedServerName, Vec<Raw<ServerSigningKeys>>>,
+        requests: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl KeyFetcher for TestFetcher {
+        type Error = NotFound;
+
+        async fn fetch_server_keys(
+            &self,
+            server_name: &ServerName,
+        ) -> Result<get_server_keys::v2::Response, Self::Error> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let server_key = self.server_keys.get(server_name).ok_or(NotFound)?.clone();
+            Ok(get_server_keys::v2::Response::new(server_key))
+        }
+
+        async fn query_notary_server(
+            &self,
+            notary: &ServerName,
+            _request: get_remote_server_keys_batch::v2::Request,
+        ) -> Result<get_remote_server_keys_batch::v2::Response, Self::Error> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            let server_keys = self.notary_keys.get(notary).ok_or(NotFound)?.clone();
+            Ok(get_remote_server_keys_batch::v2::Response::new(server_keys))
+        }
+    }
+
+    fn key_pair(version: &str) -> Ed25519KeyPair {
+        let document = Ed25519KeyPair::generate().unwrap();
+        Ed25519KeyPair::from_der(&document, version.to_owned()).unwrap()
+    }
+
+    fn key_id(version: &str) -> OwnedServerSigningKeyId {
+        format!("ed25519:{version}").try_into().unwrap()
+    }
+
+    fn in_a_day() -> MilliSecondsSinceUnixEpoch {
+        MilliSecondsSinceUnixEpoch::from_system_time(
+            SystemTime::now() + Duration::from_secs(24 * 60 * 60),
+        )
+        .unwrap()
+    }
+
+    fn key_document(
+        server_name: &ServerName,
+        key_pair: &Ed25519KeyPair,
+        valid_until_ts: MilliSecondsSinceUnixEpoch,
+        signers: &[(&ServerName, &Ed25519KeyPair)],
+    ) -> (ServerSigningKeys, Raw<ServerSigningKeys>) {
+        let mut keys = ServerSigningKeys::new(server_name.to_owned(), valid_until_ts);
+        keys.verify_keys.insert(
+            key_id(key_pair.version()),
+            VerifyKey::new(Base64::new(key_pair.public_key().to_vec())),
+        );
+
+        let mut object = match to_canonical_value(&keys).unwrap() {
+            ruma_common::CanonicalJsonValue::Object(object) => object,
+            _ => unreachable!(),
+        };
+        object.remove("signatures");
+        for (signer, key_pair) in signers {
+            sign_json(signer.as_str(), *key_pair, &mut object).unwrap();
+        }
+
+        let raw = Raw::new(&object).unwrap().cast();
+        (keys, raw)
+    }
+
+    #[tokio::test]
+    async fn fetch_from_server_then_storage() {
+        let origin = server_name!("origin.local");
+        let origin_key = key_pair("1");
+        let (_, raw_keys) = key_document(origin, &origin_key, in_a_day(), &[(origin, &origin_key)]);
+
+        let fetcher = TestFetcher {
+            server_keys: BTreeMap::from([(origin.to_owned(), raw_keys)]),
+            ..Default::default()
+        };
+        let resolver = KeyResolver::new(InMemoryKeyStorage::new(), fetcher);
+
+        let keys = resolver
+            .server_keys(origin, &[key_id("1")], Some(MilliSecondsSinceUnixEpoch::now()))
+            .await
+            .unwrap();
+        assert_eq!(keys["ed25519:1"].as_bytes(), origin_key.public_key());
+        assert_eq!(resolver.fetcher.requests.load(Ordering::SeqCst), 1);
+
+        let keys = resolver
+            .server_keys(origin, &[key_id("1")], Some(MilliSecondsSinceUnixEpoch::now()))
+            .await
+            .unwrap();
+        assert_eq!(keys["ed25519:1"].as_bytes(), origin_key.public_key());
+        assert_eq!(resolver.fetcher.requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reject_keys_without_valid_self_signature() {
+        let origin = server_name!("origin.local");
+        let origin_key = key_pair("1");
+        let (_, raw_keys) =
+            key_document(origin, &origin_key, in_a_day(), &[(origin, &key_pair("1"))]);
+
+        let fetcher = TestFetcher {
+            server_keys: BTreeMap::from([(origin.to_owned(), raw_keys)]),
+            ..Default::default()
+        };
+        let resolver = KeyResolver::new(InMemoryKeyStorage::new(), fetcher);
+
+        let err = resolver.server_keys(origin, &[key_id("1")], None).await.unwrap_err();
+        assert!(matches!(err, Error::KeysNotFound(name) if name == origin));
+        assert!(resolver.storage().server_keys(origin).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn fall_back_to_notary() {
+        let origin = server_name!("origin.local");
+        let origin_key = key_pair("1");
+        let notary = server_name!("notary.local");
+        let notary_key = key_pair("n");
+
+        let (_, notary_raw_keys) =
+            key_document(notary, &notary_key, in_a_day(), &[(notary, &notary_key)]);
+        let (_, origin_raw_keys) = key_document(
+            origin,
+            &origin_key,
+            in_a_day(),
+            &[(origin, &origin_key), (notary, &notary_key)],
+        );
+
+        let fetcher = TestFetcher {
+            server_keys: BTreeMap::from([(notary.to_owned(), notary_raw_keys)]),
+            notary_keys: BTreeMap::from([(notary.to_owned(), vec![origin_raw_keys])]),
+            ..Default::default()
+        };
+        let resolver = KeyResolver::new(InMemoryKeyStorage::new(), fetcher)
+            .notary_servers(vec![notary.to_owned()]);
+
+        let keys = resolver.server_keys(origin, &[key_id("1")], None).await.unwrap();
+        assert_eq!(keys["ed25519:1"].as_bytes(), origin_key.public_key());
+        assert!(resolver.storage().server_keys(origin).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn reject_notary_response_without_notary_signature() {
+        let origin = server_name!("origin.local");
+        let origin_key = key_pair("1");
+        let notary = server_name!("notary.local");
+        let notary_key = key_pair("n");
+
+        let (_, notary_raw_keys) =
+            key_document(notary, &notary_key, in_a_day(), &[(notary, &notary_key)]);
+        let (_, origin_raw_keys) =
+            key_document(origin, &origin_key, in_a_day(), &[(origin, &origin_key)]);
+
+        let fetcher = TestFetcher {
+            server_keys: BTreeMap::from([(notary.to_owned(), notary_raw_keys)]),
+            notary_keys: BTreeMap::from([(notary.to_owned(), vec![origin_raw_keys])]),
+            ..Default::default()
+        };
+        let resolver = KeyResolver::new(InMemoryKeyStorage::new(), fetcher)
+            .notary_servers(vec![notary.to_owned()]);
+
+        let err = resolver.server_keys(origin, &[key_id("1")], None).await.unwrap_err();
+        assert!(matches!(err, Error::KeysNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn refetch_expired_keys() {
+        let origin = server_name!("origin.local");
+        let origin_key = key_pair("1");
+        let (expired_keys, _) =
+            key_document(origin, &origin_key, MilliSecondsSinceUnixEpoch(uint!(1_000)), &[]);
+        let (_, raw_keys) = key_document(origin, &origin_key, in_a_day(), &[(origin, &origin_key)]);
+
+        let storage = InMemoryKeyStorage::new();
+        storage.store_server_keys(expired_keys).await.unwrap();
+        let fetcher = TestFetcher {
+            server_keys: BTreeMap::from([(origin.to_owned(), raw_keys)]),
+            ..Default::default()
+        };
+        let resolver = KeyResolver::new(storage, fetcher);
+
+        // The validity period is ignored.
+        resolver.server_keys(origin, &[key_id("1")], None).await.unwrap();
+        assert_eq!(resolver.fetcher.requests.load(Ordering::SeqCst), 0);
+
+        resolver
+            .server_keys(origin, &[key_id("1")], Some(MilliSecondsSinceUnixEpoch::now()))
+            .await
+            .unwrap();
+        assert_eq!(resolver.fetcher.requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn old_verify_keys() {
+        let origin = server_name!("origin.local");
+        let old_key = key_pair("old");
+        let new_key = key_pair("new");
+
+        let (mut keys, _) = key_document(origin, &new_key, in_a_day(), &[]);
+        keys.old_verify_keys.insert(
+            key_id("old"),
+            OldVerifyKey::new(
+                MilliSecondsSinceUnixEpoch(uint!(2_000)),
+                Base64::new(old_key.public_key().to_vec()),
+            ),
+        );
+
+        let storage = InMemoryKeyStorage::new();
+        storage.store_server_keys(keys).await.unwrap();
+        let resolver = KeyResolver::new(storage, TestFetcher::default());
+
+        let keys = resolver
+            .server_keys(origin, &[key_id("old")], Some(MilliSecondsSinceUnixEpoch(uint!(1_000))))
+            .await
+            .unwrap();
+        assert_eq!(keys["ed25519:old"].as_bytes(), old_key.public_key());
+
+        let err = resolver
+            .server_keys(origin, &[key_id("old")], Some(MilliSecondsSinceUnixEpoch(uint!(3_000))))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::KeysNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn replaced_keys_become_old_keys() {
+        let origin = server_name!("origin.local");
+        let old_key = key_pair("old");
+        let new_key = key_pair("new");
+
+        let (old_keys, _) =
+            key_document(origin, &old_key, MilliSecondsSinceUnixEpoch(uint!(2_000)), &[]);
+        let (_, raw_keys) = key_document(origin, &new_key, in_a_day(), &[(origin, &new_key)]);
+
+        let storage = InMemoryKeyStorage::new();
+        storage.store_server_keys(old_keys).await.unwrap();
+        let fetcher = TestFetcher {
+            server_keys: BTreeMap::from([(origin.to_owned(), raw_keys)]),
+            ..Default::default()
+        };
+        let resolver = KeyResolver::new(storage, fetcher);
+
+        resolver
+            .server_keys(origin, &[key_id("new")], Some(MilliSecondsSinceUnixEpoch::now()))
+            .await
+            .unwrap();
+
+        let keys = resolver.storage().server_keys(origin).await.unwrap().unwrap();
+        assert!(keys.verify_keys.contains_key(&key_id("new")));
+        let old_verify_key = &keys.old_verify_keys[&key_id("old")];
+        assert_eq!(old_verify_key.expired_ts, MilliSecondsSinceUnixEpoch(uint!(2_000)));
+    }
+
+    #[tokio::test]
+    async fn keys_for_event() {
+        let origin = server_name!("origin.local");
+        let origin_key = key_pair("1");
+        let (_, raw_keys) = key_document(origin, &origin_key, in_a_day(), &[(origin, &origin_key)]);
+
+        let mut event = from_json_value(json!({
+            "auth_events": [],
+            "content": {},
+            "depth": 3,
+            "origin": "origin.local",
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "prev_events": [],
+            "room_id": "!x:origin.local",
+            "sender": "@a:origin.local",
+            "type": "X",
+        }))
+        .unwrap();
+        ruma_signatures::hash_and_sign_event(
+            origin.as_str(),
+            &origin_key,
+            &mut event,
+            &RoomVersionId::V10,
+        )
+        .unwrap();
+
+        let fetcher = TestFetcher {
+            server_keys: BTreeMap::from([(origin.to_owned(), raw_keys)]),
+            ..Default::default()
+        };
+        let resolver = KeyResolver::new(InMemoryKeyStorage::new(), fetcher);
+
+        let public_key_map =
+            resolver.public_keys_for_event(&event, &RoomVersionId::V10).await.unwrap();
+        ruma_signatures::verify_event(&public_key_map, &event, &RoomVersionId::V10).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keys_for_event_with_custom_rules() {
+        let origin = server_name!("origin.local");
+        let origin_key = key_pair("1");
+        let (expired_keys, _) =
+            key_document(origin, &origin_key, MilliSecondsSinceUnixEpoch(uint!(1_000)), &[]);
+
+        let event = from_json_value(json!({
+            "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+            "sender": "@a:origin.local",
+            "signatures": { "origin.local": { "ed25519:1": "signature" } },
+            "type": "X",
+        }))
+        .unwrap();
+
+        let storage = InMemoryKeyStorage::new();
+        storage.store_server_keys(expired_keys).await.unwrap();
+        let resolver = KeyResolver::new(storage, TestFetcher::default());
+
+        // A custom room version that ignores the validity period of keys.
+        let mut rules = RoomVersionRules::V10;
+        rules.enforce_key_validity = false;
+
+        let public_key_map =
+            resolver.public_keys_for_event_with_rules(&event, &rules).await.unwrap();
+        assert_eq!(public_key_map["origin.local"]["ed25519:1"].as_bytes(), origin_key.public_key());
+
+        let err = resolver.public_keys_for_event(&event, &RoomVersionId::V10).await.unwrap_err();
+        assert!(matches!(err, Error::KeysNotFound(_)));
+    }
+}
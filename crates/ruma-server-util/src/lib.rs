@@ -4,3 +4,24 @@
 
 #![warn(missing_docs)]
 pub mod authorization;
+pub mod credentials;
+pub mod discovery;
+pub mod keys;
+pub mod pdu;
+pub mod routing;
+pub mod signing;
+
+/// Build an HTTP response with a standard Matrix error body.
+fn error_response(
+    status_code: http::StatusCode,
+    errcode: &str,
+    error: String,
+) -> http::Response<Vec<u8>> {
+    let body = serde_json::json!({ "errcode": errcode, "error": error });
+
+    http::Response::builder()
+        .status(status_code)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&body).expect("JSON values always serialize"))
+        .expect("the response is valid")
+}
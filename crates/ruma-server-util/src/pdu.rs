@@ -0,0 +1,545 @@
+//! Creation of hashed and signed PDUs.
+//!
+//! A persistent data unit (PDU) is an event as it is exchanged between homeservers. Creating one
+//! means selecting its auth events in the current state of the room, referencing the forward
+//! extremities of the room as its previous events, and finally hashing and signing it, as
+//! described in the [Matrix Server-Server API][spec].
+//!
+//! [spec]: https://spec.matrix.org/latest/server-server-api/#pdus
+
+use std::collections::BTreeMap;
+
+use js_int::{uint, UInt};
+use ruma_common::{
+    canonical_json::to_canonical_value,
+    room_version_rules::{EventFormatVersion, RoomVersionRules},
+    CanonicalJsonError, CanonicalJsonObject, CanonicalJsonValue, EventId, IdParseError,
+    MilliSecondsSinceUnixEpoch, OwnedEventId, RoomId, RoomVersionId, UserId,
+};
+use ruma_events::TimelineEventType;
+use ruma_signatures::{hash_and_sign_event_with_rules, reference_hash_with_rules, KeyPair};
+use ruma_state_res::{auth_types_for_event, StateMap};
+use serde_json::value::RawValue as RawJsonValue;
+use thiserror::Error;
+
+/// An error encountered when building a PDU.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The room version is not known to Ruma.
+    ///
+    /// [`PduBuilder::build_with_rules()`] can be used for custom room versions.
+    #[error("unsupported room version: {0}")]
+    UnsupportedRoomVersion(RoomVersionId),
+
+    /// The content of the event could not be deserialized to select its auth events.
+    #[error("invalid event content: {0}")]
+    InvalidContent(#[source] serde_json::Error),
+
+    /// The content of the event is not valid canonical JSON.
+    #[error("event content is not valid canonical JSON: {0}")]
+    CanonicalJson(#[source] CanonicalJsonError),
+
+    /// The event could not be hashed or signed.
+    #[error("failed to hash or sign the event: {0}")]
+    Signatures(#[source] ruma_signatures::Error),
+
+    /// The computed event ID is not valid.
+    #[error("invalid event ID: {0}")]
+    InvalidEventId(#[source] IdParseError),
+}
+
+/// An event of the room referenced by a new PDU, in its `auth_events` or `prev_events`.
+#[derive(Clone, Debug)]
+#[allow(clippy::exhaustive_structs)]
+pub struct EventReference {
+    /// The ID of the event.
+    pub event_id: OwnedEventId,
+
+    /// The depth of the event.
+    pub depth: UInt,
+
+    /// The SHA-256 content hash of the event, as found in its `hashes` field.
+    ///
+    /// It is only used for rooms whose events use the original format, as used by room versions 1
+    /// and 2.
+    pub sha256: String,
+}
+
+impl EventReference {
+    /// Creates a new `EventReference` with the given event ID, depth and content hash.
+    pub fn new(event_id: OwnedEventId, depth: UInt, sha256: String) -> Self {
+        Self { event_id, depth, sha256 }
+    }
+
+    /// Creates an `EventReference` from the ID and the JSON of a PDU.
+    ///
+    /// Returns `None` if the PDU doesn't have a valid `depth` or `hashes.sha256` field.
+    pub fn from_pdu(event_id: OwnedEventId, pdu: &CanonicalJsonObject) -> Option<Self> {
+        let depth = match pdu.get("depth") {
+            Some(CanonicalJsonValue::Integer(depth)) => UInt::try_from(i64::from(*depth)).ok()?,
+            _ => return None,
+        };
+        let sha256 = match pdu.get("hashes") {
+            Some(CanonicalJsonValue::Object(hashes)) => match hashes.get("sha256") {
+                Some(CanonicalJsonValue::String(sha256)) => sha256.clone(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some(Self { event_id, depth, sha256 })
+    }
+
+    fn to_canonical_json(&self, event_format: &EventFormatVersion) -> CanonicalJsonValue {
+        let event_id = CanonicalJsonValue::String(self.event_id.to_string());
+
+        match event_format {
+            EventFormatVersion::V1 => CanonicalJsonValue::Array(vec![
+                event_id,
+                CanonicalJsonValue::Object(BTreeMap::from([(
+                    "sha256".to_owned(),
+                    CanonicalJsonValue::String(self.sha256.clone()),
+                )])),
+            ]),
+            _ => event_id,
+        }
+    }
+}
+
+/// A builder for a new PDU.
+///
+/// The builder holds the parts of the event chosen by its sender. The parts that depend on the
+/// room are filled by [`PduBuilder::build`], which returns the hashed and signed PDU along with
+/// its event ID.
+///
+/// # Example
+///
+/// ```no_run
+/// # use ruma_common::{owned_event_id, room_id, user_id, RoomVersionId};
+/// # use ruma_events::TimelineEventType;
+/// # use ruma_server_util::pdu::{EventReference, PduBuilder};
+/// # use ruma_state_res::StateMap;
+/// # use serde_json::value::to_raw_value as to_raw_json_value;
+/// # let key_pair: ruma_signatures::Ed25519KeyPair = todo!();
+/// # let state: StateMap<EventReference> = todo!();
+/// # let forward_extremities: Vec<EventReference> = todo!();
+/// let content = to_raw_json_value(&serde_json::json!({ "msgtype": "m.text", "body": "Hi!" }))?;
+///
+/// let (event_id, pdu) = PduBuilder::new(TimelineEventType::RoomMessage, content).build(
+///     &RoomVersionId::V10,
+///     room_id!("!room:example.org"),
+///     user_id!("@alice:example.org"),
+///     &state,
+///     &forward_extremities,
+///     &key_pair,
+/// )?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct PduBuilder {
+    event_type: TimelineEventType,
+    content: Box<RawJsonValue>,
+    state_key: Option<String>,
+    redacts: Option<OwnedEventId>,
+    origin_server_ts: Option<MilliSecondsSinceUnixEpoch>,
+}
+
+impl PduBuilder {
+    /// Creates a new `PduBuilder` for an event with the given type and content.
+    pub fn new(event_type: TimelineEventType, content: Box<RawJsonValue>) -> Self {
+        Self { event_type, content, state_key: None, redacts: None, origin_server_ts: None }
+    }
+
+    /// Set the state key of the event, making it a state event.
+    pub fn state_key(self, state_key: impl Into<String>) -> Self {
+        Self { state_key: Some(state_key.into()), ..self }
+    }
+
+    /// Set the event redacted by this event.
+    pub fn redacts(self, event_id: OwnedEventId) -> Self {
+        Self { redacts: Some(event_id), ..self }
+    }
+
+    /// Set the timestamp of the event.
+    ///
+    /// Defaults to the current time.
+    pub fn origin_server_ts(self, origin_server_ts: MilliSecondsSinceUnixEpoch) -> Self {
+        Self { origin_server_ts: Some(origin_server_ts), ..self }
+    }
+
+    /// Build the PDU.
+    ///
+    /// The auth events of the PDU are selected in `state`, the state of the room before the
+    /// event, and its previous events are the `forward_extremities` of the room. The PDU is then
+    /// hashed and signed with `key_pair`, on behalf of the server of the `sender`.
+    ///
+    /// Returns the ID of the event and the PDU. For rooms whose events use the original format,
+    /// the event ID is random and included in the PDU, otherwise it is computed from the
+    /// reference hash of the PDU.
+    pub fn build<K: KeyPair>(
+        self,
+        room_version_id: &RoomVersionId,
+        room_id: &RoomId,
+        sender: &UserId,
+        state: &StateMap<EventReference>,
+        forward_extremities: &[EventReference],
+        key_pair: &K,
+    ) -> Result<(OwnedEventId, CanonicalJsonObject), Error> {
+        let rules = room_version_id
+            .rules()
+            .ok_or_else(|| Error::UnsupportedRoomVersion(room_version_id.clone()))?;
+
+        self.build_with_rules(&rules, room_id, sender, state, forward_extremities, key_pair)
+    }
+
+    /// Build the PDU with the given room version rules.
+    ///
+    /// This is the same as [`PduBuilder::build()`], with the rules of the room version provided
+    /// directly, to support custom room versions.
+    pub fn build_with_rules<K: KeyPair>(
+        self,
+        rules: &RoomVersionRules,
+        room_id: &RoomId,
+        sender: &UserId,
+        state: &StateMap<EventReference>,
+        forward_extremities: &[EventReference],
+        key_pair: &K,
+    ) -> Result<(OwnedEventId, CanonicalJsonObject), Error> {
+        let event_format = rules.event_format;
+
+        let auth_types = auth_types_for_event(
+            &self.event_type,
+            sender,
+            self.state_key.as_deref(),
+            &self.content,
+        )
+        .map_err(Error::InvalidContent)?;
+        let auth_events = auth_types
+            .iter()
+            .filter_map(|key| state.get(key))
+            .map(|event| event.to_canonical_json(&event_format))
+            .collect();
+        let prev_events = forward_extremities
+            .iter()
+            .map(|event| event.to_canonical_json(&event_format))
+            .collect();
+
+        let depth = forward_extremities
+            .iter()
+            .map(|event| event.depth)
+            .max()
+            .map_or(uint!(1), |depth| depth.checked_add(uint!(1)).unwrap_or(UInt::MAX));
+        let origin_server_ts =
+            self.origin_server_ts.unwrap_or_else(MilliSecondsSinceUnixEpoch::now);
+
+        let mut pdu = BTreeMap::from([
+            ("auth_events".to_owned(), CanonicalJsonValue::Array(auth_events)),
+            (
+                "content".to_owned(),
+                to_canonical_value(&self.content).map_err(Error::CanonicalJson)?,
+            ),
+            ("depth".to_owned(), CanonicalJsonValue::Integer(depth.into())),
+            ("origin_server_ts".to_owned(), CanonicalJsonValue::Integer(origin_server_ts.0.into())),
+            ("prev_events".to_owned(), CanonicalJsonValue::Array(prev_events)),
+            ("room_id".to_owned(), CanonicalJsonValue::String(room_id.to_string())),
+            ("sender".to_owned(), CanonicalJsonValue::String(sender.to_string())),
+            ("type".to_owned(), CanonicalJsonValue::String(self.event_type.to_string())),
+        ]);
+        if let Some(state_key) = self.state_key {
+            pdu.insert("state_key".to_owned(), CanonicalJsonValue::String(state_key));
+        }
+        if let Some(redacts) = self.redacts {
+            let redacts = CanonicalJsonValue::String(redacts.to_string());
+
+            // Since room version 11, `redacts` is a property of the content.
+            if rules.redaction.content_field_redacts {
+                match pdu.get_mut("content") {
+                    Some(CanonicalJsonValue::Object(content)) => {
+                        content.insert("redacts".to_owned(), redacts);
+                    }
+                    _ => {
+                        return Err(Error::InvalidContent(serde::de::Error::custom(
+                            "the content of the event is not an object",
+                        )))
+                    }
+                }
+            } else {
+                pdu.insert("redacts".to_owned(), redacts);
+            }
+        }
+
+        let event_id = match event_format {
+            EventFormatVersion::V1 => {
+                let event_id = EventId::new(sender.server_name());
+                pdu.insert("event_id".to_owned(), CanonicalJsonValue::String(event_id.to_string()));
+                Some(event_id)
+            }
+            _ => None,
+        };
+
+        hash_and_sign_event_with_rules(sender.server_name().as_str(), key_pair, &mut pdu, rules)
+            .map_err(Error::Signatures)?;
+
+        let event_id = match event_id {
+            Some(event_id) => event_id,
+            None => {
+                let hash = reference_hash_with_rules(&pdu, rules).map_err(Error::Signatures)?;
+                EventId::parse(format!("${hash}")).map_err(Error::InvalidEventId)?
+            }
+        };
+
+        Ok((event_id, pdu))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use assert_matches2::assert_matches;
+    use js_int::uint;
+    use ruma_common::{
+        event_id, room_id, room_version_rules::RoomVersionRules, serde::Base64, user_id,
+        CanonicalJsonValue, MilliSecondsSinceUnixEpoch, RoomVersionId,
+    };
+    use ruma_events::{StateEventType, TimelineEventType};
+    use ruma_signatures::{
+        reference_hash, reference_hash_with_rules, verify_event, verify_event_with_rules,
+        Ed25519KeyPair, PublicKeyMap,
+    };
+    use ruma_state_res::StateMap;
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
+
+    use super::{Error, EventReference, PduBuilder};
+
+    const PKCS8: &str = "\
+        MFECAQEwBQYDK2VwBCIEINjozvdfbsGEt6DD+7Uf4PiJ/YvTNXV2mIPc/\
+        tA0T+6tgSEA3TPraTczVkDPTRaX4K+AfUuyx7Mzq1UafTXypnl0t2k\
+    ";
+
+    fn key_pair() -> Ed25519KeyPair {
+        let document: Base64 = Base64::parse(PKCS8).unwrap();
+        Ed25519KeyPair::from_der(document.as_bytes(), "1".to_owned()).unwrap()
+    }
+
+    fn public_key_map() -> PublicKeyMap {
+        let key_set = BTreeMap::from([(
+            "ed25519:1".to_owned(),
+            Base64::new(key_pair().public_key().to_vec()),
+        )]);
+        BTreeMap::from([("example.org".to_owned(), key_set)])
+    }
+
+    fn reference(event_id: &str, depth: u32) -> EventReference {
+        EventReference::new(event_id.try_into().unwrap(), depth.into(), "hash".to_owned())
+    }
+
+    fn state() -> StateMap<EventReference> {
+        [
+            ((StateEventType::RoomCreate, String::new()), reference("$create:example.org", 1)),
+            (
+                (StateEventType::RoomMember, "@alice:example.org".to_owned()),
+                reference("$alice:example.org", 2),
+            ),
+            (
+                (StateEventType::RoomMember, "@bob:example.org".to_owned()),
+                reference("$bob:example.org", 4),
+            ),
+            ((StateEventType::RoomPowerLevels, String::new()), reference("$power:example.org", 3)),
+            ((StateEventType::RoomJoinRules, String::new()), reference("$rules:example.org", 3)),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn message() -> PduBuilder {
+        PduBuilder::new(
+            TimelineEventType::RoomMessage,
+            to_raw_json_value(&json!({ "msgtype": "m.text", "body": "Hi!" })).unwrap(),
+        )
+        .origin_server_ts(MilliSecondsSinceUnixEpoch(uint!(1_000)))
+    }
+
+    #[test]
+    fn build_message() {
+        let (event_id, pdu) = message()
+            .build(
+                &RoomVersionId::V10,
+                room_id!("!room:example.org"),
+                user_id!("@alice:example.org"),
+                &state(),
+                &[reference("$prev1:example.org", 4), reference("$prev2:example.org", 6)],
+                &key_pair(),
+            )
+            .unwrap();
+
+        let hash = reference_hash(&pdu, &RoomVersionId::V10).unwrap();
+        assert_eq!(event_id.as_str(), format!("${hash}"));
+        assert!(!pdu.contains_key("event_id"));
+        assert_eq!(pdu["depth"], CanonicalJsonValue::Integer(7.into()));
+        assert_eq!(
+            pdu["auth_events"],
+            CanonicalJsonValue::Array(vec![
+                CanonicalJsonValue::String("$power:example.org".to_owned()),
+                CanonicalJsonValue::String("$alice:example.org".to_owned()),
+                CanonicalJsonValue::String("$create:example.org".to_owned()),
+            ])
+        );
+        assert_eq!(
+            pdu["prev_events"],
+            CanonicalJsonValue::Array(vec![
+                CanonicalJsonValue::String("$prev1:example.org".to_owned()),
+                CanonicalJsonValue::String("$prev2:example.org".to_owned()),
+            ])
+        );
+        verify_event(&public_key_map(), &pdu, &RoomVersionId::V10).unwrap();
+    }
+
+    #[test]
+    fn build_original_format() {
+        let (event_id, pdu) = message()
+            .build(
+                &RoomVersionId::V1,
+                room_id!("!room:example.org"),
+                user_id!("@alice:example.org"),
+                &state(),
+                &[reference("$prev:example.org", 4)],
+                &key_pair(),
+            )
+            .unwrap();
+
+        assert_eq!(event_id.server_name().unwrap(), "example.org");
+        assert_eq!(pdu["event_id"], CanonicalJsonValue::String(event_id.to_string()));
+        assert_eq!(
+            pdu["prev_events"],
+            CanonicalJsonValue::Array(vec![CanonicalJsonValue::Array(vec![
+                CanonicalJsonValue::String("$prev:example.org".to_owned()),
+                CanonicalJsonValue::Object(BTreeMap::from([(
+                    "sha256".to_owned(),
+                    CanonicalJsonValue::String("hash".to_owned()),
+                )])),
+            ])])
+        );
+        verify_event(&public_key_map(), &pdu, &RoomVersionId::V1).unwrap();
+    }
+
+    #[test]
+    fn build_custom_room_version() {
+        let custom_version = RoomVersionId::try_from("org.example.custom").unwrap();
+        let mut rules = RoomVersionRules::V11;
+        rules.redaction.keep_room_aliases_aliases = true;
+
+        let result = message().build(
+            &custom_version,
+            room_id!("!room:example.org"),
+            user_id!("@alice:example.org"),
+            &state(),
+            &[reference("$prev:example.org", 4)],
+            &key_pair(),
+        );
+        assert_matches!(result, Err(Error::UnsupportedRoomVersion(version)));
+        assert_eq!(version, custom_version);
+
+        let (event_id, pdu) = PduBuilder::new(
+            TimelineEventType::RoomAliases,
+            to_raw_json_value(&json!({ "aliases": ["#room:example.org"] })).unwrap(),
+        )
+        .state_key("example.org")
+        .build_with_rules(
+            &rules,
+            room_id!("!room:example.org"),
+            user_id!("@alice:example.org"),
+            &state(),
+            &[reference("$prev:example.org", 4)],
+            &key_pair(),
+        )
+        .unwrap();
+
+        let hash = reference_hash_with_rules(&pdu, &rules).unwrap();
+        assert_eq!(event_id.as_str(), format!("${hash}"));
+        assert_ne!(hash, reference_hash(&pdu, &RoomVersionId::V11).unwrap());
+        verify_event_with_rules(&public_key_map(), &pdu, &rules).unwrap();
+        verify_event(&public_key_map(), &pdu, &RoomVersionId::V11).unwrap_err();
+    }
+
+    #[test]
+    fn build_redaction() {
+        let redaction = || {
+            PduBuilder::new(
+                TimelineEventType::RoomRedaction,
+                to_raw_json_value(&json!({ "reason": "Spam" })).unwrap(),
+            )
+            .redacts(event_id!("$spam:example.org").to_owned())
+        };
+        let build = |builder: PduBuilder, room_version_id: &RoomVersionId| {
+            builder
+                .build(
+                    room_version_id,
+                    room_id!("!room:example.org"),
+                    user_id!("@alice:example.org"),
+                    &state(),
+                    &[reference("$prev:example.org", 4)],
+                    &key_pair(),
+                )
+                .unwrap()
+                .1
+        };
+
+        let pdu = build(redaction(), &RoomVersionId::V10);
+        assert_eq!(pdu["redacts"], CanonicalJsonValue::String("$spam:example.org".to_owned()));
+        assert_matches!(&pdu["content"], CanonicalJsonValue::Object(content));
+        assert!(!content.contains_key("redacts"));
+        verify_event(&public_key_map(), &pdu, &RoomVersionId::V10).unwrap();
+
+        let pdu = build(redaction(), &RoomVersionId::V11);
+        assert!(!pdu.contains_key("redacts"));
+        assert_matches!(&pdu["content"], CanonicalJsonValue::Object(content));
+        assert_eq!(content["redacts"], CanonicalJsonValue::String("$spam:example.org".to_owned()));
+        assert_eq!(content["reason"], CanonicalJsonValue::String("Spam".to_owned()));
+        verify_event(&public_key_map(), &pdu, &RoomVersionId::V11).unwrap();
+    }
+
+    #[test]
+    fn build_create_event() {
+        let (_, pdu) = PduBuilder::new(
+            TimelineEventType::RoomCreate,
+            to_raw_json_value(&json!({ "creator": "@alice:example.org" })).unwrap(),
+        )
+        .state_key("")
+        .build(
+            &RoomVersionId::V6,
+            room_id!("!room:example.org"),
+            user_id!("@alice:example.org"),
+            &StateMap::new(),
+            &[],
+            &key_pair(),
+        )
+        .unwrap();
+
+        assert_eq!(pdu["auth_events"], CanonicalJsonValue::Array(vec![]));
+        assert_eq!(pdu["prev_events"], CanonicalJsonValue::Array(vec![]));
+        assert_eq!(pdu["depth"], CanonicalJsonValue::Integer(1.into()));
+        assert_eq!(pdu["state_key"], CanonicalJsonValue::String(String::new()));
+    }
+
+    #[test]
+    fn reference_from_pdu() {
+        let (event_id, pdu) = message()
+            .build(
+                &RoomVersionId::V10,
+                room_id!("!room:example.org"),
+                user_id!("@alice:example.org"),
+                &state(),
+                &[reference("$prev:example.org", 4)],
+                &key_pair(),
+            )
+            .unwrap();
+
+        let reference = EventReference::from_pdu(event_id.clone(), &pdu).unwrap();
+        assert_eq!(reference.event_id, event_id);
+        assert_eq!(reference.depth, uint!(5));
+        assert!(EventReference::from_pdu(event_id!("$a:example.org").to_owned(), &BTreeMap::new())
+            .is_none());
+    }
+}
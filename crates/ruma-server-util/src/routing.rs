@@ -0,0 +1,410 @@
+//! Routing of HTTP requests to handlers of [`IncomingRequest`] types.
+//!
+//! A [`Router`] matches the method and path of requests against the [`Metadata`] of the endpoints
+//! it knows, parses them into the request type of the matching endpoint and converts the result
+//! of its handler back into an HTTP response. It doesn't depend on any HTTP server framework;
+//! with the `tower` feature, it can be used as a `tower::Service` with [`Router::into_service()`].
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use http::{Method, StatusCode};
+use percent_encoding::percent_decode_str;
+use ruma_common::api::{
+    error::{FromHttpRequestError, IntoHttpError},
+    IncomingRequest, Metadata, OutgoingResponse,
+};
+use thiserror::Error;
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+type BoxHandler<T> = Box<
+    dyn Fn(
+            http::Request<Vec<u8>>,
+            Vec<String>,
+            T,
+        ) -> BoxFuture<Result<http::Response<Vec<u8>>, RouteError>>
+        + Send
+        + Sync,
+>;
+
+/// An error when routing a request.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum RouteError {
+    /// No endpoint matches the path of the request.
+    #[error("no endpoint matches the path of the request")]
+    NotFound,
+
+    /// Endpoints match the path of the request, but not its method.
+    #[error("the endpoint doesn't support the {0} method")]
+    MethodNotAllowed(Method),
+
+    /// The request could not be parsed into the request type of the endpoint.
+    #[error("invalid request: {0}")]
+    InvalidRequest(#[from] FromHttpRequestError),
+
+    /// The response of the handler could not be converted to an HTTP response.
+    #[error("failed to convert the response: {0}")]
+    IntoHttp(#[from] IntoHttpError),
+}
+
+impl RouteError {
+    /// The HTTP status code of the response for this error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Self::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Self::IntoHttp(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The Matrix error code of the response for this error.
+    pub fn errcode(&self) -> &'static str {
+        match self {
+            Self::NotFound | Self::MethodNotAllowed(_) => "M_UNRECOGNIZED",
+            Self::InvalidRequest(_) => "M_BAD_JSON",
+            Self::IntoHttp(_) => "M_UNKNOWN",
+        }
+    }
+
+    /// Convert this error to an HTTP response with a standard Matrix error body.
+    pub fn into_http_response(self) -> http::Response<Vec<u8>> {
+        crate::error_response(self.status_code(), self.errcode(), self.to_string())
+    }
+}
+
+/// Routes HTTP requests to the handlers of [`IncomingRequest`] types.
+///
+/// Requests are matched against the method and all the paths of the [`Metadata`] of the
+/// endpoints, including the unstable and deprecated ones. When several paths match, the one with
+/// the most literal segments wins.
+///
+/// Handlers receive the parsed request along with a context of type `T`, given to
+/// [`Router::handle()`] for every request, for example the state of the server or the result of
+/// the authentication of the request.
+///
+/// # Example
+///
+/// ```
+/// use ruma_common::api::error::MatrixError;
+/// use ruma_federation_api::discovery::get_server_version;
+/// use ruma_server_util::routing::Router;
+///
+/// let router = Router::<()>::new().route(|_request: get_server_version::v1::Request, ()| async {
+///     Ok::<_, MatrixError>(get_server_version::v1::Response::new())
+/// });
+/// ```
+pub struct Router<T = ()> {
+    routes: Vec<Route<T>>,
+}
+
+struct Route<T> {
+    metadata: Metadata,
+    handler: BoxHandler<T>,
+}
+
+impl<T> Router<T>
+where
+    T: Send + 'static,
+{
+    /// Creates a new `Router` without routes.
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Add a handler for the request type `R`.
+    ///
+    /// The error type of the handler is converted to an HTTP response like the response type, so
+    /// it should usually be the error type of the endpoint.
+    pub fn route<R, H, Fut, E>(mut self, handler: H) -> Self
+    where
+        R: IncomingRequest + Send + 'static,
+        H: Fn(R, T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R::OutgoingResponse, E>> + Send + 'static,
+        E: OutgoingResponse,
+    {
+        let handler = Arc::new(handler);
+        let handler: BoxHandler<T> = Box::new(move |request, path_args, context| {
+            let handler = handler.clone();
+            let request = R::try_from_http_request(request, &path_args);
+
+            Box::pin(async move {
+                let response = match handler(request?, context).await {
+                    Ok(response) => response.try_into_http_response()?,
+                    Err(error) => error.try_into_http_response()?,
+                };
+                Ok(response)
+            })
+        });
+
+        self.routes.push(Route { metadata: R::METADATA, handler });
+        self
+    }
+
+    /// Get the metadata of the endpoint matching the given request, if any.
+    pub fn metadata<B>(&self, request: &http::Request<B>) -> Option<&Metadata> {
+        self.find(request).ok().map(|(route, _)| &route.metadata)
+    }
+
+    /// Route the given request to the handler of the matching endpoint, with the given context.
+    ///
+    /// Errors from the handler are returned as responses, only errors before or after calling the
+    /// handler are returned as [`RouteError`]s. They can be converted to a response with
+    /// [`RouteError::into_http_response()`].
+    pub async fn handle<B>(
+        &self,
+        request: http::Request<B>,
+        context: T,
+    ) -> Result<http::Response<Vec<u8>>, RouteError>
+    where
+        B: AsRef<[u8]>,
+    {
+        let (route, path_args) = self.find(&request)?;
+        let request = request.map(|body| body.as_ref().to_owned());
+        (route.handler)(request, path_args, context).await
+    }
+
+    /// Find the route matching the given request and extract the arguments of its path.
+    fn find<B>(&self, request: &http::Request<B>) -> Result<(&Route<T>, Vec<String>), RouteError> {
+        let path = request.uri().path();
+        let mut best_match: Option<(&Route<T>, &'static str, usize)> = None;
+        let mut path_matched = false;
+
+        for route in &self.routes {
+            for template in route.metadata.history.all_paths() {
+                let Some(literal_segments) = match_path(template, path) else {
+                    continue;
+                };
+                path_matched = true;
+
+                if route.metadata.method != request.method() {
+                    continue;
+                }
+
+                if best_match.map_or(true, |(_, _, best)| literal_segments > best) {
+                    best_match = Some((route, template, literal_segments));
+                }
+            }
+        }
+
+        match best_match {
+            Some((route, template, _)) => Ok((route, path_args(template, path))),
+            None if path_matched => Err(RouteError::MethodNotAllowed(request.method().clone())),
+            None => Err(RouteError::NotFound),
+        }
+    }
+}
+
+impl<T> Default for Router<T>
+where
+    T: Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> std::fmt::Debug for Router<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Router")
+            .field("routes", &self.routes.iter().map(|route| &route.metadata).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Match the given path against the path template.
+///
+/// Returns the number of literal segments of the template if it matches.
+fn match_path(template: &str, path: &str) -> Option<usize> {
+    let mut template_segments = template.split('/');
+    let mut path_segments = path.split('/');
+    let mut literal_segments = 0;
+
+    loop {
+        match (template_segments.next(), path_segments.next()) {
+            (None, None) => return Some(literal_segments),
+            (Some(template), Some(_)) if template.starts_with(':') => {}
+            (Some(template), Some(segment)) if template == segment => literal_segments += 1,
+            _ => return None,
+        }
+    }
+}
+
+/// Extract the percent-decoded arguments of the given path, that matches the path template.
+fn path_args(template: &str, path: &str) -> Vec<String> {
+    template
+        .split('/')
+        .zip(path.split('/'))
+        .filter(|(template, _)| template.starts_with(':'))
+        .map(|(_, segment)| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .collect()
+}
+
+#[cfg(feature = "tower")]
+mod service {
+    use std::{
+        convert::Infallible,
+        sync::Arc,
+        task::{Context, Poll},
+    };
+
+    use super::{BoxFuture, RouteError, Router};
+
+    /// A `tower::Service` routing requests with a [`Router`].
+    ///
+    /// This is created with [`Router::into_service()`].
+    pub struct RouterService<T> {
+        router: Arc<Router<T>>,
+        context: T,
+    }
+
+    impl<T> Router<T>
+    where
+        T: Send + 'static,
+    {
+        /// Convert this router into a `tower::Service`, calling the handlers with a clone of the
+        /// given context.
+        ///
+        /// Routing errors are converted to responses with [`RouteError::into_http_response()`].
+        pub fn into_service(self, context: T) -> RouterService<T> {
+            RouterService { router: Arc::new(self), context }
+        }
+    }
+
+    impl<T: Clone> Clone for RouterService<T> {
+        fn clone(&self) -> Self {
+            Self { router: self.router.clone(), context: self.context.clone() }
+        }
+    }
+
+    impl<T> std::fmt::Debug for RouterService<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RouterService").field("router", &self.router).finish_non_exhaustive()
+        }
+    }
+
+    impl<T, B> tower_service::Service<http::Request<B>> for RouterService<T>
+    where
+        T: Clone + Send + Sync + 'static,
+        B: AsRef<[u8]>,
+    {
+        type Response = http::Response<Vec<u8>>;
+        type Error = Infallible;
+        type Future = BoxFuture<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<B>) -> Self::Future {
+            let router = self.router.clone();
+            let context = self.context.clone();
+            let request = request.map(|body| body.as_ref().to_owned());
+
+            Box::pin(async move {
+                Ok(router
+                    .handle(request, context)
+                    .await
+                    .unwrap_or_else(RouteError::into_http_response))
+            })
+        }
+    }
+}
+
+#[cfg(feature = "tower")]
+pub use self::service::RouterService;
+
+#[cfg(test)]
+mod tests {
+    use http::{Method, StatusCode};
+    use ruma_common::{
+        api::error::{MatrixError, MatrixErrorBody},
+        event_id,
+    };
+    use ruma_federation_api::{discovery::get_server_version, event::get_event};
+    use serde_json::{from_slice as from_json_slice, json, Value as JsonValue};
+
+    use super::{RouteError, Router};
+
+    fn router() -> Router<&'static str> {
+        Router::new()
+            .route(|_request: get_server_version::v1::Request, name: &'static str| async move {
+                let mut server = get_server_version::v1::Server::new();
+                server.name = Some(name.to_owned());
+                let mut response = get_server_version::v1::Response::new();
+                response.server = Some(server);
+                Ok::<_, MatrixError>(response)
+            })
+            .route(|request: get_event::v1::Request, _| async move {
+                Err::<get_event::v1::Response, _>(MatrixError {
+                    status_code: StatusCode::NOT_FOUND,
+                    body: MatrixErrorBody::Json(json!({
+                        "errcode": "M_NOT_FOUND",
+                        "error": request.event_id.as_str(),
+                    })),
+                })
+            })
+    }
+
+    fn request(method: Method, path: &str) -> http::Request<Vec<u8>> {
+        http::Request::builder().method(method).uri(path).body(Vec::new()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn dispatch_to_handlers() {
+        let router = router();
+
+        let response = router
+            .handle(request(Method::GET, "/_matrix/federation/v1/version"), "ruma")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: JsonValue = from_json_slice(response.body()).unwrap();
+        assert_eq!(body["server"]["name"], "ruma");
+
+        // Path arguments are decoded, and handler errors are returned as responses.
+        let response = router
+            .handle(request(Method::GET, "/_matrix/federation/v1/event/%24abc%3Aexample.org"), "")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: JsonValue = from_json_slice(response.body()).unwrap();
+        assert_eq!(body["error"], event_id!("$abc:example.org").as_str());
+    }
+
+    #[tokio::test]
+    async fn routing_errors() {
+        let router = router();
+
+        let unknown = request(Method::GET, "/_matrix/federation/v1/query/directory");
+        assert!(router.metadata(&unknown).is_none());
+        let error = router.handle(unknown, "").await.unwrap_err();
+        assert!(matches!(error, RouteError::NotFound));
+
+        let error = router
+            .handle(request(Method::POST, "/_matrix/federation/v1/version"), "")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, RouteError::MethodNotAllowed(Method::POST)));
+
+        let response = error.into_http_response();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let body: JsonValue = from_json_slice(response.body()).unwrap();
+        assert_eq!(body["errcode"], "M_UNRECOGNIZED");
+    }
+
+    #[cfg(feature = "tower")]
+    #[tokio::test]
+    async fn tower_service() {
+        use tower_service::Service;
+
+        let mut service = router().into_service("ruma");
+
+        let response = service.call(request(Method::GET, "/_matrix/federation/v1/version")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+
+        let response = service.call(request(Method::GET, "/unknown")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,389 @@
+//! Signing and verification of federation requests.
+//!
+//! Requests between homeservers are authenticated by signing a JSON object describing the request
+//! and sending the signature in an `Authorization` header of scheme `X-Matrix`, as described in
+//! the [Matrix Server-Server API][spec].
+//!
+//! [spec]: https://spec.matrix.org/latest/server-server-api/#request-authentication
+
+use std::collections::BTreeMap;
+
+use headers::authorization::Credentials;
+use http::{header::AUTHORIZATION, Request};
+use ruma_common::{
+    CanonicalJsonObject, CanonicalJsonValue, OwnedServerName, OwnedServerSigningKeyId, ServerName,
+};
+use ruma_signatures::{sign_json, verify_json, KeyPair, PublicKeyMap};
+use thiserror::Error;
+
+use crate::authorization::XMatrix;
+
+/// An error encountered when signing or verifying a federation request.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The request body is not valid canonical JSON.
+    #[error("request body is not valid canonical JSON: {0}")]
+    InvalidBody(#[source] serde_json::Error),
+
+    /// The request has no `Authorization` header of scheme `X-Matrix`.
+    #[error("missing X-Matrix Authorization header")]
+    MissingAuthorization,
+
+    /// An `Authorization` header of scheme `X-Matrix` could not be parsed.
+    #[error("malformed X-Matrix Authorization header")]
+    InvalidAuthorization,
+
+    /// The request has several `X-Matrix` headers claiming different origins.
+    #[error("X-Matrix Authorization headers have different origins")]
+    OriginMismatch,
+
+    /// The destination in an `X-Matrix` header is not the server verifying the request.
+    #[error("request is destined for {0}")]
+    DestinationMismatch(OwnedServerName),
+
+    /// The ID of the key used for signing is not a valid server signing key ID.
+    #[error("invalid signing key ID: {0}")]
+    InvalidKeyId(#[source] ruma_common::IdParseError),
+
+    /// Signing or verifying the request JSON failed.
+    #[error(transparent)]
+    Signatures(#[from] ruma_signatures::Error),
+}
+
+/// Signs an outgoing federation request.
+///
+/// Returns the [`XMatrix`] credentials to send in the `Authorization` header of the request.
+/// Requests can be signed with several keys by calling this function once per key and sending
+/// one header per signature.
+///
+/// # Parameters
+///
+/// * request: The request to sign. Its body must be empty or a JSON value.
+/// * origin: The name of the server sending the request.
+/// * destination: The name of the server receiving the request.
+/// * key_pair: The signing key of `origin`.
+///
+/// # Errors
+///
+/// Returns an error if the body of the request is not valid canonical JSON.
+pub fn sign_request<T, K>(
+    request: &Request<T>,
+    origin: &ServerName,
+    destination: &ServerName,
+    key_pair: &K,
+) -> Result<XMatrix, Error>
+where
+    T: AsRef<[u8]>,
+    K: KeyPair,
+{
+    let mut object = request_json(request, origin, destination)?;
+    sign_json(origin.as_str(), key_pair, &mut object)?;
+
+    let (key, sig) = object
+        .remove("signatures")
+        .and_then(|signatures| match signatures {
+            CanonicalJsonValue::Object(mut signatures) => signatures.remove(origin.as_str()),
+            _ => None,
+        })
+        .and_then(|signature_set| match signature_set {
+            CanonicalJsonValue::Object(signature_set) => signature_set.into_iter().next(),
+            _ => None,
+        })
+        .and_then(|(key, sig)| match sig {
+            CanonicalJsonValue::String(sig) => Some((key, sig)),
+            _ => None,
+        })
+        .expect("sign_json inserts the signature of the origin");
+    let key = OwnedServerSigningKeyId::try_from(key).map_err(Error::InvalidKeyId)?;
+
+    Ok(XMatrix::new(origin.to_owned(), Some(destination.to_owned()), key, sig))
+}
+
+/// Verifies the signatures of an incoming federation request.
+///
+/// Every `Authorization` header of scheme `X-Matrix` is taken into account, they must all name the
+/// same origin and all their signatures must be valid. Headers of other schemes are ignored.
+///
+/// Returns the name of the server that sent the request.
+///
+/// # Parameters
+///
+/// * request: The request to verify. Its body must be empty or a JSON value.
+/// * destination: The name of the server verifying the request.
+/// * public_key_map: The public keys of the origin server.
+///
+/// # Errors
+///
+/// Returns an error if the request isn't signed, is meant for another server, or if any of its
+/// signatures can't be verified.
+pub fn verify_request<T>(
+    request: &Request<T>,
+    destination: &ServerName,
+    public_key_map: &PublicKeyMap,
+) -> Result<OwnedServerName, Error>
+where
+    T: AsRef<[u8]>,
+{
+    let mut origin: Option<OwnedServerName> = None;
+    let mut signature_set = BTreeMap::new();
+
+    for value in request.headers().get_all(AUTHORIZATION) {
+        if !value.as_bytes().starts_with(XMatrix::SCHEME.as_bytes()) {
+            continue;
+        }
+
+        let credentials = XMatrix::decode(value).ok_or(Error::InvalidAuthorization)?;
+
+        if let Some(header_destination) = credentials.destination {
+            if header_destination != destination {
+                return Err(Error::DestinationMismatch(header_destination));
+            }
+        }
+
+        match &origin {
+            Some(origin) if *origin != credentials.origin => return Err(Error::OriginMismatch),
+            Some(_) => {}
+            None => origin = Some(credentials.origin),
+        }
+
+        signature_set
+            .insert(credentials.key.to_string(), CanonicalJsonValue::String(credentials.sig));
+    }
+
+    let origin = origin.ok_or(Error::MissingAuthorization)?;
+
+    let mut object = request_json(request, &origin, destination)?;
+    object.insert(
+        "signatures".to_owned(),
+        CanonicalJsonValue::Object(BTreeMap::from([(
+            origin.as_str().to_owned(),
+            CanonicalJsonValue::Object(signature_set),
+        )])),
+    );
+
+    verify_json(public_key_map, &object)?;
+
+    Ok(origin)
+}
+
+/// Builds the JSON object that is signed to authenticate a request.
+fn request_json<T>(
+    request: &Request<T>,
+    origin: &ServerName,
+    destination: &ServerName,
+) -> Result<CanonicalJsonObject, Error>
+where
+    T: AsRef<[u8]>,
+{
+    let uri = request.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+
+    let mut object = BTreeMap::from([
+        ("method".to_owned(), CanonicalJsonValue::String(request.method().as_str().to_owned())),
+        ("uri".to_owned(), CanonicalJsonValue::String(uri.to_owned())),
+        ("origin".to_owned(), CanonicalJsonValue::String(origin.as_str().to_owned())),
+        ("destination".to_owned(), CanonicalJsonValue::String(destination.as_str().to_owned())),
+    ]);
+
+    let body = request.body().as_ref();
+    if !body.is_empty() {
+        let content = serde_json::from_slice(body).map_err(Error::InvalidBody)?;
+        object.insert("content".to_owned(), content);
+    }
+
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use headers::authorization::Credentials;
+    use http::{header::AUTHORIZATION, Request};
+    use ruma_common::{serde::Base64, server_name, ServerName};
+    use ruma_signatures::{Ed25519KeyPair, PublicKeyMap};
+
+    use super::{sign_request, verify_request, Error};
+
+    const PKCS8: &str = "\
+        MFECAQEwBQYDK2VwBCIEINjozvdfbsGEt6DD+7Uf4PiJ/YvTNXV2mIPc/\
+        tA0T+6tgSEA3TPraTczVkDPTRaX4K+AfUuyx7Mzq1UafTXypnl0t2k\
+    ";
+
+    fn key_pair(version: &str) -> Ed25519KeyPair {
+        let document: Base64 = Base64::parse(PKCS8).unwrap();
+        Ed25519KeyPair::from_der(document.as_bytes(), version.to_owned()).unwrap()
+    }
+
+    fn public_key_map(origin: &ServerName, versions: &[&str]) -> PublicKeyMap {
+        let key_set = versions
+            .iter()
+            .map(|version| {
+                (format!("ed25519:{version}"), Base64::new(key_pair(version).public_key().to_vec()))
+            })
+            .collect();
+        BTreeMap::from([(origin.as_str().to_owned(), key_set)])
+    }
+
+    fn put_request(body: &str) -> Request<Vec<u8>> {
+        Request::put("https://destination.hs.example.com/_matrix/federation/v1/send/1?foo=bar")
+            .body(body.as_bytes().to_vec())
+            .unwrap()
+    }
+
+    fn with_header(mut request: Request<Vec<u8>>, header: &super::XMatrix) -> Request<Vec<u8>> {
+        request.headers_mut().append(AUTHORIZATION, header.encode());
+        request
+    }
+
+    #[test]
+    fn sign_matches_known_signature() {
+        let origin = server_name!("origin.hs.example.com");
+        let destination = server_name!("destination.hs.example.com");
+        let request = put_request(r#"{ "pdus": [], "edus": [] }"#);
+
+        let header = sign_request(&request, origin, destination, &key_pair("1")).unwrap();
+
+        assert_eq!(header.origin, origin);
+        assert_eq!(header.destination.as_deref(), Some(destination));
+        assert_eq!(header.key, "ed25519:1");
+        assert_eq!(header.sig, "kvmQ05c5skkvo0/orFcSdykdyrcEaAuqEqCCYo8uoOLgFOId6QcWdKjEEvXE7rtmR/WJu0WNrDRsbGRH1ButDQ");
+    }
+
+    #[test]
+    fn sign_and_verify_put() {
+        let origin = server_name!("origin.hs.example.com");
+        let destination = server_name!("destination.hs.example.com");
+        let request = put_request(r#"{ "pdus": [], "edus": [] }"#);
+
+        let header = sign_request(&request, origin, destination, &key_pair("1")).unwrap();
+        let request = with_header(request, &header);
+
+        let verified_origin =
+            verify_request(&request, destination, &public_key_map(origin, &["1"])).unwrap();
+        assert_eq!(verified_origin, origin);
+    }
+
+    #[test]
+    fn sign_and_verify_get_without_body() {
+        let origin = server_name!("origin.hs.example.com");
+        let destination = server_name!("destination.hs.example.com");
+        let request = Request::get("/_matrix/federation/v1/version").body(Vec::new()).unwrap();
+
+        let header = sign_request(&request, origin, destination, &key_pair("1")).unwrap();
+        let request = with_header(request, &header);
+
+        verify_request(&request, destination, &public_key_map(origin, &["1"])).unwrap();
+    }
+
+    #[test]
+    fn body_is_canonicalized() {
+        let origin = server_name!("origin.hs.example.com");
+        let destination = server_name!("destination.hs.example.com");
+
+        let header = sign_request(
+            &put_request(r#"{"edus":[],"pdus":[]}"#),
+            origin,
+            destination,
+            &key_pair("1"),
+        )
+        .unwrap();
+        let request = with_header(put_request("{\n  \"pdus\": [],\n  \"edus\": []\n}"), &header);
+
+        verify_request(&request, destination, &public_key_map(origin, &["1"])).unwrap();
+    }
+
+    #[test]
+    fn verify_multiple_signatures() {
+        let origin = server_name!("origin.hs.example.com");
+        let destination = server_name!("destination.hs.example.com");
+        let request = put_request("{}");
+
+        let header_1 = sign_request(&request, origin, destination, &key_pair("1")).unwrap();
+        let header_2 = sign_request(&request, origin, destination, &key_pair("2")).unwrap();
+        let request = with_header(with_header(request, &header_1), &header_2);
+
+        verify_request(&request, destination, &public_key_map(origin, &["1", "2"])).unwrap();
+
+        let err =
+            verify_request(&request, destination, &public_key_map(origin, &["1"])).unwrap_err();
+        assert!(matches!(err, Error::Signatures(_)), "{err:?}");
+    }
+
+    #[test]
+    fn verify_rejects_tampered_content() {
+        let origin = server_name!("origin.hs.example.com");
+        let destination = server_name!("destination.hs.example.com");
+
+        let header =
+            sign_request(&put_request(r#"{"pdus":[]}"#), origin, destination, &key_pair("1"))
+                .unwrap();
+        let request = with_header(put_request(r#"{"pdus":[{}]}"#), &header);
+
+        let err =
+            verify_request(&request, destination, &public_key_map(origin, &["1"])).unwrap_err();
+        assert!(matches!(err, Error::Signatures(_)), "{err:?}");
+    }
+
+    #[test]
+    fn verify_rejects_wrong_destination() {
+        let origin = server_name!("origin.hs.example.com");
+        let destination = server_name!("destination.hs.example.com");
+        let request = put_request("{}");
+
+        let header = sign_request(&request, origin, destination, &key_pair("1")).unwrap();
+        let request = with_header(request, &header);
+
+        let err = verify_request(
+            &request,
+            server_name!("other.hs.example.com"),
+            &public_key_map(origin, &["1"]),
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::DestinationMismatch(name) if name == destination));
+    }
+
+    #[test]
+    fn verify_without_destination_in_header() {
+        let origin = server_name!("origin.hs.example.com");
+        let destination = server_name!("destination.hs.example.com");
+        let request = put_request("{}");
+
+        let mut header = sign_request(&request, origin, destination, &key_pair("1")).unwrap();
+        header.destination = None;
+        let request = with_header(request, &header);
+
+        verify_request(&request, destination, &public_key_map(origin, &["1"])).unwrap();
+    }
+
+    #[test]
+    fn verify_rejects_mismatched_origins() {
+        let origin = server_name!("origin.hs.example.com");
+        let destination = server_name!("destination.hs.example.com");
+        let request = put_request("{}");
+
+        let header_1 = sign_request(&request, origin, destination, &key_pair("1")).unwrap();
+        let header_2 = sign_request(
+            &request,
+            server_name!("evil.hs.example.com"),
+            destination,
+            &key_pair("1"),
+        )
+        .unwrap();
+        let request = with_header(with_header(request, &header_1), &header_2);
+
+        let err =
+            verify_request(&request, destination, &public_key_map(origin, &["1"])).unwrap_err();
+        assert!(matches!(err, Error::OriginMismatch));
+    }
+
+    #[test]
+    fn verify_requires_authorization() {
+        let destination = server_name!("destination.hs.example.com");
+        let mut request = put_request("{}");
+        request.headers_mut().insert(AUTHORIZATION, "Bearer abcdef".parse().unwrap());
+
+        let err = verify_request(&request, destination, &PublicKeyMap::new()).unwrap_err();
+        assert!(matches!(err, Error::MissingAuthorization));
+    }
+}
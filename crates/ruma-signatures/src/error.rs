@@ -182,6 +182,14 @@ pub enum ParseError {
     #[error("Event Id {0:?} should have a server name for the given room version {1:?}")]
     ServerNameFromEventIdByRoomVersion(OwnedEventId, RoomVersionId),
 
+    /// For when an event ID doesn't have a server name embedded, but the event format of the room
+    /// version requires it.
+    ///
+    /// This is returned by the functions taking room version rules, the functions taking a room
+    /// version ID return [`ParseError::ServerNameFromEventIdByRoomVersion`] instead.
+    #[error("Event Id {0:?} should have a server name for the event format of the room version")]
+    ServerNameFromEventId(OwnedEventId),
+
     /// For when the extracted/"parsed" public key from a PKCS#8 v2 document doesn't match the
     /// public key derived from it's private key.
     #[error("PKCS#8 Document public key does not match public key derived from private key; derived: {0:X?} (len {}), parsed: {1:X?} (len {})", .derived_key.len(), .parsed_key.len())]
@@ -229,6 +237,10 @@ pub enum ParseError {
 }
 
 impl ParseError {
+    pub(crate) fn from_event_id(event_id: &EventId) -> Error {
+        Self::ServerNameFromEventId(event_id.to_owned()).into()
+    }
+
     pub(crate) fn from_event_id_by_room_version(
         event_id: &EventId,
         room_version: &RoomVersionId,
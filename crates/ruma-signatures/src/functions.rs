@@ -8,9 +8,11 @@ use std::{
 
 use base64::{alphabet, Engine};
 use ruma_common::{
-    canonical_json::{redact, JsonType},
+    canonical_json::{redact_with_rules, JsonType},
+    room_version_rules::{EventFormatVersion, RoomVersionRules},
     serde::{base64::Standard, Base64},
-    CanonicalJsonObject, CanonicalJsonValue, OwnedEventId, OwnedServerName, RoomVersionId, UserId,
+    CanonicalJsonObject, CanonicalJsonValue, OwnedEventId, OwnedServerName,
+    OwnedServerSigningKeyId, RoomVersionId, UserId,
 };
 use serde_json::{from_str as from_json_str, to_string as to_json_string};
 use sha2::{digest::Digest, Sha256};
@@ -323,7 +325,18 @@ pub fn reference_hash(
     value: &CanonicalJsonObject,
     version: &RoomVersionId,
 ) -> Result<String, Error> {
-    let redacted_value = redact(value.clone(), version, None)?;
+    reference_hash_with_rules(value, &room_version_rules(version))
+}
+
+/// Creates a *reference hash* for an event, using the given room version rules.
+///
+/// Functionally equivalent to [`reference_hash`], with the rules of the room version provided
+/// directly, to support custom room versions.
+pub fn reference_hash_with_rules(
+    value: &CanonicalJsonObject,
+    rules: &RoomVersionRules,
+) -> Result<String, Error> {
+    let redacted_value = redact_with_rules(value.clone(), &rules.redaction, None)?;
 
     let json =
         canonical_json_with_fields_to_remove(&redacted_value, REFERENCE_HASH_FIELDS_TO_REMOVE)?;
@@ -333,9 +346,9 @@ pub fn reference_hash(
 
     let hash = Sha256::digest(json.as_bytes());
 
-    let base64_alphabet = match version {
-        RoomVersionId::V1 | RoomVersionId::V2 | RoomVersionId::V3 => alphabet::STANDARD,
-        // Room versions higher than version 3 are url safe base64 encoded
+    let base64_alphabet = match rules.event_format {
+        EventFormatVersion::V1 | EventFormatVersion::V2 => alphabet::STANDARD,
+        // Event IDs are url safe base64 encoded since room version 4
         _ => alphabet::URL_SAFE,
     };
     let base64_engine = base64::engine::GeneralPurpose::new(
@@ -447,6 +460,22 @@ pub fn hash_and_sign_event<K>(
     object: &mut CanonicalJsonObject,
     version: &RoomVersionId,
 ) -> Result<(), Error>
+where
+    K: KeyPair,
+{
+    hash_and_sign_event_with_rules(entity_id, key_pair, object, &room_version_rules(version))
+}
+
+/// Hashes and signs an event, using the given room version rules.
+///
+/// Functionally equivalent to [`hash_and_sign_event`], with the rules of the room version provided
+/// directly, to support custom room versions.
+pub fn hash_and_sign_event_with_rules<K>(
+    entity_id: &str,
+    key_pair: &K,
+    object: &mut CanonicalJsonObject,
+    rules: &RoomVersionRules,
+) -> Result<(), Error>
 where
     K: KeyPair,
 {
@@ -463,7 +492,7 @@ where
         _ => return Err(JsonError::not_of_type("hashes", JsonType::Object)),
     };
 
-    let mut redacted = redact(object.clone(), version, None)?;
+    let mut redacted = redact_with_rules(object.clone(), &rules.redaction, None)?;
 
     sign_json(entity_id, key_pair, &mut redacted)?;
 
@@ -544,7 +573,21 @@ pub fn verify_event(
     object: &CanonicalJsonObject,
     version: &RoomVersionId,
 ) -> Result<Verified, Error> {
-    let redacted = redact(object.clone(), version, None)?;
+    verify_event_with_rules(public_key_map, object, &room_version_rules(version))
+        .map_err(|error| with_room_version(error, version))
+}
+
+/// Verifies that the signed event contains all the required valid signatures, using the given
+/// room version rules.
+///
+/// Functionally equivalent to [`verify_event`], with the rules of the room version provided
+/// directly, to support custom room versions.
+pub fn verify_event_with_rules(
+    public_key_map: &PublicKeyMap,
+    object: &CanonicalJsonObject,
+    rules: &RoomVersionRules,
+) -> Result<Verified, Error> {
+    let redacted = redact_with_rules(object.clone(), &rules.redaction, None)?;
 
     let hash = match object.get("hashes") {
         Some(hashes_value) => match hashes_value {
@@ -566,7 +609,7 @@ pub fn verify_event(
         None => return Err(JsonError::field_missing_from_object("signatures")),
     };
 
-    let servers_to_check = servers_to_check_signatures(object, version)?;
+    let servers_to_check = servers_to_check_signatures(object, rules)?;
     let canonical_json = from_json_str(&canonical_json(&redacted)?).map_err(JsonError::from)?;
 
     for entity_id in servers_to_check {
@@ -628,6 +671,65 @@ pub fn verify_event(
     Ok(Verified::Signatures)
 }
 
+/// Gets the public keys needed to verify the signatures of an event.
+///
+/// Returns a map from the name of each server that must have signed the event to the IDs of the
+/// keys it used, according to the `signatures` of the event. The public keys for all of these key
+/// IDs must be present in the [`PublicKeyMap`] given to [`verify_event`].
+///
+/// # Parameters
+///
+/// * object: The JSON object of the event.
+/// * version: Room version of the given event.
+///
+/// # Errors
+///
+/// Returns an error if the fields that determine which servers must have signed the event are
+/// missing or invalid.
+pub fn required_keys(
+    object: &CanonicalJsonObject,
+    version: &RoomVersionId,
+) -> Result<BTreeMap<OwnedServerName, Vec<OwnedServerSigningKeyId>>, Error> {
+    required_keys_with_rules(object, &room_version_rules(version))
+        .map_err(|error| with_room_version(error, version))
+}
+
+/// Gets the public keys needed to verify the signatures of an event, using the given room version
+/// rules.
+///
+/// Functionally equivalent to [`required_keys`], with the rules of the room version provided
+/// directly, to support custom room versions.
+pub fn required_keys_with_rules(
+    object: &CanonicalJsonObject,
+    rules: &RoomVersionRules,
+) -> Result<BTreeMap<OwnedServerName, Vec<OwnedServerSigningKeyId>>, Error> {
+    let signature_map = match object.get("signatures") {
+        Some(CanonicalJsonValue::Object(signatures)) => signatures,
+        Some(_) => return Err(JsonError::not_of_type("signatures", JsonType::Object)),
+        None => return Err(JsonError::field_missing_from_object("signatures")),
+    };
+
+    let mut keys = BTreeMap::new();
+
+    for server_name in servers_to_check_signatures(object, rules)? {
+        let key_ids = match signature_map.get(server_name.as_str()) {
+            Some(CanonicalJsonValue::Object(set)) => set
+                .keys()
+                .filter(|key_id| split_id(key_id).is_ok())
+                .filter_map(|key_id| OwnedServerSigningKeyId::try_from(key_id.as_str()).ok())
+                .collect(),
+            Some(_) => {
+                return Err(JsonError::not_multiples_of_type("signature sets", JsonType::Object))
+            }
+            None => Vec::new(),
+        };
+
+        keys.insert(server_name, key_ids);
+    }
+
+    Ok(keys)
+}
+
 /// Internal implementation detail of the canonical JSON algorithm.
 ///
 /// Allows customization of the fields that will be removed before serializing.
@@ -647,13 +749,13 @@ fn canonical_json_with_fields_to_remove(
 /// Extracts the server names to check signatures for given event.
 ///
 /// It will return the sender's server (unless it's a third party invite) and the event id server
-/// (on v1 and v2 room versions)
+/// (on room versions with the v1 event format, i.e. v1 and v2)
 ///
-/// Starting with room version 8, if join_authorised_via_users_server is present, a signature from
-/// that user is required.
+/// Starting with room version 8, which supports restricted join rules, if
+/// join_authorised_via_users_server is present, a signature from that user is required.
 fn servers_to_check_signatures(
     object: &CanonicalJsonObject,
-    version: &RoomVersionId,
+    rules: &RoomVersionRules,
 ) -> Result<BTreeSet<OwnedServerName>, Error> {
     let mut servers_to_check = BTreeSet::new();
 
@@ -669,15 +771,15 @@ fn servers_to_check_signatures(
         };
     }
 
-    match version {
-        RoomVersionId::V1 | RoomVersionId::V2 => match object.get("event_id") {
+    if rules.event_format == EventFormatVersion::V1 {
+        match object.get("event_id") {
             Some(CanonicalJsonValue::String(raw_event_id)) => {
                 let event_id: OwnedEventId =
                     raw_event_id.parse().map_err(|e| Error::from(ParseError::EventId(e)))?;
 
                 let server_name = event_id
                     .server_name()
-                    .ok_or_else(|| ParseError::from_event_id_by_room_version(&event_id, version))?
+                    .ok_or_else(|| ParseError::from_event_id(&event_id))?
                     .to_owned();
 
                 servers_to_check.insert(server_name);
@@ -685,34 +787,45 @@ fn servers_to_check_signatures(
             _ => {
                 return Err(JsonError::field_missing_from_object("event_id"));
             }
-        },
-        RoomVersionId::V3
-        | RoomVersionId::V4
-        | RoomVersionId::V5
-        | RoomVersionId::V6
-        | RoomVersionId::V7 => {}
-        // TODO: And for all future versions that have join_authorised_via_users_server
-        RoomVersionId::V8 | RoomVersionId::V9 | RoomVersionId::V10 | RoomVersionId::V11 => {
-            if let Some(authorized_user) = object
-                .get("content")
-                .and_then(|c| c.as_object())
-                .and_then(|c| c.get("join_authorised_via_users_server"))
-            {
-                let authorized_user = authorized_user.as_str().ok_or_else(|| {
-                    JsonError::not_of_type("join_authorised_via_users_server", JsonType::String)
-                })?;
-                let authorized_user = <&UserId>::try_from(authorized_user)
-                    .map_err(|e| Error::from(ParseError::UserId(e)))?;
+        }
+    }
 
-                servers_to_check.insert(authorized_user.server_name().to_owned());
-            }
+    if rules.authorization.restricted_join_rules {
+        if let Some(authorized_user) = object
+            .get("content")
+            .and_then(|c| c.as_object())
+            .and_then(|c| c.get("join_authorised_via_users_server"))
+        {
+            let authorized_user = authorized_user.as_str().ok_or_else(|| {
+                JsonError::not_of_type("join_authorised_via_users_server", JsonType::String)
+            })?;
+            let authorized_user = <&UserId>::try_from(authorized_user)
+                .map_err(|e| Error::from(ParseError::UserId(e)))?;
+
+            servers_to_check.insert(authorized_user.server_name().to_owned());
         }
-        _ => unimplemented!(),
     }
 
     Ok(servers_to_check)
 }
 
+/// The rules of the given room version, or those of the latest room version if it is unknown, like
+/// the redaction algorithm of `ruma_common::canonical_json::redact`.
+fn room_version_rules(version: &RoomVersionId) -> RoomVersionRules {
+    version.rules().unwrap_or(RoomVersionRules::V11)
+}
+
+/// Add the given room version to the errors of the functions taking room version rules that
+/// include it when called with a room version ID.
+fn with_room_version(error: Error, version: &RoomVersionId) -> Error {
+    match error {
+        Error::Parse(ParseError::ServerNameFromEventId(event_id)) => {
+            ParseError::from_event_id_by_room_version(&event_id, version)
+        }
+        error => error,
+    }
+}
+
 /// Checks if `object` contains an event of type `m.room.third_party_invite`
 fn is_third_party_invite(object: &CanonicalJsonObject) -> Result<bool, Error> {
     match object.get("type") {
@@ -727,14 +840,16 @@ mod tests {
 
     use assert_matches2::assert_matches;
     use ruma_common::{
-        serde::Base64, CanonicalJsonValue, RoomVersionId, ServerSigningKeyId, SigningKeyAlgorithm,
+        room_version_rules::RoomVersionRules, serde::Base64, server_name, CanonicalJsonValue,
+        RoomVersionId, ServerSigningKeyId, SigningKeyAlgorithm,
     };
     use serde_json::json;
 
     use super::canonical_json;
     use crate::{
-        sign_json, verify_event, Ed25519KeyPair, Error, PublicKeyMap, PublicKeySet,
-        VerificationError, Verified,
+        hash_and_sign_event_with_rules, reference_hash, reference_hash_with_rules, required_keys,
+        required_keys_with_rules, sign_json, verify_event, verify_event_with_rules, Ed25519KeyPair,
+        Error, ParseError, PublicKeyMap, PublicKeySet, VerificationError, Verified,
     };
 
     #[test]
@@ -841,6 +956,69 @@ mod tests {
         assert_eq!(verification, Verified::Signatures);
     }
 
+    #[test]
+    fn required_keys_for_sender_and_event_id() {
+        let key_pair_sender = generate_key_pair("1");
+        let key_pair_event = generate_key_pair("2");
+        let mut signed_event = serde_json::from_str(
+            r#"{
+                "event_id": "$event_id:domain-event",
+                "auth_events": [],
+                "content": {},
+                "depth": 3,
+                "hashes": {
+                    "sha256": "5jM4wQpv6lnBo7CLIghJuHdW+s2CMBJPUOGOC89ncos"
+                },
+                "origin": "domain",
+                "origin_server_ts": 1000000,
+                "prev_events": [],
+                "room_id": "!x:domain",
+                "sender": "@name:domain-sender",
+                "type": "X",
+                "unsigned": {
+                    "age_ts": 1000000
+                }
+            }"#,
+        )
+        .unwrap();
+        sign_json("domain-sender", &key_pair_sender, &mut signed_event).unwrap();
+        sign_json("domain-event", &key_pair_event, &mut signed_event).unwrap();
+        sign_json("domain-other", &generate_key_pair("3"), &mut signed_event).unwrap();
+
+        let keys = required_keys(&signed_event, &RoomVersionId::V1).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[server_name!("domain-sender")], ["ed25519:1"]);
+        assert_eq!(keys[server_name!("domain-event")], ["ed25519:2"]);
+
+        let keys = required_keys(&signed_event, &RoomVersionId::V6).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[server_name!("domain-sender")], ["ed25519:1"]);
+    }
+
+    #[test]
+    fn required_keys_event_id_without_server_name() {
+        let event = serde_json::from_value(json!({
+            "event_id": "$event_id",
+            "sender": "@name:domain-sender",
+            "signatures": {},
+            "type": "X",
+        }))
+        .unwrap();
+
+        assert_matches!(
+            required_keys(&event, &RoomVersionId::V1),
+            Err(Error::Parse(ParseError::ServerNameFromEventIdByRoomVersion(event_id, version)))
+        );
+        assert_eq!(event_id, "$event_id");
+        assert_eq!(version, RoomVersionId::V1);
+
+        assert_matches!(
+            required_keys_with_rules(&event, &RoomVersionRules::V1),
+            Err(Error::Parse(ParseError::ServerNameFromEventId(event_id)))
+        );
+        assert_eq!(event_id, "$event_id");
+    }
+
     #[test]
     fn verify_event_check_signatures_for_authorized_user() {
         let key_pair_sender = generate_key_pair("1");
@@ -917,6 +1095,50 @@ mod tests {
         assert_eq!(server, "domain-authorized");
     }
 
+    #[test]
+    fn sign_and_verify_event_with_custom_rules() {
+        let key_pair_sender = generate_key_pair("1");
+        let mut event = serde_json::from_str(
+            r##"{
+                "auth_events": [],
+                "content": {"aliases": ["#alias:domain"]},
+                "depth": 3,
+                "origin_server_ts": 1000000,
+                "prev_events": [],
+                "room_id": "!x:domain",
+                "sender": "@name:domain-sender",
+                "state_key": "domain-sender",
+                "type": "m.room.aliases"
+            }"##,
+        )
+        .unwrap();
+
+        // A custom room version that keeps the aliases when redacting `m.room.aliases`.
+        let mut rules = RoomVersionRules::V11;
+        rules.redaction.keep_room_aliases_aliases = true;
+
+        hash_and_sign_event_with_rules("domain-sender", &key_pair_sender, &mut event, &rules)
+            .unwrap();
+
+        let mut public_key_map = BTreeMap::new();
+        add_key_to_map(&mut public_key_map, "domain-sender", &key_pair_sender);
+
+        let verification = verify_event_with_rules(&public_key_map, &event, &rules).unwrap();
+        assert_eq!(verification, Verified::All);
+
+        // The signature doesn't match with the rules of room version 11.
+        let verification_result = verify_event(&public_key_map, &event, &RoomVersionId::V11);
+        assert_matches!(
+            verification_result,
+            Err(Error::Verification(VerificationError::Signature(_)))
+        );
+
+        assert_ne!(
+            reference_hash_with_rules(&event, &rules).unwrap(),
+            reference_hash(&event, &RoomVersionId::V11).unwrap()
+        );
+    }
+
     #[test]
     fn verification_fails_if_required_keys_are_not_given() {
         let key_pair_sender = generate_key_pair("1");
@@ -49,8 +49,9 @@ use ruma_common::serde::{AsRefStr, DisplayAsRefStr};
 pub use self::{
     error::{Error, JsonError, ParseError, VerificationError},
     functions::{
-        canonical_json, content_hash, hash_and_sign_event, reference_hash, sign_json, verify_event,
-        verify_json,
+        canonical_json, content_hash, hash_and_sign_event, hash_and_sign_event_with_rules,
+        reference_hash, reference_hash_with_rules, required_keys, required_keys_with_rules,
+        sign_json, verify_event, verify_event_with_rules, verify_json,
     },
     keys::{Ed25519KeyPair, KeyPair, PublicKeyMap, PublicKeySet},
     signatures::Signature,
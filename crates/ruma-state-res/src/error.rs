@@ -20,6 +20,11 @@ pub enum Error {
     #[error("Not found error: {0}")]
     NotFound(String),
 
+    /// Fetching an event failed for another reason than the event not being found, like a
+    /// database or I/O error.
+    #[error("Failed to fetch event: {0}")]
+    Fetch(Box<dyn std::error::Error + Send + Sync>),
+
     /// Invalid fields in the given PDU.
     #[error("Invalid PDU: {0}")]
     InvalidPdu(String),
@@ -33,4 +38,9 @@ impl Error {
     pub fn custom<E: std::error::Error + 'static>(e: E) -> Self {
         Self::Custom(Box::new(e))
     }
+
+    /// Creates an [`Error::Fetch`] from the given error.
+    pub fn fetch<E: std::error::Error + Send + Sync + 'static>(e: E) -> Self {
+        Self::Fetch(Box::new(e))
+    }
 }
@@ -1,5 +1,6 @@
-use std::{borrow::Borrow, collections::BTreeSet};
+use std::{borrow::Borrow, collections::BTreeSet, future::Future};
 
+use futures_util::future::join_all;
 use js_int::{int, Int};
 use ruma_common::{
     serde::{Base64, Raw},
@@ -14,7 +15,7 @@ use ruma_events::room::{
 };
 use serde::{
     de::{Error as _, IgnoredAny},
-    Deserialize,
+    Deserialize, Serialize,
 };
 use serde_json::{from_str as from_json_str, value::RawValue as RawJsonValue};
 use tracing::{debug, error, info, warn};
@@ -25,7 +26,7 @@ use crate::{
         deserialize_power_levels_content_invite, deserialize_power_levels_content_redact,
     },
     room_version::RoomVersion,
-    Error, Event, Result, StateEventType, TimelineEventType,
+    Error, Event, EventTypeExt, Result, StateEventType, StateMap, TimelineEventType,
 };
 
 // FIXME: field extracting could be bundled for `content`
@@ -112,6 +113,166 @@ pub fn auth_types_for_event(
     Ok(auth_types)
 }
 
+/// A rule of the [authorization rules] of the spec.
+///
+/// [authorization rules]: https://spec.matrix.org/latest/rooms/v11/#authorization-rules
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum AuthRule {
+    /// The rules for `m.room.create` events.
+    Create,
+
+    /// The `m.room.create` event must be in the auth events.
+    AuthEvents,
+
+    /// The sender's server must be allowed to participate if `m.federate` is `false`.
+    Federate,
+
+    /// The rules for `m.room.aliases` events, in room versions 1 to 5.
+    Aliases,
+
+    /// The rules for `m.room.member` events.
+    Member,
+
+    /// The sender must be joined to the room.
+    SenderMembership,
+
+    /// The rules for `m.room.third_party_invite` events.
+    ThirdPartyInvite,
+
+    /// The sender must have the required power level to send the event, and can't send state
+    /// events with the user ID of another user as state key.
+    SendEvent,
+
+    /// The rules for `m.room.power_levels` events.
+    PowerLevels,
+
+    /// The rules for `m.room.redaction` events, in room versions 1 and 2.
+    Redaction,
+}
+
+/// Why an event was rejected by the authorization rules.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub enum AuthFailure {
+    /// The `m.room.create` event has previous events.
+    #[error("the m.room.create event has previous events")]
+    CreateEventHasPrevEvents,
+
+    /// The server name of the room ID doesn't match the server name of the sender of the
+    /// `m.room.create` event.
+    #[error("the server name of the room ID doesn't match the server name of the sender")]
+    RoomIdServerNameMismatch,
+
+    /// The `m.room.create` event has an invalid room version.
+    #[error("the m.room.create event has an invalid room version")]
+    InvalidRoomVersion,
+
+    /// The `m.room.create` event doesn't have a `creator` field.
+    #[error("the m.room.create event has no creator")]
+    MissingCreator,
+
+    /// There is no `m.room.create` event in the state.
+    #[error("no m.room.create event in the state")]
+    MissingCreateEvent,
+
+    /// The `m.room.create` event is not in the auth events of the event.
+    #[error("the m.room.create event is not in the auth events")]
+    CreateEventNotInAuthEvents,
+
+    /// The room is not federated and the sender is not on the server of the room creator.
+    #[error("the room is not federated")]
+    NotFederated,
+
+    /// The state key of the event is invalid.
+    ///
+    /// It is missing from an `m.room.member` event or doesn't match the sender's server name in
+    /// an `m.room.aliases` event.
+    #[error("invalid state key")]
+    InvalidStateKey,
+
+    /// The event's state key is the user ID of another user than the sender.
+    #[error("the state key doesn't match the sender")]
+    StateKeyNotSender,
+
+    /// The `membership` field of an `m.room.member` event is missing or invalid.
+    #[error("missing or invalid membership")]
+    InvalidMembership,
+
+    /// The membership of the target user can't change to the new membership.
+    #[error("membership can't change from {from} to {to}")]
+    InvalidMembershipTransition {
+        /// The current membership of the target user.
+        from: MembershipState,
+
+        /// The membership of the event.
+        to: MembershipState,
+    },
+
+    /// The target user is banned from the room.
+    #[error("the target user is banned")]
+    TargetBanned,
+
+    /// The sender is not joined to the room.
+    #[error("the sender is not joined to the room")]
+    SenderNotJoined,
+
+    /// The join rule of the room doesn't allow the user to join.
+    #[error("the join rule doesn't allow the user to join")]
+    JoinNotAllowed,
+
+    /// The join rule of the room doesn't allow the user to knock.
+    #[error("the join rule doesn't allow the user to knock")]
+    KnockNotAllowed,
+
+    /// The user for `join_authorised_via_users_server` of a restricted join is missing, is not
+    /// joined to the room or can't invite users.
+    #[error("the restricted join was not authorised by a user that can invite")]
+    RestrictedJoinNotAuthorised,
+
+    /// The third-party invite doesn't match an `m.room.third_party_invite` event in the state.
+    #[error("invalid third-party invite")]
+    InvalidThirdPartyInvite,
+
+    /// The sender's power level is lower than the required power level.
+    #[error("the sender's power level {actual} is lower than the required power level {required}")]
+    InsufficientPowerLevel {
+        /// The required power level.
+        required: Int,
+
+        /// The sender's power level.
+        actual: Int,
+    },
+
+    /// The sender's power level is not higher than the target user's power level.
+    #[error("the sender's power level is not higher than the target user's")]
+    TargetPowerLevelTooHigh,
+
+    /// The sender tried to change the power level of another user which is equal to their own.
+    #[error("can't change the power level of another user with the same power level")]
+    PowerLevelEqualToSender,
+
+    /// The content of the `m.room.power_levels` event is invalid.
+    #[error("invalid m.room.power_levels event")]
+    InvalidPowerLevels,
+}
+
+/// The rejection of an event by the authorization rules.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[error("{failure} ({rule:?} rule)")]
+#[allow(clippy::exhaustive_structs)]
+pub struct AuthRejection {
+    /// The rule that rejected the event.
+    pub rule: AuthRule,
+
+    /// Why the rule rejected the event.
+    pub failure: AuthFailure,
+}
+
+fn reject(rule: AuthRule, failure: AuthFailure) -> Result<std::result::Result<(), AuthRejection>> {
+    Ok(Err(AuthRejection { rule, failure }))
+}
+
 /// Authenticate the incoming `event`.
 ///
 /// The steps of authentication are:
@@ -127,6 +288,21 @@ pub fn auth_check<E: Event>(
     current_third_party_invite: Option<impl Event>,
     fetch_state: impl Fn(&StateEventType, &str) -> Option<E>,
 ) -> Result<bool> {
+    Ok(auth_check_detailed(room_version, incoming_event, current_third_party_invite, fetch_state)?
+        .is_ok())
+}
+
+/// Authenticate the incoming `event`, with the reason of the rejection if it is not allowed.
+///
+/// This is the same as [`auth_check`], but returns `Ok(Err(_))` with the rule that rejected the
+/// event and the reason instead of `Ok(false)`. `Err(_)` is returned only if the authorization
+/// could not be performed, like when some content could not be deserialized.
+pub fn auth_check_detailed<E: Event>(
+    room_version: &RoomVersion,
+    incoming_event: impl Event,
+    current_third_party_invite: Option<impl Event>,
+    fetch_state: impl Fn(&StateEventType, &str) -> Option<E>,
+) -> Result<std::result::Result<(), AuthRejection>> {
     info!(
         "auth_check beginning for {} ({})",
         incoming_event.event_id(),
@@ -158,37 +334,37 @@ pub fn auth_check<E: Event>(
         // If it has any previous events, reject
         if incoming_event.prev_events().next().is_some() {
             warn!("the room creation event had previous events");
-            return Ok(false);
+            return reject(AuthRule::Create, AuthFailure::CreateEventHasPrevEvents);
         }
 
         // If the domain of the room_id does not match the domain of the sender, reject
         let Some(room_id_server_name) = incoming_event.room_id().server_name() else {
             warn!("room ID has no servername");
-            return Ok(false);
+            return reject(AuthRule::Create, AuthFailure::RoomIdServerNameMismatch);
         };
 
         if room_id_server_name != sender.server_name() {
             warn!("servername of room ID does not match servername of sender");
-            return Ok(false);
+            return reject(AuthRule::Create, AuthFailure::RoomIdServerNameMismatch);
         }
 
         // If content.room_version is present and is not a recognized version, reject
         let content: RoomCreateContentFields = from_json_str(incoming_event.content().get())?;
         if content.room_version.map(|v| v.deserialize().is_err()).unwrap_or(false) {
             warn!("invalid room version found in m.room.create event");
-            return Ok(false);
+            return reject(AuthRule::Create, AuthFailure::InvalidRoomVersion);
         }
 
         if !room_version.use_room_create_sender {
             // If content has no creator field, reject
             if content.creator.is_none() {
                 warn!("no creator field found in m.room.create content");
-                return Ok(false);
+                return reject(AuthRule::Create, AuthFailure::MissingCreator);
             }
         }
 
         info!("m.room.create event was allowed");
-        return Ok(true);
+        return Ok(Ok(()));
     }
 
     /*
@@ -219,7 +395,7 @@ pub fn auth_check<E: Event>(
     let room_create_event = match fetch_state(&StateEventType::RoomCreate, "") {
         None => {
             warn!("no m.room.create event in auth chain");
-            return Ok(false);
+            return reject(AuthRule::AuthEvents, AuthFailure::MissingCreateEvent);
         }
         Some(e) => e,
     };
@@ -228,7 +404,7 @@ pub fn auth_check<E: Event>(
     if !incoming_event.auth_events().any(|id| id.borrow() == room_create_event.event_id().borrow())
     {
         warn!("no m.room.create event in auth events");
-        return Ok(false);
+        return reject(AuthRule::AuthEvents, AuthFailure::CreateEventNotInAuthEvents);
     }
 
     // If the create event content has the field m.federate set to false and the sender domain of
@@ -244,7 +420,7 @@ pub fn auth_check<E: Event>(
         && room_create_event.sender().server_name() != incoming_event.sender().server_name()
     {
         warn!("room is not federated and event's sender domain does not match create event's sender domain");
-        return Ok(false);
+        return reject(AuthRule::Federate, AuthFailure::NotFederated);
     }
 
     // Only in some room versions 6 and below
@@ -256,11 +432,11 @@ pub fn auth_check<E: Event>(
             // If sender's domain doesn't matches state_key, reject
             if incoming_event.state_key() != Some(sender.server_name().as_str()) {
                 warn!("state_key does not match sender");
-                return Ok(false);
+                return reject(AuthRule::Aliases, AuthFailure::InvalidStateKey);
             }
 
             info!("m.room.aliases event was allowed");
-            return Ok(true);
+            return Ok(Ok(()));
         }
     }
 
@@ -273,7 +449,7 @@ pub fn auth_check<E: Event>(
         let state_key = match incoming_event.state_key() {
             None => {
                 warn!("no statekey in member event");
-                return Ok(false);
+                return reject(AuthRule::Member, AuthFailure::InvalidStateKey);
             }
             Some(s) => s,
         };
@@ -281,7 +457,7 @@ pub fn auth_check<E: Event>(
         let content: RoomMemberContentFields = from_json_str(incoming_event.content().get())?;
         if content.membership.as_ref().and_then(|m| m.deserialize().ok()).is_none() {
             warn!("no valid membership field found for m.room.member event content");
-            return Ok(false);
+            return reject(AuthRule::Member, AuthFailure::InvalidMembership);
         }
 
         let target_user =
@@ -297,7 +473,7 @@ pub fn auth_check<E: Event>(
             .map(|mem| mem.membership)
             .unwrap_or(MembershipState::Leave);
 
-        if !valid_membership_change(
+        if let Err(failure) = valid_membership_change(
             room_version,
             target_user,
             fetch_state(&StateEventType::RoomMember, target_user.as_str()).as_ref(),
@@ -311,11 +487,11 @@ pub fn auth_check<E: Event>(
             &user_for_join_auth_membership,
             room_create_event,
         )? {
-            return Ok(false);
+            return reject(AuthRule::Member, failure);
         }
 
         info!("m.room.member event was allowed");
-        return Ok(true);
+        return Ok(Ok(()));
     }
 
     // If the sender's current membership state is not join, reject
@@ -323,7 +499,7 @@ pub fn auth_check<E: Event>(
         Some(mem) => mem,
         None => {
             warn!("sender not found in room");
-            return Ok(false);
+            return reject(AuthRule::SenderMembership, AuthFailure::SenderNotJoined);
         }
     };
 
@@ -336,7 +512,7 @@ pub fn auth_check<E: Event>(
 
     if !matches!(membership_state, MembershipState::Join) {
         warn!("sender's membership is not join");
-        return Ok(false);
+        return reject(AuthRule::SenderMembership, AuthFailure::SenderNotJoined);
     }
 
     // If type is m.room.third_party_invite
@@ -377,37 +553,40 @@ pub fn auth_check<E: Event>(
 
         if sender_power_level < invite_level {
             warn!("sender's cannot send invites in this room");
-            return Ok(false);
+            return reject(
+                AuthRule::ThirdPartyInvite,
+                AuthFailure::InsufficientPowerLevel {
+                    required: invite_level,
+                    actual: sender_power_level,
+                },
+            );
         }
 
         info!("m.room.third_party_invite event was allowed");
-        return Ok(true);
+        return Ok(Ok(()));
     }
 
     // If the event type's required power level is greater than the sender's power level, reject
     // If the event has a state_key that starts with an @ and does not match the sender, reject.
-    if !can_send_event(&incoming_event, power_levels_event.as_ref(), sender_power_level) {
+    if let Err(failure) =
+        can_send_event(&incoming_event, power_levels_event.as_ref(), sender_power_level)
+    {
         warn!("user cannot send event");
-        return Ok(false);
+        return reject(AuthRule::SendEvent, failure);
     }
 
     // If type is m.room.power_levels
     if *incoming_event.event_type() == TimelineEventType::RoomPowerLevels {
         info!("starting m.room.power_levels check");
 
-        if let Some(required_pwr_lvl) = check_power_levels(
+        if let Err(failure) = check_power_levels(
             room_version,
             &incoming_event,
             power_levels_event.as_ref(),
             sender_power_level,
         ) {
-            if !required_pwr_lvl {
-                warn!("power level was not allowed");
-                return Ok(false);
-            }
-        } else {
             warn!("power level was not allowed");
-            return Ok(false);
+            return reject(AuthRule::PowerLevels, failure);
         }
         info!("power levels event allowed");
     }
@@ -430,12 +609,89 @@ pub fn auth_check<E: Event>(
         };
 
         if !check_redaction(room_version, incoming_event, sender_power_level, redact_level)? {
-            return Ok(false);
+            return reject(
+                AuthRule::Redaction,
+                AuthFailure::InsufficientPowerLevel {
+                    required: redact_level,
+                    actual: sender_power_level,
+                },
+            );
         }
     }
 
     info!("allowing event passed all checks");
-    Ok(true)
+    Ok(Ok(()))
+}
+
+/// Authenticate the incoming `event` with state fetched asynchronously.
+///
+/// This is the same as [`auth_check`], but the state is loaded with the `fetch_state` future
+/// before running the checks. Only the state returned by [`auth_types_for_event`] is requested,
+/// concurrently.
+///
+/// `fetch_state` should return [`Error::NotFound`] if there is no state for the given type and
+/// state key, any other error aborts the authentication and is returned as is.
+pub async fn auth_check_async<E, Fetch, Fut>(
+    room_version: &RoomVersion,
+    incoming_event: impl Event,
+    current_third_party_invite: Option<impl Event>,
+    fetch_state: Fetch,
+) -> Result<bool>
+where
+    E: Event,
+    Fetch: Fn(StateEventType, String) -> Fut,
+    Fut: Future<Output = Result<E>>,
+{
+    Ok(auth_check_async_detailed(
+        room_version,
+        incoming_event,
+        current_third_party_invite,
+        fetch_state,
+    )
+    .await?
+    .is_ok())
+}
+
+/// Authenticate the incoming `event` with state fetched asynchronously, with the reason of the
+/// rejection if it is not allowed.
+///
+/// This is the same as [`auth_check_async`], but returns `Ok(Err(_))` with the rule that rejected
+/// the event and the reason instead of `Ok(false)`, like [`auth_check_detailed`].
+pub async fn auth_check_async_detailed<E, Fetch, Fut>(
+    room_version: &RoomVersion,
+    incoming_event: impl Event,
+    current_third_party_invite: Option<impl Event>,
+    fetch_state: Fetch,
+) -> Result<std::result::Result<(), AuthRejection>>
+where
+    E: Event,
+    Fetch: Fn(StateEventType, String) -> Fut,
+    Fut: Future<Output = Result<E>>,
+{
+    let auth_types = auth_types_for_event(
+        incoming_event.event_type(),
+        incoming_event.sender(),
+        incoming_event.state_key(),
+        incoming_event.content(),
+    )?;
+
+    let fetched =
+        join_all(auth_types.iter().map(|(ty, key)| fetch_state(ty.clone(), key.clone()))).await;
+
+    let mut auth_events = StateMap::new();
+    for ((ty, key), result) in auth_types.into_iter().zip(fetched) {
+        match result {
+            Ok(event) => {
+                auth_events.insert((ty, key), event);
+            }
+            Err(Error::NotFound(_)) => debug!("no {ty} state with state key {key:?}"),
+            Err(e) => return Err(e),
+        }
+    }
+
+    auth_check_detailed(room_version, incoming_event, current_third_party_invite, |ty, key| {
+        auth_events.get(&ty.with_state_key(key))
+    })
 }
 
 // TODO deserializing the member, power, join_rules event contents is done in conduit
@@ -461,7 +717,7 @@ fn valid_membership_change(
     user_for_join_auth: Option<&UserId>,
     user_for_join_auth_membership: &MembershipState,
     create_room: impl Event,
-) -> Result<bool> {
+) -> Result<std::result::Result<(), AuthFailure>> {
     #[derive(Deserialize)]
     struct GetThirdPartyInvite {
         third_party_invite: Option<Raw<ThirdPartyInvite>>,
@@ -532,6 +788,8 @@ fn valid_membership_change(
         false
     };
 
+    let sender_power_level = || *sender_power.unwrap_or(&power_levels.users_default);
+
     Ok(match target_membership {
         MembershipState::Join => {
             // 1. If the only previous event is an m.room.create and the state_key is the creator,
@@ -560,25 +818,25 @@ fn valid_membership_change(
                 };
 
                 if is_creator {
-                    return Ok(true);
+                    return Ok(Ok(()));
                 }
             }
 
             if sender != target_user {
                 // If the sender does not match state_key, reject.
                 warn!("Can't make other user join");
-                false
+                Err(AuthFailure::StateKeyNotSender)
             } else if let MembershipState::Ban = target_user_current_membership {
                 // If the sender is banned, reject.
                 warn!(?target_user_membership_event_id, "Banned user can't join");
-                false
+                Err(AuthFailure::TargetBanned)
             } else if (join_rules == JoinRule::Invite
                     || room_version.allow_knocking && join_rules == JoinRule::Knock)
                 // If the join_rule is invite then allow if membership state is invite or join
                     && (target_user_current_membership == MembershipState::Join
                         || target_user_current_membership == MembershipState::Invite)
             {
-                true
+                Ok(())
             } else if room_version.restricted_join_rules
                 && matches!(join_rules, JoinRule::Restricted(_))
                 || room_version.knock_restricted_join_rule
@@ -588,19 +846,22 @@ fn valid_membership_change(
                 if matches!(
                     target_user_current_membership,
                     MembershipState::Invite | MembershipState::Join
-                ) {
+                ) || user_for_join_auth_is_valid
+                {
                     // If membership state is join or invite, allow.
-                    true
-                } else {
                     // If the join_authorised_via_users_server key in content is not a user with
                     // sufficient permission to invite other users, reject.
                     // Otherwise, allow.
-                    user_for_join_auth_is_valid
+                    Ok(())
+                } else {
+                    Err(AuthFailure::RestrictedJoinNotAuthorised)
                 }
-            } else {
+            } else if join_rules == JoinRule::Public {
                 // If the join_rule is public, allow.
+                Ok(())
+            } else {
                 // Otherwise, reject.
-                join_rules == JoinRule::Public
+                Err(AuthFailure::JoinNotAllowed)
             }
         }
         MembershipState::Invite => {
@@ -608,18 +869,17 @@ fn valid_membership_change(
             if let Some(tp_id) = third_party_invite.and_then(|i| i.deserialize().ok()) {
                 if target_user_current_membership == MembershipState::Ban {
                     warn!(?target_user_membership_event_id, "Can't invite banned user");
-                    false
+                    Err(AuthFailure::TargetBanned)
+                } else if verify_third_party_invite(
+                    Some(target_user),
+                    sender,
+                    &tp_id,
+                    current_third_party_invite,
+                ) {
+                    Ok(())
                 } else {
-                    let allow = verify_third_party_invite(
-                        Some(target_user),
-                        sender,
-                        &tp_id,
-                        current_third_party_invite,
-                    );
-                    if !allow {
-                        warn!("Third party invite invalid");
-                    }
-                    allow
+                    warn!("Third party invite invalid");
+                    Err(AuthFailure::InvalidThirdPartyInvite)
                 }
             } else if !sender_is_joined
                 || target_user_current_membership == MembershipState::Join
@@ -631,27 +891,43 @@ fn valid_membership_change(
                     "Can't invite user if sender not joined or the user is currently joined or \
                      banned",
                 );
-                false
-            } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.invite).is_some();
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to invite",
-                    );
+                if !sender_is_joined {
+                    Err(AuthFailure::SenderNotJoined)
+                } else if target_user_current_membership == MembershipState::Ban {
+                    Err(AuthFailure::TargetBanned)
+                } else {
+                    Err(AuthFailure::InvalidMembershipTransition {
+                        from: target_user_current_membership,
+                        to: MembershipState::Invite,
+                    })
                 }
-                allow
+            } else if sender_power.filter(|&p| p >= &power_levels.invite).is_some() {
+                Ok(())
+            } else {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to invite",
+                );
+                Err(AuthFailure::InsufficientPowerLevel {
+                    required: power_levels.invite,
+                    actual: sender_power_level(),
+                })
             }
         }
         MembershipState::Leave => {
             if sender == target_user {
-                let allow = target_user_current_membership == MembershipState::Join
-                    || target_user_current_membership == MembershipState::Invite;
-                if !allow {
+                if target_user_current_membership == MembershipState::Join
+                    || target_user_current_membership == MembershipState::Invite
+                {
+                    Ok(())
+                } else {
                     warn!(?target_user_membership_event_id, "Can't leave if not invited or joined");
+                    Err(AuthFailure::InvalidMembershipTransition {
+                        from: target_user_current_membership,
+                        to: MembershipState::Leave,
+                    })
                 }
-                allow
             } else if !sender_is_joined
                 || target_user_current_membership == MembershipState::Ban
                     && sender_power.filter(|&p| p < &power_levels.ban).is_some()
@@ -661,35 +937,58 @@ fn valid_membership_change(
                     ?sender_membership_event_id,
                     "Can't kick if sender not joined or user is already banned",
                 );
-                false
-            } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.kick).is_some()
-                    && target_power < sender_power;
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to kick",
-                    );
+                if !sender_is_joined {
+                    Err(AuthFailure::SenderNotJoined)
+                } else {
+                    Err(AuthFailure::InsufficientPowerLevel {
+                        required: power_levels.ban,
+                        actual: sender_power_level(),
+                    })
                 }
-                allow
+            } else if sender_power.filter(|&p| p >= &power_levels.kick).is_none() {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to kick",
+                );
+                Err(AuthFailure::InsufficientPowerLevel {
+                    required: power_levels.kick,
+                    actual: sender_power_level(),
+                })
+            } else if target_power >= sender_power {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to kick",
+                );
+                Err(AuthFailure::TargetPowerLevelTooHigh)
+            } else {
+                Ok(())
             }
         }
         MembershipState::Ban => {
             if !sender_is_joined {
                 warn!(?sender_membership_event_id, "Can't ban user if sender is not joined");
-                false
+                Err(AuthFailure::SenderNotJoined)
+            } else if sender_power.filter(|&p| p >= &power_levels.ban).is_none() {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to ban",
+                );
+                Err(AuthFailure::InsufficientPowerLevel {
+                    required: power_levels.ban,
+                    actual: sender_power_level(),
+                })
+            } else if target_power >= sender_power {
+                warn!(
+                    ?target_user_membership_event_id,
+                    ?power_levels_event_id,
+                    "User does not have enough power to ban",
+                );
+                Err(AuthFailure::TargetPowerLevelTooHigh)
             } else {
-                let allow = sender_power.filter(|&p| p >= &power_levels.ban).is_some()
-                    && target_power < sender_power;
-                if !allow {
-                    warn!(
-                        ?target_user_membership_event_id,
-                        ?power_levels_event_id,
-                        "User does not have enough power to ban",
-                    );
-                }
-                allow
+                Ok(())
             }
         }
         MembershipState::Knock if room_version.allow_knocking => {
@@ -699,7 +998,7 @@ fn valid_membership_change(
                     && matches!(join_rules, JoinRule::KnockRestricted(_))
             {
                 warn!("Join rule is not set to knock or knock_restricted, knocking is not allowed");
-                false
+                Err(AuthFailure::KnockNotAllowed)
             } else {
                 // 2. If `sender` does not match `state_key`, reject.
                 // 3. If the `sender`'s current membership is not `ban` or `join`, allow.
@@ -710,22 +1009,28 @@ fn valid_membership_change(
                         ?target_user,
                         "Can't make another user join, sender did not match target"
                     );
-                    false
+                    Err(AuthFailure::StateKeyNotSender)
                 } else if matches!(sender_membership, MembershipState::Ban | MembershipState::Join)
                 {
                     warn!(
                         ?target_user_membership_event_id,
                         "Membership state of ban or join are invalid",
                     );
-                    false
+                    Err(AuthFailure::InvalidMembershipTransition {
+                        from: sender_membership,
+                        to: MembershipState::Knock,
+                    })
                 } else {
-                    true
+                    Ok(())
                 }
             }
         }
         _ => {
             warn!("Unknown membership transition");
-            false
+            Err(AuthFailure::InvalidMembershipTransition {
+                from: target_user_current_membership,
+                to: target_membership,
+            })
         }
     })
 }
@@ -733,22 +1038,30 @@ fn valid_membership_change(
 /// Is the user allowed to send a specific event based on the rooms power levels.
 ///
 /// Does the event have the correct userId as its state_key if it's not the "" state_key.
-fn can_send_event(event: impl Event, ple: Option<impl Event>, user_level: Int) -> bool {
+fn can_send_event(
+    event: impl Event,
+    ple: Option<impl Event>,
+    user_level: Int,
+) -> std::result::Result<(), AuthFailure> {
     let event_type_power_level = get_send_level(event.event_type(), event.state_key(), ple);
 
     debug!("{} ev_type {event_type_power_level} usr {user_level}", event.event_id());
 
     if user_level < event_type_power_level {
-        return false;
+        return Err(AuthFailure::InsufficientPowerLevel {
+            required: event_type_power_level,
+            actual: user_level,
+        });
     }
 
     if event.state_key().is_some_and(|k| k.starts_with('@'))
         && event.state_key() != Some(event.sender().as_str())
     {
-        return false; // permission required to post in this room
+        // permission required to post in this room
+        return Err(AuthFailure::StateKeyNotSender);
     }
 
-    true
+    Ok(())
 }
 
 /// Confirm that the event sender has the required power levels.
@@ -757,16 +1070,16 @@ fn check_power_levels(
     power_event: impl Event,
     previous_power_event: Option<impl Event>,
     user_level: Int,
-) -> Option<bool> {
+) -> std::result::Result<(), AuthFailure> {
     match power_event.state_key() {
         Some("") => {}
         Some(key) => {
             error!("m.room.power_levels event has non-empty state key: {key}");
-            return None;
+            return Err(AuthFailure::InvalidStateKey);
         }
         None => {
             error!("check_power_levels requires an m.room.power_levels *state* event argument");
-            return None;
+            return Err(AuthFailure::InvalidStateKey);
         }
     }
 
@@ -777,7 +1090,8 @@ fn check_power_levels(
     // - If users key in content is not a dictionary with keys that are valid user IDs with values
     //   that are integers, reject.
     let user_content: RoomPowerLevelsEventContent =
-        deserialize_power_levels(power_event.content().get(), room_version)?;
+        deserialize_power_levels(power_event.content().get(), room_version)
+            .ok_or(AuthFailure::InvalidPowerLevels)?;
 
     // Validation of users is done in Ruma, synapse for loops validating user_ids and integers here
     info!("validation of power event finished");
@@ -785,11 +1099,12 @@ fn check_power_levels(
     let current_state = match previous_power_event {
         Some(current_state) => current_state,
         // If there is no previous m.room.power_levels event in the room, allow
-        None => return Some(true),
+        None => return Ok(()),
     };
 
     let current_content: RoomPowerLevelsEventContent =
-        deserialize_power_levels(current_state.content().get(), room_version)?;
+        deserialize_power_levels(current_state.content().get(), room_version)
+            .ok_or(AuthFailure::InvalidPowerLevels)?;
 
     let mut user_levels_to_check = BTreeSet::new();
     let old_list = &current_content.users;
@@ -827,7 +1142,7 @@ fn check_power_levels(
         // If the current value is equal to the sender's current power level, reject
         if user != power_event.sender() && old_level == Some(&user_level) {
             warn!("m.room.power_level cannot remove ops == to own");
-            return Some(false); // cannot remove ops level == to own
+            return Err(AuthFailure::PowerLevelEqualToSender); // cannot remove ops level == to own
         }
 
         // If the current value is higher than the sender's current power level, reject
@@ -836,7 +1151,11 @@ fn check_power_levels(
         let new_level_too_big = new_level > Some(&user_level);
         if old_level_too_big || new_level_too_big {
             warn!("m.room.power_level failed to add ops > than own");
-            return Some(false); // cannot add ops greater than own
+            // cannot add ops greater than own
+            return Err(AuthFailure::InsufficientPowerLevel {
+                required: *old_level.max(new_level).expect("one of the levels is higher"),
+                actual: user_level,
+            });
         }
     }
 
@@ -854,7 +1173,11 @@ fn check_power_levels(
         let new_level_too_big = new_level > Some(&user_level);
         if old_level_too_big || new_level_too_big {
             warn!("m.room.power_level failed to add ops > than own");
-            return Some(false); // cannot add ops greater than own
+            // cannot add ops greater than own
+            return Err(AuthFailure::InsufficientPowerLevel {
+                required: *old_level.max(new_level).expect("one of the levels is higher"),
+                actual: user_level,
+            });
         }
     }
 
@@ -869,7 +1192,11 @@ fn check_power_levels(
             let new_level_too_big = new_level > user_level;
             if old_level_too_big || new_level_too_big {
                 warn!("m.room.power_level failed to add ops > than own");
-                return Some(false); // cannot add ops greater than own
+                // cannot add ops greater than own
+                return Err(AuthFailure::InsufficientPowerLevel {
+                    required: old_level.max(new_level),
+                    actual: user_level,
+                });
             }
         }
     }
@@ -885,12 +1212,15 @@ fn check_power_levels(
 
             if old_level_too_big || new_level_too_big {
                 warn!("cannot add ops > than own");
-                return Some(false);
+                return Err(AuthFailure::InsufficientPowerLevel {
+                    required: old_lvl.max(new_lvl),
+                    actual: user_level,
+                });
             }
         }
     }
 
-    Some(true)
+    Ok(())
 }
 
 fn get_deserialize_levels(
@@ -1010,6 +1340,8 @@ fn verify_third_party_invite(
 mod tests {
     use std::sync::Arc;
 
+    use assert_matches2::assert_matches;
+    use js_int::int;
     use ruma_events::{
         room::{
             join_rules::{
@@ -1019,15 +1351,18 @@ mod tests {
         },
         StateEventType, TimelineEventType,
     };
-    use serde_json::value::to_raw_value as to_raw_json_value;
+    use serde_json::{json, value::to_raw_value as to_raw_json_value};
 
     use crate::{
-        event_auth::valid_membership_change,
+        event_auth::{
+            auth_check_async, auth_check_async_detailed, auth_check_detailed,
+            valid_membership_change, AuthFailure, AuthRejection, AuthRule,
+        },
         test_utils::{
             alice, charlie, ella, event_id, member_content_ban, member_content_join, room_id,
             to_pdu_event, PduEvent, INITIAL_EVENTS, INITIAL_EVENTS_CREATE_ROOM,
         },
-        Event, EventTypeExt, RoomVersion, StateMap,
+        Error, Event, EventTypeExt, RoomVersion, StateMap,
     };
 
     #[test]
@@ -1069,7 +1404,160 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn auth_check_async_ban() {
+        let events = INITIAL_EVENTS();
+
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+
+        let requester = to_pdu_event(
+            "HELLO",
+            alice(),
+            TimelineEventType::RoomMember,
+            Some(charlie().as_str()),
+            member_content_ban(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IMC"],
+        );
+
+        let allowed =
+            auth_check_async(&RoomVersion::V6, &requester, None::<PduEvent>, |ty, key| {
+                let event = auth_events
+                    .get(&(ty, key.clone()))
+                    .cloned()
+                    .ok_or_else(|| Error::NotFound(key));
+                async move { event }
+            })
+            .await
+            .unwrap();
+        assert!(allowed);
+
+        let result =
+            auth_check_async(&RoomVersion::V6, &requester, None::<PduEvent>, |_, _| async {
+                Err::<Arc<PduEvent>, _>(Error::fetch(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "connection reset",
+                )))
+            })
+            .await;
+        assert_matches!(result, Err(Error::Fetch(_)));
+    }
+
+    #[tokio::test]
+    async fn auth_check_async_detailed_rejection() {
+        let events = INITIAL_EVENTS();
+
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+
+        let ban = to_pdu_event(
+            "HELLO",
+            charlie(),
+            TimelineEventType::RoomMember,
+            Some(alice().as_str()),
+            member_content_ban(),
+            &["CREATE", "IMC", "IPOWER"],
+            &["IMC"],
+        );
+
+        let verdict =
+            auth_check_async_detailed(&RoomVersion::V6, &ban, None::<PduEvent>, |ty, key| {
+                let event = auth_events
+                    .get(&(ty, key.clone()))
+                    .cloned()
+                    .ok_or_else(|| Error::NotFound(key));
+                async move { event }
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            verdict,
+            Err(AuthRejection {
+                rule: AuthRule::Member,
+                failure: AuthFailure::InsufficientPowerLevel {
+                    required: int!(50),
+                    actual: int!(0)
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn auth_check_detailed_rejections() {
+        let events = INITIAL_EVENTS();
+
+        let auth_events = events
+            .values()
+            .map(|ev| (ev.event_type().with_state_key(ev.state_key().unwrap()), Arc::clone(ev)))
+            .collect::<StateMap<_>>();
+        let fetch_state = |ty: &StateEventType, key: &str| auth_events.get(&ty.with_state_key(key));
+
+        let ban = to_pdu_event(
+            "HELLO",
+            charlie(),
+            TimelineEventType::RoomMember,
+            Some(alice().as_str()),
+            member_content_ban(),
+            &["CREATE", "IMC", "IPOWER"],
+            &["IMC"],
+        );
+        assert_eq!(
+            auth_check_detailed(&RoomVersion::V6, &ban, None::<PduEvent>, fetch_state).unwrap(),
+            Err(AuthRejection {
+                rule: AuthRule::Member,
+                failure: AuthFailure::InsufficientPowerLevel {
+                    required: int!(50),
+                    actual: int!(0)
+                },
+            })
+        );
+
+        let topic = to_pdu_event(
+            "HELLO",
+            charlie(),
+            TimelineEventType::RoomTopic,
+            Some(""),
+            to_raw_json_value(&json!({ "topic": "Charlie's room" })).unwrap(),
+            &["CREATE", "IMC", "IPOWER"],
+            &["IMC"],
+        );
+        assert_eq!(
+            auth_check_detailed(&RoomVersion::V6, &topic, None::<PduEvent>, fetch_state).unwrap(),
+            Err(AuthRejection {
+                rule: AuthRule::SendEvent,
+                failure: AuthFailure::InsufficientPowerLevel {
+                    required: int!(50),
+                    actual: int!(0)
+                },
+            })
+        );
+
+        let no_create = to_pdu_event(
+            "HELLO",
+            alice(),
+            TimelineEventType::RoomTopic,
+            Some(""),
+            to_raw_json_value(&json!({ "topic": "Alice's room" })).unwrap(),
+            &["IMA", "IPOWER"],
+            &["IMA"],
+        );
+        assert_eq!(
+            auth_check_detailed(&RoomVersion::V6, &no_create, None::<PduEvent>, fetch_state)
+                .unwrap(),
+            Err(AuthRejection {
+                rule: AuthRule::AuthEvents,
+                failure: AuthFailure::CreateEventNotInAuthEvents,
+            })
+        );
     }
 
     #[test]
@@ -1097,21 +1585,24 @@ mod tests {
         let target_user = charlie();
         let sender = charlie();
 
-        assert!(!valid_membership_change(
-            &RoomVersion::V6,
-            target_user,
-            fetch_state(StateEventType::RoomMember, target_user.to_string()),
-            sender,
-            fetch_state(StateEventType::RoomMember, sender.to_string()),
-            &requester,
-            None::<PduEvent>,
-            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
-            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
-            None,
-            &MembershipState::Leave,
-            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
-        )
-        .unwrap());
+        assert_eq!(
+            valid_membership_change(
+                &RoomVersion::V6,
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                None,
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            )
+            .unwrap(),
+            Err(AuthFailure::JoinNotAllowed)
+        );
     }
 
     #[test]
@@ -1153,7 +1644,8 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_ok());
     }
 
     #[test]
@@ -1181,21 +1673,24 @@ mod tests {
         let target_user = alice();
         let sender = charlie();
 
-        assert!(!valid_membership_change(
-            &RoomVersion::V6,
-            target_user,
-            fetch_state(StateEventType::RoomMember, target_user.to_string()),
-            sender,
-            fetch_state(StateEventType::RoomMember, sender.to_string()),
-            &requester,
-            None::<PduEvent>,
-            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
-            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
-            None,
-            &MembershipState::Leave,
-            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
-        )
-        .unwrap());
+        assert_eq!(
+            valid_membership_change(
+                &RoomVersion::V6,
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                None,
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            )
+            .unwrap(),
+            Err(AuthFailure::InsufficientPowerLevel { required: int!(50), actual: int!(0) })
+        );
     }
 
     #[test]
@@ -1254,23 +1749,27 @@ mod tests {
             &MembershipState::Join,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
-
-        assert!(!valid_membership_change(
-            &RoomVersion::V9,
-            target_user,
-            fetch_state(StateEventType::RoomMember, target_user.to_string()),
-            sender,
-            fetch_state(StateEventType::RoomMember, sender.to_string()),
-            &requester,
-            None::<PduEvent>,
-            fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
-            fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
-            Some(ella()),
-            &MembershipState::Leave,
-            fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
-        )
-        .unwrap());
+        .unwrap()
+        .is_ok());
+
+        assert_eq!(
+            valid_membership_change(
+                &RoomVersion::V9,
+                target_user,
+                fetch_state(StateEventType::RoomMember, target_user.to_string()),
+                sender,
+                fetch_state(StateEventType::RoomMember, sender.to_string()),
+                &requester,
+                None::<PduEvent>,
+                fetch_state(StateEventType::RoomPowerLevels, "".to_owned()),
+                fetch_state(StateEventType::RoomJoinRules, "".to_owned()),
+                Some(ella()),
+                &MembershipState::Leave,
+                fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
+            )
+            .unwrap(),
+            Err(AuthFailure::RestrictedJoinNotAuthorised)
+        );
     }
 
     #[test]
@@ -1321,6 +1820,7 @@ mod tests {
             &MembershipState::Leave,
             fetch_state(StateEventType::RoomCreate, "".to_owned()).unwrap(),
         )
-        .unwrap());
+        .unwrap()
+        .is_ok());
     }
 }
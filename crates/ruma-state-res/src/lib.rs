@@ -2,12 +2,16 @@ use std::{
     borrow::Borrow,
     cmp::Reverse,
     collections::{BinaryHeap, HashMap, HashSet},
+    future::Future,
     hash::Hash,
 };
 
+use futures_util::future::join_all;
 use itertools::Itertools;
 use js_int::{int, Int};
-use ruma_common::{EventId, MilliSecondsSinceUnixEpoch, RoomVersionId};
+use ruma_common::{
+    room_version_rules::RoomVersionRules, EventId, MilliSecondsSinceUnixEpoch, RoomVersionId,
+};
 use ruma_events::{
     room::member::{MembershipState, RoomMemberEventContent},
     StateEventType, TimelineEventType,
@@ -18,16 +22,26 @@ use tracing::{debug, info, trace, warn};
 mod error;
 pub mod event_auth;
 mod power_levels;
+mod resolver;
 pub mod room_version;
 mod state_event;
 #[cfg(test)]
 mod test_utils;
+mod trace;
 
 pub use error::{Error, Result};
-pub use event_auth::{auth_check, auth_types_for_event};
+pub use event_auth::{
+    auth_check, auth_check_async, auth_check_async_detailed, auth_check_detailed,
+    auth_types_for_event, AuthFailure, AuthRejection, AuthRule,
+};
 use power_levels::PowerLevelsContentFields;
+pub use resolver::{AuthChainProvider, StateResolver};
 pub use room_version::RoomVersion;
 pub use state_event::Event;
+pub use trace::{
+    AuthCheckVerdict, ConflictedStateEntry, MainlinePosition, PowerOrderingEntry, ResolutionTrace,
+    StateEntry,
+};
 
 /// A mapping of event type and state_key to some value `T`, usually an `EventId`.
 pub type StateMap<T> = HashMap<(StateEventType, String), T>;
@@ -58,6 +72,72 @@ pub fn resolve<'a, E, SetIter>(
     auth_chain_sets: Vec<HashSet<E::Id>>,
     fetch_event: impl Fn(&EventId) -> Option<E>,
 ) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+{
+    resolve_inner(RoomVersion::new(room_version), state_sets, auth_chain_sets, fetch_event, None)
+}
+
+/// Resolve sets of state events with the given room version rules.
+///
+/// This is the same as [`resolve`], with the rules of the room version provided directly, to
+/// support custom room versions.
+pub fn resolve_with_rules<'a, E, SetIter>(
+    rules: &RoomVersionRules,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: impl Fn(&EventId) -> Option<E>,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+{
+    let room_version = RoomVersion::from_rules(rules);
+    resolve_inner(Ok(room_version), state_sets, auth_chain_sets, fetch_event, None)
+}
+
+/// Resolve sets of state events and record the decisions taken.
+///
+/// This is the same as [`resolve`], but also returns a [`ResolutionTrace`] with the intermediate
+/// results of each step of the algorithm, to find out why an event was picked over another.
+#[allow(clippy::type_complexity)]
+pub fn resolve_with_trace<'a, E, SetIter>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: impl Fn(&EventId) -> Option<E>,
+) -> Result<(StateMap<E::Id>, ResolutionTrace<E::Id>)>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+{
+    let mut trace = ResolutionTrace::new();
+    let resolved = resolve_inner(
+        RoomVersion::new(room_version),
+        state_sets,
+        auth_chain_sets,
+        fetch_event,
+        Some(&mut trace),
+    )?;
+    trace.resolved_state = StateEntry::from_state_map(&resolved);
+
+    Ok((resolved, trace))
+}
+
+/// The implementation of [`resolve`], [`resolve_with_rules`] and [`resolve_with_trace`].
+///
+/// An unsupported `room_version` is only an error if there is conflicting state.
+fn resolve_inner<'a, E, SetIter>(
+    room_version: Result<RoomVersion>,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: impl Fn(&EventId) -> Option<E>,
+    mut resolution_trace: Option<&mut ResolutionTrace<E::Id>>,
+) -> Result<StateMap<E::Id>>
 where
     E: Event + Clone,
     E::Id: 'a,
@@ -71,17 +151,236 @@ where
     info!("non conflicting events: {}", clean.len());
     trace!("{clean:?}");
 
+    if let Some(resolution_trace) = resolution_trace.as_deref_mut() {
+        resolution_trace.unconflicted_state = StateEntry::from_state_map(&clean);
+    }
+
     if conflicting.is_empty() {
         info!("no conflicting state found");
         return Ok(clean);
     }
 
+    resolve_conflicted(
+        &room_version?,
+        clean,
+        conflicting,
+        get_auth_chain_diff(auth_chain_sets),
+        &mut HashMap::new(),
+        fetch_event,
+        resolution_trace,
+    )
+}
+
+/// Resolve sets of state events, fetching events asynchronously.
+///
+/// This is the same algorithm as [`resolve`], but the events are loaded with the `fetch_event`
+/// future before resolving the conflicts, so it can be used with an asynchronous database. Only the
+/// events the algorithm looks at are loaded: the full conflicted set, the unconflicted state needed
+/// to authorize it, and the auth events reached while sorting it. They are requested concurrently,
+/// in a few rounds.
+///
+/// `fetch_event` should return [`Error::NotFound`] for events that are not known, they are
+/// ignored like events for which the `fetch_event` closure of [`resolve`] returns `None`. Any other
+/// error aborts the resolution and is returned as is.
+pub async fn resolve_async<'a, E, SetIter, Fetch, Fut>(
+    room_version: &RoomVersionId,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: Fetch,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    Fetch: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Result<E>>,
+{
+    resolve_async_inner(RoomVersion::new(room_version), state_sets, auth_chain_sets, fetch_event)
+        .await
+}
+
+/// Resolve sets of state events with the given room version rules, fetching events
+/// asynchronously.
+///
+/// This is the same as [`resolve_async`], with the rules of the room version provided directly, to
+/// support custom room versions.
+pub async fn resolve_async_with_rules<'a, E, SetIter, Fetch, Fut>(
+    rules: &RoomVersionRules,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: Fetch,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    Fetch: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Result<E>>,
+{
+    let room_version = RoomVersion::from_rules(rules);
+    resolve_async_inner(Ok(room_version), state_sets, auth_chain_sets, fetch_event).await
+}
+
+/// The implementation of [`resolve_async`] and [`resolve_async_with_rules`].
+async fn resolve_async_inner<'a, E, SetIter, Fetch, Fut>(
+    room_version: Result<RoomVersion>,
+    state_sets: impl IntoIterator<IntoIter = SetIter>,
+    auth_chain_sets: Vec<HashSet<E::Id>>,
+    fetch_event: Fetch,
+) -> Result<StateMap<E::Id>>
+where
+    E: Event + Clone,
+    E::Id: 'a,
+    SetIter: Iterator<Item = &'a StateMap<E::Id>> + Clone,
+    Fetch: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Result<E>>,
+{
+    info!("State resolution starting");
+
+    // Split non-conflicting and conflicting state
+    let (clean, conflicting) = separate(state_sets.into_iter());
+
+    info!("non conflicting events: {}", clean.len());
+    trace!("{clean:?}");
+
+    if conflicting.is_empty() {
+        info!("no conflicting state found");
+        return Ok(clean);
+    }
+
+    let room_version = room_version?;
+
+    let auth_chain_diff = get_auth_chain_diff(auth_chain_sets).collect::<Vec<_>>();
+
+    // Events that are not found are kept as `None`, so they are not requested again.
+    let mut events = HashMap::new();
+
+    // The full conflicted set.
+    let conflicted = fetch_missing_events(
+        auth_chain_diff.iter().chain(conflicting.values().flatten()).cloned(),
+        &fetch_event,
+        &mut events,
+    )
+    .await?;
+
+    // The unconflicted state needed to authorize the conflicted events.
+    let mut auth_state = Vec::new();
+    for event_id in &conflicted {
+        let Some(Some(event)) = events.get(event_id.borrow()) else { continue };
+        for key in auth_types_for_event(
+            event.event_type(),
+            event.sender(),
+            event.state_key(),
+            event.content(),
+        )? {
+            auth_state.extend(clean.get(&key).cloned());
+        }
+    }
+    let auth_state = fetch_missing_events(auth_state, &fetch_event, &mut events).await?;
+
+    // The auth events of the events above, to authorize them and to find the power levels of
+    // their senders, and the chain of power levels events of the mainline.
+    let mut to_follow = conflicted.into_iter().chain(auth_state).collect::<Vec<_>>();
+    while !to_follow.is_empty() {
+        let auth_events = to_follow
+            .iter()
+            .filter_map(|event_id| events.get(event_id.borrow()).and_then(Option::as_ref))
+            .flat_map(|event| event.auth_events().cloned())
+            .collect::<Vec<_>>();
+
+        to_follow = fetch_missing_events(auth_events, &fetch_event, &mut events).await?;
+        to_follow.retain(|event_id| {
+            events.get(event_id.borrow()).and_then(Option::as_ref).is_some_and(|event| {
+                is_type_and_key(event, &TimelineEventType::RoomPowerLevels, "")
+            })
+        });
+    }
+
+    resolve_conflicted(
+        &room_version,
+        clean,
+        conflicting,
+        auth_chain_diff.into_iter(),
+        &mut HashMap::new(),
+        |id| events.get(id).cloned().flatten(),
+        None,
+    )
+}
+
+/// Fetch the events with the given IDs that are not in `events` yet, concurrently.
+///
+/// Events that are not found are inserted as `None`. Returns the IDs of the events that were found.
+async fn fetch_missing_events<E, Fetch, Fut>(
+    event_ids: impl IntoIterator<Item = E::Id>,
+    fetch_event: &Fetch,
+    events: &mut HashMap<E::Id, Option<E>>,
+) -> Result<Vec<E::Id>>
+where
+    E: Event,
+    Fetch: Fn(E::Id) -> Fut,
+    Fut: Future<Output = Result<E>>,
+{
+    let event_ids = event_ids
+        .into_iter()
+        .filter(|event_id| !events.contains_key(event_id.borrow()))
+        .unique()
+        .collect::<Vec<_>>();
+
+    if event_ids.is_empty() {
+        return Ok(event_ids);
+    }
+
+    debug!("fetching {} events", event_ids.len());
+    let fetched = join_all(event_ids.iter().map(|event_id| fetch_event(event_id.clone()))).await;
+
+    let mut found = Vec::new();
+    for (event_id, result) in event_ids.into_iter().zip(fetched) {
+        match result {
+            Ok(event) => {
+                events.insert(event_id.clone(), Some(event));
+                found.push(event_id);
+            }
+            Err(Error::NotFound(_)) => {
+                debug!("event {event_id} not found");
+                events.insert(event_id, None);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(found)
+}
+
+/// Resolve the `conflicting` state on top of the `clean` (unconflicted) state.
+///
+/// This is the part of the algorithm shared by [`resolve`] and [`StateResolver`].
+/// `power_levels` caches the sender power level of each event taking part in the reverse
+/// topological power sort, it can be reused across calls for the same room.
+///
+/// The intermediate results are recorded in `resolution_trace`, if any.
+fn resolve_conflicted<E: Event + Clone>(
+    room_version: &RoomVersion,
+    clean: StateMap<E::Id>,
+    conflicting: StateMap<Vec<E::Id>>,
+    auth_chain_diff: impl Iterator<Item = E::Id>,
+    power_levels: &mut HashMap<E::Id, Int>,
+    fetch_event: impl Fn(&EventId) -> Option<E>,
+    mut resolution_trace: Option<&mut ResolutionTrace<E::Id>>,
+) -> Result<StateMap<E::Id>> {
     info!("conflicting events: {}", conflicting.len());
     debug!("{conflicting:?}");
 
+    let auth_chain_diff = auth_chain_diff.collect::<Vec<_>>();
+
+    if let Some(resolution_trace) = resolution_trace.as_deref_mut() {
+        resolution_trace.conflicted_state = ConflictedStateEntry::from_state_map(&conflicting);
+        resolution_trace.auth_difference = auth_chain_diff.iter().cloned().sorted().collect();
+    }
+
     // `all_conflicted` contains unique items
     // synapse says `full_set = {eid for eid in full_conflicted_set if eid in event_map}`
-    let all_conflicted: HashSet<_> = get_auth_chain_diff(auth_chain_sets)
+    let all_conflicted: HashSet<_> = auth_chain_diff
+        .into_iter()
         .chain(conflicting.into_values().flatten())
         // Don't honor events we cannot "verify"
         .filter(|id| fetch_event(id.borrow()).is_some())
@@ -90,6 +389,10 @@ where
     info!("full conflicted set: {}", all_conflicted.len());
     debug!("{all_conflicted:?}");
 
+    if let Some(resolution_trace) = resolution_trace.as_deref_mut() {
+        resolution_trace.full_conflicted_set = all_conflicted.iter().cloned().sorted().collect();
+    }
+
     // We used to check that all events are events from the correct room
     // this is now a check the caller of `resolve` must make.
 
@@ -101,16 +404,34 @@ where
         .collect::<Vec<_>>();
 
     // Sort the control events based on power_level/clock/event_id and outgoing/incoming edges
-    let sorted_control_levels =
-        reverse_topological_power_sort(control_events, &all_conflicted, &fetch_event)?;
+    let sorted_control_levels = reverse_topological_power_sort(
+        control_events,
+        &all_conflicted,
+        power_levels,
+        &fetch_event,
+    )?;
 
     debug!("sorted control events: {}", sorted_control_levels.len());
     trace!("{sorted_control_levels:?}");
 
-    let room_version = RoomVersion::new(room_version)?;
+    if let Some(resolution_trace) = resolution_trace.as_deref_mut() {
+        resolution_trace.power_ordering = sorted_control_levels
+            .iter()
+            .map(|event_id| PowerOrderingEntry {
+                event_id: event_id.clone(),
+                sender_power_level: power_levels[event_id.borrow()],
+            })
+            .collect();
+    }
+
     // Sequentially auth check each control event.
-    let resolved_control =
-        iterative_auth_check(&room_version, &sorted_control_levels, clean.clone(), &fetch_event)?;
+    let resolved_control = iterative_auth_check(
+        room_version,
+        &sorted_control_levels,
+        clean.clone(),
+        &fetch_event,
+        resolution_trace.as_deref_mut(),
+    )?;
 
     debug!("resolved control events: {}", resolved_control.len());
     trace!("{resolved_control:?}");
@@ -135,15 +456,21 @@ where
 
     debug!("power event: {power_event:?}");
 
-    let sorted_left_events = mainline_sort(&events_to_resolve, power_event.cloned(), &fetch_event)?;
+    let sorted_left_events = mainline_sort(
+        &events_to_resolve,
+        power_event.cloned(),
+        &fetch_event,
+        resolution_trace.as_deref_mut(),
+    )?;
 
     trace!("events left, sorted: {sorted_left_events:?}");
 
     let mut resolved_state = iterative_auth_check(
-        &room_version,
+        room_version,
         &sorted_left_events,
         resolved_control, // The control events are added to the final resolved state
         &fetch_event,
+        resolution_trace,
     )?;
 
     // Add unconflicted state to the resolved state
@@ -212,6 +539,7 @@ where
 fn reverse_topological_power_sort<E: Event>(
     events_to_sort: Vec<E::Id>,
     auth_diff: &HashSet<E::Id>,
+    power_levels: &mut HashMap<E::Id, Int>,
     fetch_event: impl Fn(&EventId) -> Option<E>,
 ) -> Result<Vec<E::Id>> {
     debug!("reverse topological sort of power events");
@@ -226,12 +554,15 @@ fn reverse_topological_power_sort<E: Event>(
     }
 
     // This is used in the `key_fn` passed to the lexico_topo_sort fn
-    let mut event_to_pl = HashMap::new();
     for event_id in graph.keys() {
+        if power_levels.contains_key(event_id.borrow()) {
+            continue;
+        }
+
         let pl = get_power_level_for_sender(event_id.borrow(), &fetch_event)?;
         info!("{event_id} power level {pl}");
 
-        event_to_pl.insert(event_id.clone(), pl);
+        power_levels.insert(event_id.clone(), pl);
 
         // TODO: if these functions are ever made async here
         // is a good place to yield every once in a while so other
@@ -240,7 +571,7 @@ fn reverse_topological_power_sort<E: Event>(
 
     lexicographical_topological_sort(&graph, |event_id| {
         let ev = fetch_event(event_id).ok_or_else(|| Error::NotFound("".into()))?;
-        let pl = *event_to_pl.get(event_id).ok_or_else(|| Error::NotFound("".into()))?;
+        let pl = *power_levels.get(event_id).ok_or_else(|| Error::NotFound("".into()))?;
         Ok((pl, ev.origin_server_ts()))
     })
 }
@@ -386,6 +717,7 @@ fn iterative_auth_check<E: Event + Clone>(
     events_to_check: &[E::Id],
     unconflicted_state: StateMap<E::Id>,
     fetch_event: impl Fn(&EventId) -> Option<E>,
+    mut resolution_trace: Option<&mut ResolutionTrace<E::Id>>,
 ) -> Result<StateMap<E::Id>> {
     info!("starting iterative auth check");
 
@@ -438,14 +770,25 @@ fn iterative_auth_check<E: Event + Clone>(
             (*pdu.event_type() == TimelineEventType::RoomThirdPartyInvite).then_some(pdu)
         });
 
-        if auth_check(room_version, &event, current_third_party, |ty, key| {
+        let verdict = auth_check_detailed(room_version, &event, current_third_party, |ty, key| {
             auth_events.get(&ty.with_state_key(key))
-        })? {
-            // add event to resolved state map
-            resolved_state.insert(event.event_type().with_state_key(state_key), event_id.clone());
-        } else {
-            // synapse passes here on AuthError. We do not add this event to resolved_state.
-            warn!("event {event_id} failed the authentication check");
+        })?;
+        match &verdict {
+            Ok(()) => {
+                // add event to resolved state map
+                resolved_state
+                    .insert(event.event_type().with_state_key(state_key), event_id.clone());
+            }
+            Err(rejection) => {
+                // synapse passes here on AuthError. We do not add this event to resolved_state.
+                warn!("event {event_id} failed the authentication check: {rejection}");
+            }
+        }
+
+        if let Some(resolution_trace) = resolution_trace.as_deref_mut() {
+            resolution_trace
+                .auth_checks
+                .push(AuthCheckVerdict { event_id: event_id.clone(), rejection: verdict.err() });
         }
 
         // TODO: if these functions are ever made async here
@@ -466,6 +809,7 @@ fn mainline_sort<E: Event>(
     to_sort: &[E::Id],
     resolved_power_level: Option<E::Id>,
     fetch_event: impl Fn(&EventId) -> Option<E>,
+    resolution_trace: Option<&mut ResolutionTrace<E::Id>>,
 ) -> Result<Vec<E::Id>> {
     debug!("mainline sort of events");
 
@@ -523,6 +867,17 @@ fn mainline_sort<E: Event>(
     let mut sort_event_ids = order_map.keys().map(|&k| k.clone()).collect::<Vec<_>>();
     sort_event_ids.sort_by_key(|sort_id| order_map.get(sort_id).unwrap());
 
+    if let Some(resolution_trace) = resolution_trace {
+        resolution_trace.mainline_ordering = sort_event_ids
+            .iter()
+            .map(|event_id| MainlinePosition {
+                event_id: event_id.clone(),
+                mainline_position: order_map[event_id].0,
+            })
+            .collect();
+        resolution_trace.mainline = mainline;
+    }
+
     Ok(sort_event_ids)
 }
 
@@ -637,14 +992,19 @@ where
 #[cfg(test)]
 mod tests {
     use std::{
+        cell::RefCell,
         collections::{HashMap, HashSet},
         sync::Arc,
     };
 
+    use assert_matches2::assert_matches;
     use js_int::{int, uint};
     use maplit::{hashmap, hashset};
     use rand::seq::SliceRandom;
-    use ruma_common::{MilliSecondsSinceUnixEpoch, OwnedEventId, RoomVersionId};
+    use ruma_common::{
+        room_version_rules::RoomVersionRules, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId,
+        RoomVersionId,
+    };
     use ruma_events::{
         room::join_rules::{JoinRule, RoomJoinRulesEventContent},
         StateEventType, TimelineEventType,
@@ -659,7 +1019,7 @@ mod tests {
             alice, bob, charlie, do_check, ella, event_id, member_content_ban, member_content_join,
             room_id, to_init_pdu_event, to_pdu_event, zara, PduEvent, TestStore, INITIAL_EVENTS,
         },
-        Event, EventTypeExt, StateMap,
+        Error, Event, EventTypeExt, StateMap,
     };
 
     fn test_event_sort() {
@@ -680,17 +1040,20 @@ mod tests {
             .map(|pdu| pdu.event_id.clone())
             .collect::<Vec<_>>();
 
-        let sorted_power_events =
-            crate::reverse_topological_power_sort(power_events, &auth_chain, |id| {
-                events.get(id).map(Arc::clone)
-            })
-            .unwrap();
+        let sorted_power_events = crate::reverse_topological_power_sort(
+            power_events,
+            &auth_chain,
+            &mut HashMap::new(),
+            |id| events.get(id).map(Arc::clone),
+        )
+        .unwrap();
 
         let resolved_power = crate::iterative_auth_check(
             &RoomVersion::V6,
             &sorted_power_events,
             HashMap::new(), // unconflicted events
             |id| events.get(id).map(Arc::clone),
+            None,
         )
         .expect("iterative auth check failed on resolved events");
 
@@ -702,9 +1065,13 @@ mod tests {
         let power_level =
             resolved_power.get(&(StateEventType::RoomPowerLevels, "".to_owned())).cloned();
 
-        let sorted_event_ids =
-            crate::mainline_sort(&events_to_sort, power_level, |id| events.get(id).map(Arc::clone))
-                .unwrap();
+        let sorted_event_ids = crate::mainline_sort(
+            &events_to_sort,
+            power_level,
+            |id| events.get(id).map(Arc::clone),
+            None,
+        )
+        .unwrap();
 
         assert_eq!(
             vec![
@@ -1065,6 +1432,206 @@ mod tests {
         assert_eq!(expected, resolved);
     }
 
+    #[test]
+    fn custom_room_version() {
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let ev_map = store.0.clone();
+        let state_sets = [state_at_bob, state_at_charlie];
+        let auth_chain_sets = || {
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect()
+        };
+        let fetch_event = |id: &EventId| ev_map.get(id).map(Arc::clone);
+
+        let custom_version = RoomVersionId::try_from("org.example.v11").unwrap();
+        assert_matches!(
+            crate::resolve(&custom_version, &state_sets, auth_chain_sets(), fetch_event),
+            Err(Error::Unsupported(_))
+        );
+
+        let resolved = crate::resolve_with_rules(
+            &RoomVersionRules::V11,
+            &state_sets,
+            auth_chain_sets(),
+            fetch_event,
+        )
+        .unwrap();
+        assert_eq!(resolved, expected);
+        assert_eq!(
+            resolved,
+            crate::resolve(&RoomVersionId::V11, &state_sets, auth_chain_sets(), fetch_event)
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn resolve_async_event_map_none() {
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let state_sets = [state_at_bob, state_at_charlie];
+        let resolved = crate::resolve_async(
+            &RoomVersionId::V2,
+            &state_sets,
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect(),
+            |id: OwnedEventId| {
+                let event = store.get_event(room_id(), &id);
+                async move { event }
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(expected, resolved);
+    }
+
+    #[tokio::test]
+    async fn resolve_async_fetches_only_needed_events() {
+        let mut events = INITIAL_EVENTS();
+        let topic = to_pdu_event(
+            "T1",
+            alice(),
+            TimelineEventType::RoomTopic,
+            Some(""),
+            to_raw_json_value(&json!({ "topic": "Alice's room" })).unwrap(),
+            &["CREATE", "IMA", "IPOWER"],
+            &["IPOWER"],
+        );
+        events.insert(topic.event_id().to_owned(), topic);
+        let store = TestStore(events.clone());
+
+        let state_set = |ids: &[&str]| {
+            ids.iter()
+                .map(|&id| {
+                    let ev = &events[&event_id(id)];
+                    (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+                })
+                .collect::<StateMap<_>>()
+        };
+        let state_sets = [
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMB", "T1"]),
+            state_set(&["CREATE", "IMA", "IPOWER", "IJR", "IMC", "T1"]),
+        ];
+        let auth_chain_sets = || {
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let fetched = RefCell::new(Vec::new());
+        let resolved = crate::resolve_async(
+            &RoomVersionId::V6,
+            &state_sets,
+            auth_chain_sets(),
+            |id: OwnedEventId| {
+                fetched.borrow_mut().push(id.clone());
+                let event = store.get_event(room_id(), &id);
+                async move { event }
+            },
+        )
+        .await
+        .unwrap();
+
+        let expected = crate::resolve(&RoomVersionId::V6, &state_sets, auth_chain_sets(), |id| {
+            events.get(id).cloned()
+        })
+        .unwrap();
+        assert_eq!(resolved, expected);
+
+        // The topic is not needed to authorize the conflicted membership events.
+        let mut fetched = fetched.into_inner();
+        fetched.sort();
+        assert_eq!(fetched, ["CREATE", "IJR", "IMA", "IMB", "IMC", "IPOWER"].map(event_id));
+    }
+
+    #[tokio::test]
+    async fn resolve_async_fetch_error() {
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+        let (state_at_bob, state_at_charlie, _) = store.set_up();
+
+        let state_sets = [state_at_bob, state_at_charlie];
+        let result = crate::resolve_async(
+            &RoomVersionId::V2,
+            &state_sets,
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect(),
+            |id: OwnedEventId| {
+                let event = if id == event_id("IMB") {
+                    Err(Error::fetch(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "connection reset",
+                    )))
+                } else {
+                    store.get_event(room_id(), &id)
+                };
+                async move { event }
+            },
+        )
+        .await;
+
+        assert_matches!(result, Err(Error::Fetch(_)));
+    }
+
+    #[test]
+    fn resolve_with_trace() {
+        let mut store = TestStore::<PduEvent>(hashmap! {});
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let ev_map = store.0.clone();
+        let state_sets = [state_at_bob, state_at_charlie];
+        let (resolved, trace) = crate::resolve_with_trace(
+            &RoomVersionId::V6,
+            &state_sets,
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect(),
+            |id| ev_map.get(id).map(Arc::clone),
+        )
+        .unwrap();
+
+        assert_eq!(resolved, expected);
+        assert_eq!(trace.unconflicted_state.len(), 3);
+        assert_eq!(trace.conflicted_state.len(), 2);
+        assert_eq!(trace.auth_difference, vec![event_id("IMB"), event_id("IMC")]);
+        assert_eq!(trace.full_conflicted_set, vec![event_id("IMB"), event_id("IMC")]);
+        assert!(trace.power_ordering.is_empty());
+        assert_eq!(trace.mainline_ordering.len(), 2);
+        assert_eq!(trace.auth_checks.len(), 2);
+        assert!(trace.auth_checks.iter().all(|verdict| verdict.rejection.is_none()));
+        assert_eq!(trace.resolved_state.len(), 5);
+
+        let json = serde_json::to_value(&trace).unwrap();
+        assert_eq!(
+            json["conflicted_state"][0],
+            json!({
+                "event_type": "m.room.member",
+                "state_key": "@bob:foo",
+                "event_ids": ["$IMB:foo"],
+            })
+        );
+    }
+
     #[test]
     fn test_lexicographical_sort() {
         let _ =
@@ -0,0 +1,334 @@
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use js_int::Int;
+use ruma_common::{room_version_rules::RoomVersionRules, RoomVersionId};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    get_auth_chain_diff, resolve_conflicted, separate, Event, Result, RoomVersion, StateMap,
+};
+
+/// A source of events for the [`StateResolver`].
+///
+/// The resolver computes the auth chain of every event it sees and memoises it, so implementors
+/// only need to be able to load events. Events are requested in batches: every call contains all
+/// the events the resolver needs next, allowing implementors backed by a database to fetch them
+/// with a single query.
+pub trait AuthChainProvider {
+    /// The type of event this provider returns.
+    type Event: Event + Clone;
+
+    /// Fetch the events with the given IDs.
+    ///
+    /// Events that are unknown to the provider should be left out of the returned list, they are
+    /// treated like events that failed to be fetched by the `fetch_event` closure of [`resolve`].
+    ///
+    /// [`resolve`]: crate::resolve
+    fn fetch_events(&self, event_ids: &[<Self::Event as Event>::Id]) -> Result<Vec<Self::Event>>;
+}
+
+/// The type of the event IDs of an [`AuthChainProvider`].
+type IdOf<P> = <<P as AuthChainProvider>::Event as Event>::Id;
+
+/// Stateful state resolution for a single room.
+///
+/// Unlike [`resolve`], which needs the complete auth chain of every state set on each call, the
+/// `StateResolver` computes auth chains itself and keeps them, along with the fetched events and
+/// the power level of event senders, for subsequent resolutions. Subsequent resolutions only need
+/// to fetch the events that were not seen before.
+///
+/// The caches are never evicted, so a resolver should not outlive the processing of a room.
+///
+/// [`resolve`]: crate::resolve
+pub struct StateResolver<P: AuthChainProvider> {
+    room_version: RoomVersion,
+    provider: P,
+    events: HashMap<IdOf<P>, P::Event>,
+    auth_chains: HashMap<IdOf<P>, Arc<HashSet<IdOf<P>>>>,
+    power_levels: HashMap<IdOf<P>, Int>,
+}
+
+impl<P: AuthChainProvider> StateResolver<P> {
+    /// Creates a new `StateResolver` for a room of the given version.
+    ///
+    /// Returns an error if the room version is not supported.
+    pub fn new(room_version: &RoomVersionId, provider: P) -> Result<Self> {
+        Ok(Self::with_room_version(RoomVersion::new(room_version)?, provider))
+    }
+
+    /// Creates a new `StateResolver` for a room with the given room version rules.
+    ///
+    /// This can be used to support custom room versions, that are unknown to
+    /// [`StateResolver::new`].
+    pub fn with_rules(rules: &RoomVersionRules, provider: P) -> Self {
+        Self::with_room_version(RoomVersion::from_rules(rules), provider)
+    }
+
+    fn with_room_version(room_version: RoomVersion, provider: P) -> Self {
+        Self {
+            room_version,
+            provider,
+            events: HashMap::new(),
+            auth_chains: HashMap::new(),
+            power_levels: HashMap::new(),
+        }
+    }
+
+    /// Get a reference to the event provider.
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+
+    /// Returns the auth chain of the event with the given ID, not including the event itself.
+    ///
+    /// The auth chain of every event on the way is memoised.
+    pub fn auth_chain(&mut self, event_id: &IdOf<P>) -> Result<Arc<HashSet<IdOf<P>>>> {
+        self.compute_auth_chains(vec![event_id.clone()])?;
+        Ok(self.auth_chains[event_id.borrow()].clone())
+    }
+
+    /// Resolve sets of state events.
+    ///
+    /// This behaves like [`resolve`], with the auth chains of the state sets computed by the
+    /// resolver and the events loaded through the [`AuthChainProvider`].
+    ///
+    /// [`resolve`]: crate::resolve
+    pub fn resolve<'a, SetIter>(
+        &mut self,
+        state_sets: impl IntoIterator<IntoIter = SetIter>,
+    ) -> Result<StateMap<IdOf<P>>>
+    where
+        IdOf<P>: 'a,
+        SetIter: Iterator<Item = &'a StateMap<IdOf<P>>> + Clone,
+    {
+        info!("State resolution starting");
+
+        let state_sets = state_sets.into_iter();
+        let (clean, conflicting) = separate(state_sets.clone());
+
+        info!("non conflicting events: {}", clean.len());
+        trace!("{clean:?}");
+
+        if conflicting.is_empty() {
+            info!("no conflicting state found");
+            return Ok(clean);
+        }
+
+        self.compute_auth_chains(
+            state_sets.clone().flat_map(|set| set.values()).cloned().collect(),
+        )?;
+
+        let auth_chain_sets = state_sets
+            .map(|set| {
+                let mut auth_chain = HashSet::new();
+                for event_id in set.values() {
+                    auth_chain.insert(event_id.clone());
+                    auth_chain.extend(self.auth_chains[event_id.borrow()].iter().cloned());
+                }
+                auth_chain
+            })
+            .collect();
+
+        let events = &self.events;
+        resolve_conflicted(
+            &self.room_version,
+            clean,
+            conflicting,
+            get_auth_chain_diff(auth_chain_sets),
+            &mut self.power_levels,
+            |id| events.get(id).cloned(),
+            None,
+        )
+    }
+
+    /// Make sure the auth chains of the given events are memoised.
+    fn compute_auth_chains(&mut self, event_ids: Vec<IdOf<P>>) -> Result<()> {
+        // Load all the events we don't know the auth chain of yet, one layer of the DAG at a time.
+        // Nothing is cached before all of them are loaded, so an error doesn't leave the caches
+        // with partial auth chains.
+        let mut fetched_events = HashMap::new();
+        let mut missing = Vec::new();
+        let mut to_fetch = event_ids.clone();
+        while !to_fetch.is_empty() {
+            to_fetch.sort_unstable();
+            to_fetch.dedup();
+            to_fetch.retain(|id| {
+                !self.events.contains_key(id.borrow())
+                    && !self.auth_chains.contains_key(id.borrow())
+                    && !fetched_events.contains_key(id.borrow())
+                    && !missing.contains(id)
+            });
+
+            if to_fetch.is_empty() {
+                break;
+            }
+
+            debug!("fetching {} events", to_fetch.len());
+            let mut next = Vec::new();
+            for event in self.provider.fetch_events(&to_fetch)? {
+                next.extend(event.auth_events().cloned());
+                fetched_events.insert(event.event_id().clone(), event);
+            }
+
+            for event_id in to_fetch {
+                if !fetched_events.contains_key(event_id.borrow()) {
+                    warn!("could not find event {event_id}, its auth chain will be empty");
+                    missing.push(event_id);
+                }
+            }
+
+            to_fetch = next;
+        }
+
+        self.events.extend(fetched_events);
+        self.auth_chains.extend(missing.into_iter().map(|id| (id, Arc::default())));
+
+        // Compute the auth chains bottom-up, reusing the ones that are already known.
+        let mut visiting = HashSet::new();
+        let mut stack = event_ids.into_iter().map(|id| (id, false)).collect::<Vec<_>>();
+        while let Some((event_id, visited)) = stack.pop() {
+            if self.auth_chains.contains_key(event_id.borrow())
+                || (!visited && !visiting.insert(event_id.clone()))
+            {
+                continue;
+            }
+
+            let event = &self.events[event_id.borrow()];
+            if visited {
+                let mut auth_chain = HashSet::new();
+                for auth_event_id in event.auth_events() {
+                    auth_chain.insert(auth_event_id.clone());
+                    // This can only be missing if the auth events contain a cycle.
+                    if let Some(chain) = self.auth_chains.get(auth_event_id.borrow()) {
+                        auth_chain.extend(chain.iter().cloned());
+                    }
+                }
+
+                self.auth_chains.insert(event_id, Arc::new(auth_chain));
+            } else {
+                let auth_events = event
+                    .auth_events()
+                    .filter(|id| !self.auth_chains.contains_key((*id).borrow()))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                stack.push((event_id, true));
+                stack.extend(auth_events.into_iter().map(|id| (id, false)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, collections::HashMap, sync::Arc};
+
+    use maplit::hashset;
+    use ruma_common::{OwnedEventId, RoomVersionId};
+
+    use super::{AuthChainProvider, StateResolver};
+    use crate::{
+        test_utils::{event_id, room_id, PduEvent, TestStore, INITIAL_EVENTS},
+        Event, EventTypeExt, Result, StateMap,
+    };
+
+    struct CountingStore {
+        events: HashMap<OwnedEventId, Arc<PduEvent>>,
+        batches: Cell<usize>,
+    }
+
+    impl CountingStore {
+        fn new(events: HashMap<OwnedEventId, Arc<PduEvent>>) -> Self {
+            Self { events, batches: Cell::new(0) }
+        }
+    }
+
+    impl AuthChainProvider for CountingStore {
+        type Event = Arc<PduEvent>;
+
+        fn fetch_events(&self, event_ids: &[OwnedEventId]) -> Result<Vec<Arc<PduEvent>>> {
+            self.batches.set(self.batches.get() + 1);
+            Ok(event_ids.iter().filter_map(|id| self.events.get(id).cloned()).collect())
+        }
+    }
+
+    fn state_set(
+        events: &HashMap<OwnedEventId, Arc<PduEvent>>,
+        ids: &[&str],
+    ) -> StateMap<OwnedEventId> {
+        ids.iter()
+            .map(|id| {
+                let ev = &events[&event_id(id)];
+                (ev.event_type().with_state_key(ev.state_key().unwrap()), ev.event_id.clone())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn auth_chain_is_memoised() {
+        let mut store = TestStore(HashMap::new());
+        store.set_up();
+
+        let mut resolver =
+            StateResolver::new(&RoomVersionId::V6, CountingStore::new(store.0)).unwrap();
+
+        let auth_chain = resolver.auth_chain(&event_id("IMB")).unwrap();
+        assert_eq!(*auth_chain, hashset![event_id("CREATE"), event_id("IJR"), event_id("IMA")]);
+        // One batch per layer of the auth DAG.
+        assert_eq!(resolver.provider().batches.get(), 3);
+
+        let auth_chain = resolver.auth_chain(&event_id("IJR")).unwrap();
+        assert_eq!(*auth_chain, hashset![event_id("CREATE"), event_id("IMA")]);
+        assert_eq!(resolver.provider().batches.get(), 3);
+    }
+
+    #[test]
+    fn same_result_as_resolve() {
+        let events = INITIAL_EVENTS();
+        let store = TestStore(events.clone());
+
+        let state_sets = [
+            state_set(&events, &["CREATE", "IJR", "IMA", "IMB", "IPOWER"]),
+            state_set(&events, &["CREATE", "IJR", "IMA", "IMC", "IPOWER"]),
+        ];
+
+        let expected = crate::resolve(
+            &RoomVersionId::V6,
+            &state_sets,
+            state_sets
+                .iter()
+                .map(|map| {
+                    store.auth_event_ids(room_id(), map.values().cloned().collect()).unwrap()
+                })
+                .collect(),
+            |id| events.get(id).cloned(),
+        )
+        .unwrap();
+
+        let mut resolver =
+            StateResolver::new(&RoomVersionId::V6, CountingStore::new(events.clone())).unwrap();
+        assert_eq!(resolver.resolve(&state_sets).unwrap(), expected);
+    }
+
+    #[test]
+    fn resolve_reuses_caches() {
+        let mut store = TestStore(HashMap::new());
+        let (state_at_bob, state_at_charlie, expected) = store.set_up();
+
+        let mut resolver =
+            StateResolver::new(&RoomVersionId::V6, CountingStore::new(store.0)).unwrap();
+
+        let resolved = resolver.resolve([&state_at_bob, &state_at_charlie]).unwrap();
+        assert_eq!(resolved, expected);
+        let batches = resolver.provider().batches.get();
+
+        // All the events of these state sets were already seen.
+        assert_eq!(resolver.resolve([&resolved, &state_at_charlie]).unwrap(), expected);
+        assert_eq!(resolver.provider().batches.get(), batches);
+    }
+}
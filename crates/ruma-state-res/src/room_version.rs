@@ -1,36 +1,10 @@
-use ruma_common::RoomVersionId;
+pub use ruma_common::room_version_rules::{
+    EventFormatVersion, RoomDisposition, StateResolutionVersion,
+};
+use ruma_common::{room_version_rules::RoomVersionRules, RoomVersionId};
 
 use crate::{Error, Result};
 
-#[derive(Debug)]
-#[allow(clippy::exhaustive_enums)]
-pub enum RoomDisposition {
-    /// A room version that has a stable specification.
-    Stable,
-    /// A room version that is not yet fully specified.
-    Unstable,
-}
-
-#[derive(Debug)]
-#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
-pub enum EventFormatVersion {
-    /// $id:server event id format
-    V1,
-    /// MSC1659-style $hash event id format: introduced for room v3
-    V2,
-    /// MSC1884-style $hash format: introduced for room v4
-    V3,
-}
-
-#[derive(Debug)]
-#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
-pub enum StateResolutionVersion {
-    /// State resolution for rooms at version 1.
-    V1,
-    /// State resolution for room at version 2 or later.
-    V2,
-}
-
 #[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
 pub struct RoomVersion {
     /// The stability of this room.
@@ -82,63 +56,53 @@ pub struct RoomVersion {
 }
 
 impl RoomVersion {
-    pub const V1: Self = Self {
-        disposition: RoomDisposition::Stable,
-        event_format: EventFormatVersion::V1,
-        state_res: StateResolutionVersion::V1,
-        enforce_key_validity: false,
-        special_case_aliases_auth: true,
-        strict_canonicaljson: false,
-        limit_notifications_power_levels: false,
-        extra_redaction_checks: false,
-        allow_knocking: false,
-        restricted_join_rules: false,
-        knock_restricted_join_rule: false,
-        integer_power_levels: false,
-        use_room_create_sender: false,
-    };
+    pub const V1: Self = Self::from_rules(&RoomVersionRules::V1);
 
-    pub const V2: Self = Self { state_res: StateResolutionVersion::V2, ..Self::V1 };
+    pub const V2: Self = Self::from_rules(&RoomVersionRules::V2);
 
-    pub const V3: Self =
-        Self { event_format: EventFormatVersion::V2, extra_redaction_checks: true, ..Self::V2 };
+    pub const V3: Self = Self::from_rules(&RoomVersionRules::V3);
 
-    pub const V4: Self = Self { event_format: EventFormatVersion::V3, ..Self::V3 };
+    pub const V4: Self = Self::from_rules(&RoomVersionRules::V4);
 
-    pub const V5: Self = Self { enforce_key_validity: true, ..Self::V4 };
+    pub const V5: Self = Self::from_rules(&RoomVersionRules::V5);
 
-    pub const V6: Self = Self {
-        special_case_aliases_auth: false,
-        strict_canonicaljson: true,
-        limit_notifications_power_levels: true,
-        ..Self::V5
-    };
+    pub const V6: Self = Self::from_rules(&RoomVersionRules::V6);
 
-    pub const V7: Self = Self { allow_knocking: true, ..Self::V6 };
+    pub const V7: Self = Self::from_rules(&RoomVersionRules::V7);
 
-    pub const V8: Self = Self { restricted_join_rules: true, ..Self::V7 };
+    pub const V8: Self = Self::from_rules(&RoomVersionRules::V8);
 
-    pub const V9: Self = Self::V8;
+    pub const V9: Self = Self::from_rules(&RoomVersionRules::V9);
 
-    pub const V10: Self =
-        Self { knock_restricted_join_rule: true, integer_power_levels: true, ..Self::V9 };
+    pub const V10: Self = Self::from_rules(&RoomVersionRules::V10);
 
-    pub const V11: Self = Self { use_room_create_sender: true, ..Self::V10 };
+    pub const V11: Self = Self::from_rules(&RoomVersionRules::V11);
 
     pub fn new(version: &RoomVersionId) -> Result<Self> {
-        Ok(match version {
-            RoomVersionId::V1 => Self::V1,
-            RoomVersionId::V2 => Self::V2,
-            RoomVersionId::V3 => Self::V3,
-            RoomVersionId::V4 => Self::V4,
-            RoomVersionId::V5 => Self::V5,
-            RoomVersionId::V6 => Self::V6,
-            RoomVersionId::V7 => Self::V7,
-            RoomVersionId::V8 => Self::V8,
-            RoomVersionId::V9 => Self::V9,
-            RoomVersionId::V10 => Self::V10,
-            RoomVersionId::V11 => Self::V11,
-            ver => return Err(Error::Unsupported(format!("found version `{ver}`"))),
-        })
+        match version.rules() {
+            Some(rules) => Ok(Self::from_rules(&rules)),
+            None => Err(Error::Unsupported(format!("found version `{version}`"))),
+        }
+    }
+
+    /// Creates a `RoomVersion` from the [`RoomVersionRules`] of a room version.
+    ///
+    /// This can be used to support custom room versions, that are unknown to [`RoomVersion::new`].
+    pub const fn from_rules(rules: &RoomVersionRules) -> Self {
+        Self {
+            disposition: rules.disposition,
+            event_format: rules.event_format,
+            state_res: rules.state_res,
+            enforce_key_validity: rules.enforce_key_validity,
+            special_case_aliases_auth: rules.authorization.special_case_aliases_auth,
+            strict_canonicaljson: rules.strict_canonical_json,
+            limit_notifications_power_levels: rules.authorization.limit_notifications_power_levels,
+            extra_redaction_checks: rules.authorization.extra_redaction_checks,
+            allow_knocking: rules.authorization.allow_knocking,
+            restricted_join_rules: rules.authorization.restricted_join_rules,
+            knock_restricted_join_rule: rules.authorization.knock_restricted_join_rule,
+            integer_power_levels: rules.authorization.integer_power_levels,
+            use_room_create_sender: rules.authorization.use_room_create_sender,
+        }
     }
 }
@@ -0,0 +1,161 @@
+use js_int::Int;
+use ruma_events::StateEventType;
+use serde::{Deserialize, Serialize};
+
+use crate::{AuthRejection, StateMap};
+
+/// A record of the decisions taken by state resolution, returned by [`resolve_with_trace`].
+///
+/// It can be serialized to compare the resolution of the same state on two servers or to attach
+/// it to a bug report. All the lists that have no inherent order are sorted, so the traces of
+/// identical resolutions are identical.
+///
+/// [`resolve_with_trace`]: crate::resolve_with_trace
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(not(feature = "unstable-exhaustive-types"), non_exhaustive)]
+pub struct ResolutionTrace<Id> {
+    /// The state that is the same in all the state sets.
+    pub unconflicted_state: Vec<StateEntry<Id>>,
+
+    /// The state that differs between the state sets.
+    pub conflicted_state: Vec<ConflictedStateEntry<Id>>,
+
+    /// The events that are in some of the auth chains of the state sets but not in all of them.
+    pub auth_difference: Vec<Id>,
+
+    /// The full conflicted set: the conflicted state and the auth difference, without the events
+    /// that could not be fetched.
+    pub full_conflicted_set: Vec<Id>,
+
+    /// The control events, and the events of the full conflicted set in their auth chains, in
+    /// reverse topological power order.
+    pub power_ordering: Vec<PowerOrderingEntry<Id>>,
+
+    /// The mainline of the resolved `m.room.power_levels` event, starting with that event.
+    pub mainline: Vec<Id>,
+
+    /// The remaining events of the full conflicted set, in mainline order.
+    pub mainline_ordering: Vec<MainlinePosition<Id>>,
+
+    /// The result of the iterative auth checks, first of the control events and then of the
+    /// remaining events, in the order in which they were checked.
+    pub auth_checks: Vec<AuthCheckVerdict<Id>>,
+
+    /// The resolved state.
+    pub resolved_state: Vec<StateEntry<Id>>,
+}
+
+impl<Id> ResolutionTrace<Id> {
+    pub(crate) fn new() -> Self {
+        Self {
+            unconflicted_state: Vec::new(),
+            conflicted_state: Vec::new(),
+            auth_difference: Vec::new(),
+            full_conflicted_set: Vec::new(),
+            power_ordering: Vec::new(),
+            mainline: Vec::new(),
+            mainline_ordering: Vec::new(),
+            auth_checks: Vec::new(),
+            resolved_state: Vec::new(),
+        }
+    }
+}
+
+/// An entry of a state map.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[allow(clippy::exhaustive_structs)]
+pub struct StateEntry<Id> {
+    /// The type of the state event.
+    pub event_type: StateEventType,
+
+    /// The state key of the state event.
+    pub state_key: String,
+
+    /// The ID of the state event.
+    pub event_id: Id,
+}
+
+impl<Id: Clone> StateEntry<Id> {
+    pub(crate) fn from_state_map(state: &StateMap<Id>) -> Vec<Self> {
+        let mut entries = state
+            .iter()
+            .map(|((event_type, state_key), event_id)| Self {
+                event_type: event_type.clone(),
+                state_key: state_key.clone(),
+                event_id: event_id.clone(),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| {
+            (a.event_type.to_string(), &a.state_key).cmp(&(b.event_type.to_string(), &b.state_key))
+        });
+        entries
+    }
+}
+
+/// A type and state key for which the state sets have different events.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[allow(clippy::exhaustive_structs)]
+pub struct ConflictedStateEntry<Id> {
+    /// The type of the state events.
+    pub event_type: StateEventType,
+
+    /// The state key of the state events.
+    pub state_key: String,
+
+    /// The IDs of the conflicting events.
+    pub event_ids: Vec<Id>,
+}
+
+impl<Id: Clone + Ord> ConflictedStateEntry<Id> {
+    pub(crate) fn from_state_map(state: &StateMap<Vec<Id>>) -> Vec<Self> {
+        let mut entries = state
+            .iter()
+            .map(|((event_type, state_key), event_ids)| {
+                let mut event_ids = event_ids.clone();
+                event_ids.sort();
+                event_ids.dedup();
+
+                Self { event_type: event_type.clone(), state_key: state_key.clone(), event_ids }
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| {
+            (a.event_type.to_string(), &a.state_key).cmp(&(b.event_type.to_string(), &b.state_key))
+        });
+        entries
+    }
+}
+
+/// An event sorted by the reverse topological power ordering.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[allow(clippy::exhaustive_structs)]
+pub struct PowerOrderingEntry<Id> {
+    /// The ID of the event.
+    pub event_id: Id,
+
+    /// The power level of the sender of the event, used to break ties.
+    pub sender_power_level: Int,
+}
+
+/// An event sorted by the mainline ordering.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[allow(clippy::exhaustive_structs)]
+pub struct MainlinePosition<Id> {
+    /// The ID of the event.
+    pub event_id: Id,
+
+    /// The index in the mainline, starting at 0 for its oldest event, of the closest
+    /// `m.room.power_levels` event in the auth chain of the event. It is also 0 if there is no
+    /// such event.
+    pub mainline_position: usize,
+}
+
+/// The result of the authorization check of an event.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[allow(clippy::exhaustive_structs)]
+pub struct AuthCheckVerdict<Id> {
+    /// The ID of the event.
+    pub event_id: Id,
+
+    /// Why the event was rejected, or `None` if it was allowed.
+    pub rejection: Option<AuthRejection>,
+}